@@ -1,20 +1,25 @@
 // A minimal implementation of parsing and viewing MJPEG-over-TCP
+// The stream relay on port 5000 requires the token of a paired device, sent here as an Authorization header (see src/stream_relay.rs)
 
 import SwiftUI
 import UIKit
 
 struct MJPEGView: UIViewRepresentable {
     private let url: URL
+    private let token: String
     private var imageView = UIImageView()
 
-    init(url: URL) {
+    init(url: URL, token: String) {
         self.url = url
+        self.token = token
     }
 
     func makeUIView(context: Context) -> UIImageView {
         let delegate = MySessionDelegate(imageView: imageView)
         let session = URLSession(configuration: .default, delegate: delegate, delegateQueue: nil)
-        let task = session.dataTask(with: url)
+        var request = URLRequest(url: url)
+        request.setValue("Bearer \(token)", forHTTPHeaderField: "Authorization")
+        let task = session.dataTask(with: request)
         task.resume()
 
         imageView.contentMode = .scaleAspectFit // Scale the image to fit and center it
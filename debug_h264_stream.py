@@ -1,4 +1,5 @@
 import socket
+import sys
 
 NALU_TYPE_NAMES = {
     1: "Coded slice of a non-IDR picture",
@@ -32,11 +33,13 @@ def get_nalu_type(nalu):
         return NALU_TYPE_NAMES.get(nalu_type_code, f"Unknown ({nalu_type_code})")
     return "Unknown"
 
-def verify_h264_stream(host, port):
+def verify_h264_stream(host, port, token):
     """
     Connect to the TCP server and verify the H.264 stream.
+    The server disconnects clients that don't send the token of a paired device first.
     """
     with socket.create_connection((host, port)) as sock:
+        sock.sendall(f"Authorization: Bearer {token}\r\n".encode())
         print(f"Connected to {host}:{port}")
         buffer = b''
         try:
@@ -61,4 +64,7 @@ def verify_h264_stream(host, port):
 if __name__ == "__main__":
     HOST = '192.168.9.1'  # Replace with the appropriate host
     PORT = 5000       # Replace with the appropriate port
-    verify_h264_stream(HOST, PORT)
+    if len(sys.argv) != 2:
+        print(f"Usage: {sys.argv[0]} <token>, the token of a paired device (POST /api/v1/pairing)")
+        sys.exit(1)
+    verify_h264_stream(HOST, PORT, sys.argv[1])
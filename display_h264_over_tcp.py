@@ -2,12 +2,32 @@
 
 # Import required libraries
 import cv2
+import socket
 import sys
+import threading
 
 
-# Stream URL
-# Replace this with your stream URL
-stream_url = 'tcp://192.168.9.1:5000'
+# Stream address
+# Replace this with your camera's address
+stream_address = ('192.168.9.1', 5000)
+
+# The camera disconnects clients that don't send the token of a paired device first (POST /api/v1/pairing),
+# which OpenCV can't do. So connect here, send the token, and let OpenCV read the stream from a local port.
+if len(sys.argv) != 2:
+    print(f'Usage: {sys.argv[0]} <token>')
+    sys.exit()
+token = sys.argv[1]
+
+def forward(listener):
+    client, _ = listener.accept()
+    with socket.create_connection(stream_address) as camera, client:
+        camera.sendall(f'Authorization: Bearer {token}\r\n'.encode())
+        while data := camera.recv(65536):
+            client.sendall(data)
+
+listener = socket.create_server(('127.0.0.1', 0))
+threading.Thread(target=forward, args=(listener,), daemon=True).start()
+stream_url = f'tcp://127.0.0.1:{listener.getsockname()[1]}'
 
 # Attempt to open the video stream
 try:
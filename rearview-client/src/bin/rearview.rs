@@ -0,0 +1,134 @@
+/*
+Command line client for Velovision Rearview, built on rearview_client.
+
+rearview [--url http://192.168.9.1:8000] [--token <token>] [--pin <certificate sha256>] <command>
+    status                      battery, temperature, mode, stream, recording, storage and uptime
+    health                      worker threads of the server, their heartbeats and last errors
+    videos                      recordings, oldest first
+    download <id> [<file>]      download a recording, resuming an interrupted download. Defaults to <id>.mkv
+    download-all <directory>    download every recording that isn't in the directory yet
+    restart                     restart streaming mode
+    led on|off                  blink the LED or turn it off
+    events                      print events as they happen, one JSON object per line
+    pair <name>                 pair this computer: asks for the code blinked by the LED and prints a token
+    devices                     paired devices
+    revoke <device id>          revoke the token of a paired device
+
+Every command but status, health and events requires a token, from --token or REARVIEW_TOKEN.
+With an https:// URL (port 8443), the camera's certificate must be pinned with --pin or REARVIEW_PIN,
+using the SHA-256 that pair prints. Pair over HTTPS to keep the token from other devices on the hotspot:
+    rearview --url https://192.168.9.1:8443 --pin <certificate sha256 from GET /api/v1/tls> pair laptop
+*/
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process::exit;
+
+use rearview_client::{Client, Error};
+
+const DEFAULT_URL: &str = "http://192.168.9.1:8000";
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let url = option(&mut args, "--url", "REARVIEW_URL").unwrap_or_else(|| DEFAULT_URL.to_string());
+    let client = match option(&mut args, "--pin", "REARVIEW_PIN") {
+        Some(certificate_sha256) => Client::new(&url).with_pinned_certificate(&certificate_sha256),
+        None => Client::new(&url),
+    };
+    let client = match option(&mut args, "--token", "REARVIEW_TOKEN") {
+        Some(token) => client.with_token(&token),
+        None => client,
+    };
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args.as_slice() {
+        ["status"] => client.status().map(|status| print_json(&status)),
+        ["health"] => client.health().map(|health| print_json(&health)),
+        ["videos"] => client.videos().map(|videos| {
+            for video in videos {
+                println!("{}\t{}\t{} bytes", video.id, video.date_updated, video.size_bytes);
+            }
+        }),
+        ["download", id] => download(&client, id, &format!("{}.mkv", id)),
+        ["download", id, file] => download(&client, id, file),
+        ["download-all", directory] => download_all(&client, Path::new(directory)),
+        ["restart"] => client.restart_streaming(),
+        ["led", "on"] => client.set_led(true),
+        ["led", "off"] => client.set_led(false),
+        ["events"] => client.events().and_then(|events| {
+            for event in events {
+                println!("{}", serde_json::to_string(&event?).unwrap_or_default());
+            }
+            Ok(())
+        }),
+        ["pair", name] => pair(&client, name),
+        ["devices"] => client.devices().map(|devices| {
+            for device in devices {
+                println!("{}\t{}\t{}", device.id, device.paired_at, device.name);
+            }
+        }),
+        ["revoke", id] => client.revoke(id),
+        _ => usage(),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn option(args: &mut Vec<String>, flag: &str, variable: &str) -> Option<String> {
+    // "--flag value" takes precedence over the environment variable
+    match args.iter().position(|arg| arg == flag) {
+        Some(position) if position + 1 < args.len() => {
+            let value = args.remove(position + 1);
+            args.remove(position);
+            Some(value)
+        }
+        Some(_) => usage(),
+        None => std::env::var(variable).ok(),
+    }
+}
+
+fn pair(client: &Client, name: &str) -> Result<(), Error> {
+    let pairing = client.pair(name)?;
+    println!("Count the blinks of the camera's LED: {} digits, each blinked as that many quick blinks.", pairing.code_length);
+    print!("Code: ");
+    io::stdout().flush()?;
+    let mut code = String::new();
+    io::stdin().lock().read_line(&mut code)?;
+    let paired = client.confirm_pairing(&pairing.pairing_id, code.trim())?;
+    println!("Paired as {} ({}). Keep this token, it is only shown once:", paired.device.name, paired.device.id);
+    println!("export REARVIEW_TOKEN={}", paired.token);
+    if let Some(tls) = pairing.tls {
+        println!("export REARVIEW_PIN={} # for https://<camera>:{}", tls.certificate_sha256, tls.https_port);
+    }
+    Ok(())
+}
+
+fn download(client: &Client, id: &str, file: &str) -> Result<(), Error> {
+    let size = client.download(id, file)?;
+    println!("{} -> {} ({} bytes)", id, file, size);
+    Ok(())
+}
+
+fn download_all(client: &Client, directory: &Path) -> Result<(), Error> {
+    std::fs::create_dir_all(directory)?;
+    for video in client.videos()? {
+        let file = directory.join(format!("{}.mkv", video.id));
+        // The newest recording may still be growing, so only skip files that are already as large
+        if std::fs::metadata(&file).is_ok_and(|m| m.len() >= video.size_bytes) {
+            continue;
+        }
+        download(client, &video.id, &file.to_string_lossy())?;
+    }
+    Ok(())
+}
+
+fn print_json<T: serde::Serialize>(value: &T) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap_or_default());
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: rearview [--url {}] [--token <token>] [--pin <certificate sha256>] status | videos | download <id> [<file>] | download-all <directory> | restart | led on|off | events | pair <name> | devices | revoke <device id>", DEFAULT_URL);
+    eprintln!("The URL, token and pin can also be set with REARVIEW_URL, REARVIEW_TOKEN and REARVIEW_PIN.");
+    exit(2);
+}
@@ -0,0 +1,421 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+
+use crate::types::{Device, DeviceList, ErrorBody, Event, Health, Led, Paired, Pairing, PairingConfirmation, PairingRequest, Restart, Status, StreamHealth, Video, VideoList};
+
+#[derive(Debug)]
+pub enum Error {
+    Api { status: u16, body: ErrorBody }, // the server answered with an error
+    Transport(String), // the server couldn't be reached, or the connection broke
+    Io(io::Error), // writing a download failed
+    Decode(String), // the server answered with something else than expected
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Api { status, body } => write!(f, "{} {}: {}", status, body.code, body.message),
+            Error::Transport(e) => write!(f, "Failed to reach the camera: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Decode(e) => write!(f, "Unexpected response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<ureq::Error> for Error {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::Status(status, response) => {
+                // Errors outside /api/v1 are plain text
+                let text = response.into_string().unwrap_or_default();
+                let body = serde_json::from_str(&text).unwrap_or(ErrorBody { code: "http_error".to_string(), message: text, details: None });
+                Error::Api { status, body }
+            }
+            ureq::Error::Transport(transport) => Error::Transport(transport.to_string()),
+        }
+    }
+}
+
+pub struct Client {
+    base_url: String,
+    agent: ureq::Agent,
+    token: Option<String>,
+}
+
+impl Client {
+    pub fn new(base_url: &str) -> Self {
+        Client { base_url: base_url.trim_end_matches('/').to_string(), agent: agent_builder().build(), token: None }
+    }
+
+    #[cfg(feature = "https")]
+    pub fn with_pinned_certificate(mut self, certificate_sha256: &str) -> Self {
+        /*
+        For an https:// base URL, e.g. https://192.168.9.1:8443, trusts only the camera's own certificate,
+        with the SHA-256 from Pairing::tls or GET /api/v1/tls.
+        */
+        self.agent = agent_builder().tls_config(crate::pinning::tls_config(certificate_sha256)).build();
+        self
+    }
+
+    pub fn with_token(mut self, token: &str) -> Self {
+        // Required for everything but reading status, see Client::pair
+        self.token = Some(token.to_string());
+        self
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = self.agent.request(method, &format!("{}{}", self.base_url, path));
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, Error> {
+        let response = self.request("GET", path).call()?;
+        response.into_json().map_err(|e| Error::Decode(e.to_string()))
+    }
+
+    fn send<T: DeserializeOwned>(&self, method: &str, path: &str, body: impl serde::Serialize) -> Result<T, Error> {
+        let response = self.request(method, path).send_json(body)?;
+        response.into_json().map_err(|e| Error::Decode(e.to_string()))
+    }
+
+    pub fn status(&self) -> Result<Status, Error> {
+        self.get("/api/v1/status")
+    }
+
+    pub fn health(&self) -> Result<Health, Error> {
+        // An unhealthy camera answers 503 with the same document
+        let response = match self.request("GET", "/health").call() {
+            Ok(response) | Err(ureq::Error::Status(503, response)) => response,
+            Err(e) => return Err(e.into()),
+        };
+        response.into_json().map_err(|e| Error::Decode(e.to_string()))
+    }
+
+    pub fn stream_health(&self) -> Result<StreamHealth, Error> {
+        #[derive(serde_derive::Deserialize)]
+        struct StreamStatus {
+            health: StreamHealth,
+        }
+        self.get::<StreamStatus>("/api/v1/stream").map(|status| status.health)
+    }
+
+    pub fn videos(&self) -> Result<Vec<Video>, Error> {
+        // Oldest first
+        self.get::<VideoList>("/api/v1/videos").map(|list| list.videos)
+    }
+
+    pub fn download<P: AsRef<Path>>(&self, id: &str, destination: P) -> Result<u64, Error> {
+        /*
+        Downloads to <destination>.part and renames it once complete.
+        An interrupted download is resumed from the end of the .part file with a Range request, with If-Range set to
+        the ETag of the first response, kept in <destination>.part.etag. The camera overwrites recordings in a loop,
+        so if the recording changed since, the camera sends all of it and the download starts over.
+        Returns the size of the recording.
+        */
+        let destination = destination.as_ref();
+        let with_suffix = |suffix: &str| {
+            let mut path = destination.as_os_str().to_owned();
+            path.push(suffix);
+            PathBuf::from(path)
+        };
+        let (partial, validator_path) = (with_suffix(".part"), with_suffix(".part.etag"));
+
+        // A .part file without its ETag can't be checked, so it's downloaded again
+        let validator = fs::read_to_string(&validator_path).ok().filter(|validator| !validator.is_empty());
+        let offset = match validator {
+            Some(_) => fs::metadata(&partial).map(|m| m.len()).unwrap_or(0),
+            None => 0,
+        };
+        let mut request = self.request("GET", &format!("/api/v1/videos/{}/file", id));
+        if let (Some(validator), true) = (&validator, offset > 0) {
+            request = request.set("Range", &format!("bytes={}-", offset)).set("If-Range", validator);
+        }
+        let response = match request.call() {
+            Ok(response) => response,
+            // The .part file already holds the whole recording
+            Err(ureq::Error::Status(416, _)) if offset > 0 => {
+                fs::rename(&partial, destination)?;
+                let _ = fs::remove_file(&validator_path);
+                return Ok(offset);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // 206 continues the .part file, 200 means the server sent the whole recording
+        let resuming = response.status() == 206;
+        if !resuming {
+            let validator = response.header("ETag").or(response.header("Last-Modified")).unwrap_or_default();
+            fs::write(&validator_path, validator)?;
+        }
+        let mut file = OpenOptions::new().create(true).write(true).append(resuming).truncate(!resuming).open(&partial)?;
+        io::copy(&mut response.into_reader(), &mut file)?;
+        let size = file.metadata()?.len();
+        drop(file);
+        fs::rename(&partial, destination)?;
+        let _ = fs::remove_file(&validator_path);
+        Ok(size)
+    }
+
+    pub fn restart_streaming(&self) -> Result<(), Error> {
+        // Streaming mode waits for a client again, and the camera falls back to standalone mode if none connects
+        self.send::<Restart>("POST", "/api/v1/stream/restart", serde_json::json!({})).map(|_| ())
+    }
+
+    pub fn set_led(&self, blinking: bool) -> Result<(), Error> {
+        self.send::<Led>("PUT", "/api/v1/led", Led { blinking }).map(|_| ())
+    }
+
+    pub fn pair(&self, name: &str) -> Result<Pairing, Error> {
+        /*
+        Starts pairing: the camera blinks a code on its LED, to be passed to confirm_pairing.
+        Each digit is that many quick blinks, with a long pause between digits.
+        */
+        self.send("POST", "/api/v1/pairing", PairingRequest { name: name.to_string() })
+    }
+
+    pub fn confirm_pairing(&self, pairing_id: &str, code: &str) -> Result<Paired, Error> {
+        // The token in the result is only returned once, keep it for Client::with_token
+        self.send("POST", &format!("/api/v1/pairing/{}/confirm", pairing_id), PairingConfirmation { code: code.to_string() })
+    }
+
+    pub fn devices(&self) -> Result<Vec<Device>, Error> {
+        self.get::<DeviceList>("/api/v1/devices").map(|list| list.devices)
+    }
+
+    pub fn revoke(&self, device_id: &str) -> Result<(), Error> {
+        let response = self.request("DELETE", &format!("/api/v1/devices/{}", device_id)).call()?;
+        response.into_json::<DeviceList>().map(|_| ()).map_err(|e| Error::Decode(e.to_string()))
+    }
+
+    pub fn events(&self) -> Result<EventStream, Error> {
+        let response = self.request("GET", "/events").call()?;
+        Ok(EventStream { reader: BufReader::new(response.into_reader()) })
+    }
+}
+
+fn agent_builder() -> ureq::AgentBuilder {
+    // No read timeout, which would cut off the event stream and long downloads
+    ureq::AgentBuilder::new().timeout_connect(Duration::from_secs(5))
+}
+
+pub struct EventStream {
+    reader: BufReader<Box<dyn Read + Send + Sync + 'static>>,
+}
+
+impl Iterator for EventStream {
+    type Item = Result<Event, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Server-Sent Events: "field: value" lines ending with an empty line. Comments start with ':'.
+        let mut data = String::new();
+        loop {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => (),
+                Err(e) => return Some(Err(Error::Transport(e.to_string()))),
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                if data.is_empty() {
+                    continue;
+                }
+                return Some(serde_json::from_str(&data).map_err(|e| Error::Decode(format!("{}: {}", e, data))));
+            }
+            if let Some(value) = line.strip_prefix("data:") {
+                if !data.is_empty() {
+                    data.push('\n');
+                }
+                data.push_str(value.trim_start());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::{self, JoinHandle};
+    use tiny_http::{Header, Request, Response, ResponseBox, Server};
+
+    fn serve_once<F: FnOnce(&Request) -> ResponseBox + Send + 'static>(handler: F) -> (Client, JoinHandle<()>) {
+        // Answers a single request, asserting inside handler panics the server thread, see join()
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap();
+        let thread = thread::spawn(move || {
+            let request = server.recv().unwrap();
+            let response = handler(&request);
+            request.respond(response).unwrap();
+        });
+        (Client::new(&format!("http://{}", address)), thread)
+    }
+
+    fn header(request: &Request, name: &'static str) -> Option<String> {
+        request.headers().iter().find(|h| h.field.equiv(name)).map(|h| h.value.to_string())
+    }
+
+    fn recording(status: u16, body: &str, etag: &str) -> ResponseBox {
+        Response::from_string(body).with_status_code(status).with_header(Header::from_bytes(&b"ETag"[..], etag.as_bytes()).unwrap()).boxed()
+    }
+
+    struct Destination(PathBuf); // in a directory of its own, removed with it
+
+    impl std::ops::Deref for Destination {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for Destination {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for Destination {
+        fn drop(&mut self) {
+            if let Some(dir) = self.0.parent() {
+                let _ = fs::remove_dir_all(dir);
+            }
+        }
+    }
+
+    fn destination(name: &str) -> Destination {
+        let dir = std::env::temp_dir().join(format!("rearview-client-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Destination(dir.join("loop0001.mkv"))
+    }
+
+    fn sidecar(destination: &Path, suffix: &str) -> PathBuf {
+        let mut path = destination.as_os_str().to_owned();
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+
+    #[test]
+    fn downloads_a_recording() {
+        let (client, server) = serve_once(|request| {
+            assert_eq!(request.url(), "/api/v1/videos/loop0001/file");
+            assert_eq!(header(request, "Range"), None);
+            recording(200, "whole recording", "\"f-1\"")
+        });
+        let destination = destination("fresh");
+        assert_eq!(client.download("loop0001", &destination).unwrap(), 15);
+        server.join().unwrap();
+        assert_eq!(fs::read_to_string(&destination).unwrap(), "whole recording");
+        assert!(!sidecar(&destination, ".part").exists());
+        assert!(!sidecar(&destination, ".part.etag").exists());
+    }
+
+    #[test]
+    fn resumes_the_same_recording() {
+        let destination = destination("resume");
+        fs::write(sidecar(&destination, ".part"), "whole ").unwrap();
+        fs::write(sidecar(&destination, ".part.etag"), "\"f-1\"").unwrap();
+        let (client, server) = serve_once(|request| {
+            assert_eq!(header(request, "Range").as_deref(), Some("bytes=6-"));
+            assert_eq!(header(request, "If-Range").as_deref(), Some("\"f-1\""));
+            recording(206, "recording", "\"f-1\"")
+        });
+        assert_eq!(client.download("loop0001", &destination).unwrap(), 15);
+        server.join().unwrap();
+        assert_eq!(fs::read_to_string(&destination).unwrap(), "whole recording");
+        assert!(!sidecar(&destination, ".part.etag").exists());
+    }
+
+    #[test]
+    fn restarts_when_the_recording_changed() {
+        // The camera overwrote the recording, so it ignores the Range and sends all of it
+        let destination = destination("changed");
+        fs::write(sidecar(&destination, ".part"), "stale bytes").unwrap();
+        fs::write(sidecar(&destination, ".part.etag"), "\"f-1\"").unwrap();
+        let (client, server) = serve_once(|request| {
+            assert_eq!(header(request, "If-Range").as_deref(), Some("\"f-1\""));
+            recording(200, "new", "\"3-2\"")
+        });
+        assert_eq!(client.download("loop0001", &destination).unwrap(), 3);
+        server.join().unwrap();
+        assert_eq!(fs::read_to_string(&destination).unwrap(), "new");
+    }
+
+    #[test]
+    fn does_not_resume_without_a_validator() {
+        let destination = destination("no-validator");
+        fs::write(sidecar(&destination, ".part"), "unknown bytes").unwrap();
+        let (client, server) = serve_once(|request| {
+            assert_eq!(header(request, "Range"), None);
+            recording(200, "whole recording", "\"f-1\"")
+        });
+        assert_eq!(client.download("loop0001", &destination).unwrap(), 15);
+        server.join().unwrap();
+        assert_eq!(fs::read_to_string(&destination).unwrap(), "whole recording");
+    }
+
+    #[test]
+    fn finishes_a_complete_part_file() {
+        let destination = destination("complete");
+        fs::write(sidecar(&destination, ".part"), "whole recording").unwrap();
+        fs::write(sidecar(&destination, ".part.etag"), "\"f-1\"").unwrap();
+        let (client, server) = serve_once(|_| Response::from_string("{}").with_status_code(416).boxed());
+        assert_eq!(client.download("loop0001", &destination).unwrap(), 15);
+        server.join().unwrap();
+        assert_eq!(fs::read_to_string(&destination).unwrap(), "whole recording");
+        assert!(!sidecar(&destination, ".part.etag").exists());
+    }
+
+    #[test]
+    fn maps_api_errors() {
+        let (client, server) = serve_once(|_| {
+            Response::from_string(r#"{"code":"not_found","message":"No recording loop0009","details":null}"#).with_status_code(404).boxed()
+        });
+        match client.download("loop0009", destination("not-found")) {
+            Err(Error::Api { status: 404, body }) => assert_eq!((body.code.as_str(), body.message.as_str()), ("not_found", "No recording loop0009")),
+            other => panic!("expected a 404, got {:?}", other),
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn maps_plain_text_errors() {
+        let (client, server) = serve_once(|_| Response::from_string("Bad gateway").with_status_code(502).boxed());
+        match client.status() {
+            Err(Error::Api { status: 502, body }) => assert_eq!((body.code.as_str(), body.message.as_str()), ("http_error", "Bad gateway")),
+            other => panic!("expected a 502, got {:?}", other),
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn maps_unexpected_bodies() {
+        let (client, server) = serve_once(|_| Response::from_string("<html></html>").boxed());
+        assert!(matches!(client.videos(), Err(Error::Decode(_))));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn maps_unreachable_cameras() {
+        // Nothing listens on the port once the server is dropped
+        let address = Server::http("127.0.0.1:0").unwrap().server_addr().to_ip().unwrap();
+        let client = Client::new(&format!("http://{}", address));
+        assert!(matches!(client.status(), Err(Error::Transport(_))));
+    }
+}
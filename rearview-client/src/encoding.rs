@@ -0,0 +1,92 @@
+/*
+Hex, base64 and random bytes, for tokens, pairing IDs and certificate fingerprints.
+Shared with supreme-server like the types, so the camera and its clients encode them the same way.
+*/
+use std::fs::File;
+use std::io::{self, Read};
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn hex(bytes: &[u8]) -> String {
+    // Lowercase, like certificate_sha256 and tokens
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn base64(data: &[u8]) -> String {
+    // Standard alphabet with padding (RFC 4648)
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    // Padding is optional, anything outside the standard alphabet is invalid
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+    let (mut bits, mut bit_count) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let value = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+        bits = bits << 6 | value;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            bytes.push((bits >> bit_count) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+pub fn random_bytes(count: usize) -> io::Result<Vec<u8>> {
+    // From the kernel's CSPRNG, good enough for tokens
+    let mut bytes = vec![0; count];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_hex() {
+        assert_eq!(hex(&[0x00, 0x0f, 0xab, 0xff]), "000fabff");
+        assert_eq!(hex(&[]), "");
+    }
+
+    #[test]
+    fn encodes_base64() {
+        assert_eq!(base64(b"app:secret"), "YXBwOnNlY3JldA==");
+        assert_eq!(base64(b"a"), "YQ==");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"abc"), "YWJj");
+        assert_eq!(base64(&[0xFB, 0xFF]), "+/8=");
+        assert_eq!(base64(b""), "");
+    }
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(base64_decode("YXBwOnNlY3JldA==").as_deref(), Some(&b"app:secret"[..]));
+        assert_eq!(base64_decode("YQ").as_deref(), Some(&b"a"[..]));
+        assert_eq!(base64_decode("").as_deref(), Some(&b""[..]));
+        assert_eq!(base64_decode("YX*w"), None);
+        for data in [&b"x"[..], b"xy", b"xyz", &[0, 0xFF, 0x80, 0x7F]] {
+            assert_eq!(base64_decode(&base64(data)).as_deref(), Some(data));
+        }
+    }
+
+    #[test]
+    fn reads_random_bytes() {
+        let (a, b) = (random_bytes(16).unwrap(), random_bytes(16).unwrap());
+        assert_eq!(a.len(), 16);
+        assert_ne!(a, b);
+    }
+}
@@ -0,0 +1,23 @@
+/*
+Client for the HTTP API of Velovision Rearview, for ride upload scripts, test harnesses and desktop tools.
+
+    let client = rearview_client::Client::new("http://192.168.9.1:8000").with_token(&token);
+    for video in client.videos()? {
+        client.download(&video.id, format!("{}.mkv", video.id))?;
+    }
+
+The token comes from pairing once, see Client::pair. Over HTTPS, the camera's certificate is pinned instead of trusted:
+    Client::new("https://192.168.9.1:8443").with_pinned_certificate(&certificate_sha256).with_token(&token)
+
+Without the default http feature, only the request and response types in rearview_client::types,
+which supreme-server serializes, so the two can't drift apart, and the hex and base64 helpers in rearview_client::encoding.
+*/
+pub mod encoding;
+pub mod types;
+
+#[cfg(feature = "http")]
+mod client;
+#[cfg(feature = "https")]
+mod pinning;
+#[cfg(feature = "http")]
+pub use client::{Client, Error, EventStream};
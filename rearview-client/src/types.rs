@@ -0,0 +1,340 @@
+/*
+Request and response bodies of the /api/v1 routes, and the events of GET /events.
+
+supreme-server serializes these same types, so a field added or renamed here changes both sides at once.
+Names are plain strings (e.g. the preset "high-quality") rather than the server's enums,
+so that a client keeps working against a server that knows more presets or codecs than it does.
+*/
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+// Every /api/v1 error, with the matching HTTP status
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    pub details: Option<Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Battery {
+    pub percent: i32,
+    pub millivolts: i32,
+    pub charge_rate_percent_per_hour: f32,
+    pub charging: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FuelGauge {
+    pub version: String, // e.g. "0x0012"
+    pub alert: bool, // the ALRT pin was asserted at the latest reading
+    pub alert_threshold_percent: u8,
+    pub reset_millivolts: u16,
+    pub hibernation_enabled: bool, // set with PUT /api/v1/battery/gauge/hibernation, off after a restart
+    pub hibernating: bool,
+    pub flags: Vec<String>, // "reset", "voltage_high", "voltage_low", "voltage_reset", "soc_low" or "soc_change", raised at the latest reading
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuickStart {
+    pub quick_started: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hibernation {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CpuTemp {
+    pub celsius: f32,
+}
+
+// GET /api/v1/status
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Section<T> {
+    // Each section of the status is read on its own, and reports why it couldn't be read instead of its values
+    Ok(T),
+    Error { error: String },
+}
+
+impl<T> Section<T> {
+    pub fn ok(&self) -> Option<&T> {
+        match self {
+            Section::Ok(value) => Some(value),
+            Section::Error { .. } => None,
+        }
+    }
+}
+
+impl<T> From<Result<T, String>> for Section<T> {
+    fn from(result: Result<T, String>) -> Self {
+        match result {
+            Ok(value) => Section::Ok(value),
+            Err(error) => Section::Error { error },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    pub version: String,
+    pub battery: Section<BatteryStatus>,
+    pub cpu_temp: Section<CpuTemp>,
+    pub mode: String, // "streaming", "standalone" or "idle"
+    pub stream: StreamHealth,
+    pub recording: Section<Recording>,
+    pub storage: Section<Storage>,
+    pub uptime: Section<Uptime>,
+    pub workers: Vec<Worker>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatteryStatus {
+    pub percent: i32,
+    pub millivolts: i32,
+    pub charge_rate_percent_per_hour: f32,
+    pub charging: bool,
+    pub valid: bool, // false when the reading is old, or the latest attempt failed
+    pub age_secs: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recording {
+    pub recording: bool,
+    pub chunk_count: usize,
+    pub latest_chunk: Option<Chunk>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chunk {
+    pub path: String,
+    pub size_bytes: u64,
+    pub date_updated: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Storage {
+    pub free_bytes: u64,
+    pub total_bytes: u64,
+    pub free_percent: u64,
+    pub videos_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Uptime {
+    pub system_secs: u64,
+    pub server_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerState {
+    Running,
+    Hung, // running, but its last heartbeat is older than heartbeat_timeout_secs
+    Restarting, // failed, and starts again after restart_in_secs
+    Stopped, // finished on its own, e.g. because what it served went away
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Worker {
+    pub name: String, // "battery", "battery-alert", "thermal", "storage", "mode", "led", "button", "http-priority-1" and -2, "http-1" to -3
+    pub state: WorkerState,
+    pub restarts: u32,
+    pub restart_in_secs: Option<u64>, // while restarting
+    pub last_heartbeat_age_ms: u64,
+    pub heartbeat_timeout_secs: u64,
+    pub last_error: Option<String>,
+}
+
+// GET /health, answered with 503 when the server is unhealthy
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerHealth {
+    Healthy, // every worker runs
+    Degraded, // a worker is failing/restarting, the others carry on
+    Unhealthy, // a worker hangs, and the systemd watchdog will restart the server
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Health {
+    pub status: ServerHealth,
+    pub watchdog_secs: Option<u64>, // WatchdogSec of the systemd unit, when the server runs under one
+    pub workers: Vec<Worker>,
+}
+
+// Stream health, in GET /api/v1/status and GET /api/v1/stream
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Healthy, // frames arrive at close to the expected rate
+    Degraded, // frames arrive, but at less than half the expected rate
+    Stalled, // the pipeline runs but no frame arrived recently
+    Stopped, // the streaming service isn't running, e.g. in standalone mode
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PipelineState {
+    pub service: String,
+    pub active: bool,
+    pub main_pid: Option<u32>,
+    pub process_state: Option<String>, // from /proc/<pid>/stat, e.g. "R (running)", "S (sleeping)", "D (disk sleep)"
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamHealth {
+    pub status: HealthStatus,
+    pub reason: String,
+    pub transport: String,
+    pub codec: Option<String>,
+    pub frames_per_second: Option<f64>,
+    pub expected_frames_per_second: u32,
+    pub last_frame_age_ms: Option<u64>,
+    pub frames_received: u64,
+    pub pipeline: PipelineState,
+}
+
+impl StreamHealth {
+    pub fn is_streaming(&self) -> bool {
+        matches!(self.status, HealthStatus::Healthy | HealthStatus::Degraded)
+    }
+}
+
+// Recordings
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Video {
+    pub id: String, // file name without .mkv, used in /api/v1/videos/{id}
+    pub path: String,
+    pub size_bytes: u64,
+    pub date_updated: String,
+    pub hls: String, // playlist URL path
+    pub file: String, // download URL path
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VideoList {
+    pub videos: Vec<Video>,
+}
+
+// Control
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Led {
+    pub blinking: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Restart {
+    pub restarting: bool,
+}
+
+// Pairing, see POST /api/v1/pairing
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairingRequest {
+    pub name: String, // shown in GET /api/v1/devices, e.g. "iPhone"
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pairing {
+    pub pairing_id: String,
+    pub expires_in_secs: u64,
+    pub code_length: usize, // digits blinked by the LED, each from 1 to 9
+    pub tls: Option<Tls>, // null when the camera only serves plain HTTP
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tls {
+    pub https_port: u16,
+    pub certificate_sha256: String, // lowercase hex of the SHA-256 of the DER certificate, to pin it
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairingConfirmation {
+    pub code: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    pub paired_at: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Paired {
+    pub device: Device,
+    pub token: String, // sent as "Authorization: Bearer <token>", only returned once
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceList {
+    pub devices: Vec<Device>,
+}
+
+// Video settings
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresetRequest {
+    pub preset: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CodecRequest {
+    pub codec: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransportRequest {
+    pub transport: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SrtSettings {
+    pub latency_ms: u32, // receive buffer of the caller, trades delay for resilience to packet loss
+    pub passphrase: Option<String>, // enables AES encryption when set
+}
+
+impl Default for SrtSettings {
+    fn default() -> Self {
+        SrtSettings {
+            latency_ms: 200,
+            passphrase: None,
+        }
+    }
+}
+
+// GET /events
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Battery { percent: i32, millivolts: i32 },
+    ModeChanged { mode: String }, // "streaming" or "standalone"
+    RecordingChunkClosed { path: String, size_bytes: u64 },
+    StorageWarning { free_bytes: u64, total_bytes: u64 },
+    ThermalWarning { celsius: f32, throttling_soon: bool }, // the Pi throttles at 80°C
+    ButtonPressed { button: String },
+    ShutdownImminent { reason: String }, // "low_battery" or "button"
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        // The SSE event field
+        match self {
+            Event::Battery { .. } => "battery",
+            Event::ModeChanged { .. } => "mode_changed",
+            Event::RecordingChunkClosed { .. } => "recording_chunk_closed",
+            Event::StorageWarning { .. } => "storage_warning",
+            Event::ThermalWarning { .. } => "thermal_warning",
+            Event::ButtonPressed { .. } => "button_pressed",
+            Event::ShutdownImminent { .. } => "shutdown_imminent",
+        }
+    }
+}
@@ -0,0 +1,773 @@
+/*
+Versioned JSON API under /api/v1, and the state its handlers share.
+
+Every response is JSON, and every error is {"code": "...", "message": "...", "details": ...} with a matching status, see src/router.rs.
+The routes are documented as OpenAPI 3 at GET /openapi.json, see src/openapi.rs.
+Settings are changed with a JSON body and answered with the same document as the GET on that path:
+    curl -X PUT -d '{"preset": "high-quality"}' http://192.168.9.1:8000/api/v1/video/preset
+
+The routes the app used before /api/v1 are kept as deprecated aliases with their original plain-text responses,
+and /status, /stream/clients and /video/... as supported short aliases, see src/legacy_api.rs.
+Long-lived responses (/stream.mjpeg, /events, /snapshot.jpg and HLS) are served on their own threads in main.rs, outside this table.
+
+Reading status is open, while everything that changes the camera or reads recordings requires the token of a paired device:
+    curl -H "Authorization: Bearer <token>" http://192.168.9.1:8000/api/v1/videos
+See src/auth.rs for pairing.
+*/
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicBool, AtomicI32};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Response};
+
+use crate::auth::Auth;
+use crate::fuel_gauge::{BatteryStats, BatteryStatus, FuelGauge};
+use crate::legacy_api;
+use crate::openapi::{any_object, array, boolean, described, integer, nullable, number, object, string, string_enum, Body, RouteDoc};
+use crate::router::{json_response, ApiError, ApiRequest, HttpResponse, Router};
+use crate::srt::{self, SrtMonitor};
+use crate::standalone_filesystem::{self, VIDEOS_DIR};
+use crate::status;
+use crate::supervisor::Supervisor;
+use crate::stream_health;
+use crate::stream_relay::{self, StreamRelay};
+use crate::tcp_stream_monitor::ConnectionTracker;
+use crate::video_settings::{self, SrtSettings, StreamCodec, StreamTransport, VideoPreset, VideoSettings};
+use rearview_client::types::{self, Battery, CodecRequest, DeviceList, Hibernation, Led, PairingConfirmation, PairingRequest, PresetRequest, QuickStart, Restart, ServerHealth, Tls, TransportRequest, Video, VideoList};
+use crate::CPU_TEMP_PATH;
+
+// A 70 MB recording over the hotspot, with room for a weak signal
+pub const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+pub struct AppState {
+    pub video_settings: Mutex<VideoSettings>,
+    pub battery_soc: Arc<(AtomicI32, AtomicBool)>,
+    pub battery_voltage: Arc<(AtomicI32, AtomicBool)>,
+    pub battery_status: Arc<Mutex<BatteryStatus>>,
+    pub fuel_gauge: Arc<Mutex<FuelGauge>>,
+    pub relay: StreamRelay,
+    pub srt_monitor: SrtMonitor,
+    pub connection_tracker: ConnectionTracker,
+    pub led_tx: Sender<(bool, u64, u64)>,
+    pub restart_streaming_tx: Sender<()>,
+    pub auth: Arc<Auth>,
+    pub tls: Option<Tls>, // certificate served on the HTTPS port, see src/tls.rs
+    pub supervisor: Supervisor,
+    pub watchdog: Option<Duration>, // WatchdogSec of the systemd unit, see src/watchdog.rs
+    pub started: Instant,
+    pub openapi: Value, // generated from the router at startup
+}
+
+impl AppState {
+    pub fn update_video_settings(&self, change: impl FnOnce(&mut VideoSettings)) -> Result<(), ApiError> {
+        // Only kept once it's saved and the pipelines environment file is rewritten
+        let mut settings = self.video_settings.lock().unwrap();
+        let mut updated = settings.clone();
+        change(&mut updated);
+        updated.save(video_settings::VIDEO_SETTINGS_PATH).and_then(|_| updated.apply()).map_err(|e| {
+            ApiError::internal("Failed to save video settings").with_details(json!(e.to_string()))
+        })?;
+        *settings = updated;
+        Ok(())
+    }
+}
+
+pub fn router() -> Router<AppState> {
+    let router = Router::new()
+        .authenticate_with(authenticate)
+        .route(Method::Get, "/openapi.json", get_openapi, RouteDoc::new("OpenAPI 3 document of these routes", Body::json(any_object())))
+        .route(Method::Get, "/health", get_health, RouteDoc::new("Worker threads with their heartbeats and last errors, answered with 503 when one of them hangs", Body::json(health_schema())).priority())
+        .route(Method::Get, "/api/v1/status", get_status, RouteDoc::new("Battery, temperature, mode, stream, recording, storage and uptime", Body::json(status_schema())).priority())
+        .route(Method::Get, "/api/v1/battery", get_battery, RouteDoc::new("Battery state of charge, cell voltage and charge rate", Body::json(object(json!({
+            "percent": integer(),
+            "millivolts": integer(),
+            "charge_rate_percent_per_hour": number(),
+            "charging": boolean(),
+        })))).priority())
+        .route(Method::Get, "/api/v1/battery/gauge", get_fuel_gauge, RouteDoc::new("Configuration and alerts of the MAX17048 fuel gauge", Body::json(fuel_gauge_schema())))
+        .route(Method::Put, "/api/v1/battery/gauge/hibernation", put_fuel_gauge_hibernation,
+            RouteDoc::new("Let the fuel gauge hibernate at low current, until the server restarts", Body::json(object(json!({ "enabled": boolean() }))))
+            .request(Body::json(object(json!({ "enabled": boolean() }))))
+            .requires_auth())
+        .route(Method::Post, "/api/v1/battery/gauge/quick-start", quick_start_fuel_gauge, RouteDoc::new("Estimate the state of charge again from the cell voltage", Body::json(object(json!({ "quick_started": boolean() })))).status(202).requires_auth())
+        .route(Method::Get, "/api/v1/cpu-temp", get_cpu_temp, RouteDoc::new("CPU temperature", Body::json(object(json!({ "celsius": number() })))).priority())
+        .route(Method::Get, "/api/v1/stream", get_stream, RouteDoc::new("Health of the live stream and the TCP clients connected to it", Body::json(stream_status_schema())).priority())
+        .route(Method::Get, "/api/v1/stream/clients", get_stream_clients, RouteDoc::new("Clients of the stream relay and the SRT listener", Body::json(stream_clients_schema())).priority())
+        .route(Method::Post, "/api/v1/stream/restart", restart_stream, RouteDoc::new("Restart streaming mode and wait for a client", Body::json(object(json!({ "restarting": boolean() })))).status(202).requires_auth().priority())
+        .route(Method::Put, "/api/v1/led", put_led, RouteDoc::new("Blink the LED or turn it off", Body::json(object(json!({ "blinking": boolean() }))))
+            .request(Body::json(object(json!({ "blinking": boolean() }))))
+            .requires_auth().priority())
+        .route(Method::Get, "/api/v1/videos", list_videos, RouteDoc::new("Recordings, oldest first", Body::json(object(json!({ "videos": array(video_schema()) }))))
+            .query("order", string_enum(&["oldest", "newest"]), "Sort order, oldest by default")
+            .query("limit", integer(), "Maximum number of recordings")
+            .requires_auth())
+        .route(Method::Get, "/api/v1/videos/{id}", get_video, RouteDoc::new("One recording", Body::json(video_schema())).requires_auth())
+        .route(Method::Get, "/api/v1/videos/{id}/file", download_video, RouteDoc::new("Download a recording, or part of it with a Range header and If-Range with the ETag", Body::binary("video/x-matroska")).requires_auth().timeout(DOWNLOAD_TIMEOUT))
+        .route(Method::Get, "/api/v1/video/preset", get_preset, RouteDoc::new("Video preset and the pipelines it renders to", Body::json(preset_schema())))
+        .route(Method::Put, "/api/v1/video/preset", put_preset, RouteDoc::new("Select the video preset", Body::json(preset_schema()))
+            .request(Body::json(object(json!({ "preset": string_enum(&preset_names()) }))))
+            .requires_auth())
+        .route(Method::Get, "/api/v1/video/codec", get_codec, RouteDoc::new("Codec of the live stream", Body::json(codec_schema())))
+        .route(Method::Put, "/api/v1/video/codec", put_codec, RouteDoc::new("Select the codec of the live stream", Body::json(codec_schema()))
+            .request(Body::json(object(json!({ "codec": string_enum(&codec_names()) }))))
+            .requires_auth())
+        .route(Method::Get, "/api/v1/video/transport", get_transport, RouteDoc::new("Transport of the live stream and SRT settings", Body::json(transport_schema())))
+        .route(Method::Put, "/api/v1/video/transport", put_transport, RouteDoc::new("Select the transport of the live stream", Body::json(transport_schema()))
+            .request(Body::json(object(json!({ "transport": string_enum(&transport_names()) }))))
+            .requires_auth())
+        .route(Method::Put, "/api/v1/video/srt", put_srt, RouteDoc::new("Set the SRT latency and passphrase", Body::json(transport_schema()))
+            .request(Body::json(srt_settings_schema()))
+            .requires_auth())
+        .route(Method::Get, "/api/v1/tls", get_tls, RouteDoc::new("HTTPS port and the SHA-256 of the certificate, to pin it", Body::json(tls_schema())))
+        .route(Method::Post, "/api/v1/pairing", start_pairing, RouteDoc::new("Blink a pairing code on the LED", Body::json(pairing_schema())).status(202)
+            .request(Body::json(object(json!({ "name": string() })))))
+        .route(Method::Post, "/api/v1/pairing/{pairing_id}/confirm", confirm_pairing, RouteDoc::new("Exchange the blinked code for a token", Body::json(paired_schema()))
+            .request(Body::json(object(json!({ "code": string() })))))
+        .route(Method::Get, "/api/v1/devices", list_devices, RouteDoc::new("Paired devices", Body::json(object(json!({ "devices": array(device_schema()) })))).requires_auth())
+        .route(Method::Delete, "/api/v1/devices/{id}", revoke_device, RouteDoc::new("Revoke the token of a paired device", Body::json(object(json!({ "devices": array(device_schema()) })))).requires_auth());
+    legacy_api::add_routes(router)
+}
+
+fn authenticate(state: &AppState, request: &ApiRequest) -> Result<(), ApiError> {
+    state.auth.authenticate_api_request(request).map(|_| ())
+}
+
+fn get_openapi(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    Ok(json_response(&state.openapi))
+}
+
+pub fn get_status(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    // See GET /status in src/legacy_api.rs for an example
+    // A copy, so that a settings change doesn't wait for the systemctl calls of the report
+    let settings = state.video_settings.lock().unwrap().clone();
+    let report = status::report(&status::StatusSources {
+        battery: &state.battery_status,
+        cpu_temp_path: CPU_TEMP_PATH,
+        relay: &state.relay,
+        srt: &state.srt_monitor,
+        video_settings: &settings,
+        started: state.started,
+        workers: state.supervisor.workers(),
+    });
+    Ok(json_response(&report))
+}
+
+fn get_health(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    /*
+    For monitoring, like the systemd watchdog: "healthy", "degraded" when a worker is failing/restarting, "unhealthy" when one hangs.
+    { "status": "degraded", "watchdog_secs": 30, "workers": [{ "name": "led", "state": "restarting", "restarts": 3, "restart_in_secs": 7, ... }, ...] }
+    */
+    let health = state.supervisor.health(state.watchdog);
+    let status = if health.status == ServerHealth::Unhealthy { 503 } else { 200 };
+    Ok(json_response(&health).with_status_code(status))
+}
+
+fn get_battery(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    // { "percent": 87, "millivolts": 3950, "charge_rate_percent_per_hour": -4.2, "charging": false }
+    let stats = latest_battery_reading(state)?;
+    Ok(json_response(&Battery {
+        percent: stats.state_of_charge_percent,
+        millivolts: stats.cell_millivolts,
+        charge_rate_percent_per_hour: (stats.charge_rate_percent_per_hour * 10.0).round() / 10.0,
+        charging: stats.charge_rate_percent_per_hour > 0.0,
+    }))
+}
+
+fn get_fuel_gauge(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    // { "version": "0x0012", "alert": false, "alert_threshold_percent": 10, "reset_millivolts": 2800, "hibernation_enabled": false, "hibernating": false, "flags": [] }
+    let gauge = latest_battery_reading(state)?.gauge;
+    Ok(json_response(&types::FuelGauge {
+        version: format!("{:#06x}", gauge.version),
+        alert: gauge.alert,
+        alert_threshold_percent: gauge.alert_threshold_percent,
+        reset_millivolts: gauge.reset_millivolts,
+        hibernation_enabled: gauge.hibernation_enabled,
+        hibernating: gauge.hibernating,
+        flags: gauge.flags.names().into_iter().map(String::from).collect(),
+    }))
+}
+
+fn put_fuel_gauge_hibernation(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    // Not persisted: the gauge is opened with hibernation off after a restart
+    let hibernation: Hibernation = request.json_body()?;
+    state.fuel_gauge.lock().unwrap().set_hibernation(hibernation.enabled)
+        .map_err(|e| ApiError::unavailable("battery_unavailable", e.to_string()))?;
+    log::info!("Fuel gauge hibernation {}", if hibernation.enabled { "enabled" } else { "disabled" });
+    Ok(json_response(&hibernation))
+}
+
+fn quick_start_fuel_gauge(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    // Only for a state of charge that's clearly wrong, e.g. after the battery was swapped under load. Takes effect within a second.
+    state.fuel_gauge.lock().unwrap().quick_start()
+        .map_err(|e| ApiError::unavailable("battery_unavailable", e.to_string()))?;
+    log::info!("Fuel gauge quick-started");
+    Ok(json_response(&QuickStart { quick_started: true }).with_status_code(202))
+}
+
+fn latest_battery_reading(state: &AppState) -> Result<BatteryStats, ApiError> {
+    // The last good reading, as GET /api/v1/status marks it when it's old
+    match state.battery_status.lock().unwrap().reading {
+        Some((stats, _)) => Ok(stats),
+        None => Err(ApiError::unavailable("battery_unavailable", "Failed to read the fuel gauge")),
+    }
+}
+
+fn get_cpu_temp(_: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    // { "celsius": 52.6 }
+    let temp = status::cpu_temp(CPU_TEMP_PATH).map_err(|e| ApiError::unavailable("cpu_temp_unavailable", e))?;
+    Ok(json_response(&temp))
+}
+
+pub fn stream_status(state: &AppState) -> Value {
+    // Same document as GET /camera-stream-status, see the example in src/legacy_api.rs
+    let settings = state.video_settings.lock().unwrap().clone();
+    let health = stream_health::check(&state.relay, &state.srt_monitor, &settings);
+    let connections = state.connection_tracker.clients(stream_relay::CLIENT_PORT);
+    json!({
+        "streaming": health.is_streaming(),
+        "health": health,
+        "clients": connections.len(),
+        "connections": connections,
+    })
+}
+
+fn get_stream(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    Ok(json_response(&stream_status(state)))
+}
+
+pub fn stream_clients(state: &AppState) -> Value {
+    // Same document as GET /stream/clients, see the example in src/legacy_api.rs
+    let settings = state.video_settings.lock().unwrap();
+    json!({
+        "upstream_connected": state.relay.upstream_connected(),
+        "upstream_codec": state.relay.upstream_codec().map(|c| c.name()),
+        "frames_received": state.relay.frames_received(),
+        "h264_validation": state.relay.h264_report(),
+        "srt": {
+            "listening": state.srt_monitor.listening(),
+            "caller_connected": state.srt_monitor.caller_connected(),
+            "port": srt::SRT_PORT,
+            "latency_ms": settings.srt.latency_ms,
+            "encrypted": settings.srt.passphrase.is_some(),
+        },
+        "count": state.relay.client_count(),
+        "clients": state.relay.client_stats(),
+    })
+}
+
+fn get_stream_clients(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    Ok(json_response(&stream_clients(state)))
+}
+
+fn restart_stream(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    // Streaming mode waits for a client again, and falls back to standalone mode if none connects
+    state.restart_streaming_tx.send(()).map_err(|_| ApiError::internal("Streaming mode controller is not running"))?;
+    Ok(json_response(&Restart { restarting: true }).with_status_code(202))
+}
+
+fn put_led(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    // { "blinking": true } blinks 100 ms every 1.1 s like PUT /blink-on, false turns the LED off
+    let led: Led = request.json_body()?;
+    let instruction = if led.blinking { (true, 100, 1000) } else { (false, 0, 0) };
+    state.led_tx.send(instruction).map_err(|_| ApiError::internal("LED controller is not running"))?;
+    Ok(json_response(&led))
+}
+
+fn start_pairing(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    // See src/auth.rs for the whole pairing flow
+    let body: PairingRequest = request.json_body()?;
+    let mut pairing = state.auth.start_pairing(&body.name)?;
+    pairing.tls = state.tls.clone();
+    Ok(json_response(&pairing).with_status_code(202))
+}
+
+fn get_tls(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    // { "https_port": 8443, "certificate_sha256": "3f2a..." }
+    let tls = state.tls.as_ref().ok_or_else(|| ApiError::not_found("HTTPS is not enabled on this camera"))?;
+    Ok(json_response(tls))
+}
+
+fn confirm_pairing(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    let body: PairingConfirmation = request.json_body()?;
+    Ok(json_response(&state.auth.confirm_pairing(request.param("pairing_id"), &body.code)?))
+}
+
+fn list_devices(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    Ok(json_response(&DeviceList { devices: state.auth.devices() }))
+}
+
+fn revoke_device(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    // Answered with the devices that remain. A device may revoke itself.
+    state.auth.revoke(request.param("id"))?;
+    list_devices(state, request)
+}
+
+fn video_info(path: &std::path::Path, modified: std::time::SystemTime) -> Video {
+    let id = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    Video {
+        path: path.to_str().unwrap_or("").to_string(),
+        size_bytes: fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+        date_updated: standalone_filesystem::format_system_time_to_string(modified),
+        hls: format!("/videos/{}/index.m3u8", id),
+        file: format!("/api/v1/videos/{}/file", id),
+        id,
+    }
+}
+
+fn list_videos(_: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    /*
+    Recordings, oldest first: GET /api/v1/videos?order=newest&limit=10
+    { "videos": [ { "id": "loop0001", "path": "...", "size_bytes": 52428800, "date_updated": "2023-06-17T09:13:00", "hls": "...", "file": "..." } ] }
+    */
+    let mut videos = standalone_filesystem::files_sorted_by_date(VIDEOS_DIR)
+        .map_err(|e| ApiError::internal(format!("Failed to list {}", VIDEOS_DIR)).with_details(json!(e.to_string())))?;
+    videos.retain(|(path, _)| path.extension().is_some_and(|ext| ext == "mkv"));
+    match request.query.get("order").map(String::as_str) {
+        None | Some("oldest") => (),
+        Some("newest") => videos.reverse(),
+        Some(other) => {
+            return Err(ApiError::bad_request("invalid_query", format!("Unknown order: {}", other))
+                .with_details(json!({ "allowed": ["oldest", "newest"] })));
+        }
+    }
+    if let Some(limit) = request.query_parsed::<usize>("limit")? {
+        videos.truncate(limit);
+    }
+    let videos = videos.iter().map(|(path, modified)| video_info(path, *modified)).collect();
+    Ok(json_response(&VideoList { videos }))
+}
+
+fn video_path(id: &str) -> Result<std::path::PathBuf, ApiError> {
+    standalone_filesystem::recording_path(id).map_err(|(status, message)| match status {
+        404 => ApiError::not_found(message),
+        _ => ApiError::new(status, "invalid_video_id", message),
+    })
+}
+
+fn get_video(_: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    let path = video_path(request.param("id"))?;
+    let modified = fs::metadata(&path).and_then(|m| m.modified())
+        .map_err(|e| ApiError::internal("Failed to read recording").with_details(json!(e.to_string())))?;
+    Ok(json_response(&video_info(&path, modified)))
+}
+
+fn download_video(_: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    /*
+    curl -o loop0001.mkv http://192.168.9.1:8000/api/v1/videos/loop0001/file
+    An interrupted download continues with a Range header: curl -C - -o loop0001.mkv ...
+
+    Recordings are overwritten as the loop comes around, so a resumed download should send If-Range with the ETag
+    or Last-Modified of its first response. When the recording changed since, the Range is ignored and the whole file is sent.
+    */
+    let id = request.param("id");
+    let path = video_path(id)?;
+    let read_error = |e: std::io::Error| ApiError::internal("Failed to read recording").with_details(json!(e.to_string()));
+    let mut file = fs::File::open(&path).map_err(read_error)?;
+    let metadata = file.metadata().map_err(read_error)?;
+    let size = metadata.len();
+    let modified = metadata.modified().map_err(read_error)?;
+    // Strong, since a recording still being written changes size with every write
+    let etag = format!("\"{:x}-{:x}\"", size, modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+    let last_modified = httpdate::fmt_http_date(modified);
+
+    let same_recording = match request.header("If-Range") {
+        Some(validator) => validator.trim() == etag || validator.trim() == last_modified,
+        None => true,
+    };
+    let range = match request.header("Range").filter(|_| same_recording) {
+        Some(range) => match parse_range(range, size) {
+            Ok(range) => range,
+            Err(error) => {
+                let content_range = Header::from_bytes(&b"Content-Range"[..], format!("bytes */{}", size).as_bytes()).unwrap();
+                return Ok(error.to_response().with_header(content_range));
+            }
+        },
+        None => None,
+    };
+    // Streamed from the file as tiny_http sends it, so a 70 MB recording isn't held in memory
+    let (start, end) = range.unwrap_or((0, size.saturating_sub(1)));
+    let length = if size == 0 { 0 } else { end + 1 - start };
+    file.seek(SeekFrom::Start(start)).map_err(read_error)?;
+    let body: Box<dyn Read + Send> = Box::new(file.take(length));
+
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"video/x-matroska"[..]).unwrap();
+    let disposition = Header::from_bytes(&b"Content-Disposition"[..], format!("attachment; filename=\"{}.mkv\"", id).as_bytes()).unwrap();
+    let accept_ranges = Header::from_bytes(&b"Accept-Ranges"[..], &b"bytes"[..]).unwrap();
+    let etag = Header::from_bytes(&b"ETag"[..], etag.as_bytes()).unwrap();
+    let last_modified = Header::from_bytes(&b"Last-Modified"[..], last_modified.as_bytes()).unwrap();
+    let response = Response::empty(200).with_data(body, Some(length as usize))
+        .with_header(content_type).with_header(disposition).with_header(accept_ranges).with_header(etag).with_header(last_modified);
+    match range {
+        Some((start, end)) => {
+            let content_range = Header::from_bytes(&b"Content-Range"[..], format!("bytes {}-{}/{}", start, end, size).as_bytes()).unwrap();
+            Ok(response.with_header(content_range).with_status_code(206))
+        }
+        None => Ok(response.with_status_code(200)),
+    }
+}
+
+fn parse_range(header: &str, size: u64) -> Result<Option<(u64, u64)>, ApiError> {
+    /*
+    A single "bytes=start-", "bytes=start-end" or "bytes=-length" range (the last length bytes), as (start, end) with end inclusive.
+    As RFC 9110 allows, the whole recording is sent (Ok(None)) for a Range it doesn't understand, for several ranges,
+    and for an empty recording, which has no bytes to select.
+    Only a range starting at or past the end of the recording, or asking for the last 0 bytes, is answered with 416.
+    */
+    let unsatisfiable = || ApiError::new(416, "range_not_satisfiable", format!("Recording is {} bytes", size));
+    let Some((start, end)) = header.trim().strip_prefix("bytes=").and_then(|range| range.split_once('-')) else {
+        return Ok(None);
+    };
+    if size == 0 {
+        return Ok(None);
+    }
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", length) => match length.parse::<u64>() {
+            Ok(0) => return Err(unsatisfiable()),
+            Ok(length) => (size - length.min(size), size - 1),
+            Err(_) => return Ok(None),
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(start) => (start, size - 1),
+            Err(_) => return Ok(None),
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(size - 1)),
+            _ => return Ok(None),
+        },
+    };
+    match start < size {
+        true => Ok(Some((start, end))),
+        false => Err(unsatisfiable()),
+    }
+}
+
+fn invalid_value(what: &str, value: &str, allowed: Vec<&'static str>) -> ApiError {
+    ApiError::bad_request("invalid_value", format!("Unknown {}: {}", what, value)).with_details(json!({ "allowed": allowed }))
+}
+
+pub fn get_preset(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    // Same document as GET /video/preset, see the example in src/legacy_api.rs
+    let settings = state.video_settings.lock().unwrap();
+    let available: Vec<_> = video_settings::ALL_PRESETS.iter().map(|p| p.name()).collect();
+    Ok(json_response(&json!({
+        "preset": settings.preset.name(),
+        "available": available,
+        "stream_pipeline": settings.stream_pipeline().render().unwrap_or_default(),
+        "record_pipeline": settings.preset.record_pipeline().render().unwrap_or_default(),
+    })))
+}
+
+fn put_preset(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    let body: PresetRequest = request.json_body()?;
+    let preset = VideoPreset::from_name(&body.preset).ok_or_else(|| {
+        invalid_value("video preset", &body.preset, video_settings::ALL_PRESETS.iter().map(|p| p.name()).collect())
+    })?;
+    state.update_video_settings(|settings| settings.preset = preset)?;
+    get_preset(state, request)
+}
+
+pub fn get_codec(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    // Same document as GET /video/codec, see the example in src/legacy_api.rs
+    let available: Vec<_> = video_settings::ALL_CODECS.iter().map(|c| c.name()).collect();
+    Ok(json_response(&json!({
+        "codec": state.video_settings.lock().unwrap().codec.name(),
+        "active": state.relay.upstream_codec().map(|c| c.name()),
+        "available": available,
+    })))
+}
+
+fn put_codec(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    let body: CodecRequest = request.json_body()?;
+    let codec = StreamCodec::from_name(&body.codec).ok_or_else(|| {
+        invalid_value("stream codec", &body.codec, video_settings::ALL_CODECS.iter().map(|c| c.name()).collect())
+    })?;
+    state.update_video_settings(|settings| settings.codec = codec)?;
+    get_codec(state, request)
+}
+
+pub fn get_transport(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    // Same document as GET /video/transport, see the example in src/legacy_api.rs
+    let settings = state.video_settings.lock().unwrap();
+    let available: Vec<_> = video_settings::ALL_TRANSPORTS.iter().map(|t| t.name()).collect();
+    Ok(json_response(&json!({
+        "transport": settings.transport.name(),
+        "available": available,
+        "srt": {
+            "port": srt::SRT_PORT,
+            "latency_ms": settings.srt.latency_ms,
+            "encrypted": settings.srt.passphrase.is_some(),
+        },
+    })))
+}
+
+fn put_transport(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    let body: TransportRequest = request.json_body()?;
+    let transport = StreamTransport::from_name(&body.transport).ok_or_else(|| {
+        invalid_value("stream transport", &body.transport, video_settings::ALL_TRANSPORTS.iter().map(|t| t.name()).collect())
+    })?;
+    state.update_video_settings(|settings| settings.transport = transport)?;
+    get_transport(state, request)
+}
+
+pub fn validate_srt_settings(state: &AppState, srt_settings: &SrtSettings) -> Result<(), ApiError> {
+    // Validated through the pipeline it renders to
+    let preset = state.video_settings.lock().unwrap().preset;
+    preset.srt_pipeline(srt_settings).validate()
+        .map_err(|e| ApiError::bad_request("invalid_value", e.to_string()))
+}
+
+fn put_srt(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    // { "latency_ms": 300, "passphrase": "correcthorsebattery" }, answered like GET /api/v1/video/transport
+    let srt_settings: SrtSettings = request.json_body()?;
+    validate_srt_settings(state, &srt_settings)?;
+    state.update_video_settings(|settings| settings.srt = srt_settings)?;
+    get_transport(state, request)
+}
+
+// Schemas of the documents above, for GET /openapi.json
+
+fn preset_names() -> Vec<&'static str> {
+    video_settings::ALL_PRESETS.iter().map(|p| p.name()).collect()
+}
+
+fn codec_names() -> Vec<&'static str> {
+    video_settings::ALL_CODECS.iter().map(|c| c.name()).collect()
+}
+
+fn transport_names() -> Vec<&'static str> {
+    video_settings::ALL_TRANSPORTS.iter().map(|t| t.name()).collect()
+}
+
+fn section_schema(schema: Value) -> Value {
+    // A /status section is either its values or the error that prevented reading them
+    json!({ "oneOf": [schema, object(json!({ "error": string() }))] })
+}
+
+pub fn status_schema() -> Value {
+    object(json!({
+        "version": string(),
+        "battery": section_schema(object(json!({
+            "percent": integer(),
+            "millivolts": integer(),
+            "charge_rate_percent_per_hour": number(),
+            "charging": boolean(),
+            "valid": boolean(),
+            "age_secs": integer(),
+            "last_error": nullable(string()),
+        }))),
+        "cpu_temp": section_schema(object(json!({ "celsius": number() }))),
+        "mode": string_enum(&["streaming", "standalone", "idle"]),
+        "stream": stream_health_schema(),
+        "recording": section_schema(object(json!({
+            "recording": boolean(),
+            "chunk_count": integer(),
+            "latest_chunk": nullable(object(json!({ "path": string(), "size_bytes": integer(), "date_updated": string() }))),
+        }))),
+        "storage": section_schema(object(json!({
+            "free_bytes": integer(),
+            "total_bytes": integer(),
+            "free_percent": integer(),
+            "videos_bytes": integer(),
+        }))),
+        "uptime": section_schema(object(json!({ "system_secs": integer(), "server_secs": integer() }))),
+        "workers": array(worker_schema()),
+    }))
+}
+
+fn worker_schema() -> Value {
+    object(json!({
+        "name": string(),
+        "state": string_enum(&["running", "hung", "restarting", "stopped"]),
+        "restarts": integer(),
+        "restart_in_secs": nullable(integer()),
+        "last_heartbeat_age_ms": integer(),
+        "heartbeat_timeout_secs": integer(),
+        "last_error": nullable(string()),
+    }))
+}
+
+fn fuel_gauge_schema() -> Value {
+    object(json!({
+        "version": string(),
+        "alert": boolean(),
+        "alert_threshold_percent": integer(),
+        "reset_millivolts": integer(),
+        "hibernation_enabled": boolean(),
+        "hibernating": boolean(),
+        "flags": array(string_enum(&["reset", "voltage_high", "voltage_low", "voltage_reset", "soc_low", "soc_change"])),
+    }))
+}
+
+fn health_schema() -> Value {
+    object(json!({
+        "status": string_enum(&["healthy", "degraded", "unhealthy"]),
+        "watchdog_secs": nullable(integer()),
+        "workers": array(worker_schema()),
+    }))
+}
+
+fn stream_health_schema() -> Value {
+    object(json!({
+        "status": string_enum(&["healthy", "degraded", "stalled", "stopped"]),
+        "reason": string(),
+        "transport": string_enum(&transport_names()),
+        "codec": nullable(string_enum(&codec_names())),
+        "frames_per_second": nullable(number()),
+        "expected_frames_per_second": integer(),
+        "last_frame_age_ms": nullable(integer()),
+        "frames_received": integer(),
+        "pipeline": object(json!({
+            "service": string(),
+            "active": boolean(),
+            "main_pid": nullable(integer()),
+            "process_state": nullable(string()),
+        })),
+    }))
+}
+
+pub fn stream_status_schema() -> Value {
+    object(json!({
+        "streaming": boolean(),
+        "health": stream_health_schema(),
+        "clients": integer(),
+        "connections": array(object(json!({
+            "local": string(),
+            "peer": string(),
+            "state": string(),
+            "connected_secs": integer(),
+        }))),
+    }))
+}
+
+pub fn stream_clients_schema() -> Value {
+    object(json!({
+        "upstream_connected": boolean(),
+        "upstream_codec": nullable(string_enum(&codec_names())),
+        "frames_received": integer(),
+        "h264_validation": nullable(any_object()),
+        "srt": object(json!({
+            "listening": boolean(),
+            "caller_connected": described(boolean(),
+                "Heuristic: true while the host sends UDP datagrams at a video bitrate and the SRT port is bound. \
+                 The datagram count is system-wide, so other UDP traffic can make it true without a caller."),
+            "port": integer(),
+            "latency_ms": integer(),
+            "encrypted": boolean(),
+        })),
+        "count": integer(),
+        "clients": array(object(json!({
+            "id": integer(),
+            "kind": string(),
+            "peer": string(),
+            "connected_secs": integer(),
+            "frames_sent": integer(),
+            "frames_dropped": integer(),
+            "bytes_sent": integer(),
+        }))),
+    }))
+}
+
+fn video_schema() -> Value {
+    object(json!({
+        "id": string(),
+        "path": string(),
+        "size_bytes": integer(),
+        "date_updated": string(),
+        "hls": string(),
+        "file": string(),
+    }))
+}
+
+fn tls_schema() -> Value {
+    object(json!({ "https_port": integer(), "certificate_sha256": string() }))
+}
+
+fn pairing_schema() -> Value {
+    object(json!({ "pairing_id": string(), "expires_in_secs": integer(), "code_length": integer(), "tls": nullable(tls_schema()) }))
+}
+
+fn device_schema() -> Value {
+    object(json!({ "id": string(), "name": string(), "paired_at": string() }))
+}
+
+fn paired_schema() -> Value {
+    object(json!({ "device": device_schema(), "token": string() }))
+}
+
+pub fn preset_schema() -> Value {
+    object(json!({
+        "preset": string_enum(&preset_names()),
+        "available": array(string()),
+        "stream_pipeline": string(),
+        "record_pipeline": string(),
+    }))
+}
+
+pub fn codec_schema() -> Value {
+    object(json!({
+        "codec": string_enum(&codec_names()),
+        "active": nullable(string_enum(&codec_names())),
+        "available": array(string()),
+    }))
+}
+
+pub fn transport_schema() -> Value {
+    object(json!({
+        "transport": string_enum(&transport_names()),
+        "available": array(string()),
+        "srt": object(json!({ "port": integer(), "latency_ms": integer(), "encrypted": boolean() })),
+    }))
+}
+
+pub fn srt_settings_schema() -> Value {
+    object(json!({
+        "latency_ms": json!({ "type": "integer", "minimum": 20, "maximum": 8000 }),
+        "passphrase": nullable(json!({ "type": "string", "minLength": 10, "maxLength": 79 })),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("bytes=0-", 1000).unwrap(), Some((0, 999)));
+        assert_eq!(parse_range("bytes=500-", 1000).unwrap(), Some((500, 999)));
+        assert_eq!(parse_range("bytes=10-19", 1000).unwrap(), Some((10, 19)));
+        assert_eq!(parse_range("bytes=990-2000", 1000).unwrap(), Some((990, 999)));
+        assert_eq!(parse_range("bytes=999-999", 1000).unwrap(), Some((999, 999)));
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(parse_range("bytes=-500", 1000).unwrap(), Some((500, 999)));
+        assert_eq!(parse_range("bytes=-1", 1000).unwrap(), Some((999, 999)));
+        // Longer than the recording selects all of it
+        assert_eq!(parse_range("bytes=-5000", 1000).unwrap(), Some((0, 999)));
+    }
+
+    #[test]
+    fn rejects_ranges_past_the_end() {
+        for range in ["bytes=1000-", "bytes=1000-1001", "bytes=-0"] {
+            assert_eq!(parse_range(range, 1000).unwrap_err().status, 416, "{}", range);
+        }
+    }
+
+    #[test]
+    fn sends_empty_recordings_whole() {
+        assert_eq!(parse_range("bytes=0-", 0).unwrap(), None);
+        assert_eq!(parse_range("bytes=-500", 0).unwrap(), None);
+    }
+
+    #[test]
+    fn ignores_ranges_it_does_not_understand() {
+        for range in ["items=0-1", "bytes=5", "bytes=a-", "bytes=20-10", "bytes=0-1,5-6", "bytes=-"] {
+            assert_eq!(parse_range(range, 1000).unwrap(), None, "{}", range);
+        }
+    }
+}
@@ -0,0 +1,320 @@
+/*
+Pairing and bearer tokens for the control API.
+
+A phone pairs by asking for a code, which the camera blinks on its LED, and sending the code back:
+    POST /api/v1/pairing { "name": "iPhone" }                   -> 202 { "pairing_id": "...", "expires_in_secs": 180, "code_length": 5, "tls": { ... } }
+    POST /api/v1/pairing/{pairing_id}/confirm { "code": "31524" } -> { "device": { ... }, "token": "..." }
+Each digit is blinked as that many quick blinks (1 to 9), with a long pause between digits, see led_control::blink_code.
+Seeing the LED is what proves the phone is with the camera, so the pairing routes themselves are open.
+Only one pairing is pending at a time, and another can't be started until it's confirmed, expires or runs out of attempts,
+so nobody else on the network can replace the code the user is reading off the LED.
+The certificate fingerprint in "tls" lets the app confirm the pairing over HTTPS and pin it from then on, see src/tls.rs.
+
+The token is then sent on every protected request, as a header or, where a player can't set headers
+(AVPlayer for HLS, <img> for /stream.mjpeg and /snapshot.jpg, EventSource for /events), as a query parameter:
+    Authorization: Bearer <token>
+    GET /videos/loop0001/index.m3u8?access_token=<token>
+
+The raw stream ports require the token too, see authenticate_credentials:
+    - RTSP 8554, in the URL or as the password of Basic credentials, which VLC and ffmpeg send for rtsp://app:<token>@192.168.9.1:8554/live
+        rtsp://192.168.9.1:8554/live?access_token=<token>
+    - TCP 5000, as a first line sent by the client before the stream starts, or in an HTTP request's header or URL,
+      see stream_relay::StreamRelay::serve_tcp_client
+        Authorization: Bearer <token>
+SRT 5000 is served by gstreamer and has its own passphrase instead, see PUT /api/v1/video/srt.
+
+Only a SHA-256 of each token is stored, in PAIRED_DEVICES_PATH. A device is revoked with DELETE /api/v1/devices/{id}.
+*/
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tiny_http::Request;
+
+use rearview_client::encoding::{base64_decode, hex, random_bytes};
+use rearview_client::types::{Device, Paired, Pairing};
+
+use crate::router::{ApiError, ApiRequest};
+use crate::standalone_filesystem;
+
+pub const PAIRED_DEVICES_PATH: &str = "/opt/velovision/paired_devices.json";
+pub const TOKEN_QUERY_PARAMETER: &str = "access_token";
+
+const CODE_LENGTH: usize = 5; // 9^5 codes
+const PAIRING_EXPIRES_AFTER: Duration = Duration::from_secs(180); // the code takes up to a minute to blink twice
+const MAX_CODE_ATTEMPTS: u32 = 5;
+const PAIRING_COOLDOWN: Duration = Duration::from_secs(30); // between new pairings, so codes can't be guessed by restarting
+const TOKEN_BYTES: usize = 32;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PairedDevice {
+    id: String,
+    name: String,
+    token_sha256: String,
+    paired_at: String,
+}
+
+impl PairedDevice {
+    fn info(&self) -> Device {
+        Device { id: self.id.clone(), name: self.name.clone(), paired_at: self.paired_at.clone() }
+    }
+}
+
+struct PendingPairing {
+    id: String,
+    name: String,
+    code: String,
+    started: Instant,
+    attempts: u32,
+}
+
+#[derive(Default)]
+struct PairingState {
+    pending: Option<PendingPairing>,
+    last_started: Option<Instant>,
+}
+
+pub struct Auth {
+    path: PathBuf,
+    devices: Mutex<Vec<PairedDevice>>,
+    pairing: Mutex<PairingState>,
+    code_tx: Sender<Vec<u8>>, // blinks a code on the LED, see led_control::start_listener
+}
+
+impl Auth {
+    pub fn load<P: AsRef<Path>>(path: P, code_tx: Sender<Vec<u8>>) -> Auth {
+        // An unreadable file means nothing is paired, and devices pair again rather than the camera failing to start
+        let devices = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                log::warn!("Ignoring invalid paired devices in {}: {}", path.as_ref().display(), e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        Auth {
+            path: path.as_ref().to_path_buf(),
+            devices: Mutex::new(devices),
+            pairing: Mutex::new(PairingState::default()),
+            code_tx,
+        }
+    }
+
+    pub fn start_pairing(&self, name: &str) -> Result<Pairing, ApiError> {
+        let name = name.trim();
+        if name.is_empty() || name.len() > 64 {
+            return Err(ApiError::bad_request("invalid_value", "Device name must be 1 to 64 characters"));
+        }
+        let mut pairing = self.pairing.lock().unwrap();
+        // A code that was missed can be asked for again once it expires, or after using up its attempts
+        if let Some(pending) = pairing.pending.as_ref().filter(|p| p.started.elapsed() < PAIRING_EXPIRES_AFTER && p.attempts < MAX_CODE_ATTEMPTS) {
+            let wait = PAIRING_EXPIRES_AFTER.saturating_sub(pending.started.elapsed());
+            return Err(ApiError::new(409, "pairing_pending", format!("Another pairing is pending, try again in {} seconds", wait.as_secs() + 1)));
+        }
+        if let Some(last_started) = pairing.last_started {
+            let wait = PAIRING_COOLDOWN.saturating_sub(last_started.elapsed());
+            if !wait.is_zero() {
+                return Err(ApiError::new(429, "pairing_rate_limited", format!("Try again in {} seconds", wait.as_secs() + 1)));
+            }
+        }
+
+        let random = random_bytes(CODE_LENGTH + 8).map_err(|e| ApiError::internal(format!("Failed to generate a pairing code: {}", e)))?;
+        let digits: Vec<u8> = random[..CODE_LENGTH].iter().map(|b| b % 9 + 1).collect();
+        let id = hex(&random[CODE_LENGTH..]);
+        self.code_tx.send(digits.clone()).map_err(|_| ApiError::internal("LED controller is not running"))?;
+        log::info!("Pairing {} started for {}", id, name);
+
+        pairing.last_started = Some(Instant::now());
+        pairing.pending = Some(PendingPairing {
+            id: id.clone(),
+            name: name.to_string(),
+            code: digits.iter().map(|d| d.to_string()).collect(),
+            started: Instant::now(),
+            attempts: 0,
+        });
+        Ok(Pairing { pairing_id: id, expires_in_secs: PAIRING_EXPIRES_AFTER.as_secs(), code_length: CODE_LENGTH, tls: None })
+    }
+
+    pub fn confirm_pairing(&self, pairing_id: &str, code: &str) -> Result<Paired, ApiError> {
+        let mut state = self.pairing.lock().unwrap();
+        let pairing = match state.pending.as_mut() {
+            Some(pairing) if pairing.id == pairing_id && pairing.started.elapsed() < PAIRING_EXPIRES_AFTER => pairing,
+            _ => return Err(ApiError::not_found("No pending pairing with this id, it may have expired")),
+        };
+        if pairing.attempts >= MAX_CODE_ATTEMPTS {
+            return Err(ApiError::new(429, "too_many_attempts", "Too many wrong codes, start pairing again"));
+        }
+        pairing.attempts += 1;
+        if code.trim() != pairing.code {
+            let remaining = MAX_CODE_ATTEMPTS - pairing.attempts;
+            return Err(ApiError::new(403, "wrong_code", format!("Wrong code, {} attempts left", remaining)));
+        }
+
+        let random = random_bytes(TOKEN_BYTES + 8).map_err(|e| ApiError::internal(format!("Failed to generate a token: {}", e)))?;
+        let token = hex(&random[..TOKEN_BYTES]);
+        let device = PairedDevice {
+            id: hex(&random[TOKEN_BYTES..]),
+            name: pairing.name.clone(),
+            token_sha256: sha256_hex(&token),
+            paired_at: standalone_filesystem::format_system_time_to_string(SystemTime::now()),
+        };
+        let mut devices = self.devices.lock().unwrap();
+        let mut updated = devices.clone();
+        updated.push(device.clone());
+        self.save(&updated)?;
+        *devices = updated;
+        state.pending = None;
+        log::info!("Paired {} ({})", device.name, device.id);
+        Ok(Paired { device: device.info(), token })
+    }
+
+    pub fn devices(&self) -> Vec<Device> {
+        self.devices.lock().unwrap().iter().map(PairedDevice::info).collect()
+    }
+
+    pub fn revoke(&self, id: &str) -> Result<(), ApiError> {
+        let mut devices = self.devices.lock().unwrap();
+        if !devices.iter().any(|device| device.id == id) {
+            return Err(ApiError::not_found(format!("No paired device {}", id)));
+        }
+        let updated: Vec<PairedDevice> = devices.iter().filter(|device| device.id != id).cloned().collect();
+        self.save(&updated)?;
+        *devices = updated;
+        log::info!("Revoked device {}", id);
+        Ok(())
+    }
+
+    pub fn authenticate(&self, token: Option<&str>) -> Result<Device, ApiError> {
+        let token = token.ok_or_else(|| {
+            ApiError::new(401, "unauthorized", "This route requires a token, see POST /api/v1/pairing")
+        })?;
+        let token_sha256 = sha256_hex(token);
+        self.devices.lock().unwrap().iter()
+            .find(|device| constant_time_eq(device.token_sha256.as_bytes(), token_sha256.as_bytes()))
+            .map(PairedDevice::info)
+            .ok_or_else(|| ApiError::new(401, "unauthorized", "Unknown or revoked token"))
+    }
+
+    pub fn authenticate_request(&self, request: &Request) -> Result<Device, ApiError> {
+        // For the long-lived routes served outside the route table in main.rs
+        let header = request.headers().iter().find(|h| h.field.equiv("Authorization")).map(|h| h.value.as_str());
+        self.authenticate(bearer_token(header).or(query_token(request.url())))
+    }
+
+    pub fn authenticate_credentials(&self, url: &str, authorization: Option<&str>) -> Result<Device, ApiError> {
+        // For the raw stream ports, which aren't HTTP: a Bearer token or Basic password, or the token in the URL
+        let password = basic_password(authorization);
+        self.authenticate(bearer_token(authorization).or(password.as_deref()).or(query_token(url)))
+    }
+
+    pub fn authenticate_api_request(&self, request: &ApiRequest) -> Result<Device, ApiError> {
+        let query_token = request.query.get(TOKEN_QUERY_PARAMETER).map(String::as_str);
+        self.authenticate(bearer_token(request.header("Authorization")).or(query_token))
+    }
+
+    fn save(&self, devices: &[PairedDevice]) -> Result<(), ApiError> {
+        // Only readable by the server, and replaced in one rename so a power cut can't leave half a file
+        let contents = serde_json::to_string_pretty(devices).unwrap_or_default();
+        standalone_filesystem::write_atomically(&self.path, contents.as_bytes(), 0o600)
+            .map_err(|e| ApiError::internal(format!("Failed to save {}: {}", self.path.display(), e)))
+    }
+}
+
+fn bearer_token(header: Option<&str>) -> Option<&str> {
+    header?.strip_prefix("Bearer ").map(str::trim)
+}
+
+fn basic_password(header: Option<&str>) -> Option<String> {
+    // The user name is ignored, only the token is checked
+    let credentials = String::from_utf8(base64_decode(header?.strip_prefix("Basic ")?.trim())?).ok()?;
+    credentials.split_once(':').map(|(_, password)| password.to_string())
+}
+
+fn query_token(url: &str) -> Option<&str> {
+    let query = url.split_once('?').map(|(_, query)| query).unwrap_or_default();
+    query.split('&').find_map(|pair| pair.strip_prefix(TOKEN_QUERY_PARAMETER)?.strip_prefix('='))
+}
+
+fn sha256_hex(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn auth_with_token(token: &str) -> Auth {
+        let path = std::env::temp_dir().join(format!("paired_devices_{}_{}.json", std::process::id(), token));
+        let device = PairedDevice { id: "d1".to_string(), name: "iPhone".to_string(), token_sha256: sha256_hex(token), paired_at: String::new() };
+        fs::write(&path, serde_json::to_string(&[device]).unwrap()).unwrap();
+        let auth = Auth::load(&path, mpsc::channel().0);
+        let _ = fs::remove_file(&path);
+        auth
+    }
+
+    #[test]
+    fn reads_basic_password() {
+        assert_eq!(basic_password(Some("Basic YXBwOnNlY3JldA==")).as_deref(), Some("secret"));
+        assert_eq!(basic_password(Some("Bearer secret")), None);
+        assert_eq!(basic_password(None), None);
+    }
+
+    #[test]
+    fn reads_query_token() {
+        assert_eq!(query_token("rtsp://192.168.9.1:8554/live?access_token=secret"), Some("secret"));
+        assert_eq!(query_token("/events?x=1&access_token=secret"), Some("secret"));
+        assert_eq!(query_token("/events"), None);
+    }
+
+    #[test]
+    fn authenticates_stream_credentials() {
+        let auth = auth_with_token("secret");
+        assert!(auth.authenticate_credentials("rtsp://192.168.9.1:8554/live?access_token=secret", None).is_ok());
+        assert!(auth.authenticate_credentials("rtsp://192.168.9.1:8554/live", Some("Basic YXBwOnNlY3JldA==")).is_ok());
+        assert!(auth.authenticate_credentials("", Some("Bearer secret")).is_ok());
+        assert!(auth.authenticate_credentials("rtsp://192.168.9.1:8554/live?access_token=wrong", None).is_err());
+        assert!(auth.authenticate_credentials("rtsp://192.168.9.1:8554/live", None).is_err());
+    }
+
+    #[test]
+    fn pending_pairing_cannot_be_replaced() {
+        let (code_tx, codes) = mpsc::channel();
+        let auth = Auth::load(std::env::temp_dir().join("no_paired_devices.json"), code_tx);
+        let first = auth.start_pairing("iPhone").unwrap();
+        let error = auth.start_pairing("Someone else").unwrap_err();
+        assert_eq!((error.status, error.code), (409, "pairing_pending"));
+        assert_eq!(codes.try_iter().count(), 1);
+
+        // Expired, and past the cooldown
+        {
+            let mut state = auth.pairing.lock().unwrap();
+            let long_ago = Instant::now().checked_sub(PAIRING_EXPIRES_AFTER).unwrap();
+            state.pending.as_mut().unwrap().started = long_ago;
+            state.last_started = Some(long_ago);
+        }
+        let second = auth.start_pairing("iPhone").unwrap();
+        assert_ne!(first.pairing_id, second.pairing_id);
+        assert!(auth.confirm_pairing(&first.pairing_id, "11111").is_err());
+    }
+
+    #[test]
+    fn pairing_out_of_attempts_can_be_restarted() {
+        let (code_tx, _codes) = mpsc::channel();
+        let auth = Auth::load(std::env::temp_dir().join("no_paired_devices.json"), code_tx);
+        let pairing = auth.start_pairing("iPhone").unwrap();
+        // Digits are 1 to 9, so a code of zeros is always wrong
+        for _ in 0..MAX_CODE_ATTEMPTS {
+            assert_eq!(auth.confirm_pairing(&pairing.pairing_id, "00000").unwrap_err().code, "wrong_code");
+        }
+        auth.pairing.lock().unwrap().last_started = Instant::now().checked_sub(PAIRING_COOLDOWN);
+        assert!(auth.start_pairing("iPhone").is_ok());
+    }
+}
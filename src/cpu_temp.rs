@@ -1,4 +1,15 @@
 use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use crate::error;
+use crate::events::{Event, EventBus};
+use crate::supervisor::Supervisor;
+
+// The Pi throttles the CPU at 80°C. Warn a little before, and again only after cooling down.
+const THERMAL_WARNING_CELSIUS: f32 = 75.0;
+const THERMAL_THROTTLE_CELSIUS: f32 = 80.0;
+const THERMAL_CLEAR_CELSIUS: f32 = 70.0;
 
 pub fn read_cpu_temp(sys_temp_path: &str) -> Result<String, String> {
     let response: String;
@@ -23,4 +34,28 @@ pub fn read_cpu_temp(sys_temp_path: &str) -> Result<String, String> {
             Err(response)
         }
     }
-}
\ No newline at end of file
+}
+
+pub fn start_thermal_monitor(sys_temp_path: &'static str, events: EventBus, supervisor: &Supervisor) -> error::Result<()> {
+    let mut warned = false;
+    let mut warned_throttling = false;
+    supervisor.spawn("thermal", Duration::from_secs(60), move |heartbeat| {
+        loop {
+            heartbeat.beat();
+            let millidegrees = fs::read_to_string(sys_temp_path).ok().and_then(|t| t.trim().parse::<i32>().ok());
+            if let Some(millidegrees) = millidegrees {
+                let celsius = millidegrees as f32 / 1000.0;
+                let throttling_soon = celsius >= THERMAL_THROTTLE_CELSIUS;
+                if (celsius >= THERMAL_WARNING_CELSIUS && !warned) || (throttling_soon && !warned_throttling) {
+                    events.publish(Event::ThermalWarning { celsius, throttling_soon });
+                    warned = true;
+                    warned_throttling = throttling_soon;
+                } else if celsius < THERMAL_CLEAR_CELSIUS {
+                    warned = false;
+                    warned_throttling = false;
+                }
+            }
+            thread::sleep(Duration::from_secs(5));
+        }
+    })
+}
@@ -0,0 +1,71 @@
+/*
+Error type of the server's own work: modes, workers, hardware and startup.
+
+Every error says what was being done when it happened, so that the log reads like
+    Failed to stop velovision-standalone-mode.service: systemctl exited with status 5
+rather than a bare io::Error. Add that context where the error is first seen:
+    fs::read_dir(VIDEOS_DIR).context(format!("Failed to list {}", VIDEOS_DIR))?;
+
+HTTP handlers answer with router::ApiError instead, which also carries a status code.
+*/
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io { context: String, source: io::Error },
+    Gpio { context: String, source: rppal::gpio::Error },
+    I2c { context: String, source: rppal::i2c::Error },
+    Systemctl { context: String, status: Option<i32> }, // systemctl ran, but failed with this exit code
+    ChannelClosed { channel: &'static str }, // the thread on the other end stopped
+    Other(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { context, source } => write!(f, "{}: {}", context, source),
+            Error::Gpio { context, source } => write!(f, "{}: {}", context, source),
+            Error::I2c { context, source } => write!(f, "{}: {}", context, source),
+            Error::Systemctl { context, status: Some(status) } => write!(f, "{}: systemctl exited with status {}", context, status),
+            Error::Systemctl { context, status: None } => write!(f, "{}: systemctl was killed by a signal", context),
+            Error::ChannelClosed { channel } => write!(f, "The {} channel is closed", channel),
+            Error::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Gpio { source, .. } => Some(source),
+            Error::I2c { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+pub trait Context<T> {
+    fn context(self, context: impl Into<String>) -> Result<T>;
+}
+
+impl<T> Context<T> for std::result::Result<T, io::Error> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|source| Error::Io { context: context.into(), source })
+    }
+}
+
+impl<T> Context<T> for std::result::Result<T, rppal::gpio::Error> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|source| Error::Gpio { context: context.into(), source })
+    }
+}
+
+impl<T> Context<T> for std::result::Result<T, rppal::i2c::Error> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|source| Error::I2c { context: context.into(), source })
+    }
+}
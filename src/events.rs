@@ -0,0 +1,153 @@
+/*
+Device events pushed to the app as Server-Sent Events on GET /events,
+so that it can react immediately instead of polling /battery-percent, /cpu-temp and /camera-stream-status.
+Like the other routes it requires the token of a paired device, as ?access_token=<token> since EventSource can't set headers.
+
+Each event is sent as:
+    id: 42
+    event: battery
+    data: {"type":"battery","percent":87,"millivolts":3950}
+
+A client that connects gets the latest battery and mode events right away, then every event as it happens.
+A comment line is sent every 15 seconds so that the hotspot and the app notice a dead connection.
+A client that falls SUBSCRIBER_BUFFER events behind, e.g. over a stalled connection, is disconnected
+rather than buffered for without limit. EventSource reconnects by itself and gets the latest state again.
+*/
+use std::io::Write;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+const SUBSCRIBER_BUFFER: usize = 64;
+
+pub use rearview_client::types::Event;
+
+fn is_state(event: &Event) -> bool {
+    // Replayed to clients that connect later, because they describe the current state rather than a moment
+    matches!(event, Event::Battery { .. } | Event::ModeChanged { .. })
+}
+
+struct Message {
+    id: u64,
+    event: Event,
+}
+
+impl Message {
+    fn to_sse(&self) -> String {
+        let data = serde_json::to_string(&self.event).unwrap_or_default();
+        format!("id: {}\nevent: {}\ndata: {}\n\n", self.id, self.event.name(), data)
+    }
+}
+
+#[derive(Default)]
+struct BusState {
+    next_id: u64,
+    subscribers: Vec<SyncSender<Arc<Message>>>,
+    latest_state: Vec<Arc<Message>>,
+}
+
+#[derive(Clone, Default)]
+pub struct EventBus {
+    state: Arc<Mutex<BusState>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus::default()
+    }
+
+    pub fn publish(&self, event: Event) {
+        log::debug!("Event: {:?}", event);
+        let mut state = self.state.lock().unwrap();
+        let message = Arc::new(Message { id: state.next_id, event });
+        state.next_id += 1;
+
+        if is_state(&message.event) {
+            let name = message.event.name();
+            state.latest_state.retain(|m| m.event.name() != name);
+            state.latest_state.push(message.clone());
+        }
+        // Subscribers that disconnected have dropped their receiver, and slow ones are dropped here
+        state.subscribers.retain(|subscriber| match subscriber.try_send(message.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                log::warn!("Event stream client is {} events behind, disconnecting it", SUBSCRIBER_BUFFER);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+
+    fn subscribe(&self) -> (Vec<Arc<Message>>, Receiver<Arc<Message>>) {
+        let (tx, rx) = mpsc::sync_channel(SUBSCRIBER_BUFFER);
+        let mut state = self.state.lock().unwrap();
+        state.subscribers.push(tx);
+        (state.latest_state.clone(), rx)
+    }
+
+    pub fn serve_sse(&self, request: tiny_http::Request) {
+        /*
+        The response lasts as long as the client listens, so like /stream.mjpeg it is written
+        directly to the socket instead of going through tiny_http's Response.
+        */
+        let peer = request.remote_addr().map(|a| a.to_string()).unwrap_or_default();
+        log::info!("Event stream client connected: {}", peer);
+        let (replay, events) = self.subscribe();
+
+        let mut writer = request.into_writer();
+        let header = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\nretry: 3000\n\n";
+        if writer.write_all(header.as_bytes()).and_then(|_| writer.flush()).is_err() {
+            return;
+        }
+        for message in replay {
+            if writer.write_all(message.to_sse().as_bytes()).and_then(|_| writer.flush()).is_err() {
+                return;
+            }
+        }
+
+        loop {
+            let chunk = match events.recv_timeout(KEEP_ALIVE_INTERVAL) {
+                Ok(message) => message.to_sse(),
+                Err(RecvTimeoutError::Timeout) => ": keep-alive\n\n".to_string(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if writer.write_all(chunk.as_bytes()).and_then(|_| writer.flush()).is_err() {
+                break;
+            }
+        }
+        log::info!("Event stream client disconnected: {}", peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn battery(percent: i32) -> Event {
+        Event::Battery { percent, millivolts: 3900 }
+    }
+
+    #[test]
+    fn replays_latest_state() {
+        let bus = EventBus::new();
+        bus.publish(battery(90));
+        bus.publish(battery(89));
+        let (replay, _events) = bus.subscribe();
+        assert_eq!(replay.len(), 1);
+        assert!(matches!(replay[0].event, Event::Battery { percent: 89, .. }));
+    }
+
+    #[test]
+    fn drops_subscriber_that_falls_behind() {
+        let bus = EventBus::new();
+        let (_, events) = bus.subscribe();
+        for percent in 0..SUBSCRIBER_BUFFER as i32 + 1 {
+            bus.publish(battery(percent));
+        }
+        assert!(bus.state.lock().unwrap().subscribers.is_empty());
+        // What was buffered is still delivered before the stream ends
+        assert_eq!(events.try_iter().count(), SUBSCRIBER_BUFFER);
+        assert!(matches!(events.recv_timeout(Duration::ZERO), Err(RecvTimeoutError::Disconnected)));
+    }
+}
@@ -1,64 +1,248 @@
 use std::sync::atomic::{AtomicI32, AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use rppal::gpio::{Gpio, Trigger};
 use rppal::i2c::I2c;
-use rppal::i2c::Error as I2cError;
 
-const FUEL_GAUGE_ADDR: u16 = 0x36;
-const SOC_REGISTER: u8 = 0x04;
-const VCELL_REGISTER: u8 = 0x02;
+use crate::error::{Context, Result};
+use crate::max17048::{self, Max17048, MockRegisters, Registers, StatusFlags};
+use crate::supervisor::{Heartbeat, Supervisor};
 
+// Low battery thresholds to shut down at, which the fuel gauge also alerts at.
+// Hardware cutoff is at 3.0V. We shut down at 3.4V to allow for typical 0.3V sag at
+// boot.
+pub const SHUTDOWN_MILLIVOLTS: i32 = 3450;
+pub const SHUTDOWN_PERCENT: i32 = 10;
+// Below the hardware cutoff, so only a removed battery counts as one
+const RESET_MILLIVOLTS: u16 = 2800;
+
+// The gauge's ALRT pin, open drain and pulled low on an alert. Not routed on I/O board v1.3,
+// so only watched with FUEL_GAUGE_ALERT=1 on boards where it is, see POWER_MANAGEMENT.md.
+pub const ALERT_PIN: u8 = 27;
+// The battery thread reads the gauge this often, or every IDLE_POLL_INTERVAL while the ALRT interrupt is armed
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+pub const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
 pub struct BatteryStats {
     pub state_of_charge_percent: i32,
     pub cell_millivolts: i32,
+    pub charge_rate_percent_per_hour: f32, // positive while charging
+    pub gauge: GaugeState,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GaugeState {
+    pub version: u16,
+    pub alert: bool, // the ALRT pin was asserted
+    pub alert_threshold_percent: u8,
+    pub reset_millivolts: u16,
+    pub hibernation_enabled: bool,
+    pub hibernating: bool,
+    pub flags: StatusFlags, // raised since the previous reading
+}
+
+#[derive(Debug, Default)]
+pub struct BatteryStatus {
+    // Latest successful reading and when it was taken, and the error of the latest attempt if it failed
+    pub reading: Option<(BatteryStats, Instant)>,
+    pub error: Option<String>,
 }
 
-fn get_battery_stats() -> Result<BatteryStats, I2cError> {
+type Gauge = Max17048<Box<dyn Registers + Send>>;
+
+#[derive(Default)]
+pub struct FuelGauge {
+    // Opened on the first reading, and again after a reading failed
+    gauge: Option<Gauge>,
+    // Off unless enabled through the API, and applied again whenever the gauge is opened
+    hibernation: bool,
+}
+
+impl FuelGauge {
+    pub fn new() -> Self {
+        FuelGauge::default()
+    }
+
+    pub fn read(&mut self) -> Result<BatteryStats> {
+        /*
+        Only called by the battery thread, which stores the latest battery state of charge to an atomic variable.
+        Load from that atomic variable instead of calling this function.
+        */
+        let result = self.gauge().and_then(read_stats);
+        if result.is_err() {
+            self.gauge = None;
+        }
+        result
+    }
+
+    pub fn quick_start(&mut self) -> Result<()> {
+        let result = self.gauge().and_then(|gauge| gauge.quick_start());
+        if result.is_err() {
+            self.gauge = None;
+        }
+        result
+    }
+
+    pub fn set_hibernation(&mut self, enabled: bool) -> Result<()> {
+        self.hibernation = enabled;
+        let result = self.gauge().and_then(|gauge| gauge.set_hibernation(enabled));
+        if result.is_err() {
+            self.gauge = None;
+        }
+        result
+    }
+
+    fn gauge(&mut self) -> Result<&mut Gauge> {
+        if self.gauge.is_none() {
+            self.gauge = Some(open(self.hibernation)?);
+        }
+        Ok(self.gauge.as_mut().expect("opened above"))
+    }
+}
+
+fn open(hibernation: bool) -> Result<Gauge> {
+    let registers: Box<dyn Registers + Send> = if std::env::var_os("SIMULATE_FUEL_GAUGE").is_some() {
+        log::warn!("Simulating the fuel gauge, battery readings are made up");
+        Box::new(MockRegisters::new(80.0, 3900.0, -12.0))
+    } else {
+        let mut i2c = I2c::new().context("Failed to open the I2C bus")?;
+        i2c.set_slave_address(max17048::ADDRESS).context("Failed to address the fuel gauge")?;
+        Box::new(i2c)
+    };
+    let mut gauge = Max17048::new(registers)?;
+
+    /*
+    Configured every time it's opened, since the gauge keeps its registers only while the battery is connected.
+    The ALRT pin is asserted at the shutdown thresholds. Hibernation is off by default: the camera draws enough current
+    that hibernating saves nothing, and the SOC would only be updated every 45 s.
+    */
+    gauge.set_alert_threshold(SHUTDOWN_PERCENT as u8)?;
+    gauge.set_voltage_alerts(SHUTDOWN_MILLIVOLTS as u16, u16::MAX)?;
+    gauge.set_reset_voltage(RESET_MILLIVOLTS)?;
+    gauge.set_hibernation(hibernation)?;
+    let version = gauge.version()?;
+    if gauge.status()?.reset {
+        log::info!("Fuel gauge version {:#06x} was reset, configured it again", version);
+    }
+    gauge.clear_status(StatusFlags { reset: true, ..StatusFlags::default() })?;
+    Ok(gauge)
+}
+
+fn read_stats<R: Registers>(gauge: &mut Max17048<R>) -> Result<BatteryStats> {
+    let flags = gauge.status()?;
+    let config = gauge.config()?;
+    // Cleared once read, so each flag is reported once and the ALRT pin is released
+    if flags.any() {
+        log::info!("Fuel gauge flags raised: {}", flags.names().join(", "));
+        gauge.clear_status(flags)?;
+    }
+    if config.alert {
+        gauge.clear_alert()?;
+    }
+    Ok(BatteryStats {
+        state_of_charge_percent: gauge.state_of_charge()?.round() as i32,
+        cell_millivolts: gauge.cell_millivolts()?.round() as i32,
+        charge_rate_percent_per_hour: gauge.charge_rate()?,
+        gauge: GaugeState {
+            version: gauge.version()?,
+            alert: config.alert,
+            alert_threshold_percent: config.alert_threshold_percent,
+            reset_millivolts: gauge.reset_voltage()?,
+            hibernation_enabled: gauge.hibernation_enabled()?,
+            hibernating: gauge.is_hibernating()?,
+            flags,
+        },
+    })
+}
+
+pub fn watch_alert(alerts: Sender<()>, armed: Arc<AtomicBool>, supervisor: &Supervisor) -> Result<()> {
     /*
-    Do not call from more than one location because this function requires access to i2c bus
-    This function is used by an updater thread to store the latest battery state of charge to an atomic variable.
-    Load from that atomic variable instead of calling this function.
+    Sends on alerts whenever the ALRT pin is asserted, so the battery thread reads the gauge right away.
+    armed is true while the interrupt is set, and the battery thread polls every second otherwise.
+    An unconnected pin would arm just the same and never be asserted, so it is only watched when FUEL_GAUGE_ALERT is set.
     */
-    let mut i2c = I2c::new()?;
-    i2c.set_slave_address(FUEL_GAUGE_ADDR)?;
-
-    // Read two bytes (upper and lower bytes) from the SOC register
-    let mut buf = [0u8; 2];
-    i2c.write_read(&[SOC_REGISTER], &mut buf)?;
-
-    let soc_percentage = ((buf[0] as u16) << 8 | buf[1] as u16) as f32 / 256.0;
-    //println!("State of Charge: {:.2}%", soc_percentage);
-
-    // Read from VCELL register
-    let mut vcell_buf = [0u8; 2];
-    i2c.write_read(&[VCELL_REGISTER], &mut vcell_buf)?;
-    // Datasheet for MAX17048 says VCELL has units: 78.125μV
-    // We'll return it as mV
-    let vcell_value = ((vcell_buf[0] as u16) << 8 | vcell_buf[1] as u16) as f32 * 78.125 / 1_000.0;
-    //println!("VCELL: {:.2}", vcell_value);
-
-    Ok(
-        BatteryStats {
-            state_of_charge_percent: soc_percentage.round() as i32,
-            cell_millivolts: vcell_value.round() as i32,
+    if std::env::var_os("FUEL_GAUGE_ALERT").is_none() {
+        log::info!("Fuel gauge alert not enabled, reading the gauge every {} seconds", POLL_INTERVAL.as_secs());
+        return Ok(());
+    }
+    supervisor.spawn("battery-alert", Duration::from_secs(30), move |heartbeat| {
+        let result = watch_alert_pin(&alerts, &armed, heartbeat);
+        armed.store(false, Ordering::Relaxed);
+        result
+    })
+}
+
+fn watch_alert_pin(alerts: &Sender<()>, armed: &AtomicBool, heartbeat: &Heartbeat) -> Result<()> {
+    let mut pin = Gpio::new().and_then(|gpio| gpio.get(ALERT_PIN))
+        .context(format!("Failed to open the fuel gauge alert on GPIO {}", ALERT_PIN))?
+        .into_input_pullup();
+    pin.set_interrupt(Trigger::FallingEdge).context("Failed to watch the fuel gauge alert")?;
+    armed.store(true, Ordering::Relaxed);
+    // Already asserted when the interrupt was set, e.g. while the server restarted
+    let mut asserted = pin.is_low();
+    loop {
+        heartbeat.beat();
+        if asserted {
+            log::info!("Fuel gauge alert asserted");
+            if alerts.send(()).is_err() {
+                return Ok(());
+            }
         }
-    )
+        asserted = pin.poll_interrupt(true, Some(Duration::from_secs(1)))
+            .context("Failed to watch the fuel gauge alert")?
+            .is_some();
+    }
 }
 
 pub fn store_battery_stats(
+    fuel_gauge: &Mutex<FuelGauge>,
     atomic_soc: &Arc<(AtomicI32, AtomicBool)>,
     atomic_voltage: &Arc<(AtomicI32, AtomicBool)>,
-    ) {
-    let new_stats = get_battery_stats();
+    status: &Mutex<BatteryStatus>,
+    ) -> Option<BatteryStats> {
+    // Returns the new reading, with the STATUS flags raised since the last one
+    let new_stats = fuel_gauge.lock().unwrap().read();
     match new_stats {
         Ok(stats) => {
+            *status.lock().unwrap() = BatteryStatus { reading: Some((stats, Instant::now())), error: None };
+
             atomic_soc.0.store(stats.state_of_charge_percent, Ordering::Relaxed);
             atomic_soc.1.store(true, Ordering::Relaxed);
 
             atomic_voltage.0.store(stats.cell_millivolts, Ordering::Relaxed);
             atomic_voltage.1.store(true, Ordering::Relaxed);
+            Some(stats)
+        }
+        Err(e) => {
+            log::warn!("Failed to fetch battery stats from I2C fuel gauge: {}", e);
+            status.lock().unwrap().error = Some(format!("Failed to read the I2C fuel gauge: {}", e));
+            None
         }
-        Err(_) => { println!("Failed to fetch battery stats from I2C fuel gauge"); }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_stats_reports_and_clears_flags_once() {
+        let mut registers = MockRegisters::new(8.0, 3400.0, -12.0);
+        registers.raise(StatusFlags { voltage_low: true, soc_low: true, ..StatusFlags::default() });
+        let mut gauge = Max17048::new(registers).unwrap();
+
+        let first = read_stats(&mut gauge).unwrap();
+        assert!(first.gauge.alert);
+        assert_eq!(first.gauge.flags.names(), vec!["reset", "voltage_low", "soc_low"]);
+        assert_eq!(first.state_of_charge_percent, 8);
+        assert!(first.charge_rate_percent_per_hour < 0.0);
+
+        let second = read_stats(&mut gauge).unwrap();
+        assert!(!second.gauge.alert);
+        assert!(!second.gauge.flags.any());
+        assert!(!gauge.config().unwrap().alert);
+    }
+}
@@ -0,0 +1,639 @@
+/*
+H.264 Annex-B byte stream parser and validator.
+
+Splits a byte stream into NAL units, decodes the sequence parameter set (resolution, profile, level)
+and checks the ordering the iOS decoder depends on: SPS and PPS must be sent ahead of every IDR frame
+(v4l2h264enc repeat_sequence_header=1, see VIDEO_ENCODING.md).
+
+Replaces debug_h264_stream.py: `supreme-server --check-h264 192.168.9.1:5000 <token>`
+*/
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use serde_derive::Serialize;
+
+pub const NAL_SLICE: u8 = 1;
+pub const NAL_IDR: u8 = 5;
+pub const NAL_SEI: u8 = 6;
+pub const NAL_SPS: u8 = 7;
+pub const NAL_PPS: u8 = 8;
+pub const NAL_AUD: u8 = 9;
+
+const MAX_BUFFER: usize = 4 * 1024 * 1024;
+const MAX_RECENT_VIOLATIONS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NalUnit {
+    pub nal_type: u8,
+    pub nal_ref_idc: u8,
+    pub forbidden_bit: bool,
+    pub data: Vec<u8>, // NAL header byte and payload, without start code
+}
+
+impl NalUnit {
+    fn from_bytes(data: Vec<u8>) -> Option<NalUnit> {
+        let header = *data.first()?;
+        Some(NalUnit {
+            nal_type: header & 0x1F,
+            nal_ref_idc: (header >> 5) & 0x03,
+            forbidden_bit: header & 0x80 != 0,
+            data,
+        })
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self.nal_type {
+            NAL_SLICE => "Coded slice of a non-IDR picture",
+            NAL_IDR => "Coded slice of an IDR picture",
+            NAL_SEI => "Supplemental enhancement information (SEI)",
+            NAL_SPS => "Sequence parameter set",
+            NAL_PPS => "Picture parameter set",
+            NAL_AUD => "Access unit delimiter",
+            _ => "Other",
+        }
+    }
+
+    pub fn is_vcl(&self) -> bool {
+        (1..=5).contains(&self.nal_type)
+    }
+
+    pub fn to_annex_b(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.data.len() + 4);
+        out.extend_from_slice(&[0, 0, 0, 1]);
+        out.extend_from_slice(&self.data);
+        out
+    }
+
+    pub fn rbsp(&self) -> Vec<u8> {
+        // Payload after the header byte, with emulation prevention bytes (00 00 03) removed
+        let mut rbsp = Vec::with_capacity(self.data.len());
+        let mut zeros = 0;
+        for &byte in self.data.iter().skip(1) {
+            if zeros >= 2 && byte == 0x03 {
+                zeros = 0;
+                continue;
+            }
+            zeros = if byte == 0 { zeros + 1 } else { 0 };
+            rbsp.push(byte);
+        }
+        rbsp
+    }
+}
+
+fn find_start_code(buf: &[u8], from: usize) -> Option<usize> {
+    // Position of the next 00 00 01. A four byte start code is a zero byte followed by this.
+    let mut i = from;
+    while i + 2 < buf.len() {
+        if buf[i + 2] > 1 {
+            i += 3;
+        } else if buf[i] == 0 && buf[i + 1] == 0 && buf[i + 2] == 1 {
+            return Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+#[derive(Default)]
+pub struct AnnexBParser {
+    buf: Vec<u8>,
+}
+
+impl AnnexBParser {
+    pub fn new() -> Self {
+        AnnexBParser { buf: Vec::new() }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() > MAX_BUFFER {
+            log::warn!("H.264 parser discarding {} bytes without a start code", self.buf.len());
+            self.buf.clear();
+        }
+    }
+
+    pub fn next_nal(&mut self) -> Option<NalUnit> {
+        /*
+        A NAL unit is complete once the start code of the following one has arrived,
+        so the last NAL unit in the buffer is held back until more data is pushed.
+        */
+        loop {
+            let start = find_start_code(&self.buf, 0)?;
+            let end = find_start_code(&self.buf, start + 3)?;
+
+            let mut data = self.buf[start + 3..end].to_vec();
+            while data.last() == Some(&0) {
+                data.pop(); // trailing_zero_8bits, or the leading zero of a four byte start code
+            }
+            self.buf.drain(..end);
+
+            if let Some(nal) = NalUnit::from_bytes(data) {
+                return Some(nal);
+            }
+        }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize, // in bits
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<u32> {
+        let byte = *self.data.get(self.pos / 8)?;
+        let bit = (byte >> (7 - (self.pos % 8))) & 1;
+        self.pos += 1;
+        Some(bit as u32)
+    }
+
+    fn read_bits(&mut self, n: u32) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit()?;
+        }
+        Some(value)
+    }
+
+    fn read_ue(&mut self) -> Option<u32> {
+        // Unsigned Exp-Golomb code
+        let mut leading_zeros = 0;
+        while self.read_bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u32 << leading_zeros) - 1 + self.read_bits(leading_zeros)?)
+    }
+
+    fn read_se(&mut self) -> Option<i32> {
+        let code = self.read_ue()? as i64;
+        let value = if code % 2 == 1 { (code + 1) / 2 } else { -(code / 2) };
+        Some(value as i32)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Sps {
+    pub sps_id: u32,
+    pub profile_idc: u8,
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub chroma_format_idc: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Sps {
+    pub fn parse(nal: &NalUnit) -> Option<Sps> {
+        if nal.nal_type != NAL_SPS {
+            return None;
+        }
+        let rbsp = nal.rbsp();
+        let mut r = BitReader::new(&rbsp);
+
+        let profile_idc = r.read_bits(8)? as u8;
+        let constraint_flags = r.read_bits(8)? as u8;
+        let level_idc = r.read_bits(8)? as u8;
+        let sps_id = r.read_ue()?;
+
+        let mut chroma_format_idc = 1;
+        let mut separate_colour_plane = false;
+        if [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135].contains(&profile_idc) {
+            chroma_format_idc = r.read_ue()?;
+            if chroma_format_idc == 3 {
+                separate_colour_plane = r.read_bit()? == 1;
+            }
+            r.read_ue()?; // bit_depth_luma_minus8
+            r.read_ue()?; // bit_depth_chroma_minus8
+            r.read_bit()?; // qpprime_y_zero_transform_bypass_flag
+            if r.read_bit()? == 1 {
+                // seq_scaling_matrix_present_flag
+                let lists = if chroma_format_idc != 3 { 8 } else { 12 };
+                for i in 0..lists {
+                    if r.read_bit()? == 1 {
+                        skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+
+        r.read_ue()?; // log2_max_frame_num_minus4
+        match r.read_ue()? {
+            0 => {
+                r.read_ue()?; // log2_max_pic_order_cnt_lsb_minus4
+            }
+            1 => {
+                r.read_bit()?; // delta_pic_order_always_zero_flag
+                r.read_se()?; // offset_for_non_ref_pic
+                r.read_se()?; // offset_for_top_to_bottom_field
+                for _ in 0..r.read_ue()? {
+                    r.read_se()?; // offset_for_ref_frame
+                }
+            }
+            _ => (),
+        }
+        r.read_ue()?; // max_num_ref_frames
+        r.read_bit()?; // gaps_in_frame_num_value_allowed_flag
+
+        let width_in_mbs = r.read_ue()? + 1;
+        let height_in_map_units = r.read_ue()? + 1;
+        let frame_mbs_only = r.read_bit()?;
+        if frame_mbs_only == 0 {
+            r.read_bit()?; // mb_adaptive_frame_field_flag
+        }
+        r.read_bit()?; // direct_8x8_inference_flag
+
+        let (mut crop_left, mut crop_right, mut crop_top, mut crop_bottom) = (0, 0, 0, 0);
+        if r.read_bit()? == 1 {
+            crop_left = r.read_ue()?;
+            crop_right = r.read_ue()?;
+            crop_top = r.read_ue()?;
+            crop_bottom = r.read_ue()?;
+        }
+
+        // Cropping is in units of chroma samples, see equations 7-19 to 7-22 of the H.264 spec
+        let chroma_array_type = if separate_colour_plane { 0 } else { chroma_format_idc };
+        let (sub_width_c, sub_height_c) = match chroma_array_type {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        let crop_unit_x = if chroma_array_type == 0 { 1 } else { sub_width_c };
+        let crop_unit_y = (2 - frame_mbs_only) * if chroma_array_type == 0 { 1 } else { sub_height_c };
+
+        let width = (width_in_mbs * 16).checked_sub((crop_left + crop_right) * crop_unit_x)?;
+        let height = ((2 - frame_mbs_only) * height_in_map_units * 16).checked_sub((crop_top + crop_bottom) * crop_unit_y)?;
+
+        Some(Sps { sps_id, profile_idc, constraint_flags, level_idc, chroma_format_idc, width, height })
+    }
+
+    pub fn profile_name(&self) -> &'static str {
+        match self.profile_idc {
+            66 if self.constraint_flags & 0x40 != 0 => "Constrained Baseline",
+            66 => "Baseline",
+            77 => "Main",
+            88 => "Extended",
+            100 => "High",
+            110 => "High 10",
+            122 => "High 4:2:2",
+            244 => "High 4:4:4 Predictive",
+            _ => "Unknown",
+        }
+    }
+
+    pub fn level_name(&self) -> String {
+        // level_idc 11 with constraint_set3_flag is level 1b
+        if self.level_idc == 11 && self.constraint_flags & 0x10 != 0 {
+            return "1b".to_string();
+        }
+        if self.level_idc.is_multiple_of(10) {
+            format!("{}", self.level_idc / 10)
+        } else {
+            format!("{}.{}", self.level_idc / 10, self.level_idc % 10)
+        }
+    }
+}
+
+fn skip_scaling_list(r: &mut BitReader, size: usize) -> Option<()> {
+    let mut last_scale = 8;
+    let mut next_scale = 8;
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = r.read_se()?;
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Some(())
+}
+
+fn parse_pps_ids(nal: &NalUnit) -> Option<(u32, u32)> {
+    // (pic_parameter_set_id, seq_parameter_set_id)
+    let rbsp = nal.rbsp();
+    let mut r = BitReader::new(&rbsp);
+    Some((r.read_ue()?, r.read_ue()?))
+}
+
+fn first_mb_in_slice(nal: &NalUnit) -> Option<u32> {
+    let rbsp = nal.rbsp();
+    BitReader::new(&rbsp).read_ue()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Violation {
+    ForbiddenBitSet,
+    InvalidSps,
+    InvalidPps,
+    PpsReferencesUnknownSps,
+    SliceBeforeFirstIdr,
+    IdrWithoutSps,
+    IdrWithoutPps,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ViolationRecord {
+    pub nal_index: u64,
+    pub violation: Violation,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamReport {
+    pub nal_units: u64,
+    pub idr_frames: u64,
+    pub non_idr_slices: u64,
+    pub sps_count: u64,
+    pub pps_count: u64,
+    pub sps: Option<Sps>,
+    pub profile: Option<&'static str>,
+    pub level: Option<String>,
+    pub violation_count: u64,
+    pub recent_violations: Vec<ViolationRecord>,
+}
+
+#[derive(Default)]
+pub struct StreamValidator {
+    nal_units: u64,
+    idr_frames: u64,
+    non_idr_slices: u64,
+    sps_count: u64,
+    pps_count: u64,
+    sps: Option<Sps>,
+    sps_ids: Vec<u32>,
+    sps_since_vcl: bool,
+    pps_since_vcl: bool,
+    seen_idr: bool,
+    previous_vcl_was_idr: bool,
+    violation_count: u64,
+    recent_violations: Vec<ViolationRecord>,
+}
+
+impl StreamValidator {
+    pub fn new() -> Self {
+        StreamValidator::default()
+    }
+
+    pub fn inspect(&mut self, nal: &NalUnit) -> Option<Violation> {
+        let violation = self.check(nal);
+        if let Some(violation) = violation {
+            self.violation_count += 1;
+            if self.recent_violations.len() >= MAX_RECENT_VIOLATIONS {
+                self.recent_violations.remove(0);
+            }
+            self.recent_violations.push(ViolationRecord { nal_index: self.nal_units, violation });
+        }
+        self.nal_units += 1;
+        violation
+    }
+
+    fn check(&mut self, nal: &NalUnit) -> Option<Violation> {
+        if nal.forbidden_bit {
+            return Some(Violation::ForbiddenBitSet);
+        }
+
+        match nal.nal_type {
+            NAL_SPS => {
+                self.sps_count += 1;
+                self.sps_since_vcl = true;
+                match Sps::parse(nal) {
+                    Some(sps) => {
+                        if !self.sps_ids.contains(&sps.sps_id) {
+                            self.sps_ids.push(sps.sps_id);
+                        }
+                        self.sps = Some(sps);
+                        None
+                    }
+                    None => Some(Violation::InvalidSps),
+                }
+            }
+            NAL_PPS => {
+                self.pps_count += 1;
+                let (_, sps_id) = match parse_pps_ids(nal) {
+                    Some(ids) => ids,
+                    None => return Some(Violation::InvalidPps),
+                };
+                self.pps_since_vcl = true;
+                if !self.sps_ids.contains(&sps_id) {
+                    return Some(Violation::PpsReferencesUnknownSps);
+                }
+                None
+            }
+            NAL_IDR => {
+                // Further slices of the same IDR picture (first_mb_in_slice != 0) don't need their own SPS/PPS
+                let continues_picture = self.previous_vcl_was_idr && first_mb_in_slice(nal).unwrap_or(0) != 0;
+                let violation = if continues_picture {
+                    None
+                } else {
+                    self.idr_frames += 1;
+                    if !self.sps_since_vcl {
+                        Some(Violation::IdrWithoutSps)
+                    } else if !self.pps_since_vcl {
+                        Some(Violation::IdrWithoutPps)
+                    } else {
+                        None
+                    }
+                };
+                self.seen_idr = true;
+                self.previous_vcl_was_idr = true;
+                self.sps_since_vcl = false;
+                self.pps_since_vcl = false;
+                violation
+            }
+            t if (1..=4).contains(&t) => {
+                self.non_idr_slices += 1;
+                self.previous_vcl_was_idr = false;
+                self.sps_since_vcl = false;
+                self.pps_since_vcl = false;
+                if !self.seen_idr {
+                    return Some(Violation::SliceBeforeFirstIdr);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    pub fn report(&self) -> StreamReport {
+        StreamReport {
+            nal_units: self.nal_units,
+            idr_frames: self.idr_frames,
+            non_idr_slices: self.non_idr_slices,
+            sps_count: self.sps_count,
+            pps_count: self.pps_count,
+            sps: self.sps.clone(),
+            profile: self.sps.as_ref().map(|s| s.profile_name()),
+            level: self.sps.as_ref().map(|s| s.level_name()),
+            violation_count: self.violation_count,
+            recent_violations: self.recent_violations.clone(),
+        }
+    }
+}
+
+pub fn check_tcp_stream(addr: &str, token: Option<&str>, duration: Duration, verbose: bool) -> io::Result<StreamReport> {
+    /*
+    Connects to an H.264-over-TCP stream and validates it for the given duration.
+    The stream relay only sends to clients with the token of a paired device, see src/auth.rs.
+    With verbose, every NAL unit is printed like debug_h264_stream.py did.
+    */
+    let mut stream = TcpStream::connect(addr)?;
+    if let Some(token) = token {
+        stream.write_all(format!("Authorization: Bearer {}\r\n", token).as_bytes())?;
+    }
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    let mut parser = AnnexBParser::new();
+    let mut validator = StreamValidator::new();
+    let mut chunk = [0u8; 64 * 1024];
+    let started = Instant::now();
+
+    while started.elapsed() < duration {
+        let n = match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+        parser.push(&chunk[..n]);
+        while let Some(nal) = parser.next_nal() {
+            let violation = validator.inspect(&nal);
+            if verbose {
+                match violation {
+                    Some(v) => println!("Found NALU, Type: {}, Length: {} bytes, VIOLATION: {:?}", nal.type_name(), nal.data.len(), v),
+                    None => println!("Found NALU, Type: {}, Length: {} bytes", nal.type_name(), nal.data.len()),
+                }
+            }
+        }
+    }
+
+    Ok(validator.report())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SPS and PPS from x264, 1280x720 High profile level 3.1 and 1920x1080 High profile level 4
+    const SPS_720P: &[u8] = &[
+        0x67, 0x64, 0x00, 0x1f, 0xac, 0xd9, 0x40, 0x50, 0x05, 0xbb, 0x01, 0x10, 0x00, 0x00, 0x03, 0x00,
+        0x10, 0x00, 0x00, 0x03, 0x03, 0xc0, 0xf1, 0x83, 0x19, 0x60,
+    ];
+    const SPS_1080P: &[u8] = &[
+        0x67, 0x64, 0x00, 0x28, 0xac, 0xd9, 0x40, 0x78, 0x02, 0x27, 0xe5, 0xc0, 0x44, 0x00, 0x00, 0x03,
+        0x00, 0x04, 0x00, 0x00, 0x03, 0x00, 0xf0, 0x3c, 0x60, 0xc6, 0x58,
+    ];
+    const PPS: &[u8] = &[0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0];
+    // Slice headers only: first_mb_in_slice is 0, or 1 for the second slice of a picture
+    const IDR: &[u8] = &[0x65, 0x88, 0x84];
+    const IDR_SECOND_SLICE: &[u8] = &[0x65, 0x40, 0x84];
+    const SLICE: &[u8] = &[0x41, 0x9a, 0x02];
+
+    fn nal(data: &[u8]) -> NalUnit {
+        NalUnit::from_bytes(data.to_vec()).unwrap()
+    }
+
+    fn drain(parser: &mut AnnexBParser) -> Vec<NalUnit> {
+        std::iter::from_fn(|| parser.next_nal()).collect()
+    }
+
+    #[test]
+    fn splits_three_and_four_byte_start_codes() {
+        let mut stream = vec![0, 0, 0, 1];
+        stream.extend_from_slice(SPS_720P);
+        stream.extend_from_slice(&[0, 0, 1]);
+        stream.extend_from_slice(PPS);
+        stream.extend_from_slice(&[0, 0, 0, 1]);
+        stream.extend_from_slice(IDR);
+
+        let mut parser = AnnexBParser::new();
+        parser.push(&stream);
+        // The IDR is held back until the next start code shows where it ends
+        assert_eq!(drain(&mut parser), vec![nal(SPS_720P), nal(PPS)]);
+        parser.push(&[0, 0, 0, 1, NAL_AUD]);
+        assert_eq!(drain(&mut parser), vec![nal(IDR)]);
+    }
+
+    #[test]
+    fn joins_nal_units_split_across_pushes() {
+        let stream: Vec<u8> = [SPS_1080P, PPS, IDR, SLICE].iter().flat_map(|data| nal(data).to_annex_b()).chain([0, 0, 1, NAL_AUD]).collect();
+        let mut parser = AnnexBParser::new();
+        let mut nal_units = Vec::new();
+        // One byte at a time, so start codes are split too
+        for byte in stream {
+            parser.push(&[byte]);
+            nal_units.extend(drain(&mut parser));
+        }
+        assert_eq!(nal_units, vec![nal(SPS_1080P), nal(PPS), nal(IDR), nal(SLICE)]);
+    }
+
+    #[test]
+    fn removes_emulation_prevention_bytes() {
+        assert_eq!(nal(&[0x06, 0x00, 0x00, 0x03, 0x01, 0x80]).rbsp(), vec![0x00, 0x00, 0x01, 0x80]);
+    }
+
+    #[test]
+    fn parses_sps() {
+        let sps = Sps::parse(&nal(SPS_720P)).unwrap();
+        assert_eq!((sps.width, sps.height), (1280, 720));
+        assert_eq!((sps.profile_name(), sps.level_name().as_str()), ("High", "3.1"));
+
+        // 1088 rows of macroblocks, cropped to 1080
+        let sps = Sps::parse(&nal(SPS_1080P)).unwrap();
+        assert_eq!((sps.width, sps.height), (1920, 1080));
+        assert_eq!((sps.profile_name(), sps.level_name().as_str()), ("High", "4"));
+
+        assert_eq!(Sps::parse(&nal(PPS)), None);
+    }
+
+    fn validate(nal_units: &[&[u8]]) -> (Vec<Option<Violation>>, StreamReport) {
+        let mut validator = StreamValidator::new();
+        let violations = nal_units.iter().map(|data| validator.inspect(&nal(data))).collect();
+        (violations, validator.report())
+    }
+
+    #[test]
+    fn accepts_sps_and_pps_ahead_of_every_idr() {
+        let (violations, report) = validate(&[SPS_720P, PPS, IDR, SLICE, SPS_720P, PPS, IDR]);
+        assert!(violations.iter().all(Option::is_none));
+        assert_eq!((report.idr_frames, report.non_idr_slices), (2, 1));
+        assert_eq!(report.sps.map(|sps| (sps.width, sps.height)), Some((1280, 720)));
+    }
+
+    #[test]
+    fn flags_idr_without_sps_or_pps() {
+        let (violations, report) = validate(&[SPS_720P, PPS, IDR, SLICE, IDR, SLICE, SPS_720P, IDR]);
+        assert_eq!(violations[4], Some(Violation::IdrWithoutSps));
+        assert_eq!(violations[7], Some(Violation::IdrWithoutPps));
+        assert_eq!(report.violation_count, 2);
+    }
+
+    #[test]
+    fn accepts_multi_slice_idr() {
+        // Only the first slice of the picture needs SPS and PPS ahead of it
+        let (violations, report) = validate(&[SPS_720P, PPS, IDR, IDR_SECOND_SLICE, SLICE]);
+        assert!(violations.iter().all(Option::is_none));
+        assert_eq!(report.idr_frames, 1);
+
+        // A new IDR picture after another IDR picture does
+        let (violations, _) = validate(&[SPS_720P, PPS, IDR, IDR]);
+        assert_eq!(violations[3], Some(Violation::IdrWithoutSps));
+    }
+
+    #[test]
+    fn flags_slice_before_first_idr_and_unknown_sps() {
+        let (violations, _) = validate(&[SLICE]);
+        assert_eq!(violations[0], Some(Violation::SliceBeforeFirstIdr));
+        let (violations, _) = validate(&[PPS]);
+        assert_eq!(violations[0], Some(Violation::PpsReferencesUnknownSps));
+    }
+}
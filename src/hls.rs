@@ -0,0 +1,503 @@
+/*
+HTTP Live Streaming (RFC 8216) for AVPlayer, on the control port.
+
+    GET /live/index.m3u8            live H.264 stream, requires the h264 stream codec
+    GET /live/segment{n}.ts
+    GET /videos/{id}/index.m3u8     a recorded chunk as a VOD playlist, e.g. /videos/log0001/index.m3u8
+    GET /videos/{id}/segment{n}.ts
+
+Segments are MPEG-TS (src/mpegts.rs) cut at IDR frames. Nothing is transcoded.
+Live segments are muxed from the stream relay while a client keeps requesting the playlist,
+and are as short as the encoder's keyframe interval allows, to keep latency low.
+VOD segments are remuxed from the Matroska chunk (src/matroska.rs) on every request,
+from an index of its frames that is kept until the file changes.
+*/
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use tiny_http::{Header, Request, Response};
+
+use crate::h264;
+use crate::matroska::{self, Frame, H264Track};
+use crate::mpegts::TsMuxer;
+use crate::standalone_filesystem;
+use crate::stream_relay::StreamRelay;
+use crate::video_settings::StreamCodec;
+
+const MIN_LIVE_SEGMENT_DURATION: Duration = Duration::from_secs(1); // cut at the first IDR after this
+const MAX_LIVE_SEGMENTS: usize = 6;
+const MIN_PLAYLIST_SEGMENTS: usize = 2; // AVPlayer stalls when it starts with a single segment
+const LIVE_STARTUP_TIMEOUT: Duration = Duration::from_secs(15);
+const LIVE_IDLE_TIMEOUT: Duration = Duration::from_secs(30); // stop muxing once no client asks for the playlist
+const MAX_FRAME_GAP: Duration = Duration::from_secs(2); // a longer gap in the stream is marked as a discontinuity
+const VOD_SEGMENT_DURATION_NS: u64 = 6_000_000_000;
+const VOD_CACHED_INDEXES: usize = 4; // a player watches one chunk at a time, maybe moving on to the next
+const PLAYLIST_TYPE: &str = "application/vnd.apple.mpegurl";
+const SEGMENT_TYPE: &str = "video/mp2t";
+
+struct LiveSegment {
+    sequence: u64,
+    duration: Duration,
+    discontinuity: bool,
+    data: Arc<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct LiveState {
+    segments: VecDeque<LiveSegment>,
+    next_sequence: u64,
+    discontinuity_sequence: u64,
+    last_request: Option<Instant>,
+    running: bool,
+}
+
+struct VodIndex {
+    track: H264Track,
+    segments: Vec<(Range<usize>, f64)>, // frames and duration in seconds
+}
+
+struct CachedIndex {
+    path: PathBuf,
+    modified: SystemTime,
+    size: u64,
+    index: Arc<VodIndex>,
+}
+
+#[derive(Clone)]
+pub struct HlsServer {
+    relay: StreamRelay,
+    live: Arc<(Mutex<LiveState>, Condvar)>,
+    vod_indexes: Arc<Mutex<VecDeque<CachedIndex>>>, // most recently used last
+}
+
+impl HlsServer {
+    pub fn new(relay: StreamRelay) -> Self {
+        HlsServer {
+            relay,
+            live: Arc::new((Mutex::new(LiveState::default()), Condvar::new())),
+            vod_indexes: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    pub fn serve(&self, request: Request) {
+        let path = request.url().split('?').next().unwrap_or_default().to_string();
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+        // Segment URIs are relative and carry the playlist's query, e.g. its access_token, since AVPlayer doesn't
+        let query = request.url().split_once('?').map(|(_, query)| query.to_string());
+        let result = match segments.as_slice() {
+            ["live", "index.m3u8"] => self.live_playlist().map(|body| (with_query(body, &query).into_bytes(), PLAYLIST_TYPE)),
+            ["live", file] => match segment_number(file) {
+                Some(sequence) => self.live_segment(sequence).map(|data| (data.to_vec(), SEGMENT_TYPE)),
+                None => Err((404, "Not found".to_string())),
+            },
+            ["videos", id, "index.m3u8"] => self.vod_playlist(id).map(|body| (with_query(body, &query).into_bytes(), PLAYLIST_TYPE)),
+            ["videos", id, file] => match segment_number(file) {
+                Some(index) => self.vod_segment(id, index as usize).map(|data| (data, SEGMENT_TYPE)),
+                None => Err((404, "Not found".to_string())),
+            },
+            _ => Err((404, "Not found".to_string())),
+        };
+
+        let response = match result {
+            Ok((body, content_type)) => {
+                let content_type = Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap();
+                // Live playlists change with every segment
+                let cache_control = Header::from_bytes(&b"Cache-Control"[..], &b"no-cache"[..]).unwrap();
+                Response::from_data(body).with_header(content_type).with_header(cache_control).with_status_code(200)
+            }
+            Err((status, message)) => {
+                if status >= 500 {
+                    log::warn!("HLS request for {} failed: {}", path, message);
+                }
+                Response::from_string(message).with_status_code(status)
+            }
+        };
+        let _ = request.respond(response);
+    }
+
+    fn live_playlist(&self) -> Result<String, (u16, String)> {
+        /*
+        Starts the segmenter if needed and waits until enough segments exist to start playback.
+        Once clients stop asking for the playlist, the segmenter stops by itself.
+        */
+        match self.relay.upstream_codec() {
+            Some(StreamCodec::H264) => (),
+            Some(StreamCodec::Mjpeg) => return Err((409, "Live HLS requires the h264 stream codec, see PUT /video/codec".to_string())),
+            None => return Err((503, "Camera stream is not running".to_string())),
+        }
+
+        let (state, segments_changed) = &*self.live;
+        let mut live = state.lock().unwrap();
+        live.last_request = Some(Instant::now());
+        if !live.running {
+            live.running = true;
+            let server = self.clone();
+            thread::spawn(move || server.run_segmenter());
+        }
+        let (live, timeout) = segments_changed
+            .wait_timeout_while(live, LIVE_STARTUP_TIMEOUT, |live| live.running && live.segments.len() < MIN_PLAYLIST_SEGMENTS)
+            .unwrap();
+        if timeout.timed_out() || live.segments.len() < MIN_PLAYLIST_SEGMENTS {
+            return Err((503, "Live stream segments are not ready yet".to_string()));
+        }
+
+        Ok(render_live_playlist(&live))
+    }
+
+    fn live_segment(&self, sequence: u64) -> Result<Arc<Vec<u8>>, (u16, String)> {
+        let live = self.live.0.lock().unwrap();
+        live.segments.iter()
+            .find(|segment| segment.sequence == sequence)
+            .map(|segment| segment.data.clone())
+            .ok_or((404, format!("Live segment {} is no longer available", sequence)))
+    }
+
+    fn run_segmenter(&self) {
+        /*
+        Groups the relay's NAL units into access units the same way as the RTSP server:
+        a new access unit starts with the first NAL unit after a slice.
+        Timestamps come from the arrival time, like the RTP timestamps.
+        */
+        log::info!("HLS live segmenter started");
+        let mut subscription = self.relay.subscribe("hls", "HTTP Live Streaming".to_string());
+        let started = Instant::now();
+        let mut muxer = TsMuxer::new();
+        let mut segment_start: Option<Duration> = None;
+        let mut discontinuity = false;
+        let mut access_unit: Vec<u8> = Vec::new();
+        let mut access_unit_keyframe = false;
+        let mut access_unit_time = Duration::ZERO;
+        let mut last_access_unit_time = Duration::ZERO;
+        let mut previous_was_vcl = true;
+
+        loop {
+            let idle = self.live.0.lock().unwrap().last_request.is_none_or(|t| t.elapsed() > LIVE_IDLE_TIMEOUT);
+            if idle || self.relay.upstream_codec() != Some(StreamCodec::H264) {
+                break;
+            }
+            let Some(packets) = subscription.next_packet(Duration::from_secs(1)) else { continue };
+
+            for packet in packets {
+                let nal = &packet.data[4.min(packet.data.len())..];
+                let Some(&header) = nal.first() else { continue };
+                let is_vcl = (1..=5).contains(&(header & 0x1F));
+
+                if previous_was_vcl && !access_unit.is_empty() {
+                    // The previous access unit is complete
+                    if let Some(start) = segment_start {
+                        if access_unit_time.saturating_sub(last_access_unit_time) > MAX_FRAME_GAP {
+                            // The stream stalled or restarted: end the segment at its last frame
+                            self.push_live_segment(&mut muxer, last_access_unit_time.saturating_sub(start), discontinuity);
+                            segment_start = None;
+                            discontinuity = true;
+                        } else if access_unit_keyframe && access_unit_time - start >= MIN_LIVE_SEGMENT_DURATION {
+                            self.push_live_segment(&mut muxer, access_unit_time - start, discontinuity);
+                            segment_start = None;
+                            discontinuity = false;
+                        }
+                    }
+                    if segment_start.is_none() && access_unit_keyframe {
+                        segment_start = Some(access_unit_time);
+                        muxer.begin_segment();
+                    }
+                    if segment_start.is_some() {
+                        muxer.write_access_unit(&access_unit, duration_to_90khz(access_unit_time), access_unit_keyframe);
+                    }
+                    last_access_unit_time = access_unit_time;
+                    access_unit.clear();
+                    access_unit_keyframe = false;
+                }
+                if previous_was_vcl {
+                    access_unit_time = started.elapsed();
+                }
+                previous_was_vcl = is_vcl;
+                access_unit.extend_from_slice(&packet.data);
+                access_unit_keyframe |= header & 0x1F == h264::NAL_IDR;
+            }
+        }
+
+        let (state, segments_changed) = &*self.live;
+        let mut live = state.lock().unwrap();
+        live.running = false;
+        live.segments.clear();
+        segments_changed.notify_all();
+        log::info!("HLS live segmenter stopped");
+    }
+
+    fn push_live_segment(&self, muxer: &mut TsMuxer, duration: Duration, discontinuity: bool) {
+        let (state, segments_changed) = &*self.live;
+        let mut live = state.lock().unwrap();
+        let sequence = live.next_sequence;
+        live.next_sequence += 1;
+        live.segments.push_back(LiveSegment {
+            sequence,
+            duration,
+            discontinuity,
+            data: Arc::new(muxer.take_segment()),
+        });
+        while live.segments.len() > MAX_LIVE_SEGMENTS {
+            if live.segments.pop_front().is_some_and(|segment| segment.discontinuity) {
+                live.discontinuity_sequence += 1;
+            }
+        }
+        segments_changed.notify_all();
+    }
+
+    fn vod_index(&self, path: &Path) -> Result<Arc<VodIndex>, (u16, String)> {
+        // Parsing a chunk reads all its block headers, so the index is kept for the segment requests that follow the playlist
+        let read_error = |e: std::io::Error| (500, format!("Failed to read {}: {}", path.display(), e));
+        let metadata = fs::metadata(path).map_err(read_error)?;
+        let modified = metadata.modified().map_err(read_error)?;
+        let mut indexes = self.vod_indexes.lock().unwrap();
+        if let Some(position) = indexes.iter().position(|cached| cached.path == path && cached.modified == modified && cached.size == metadata.len()) {
+            let cached = indexes.remove(position).expect("found above");
+            let index = cached.index.clone();
+            indexes.push_back(cached);
+            return Ok(index);
+        }
+        drop(indexes);
+
+        let track = matroska::read_h264_track(path).map_err(read_error)?;
+        let segments = vod_segments(&track.frames);
+        let index = Arc::new(VodIndex { track, segments });
+        let mut indexes = self.vod_indexes.lock().unwrap();
+        indexes.retain(|cached| cached.path != path);
+        indexes.push_back(CachedIndex { path: path.to_path_buf(), modified, size: metadata.len(), index: index.clone() });
+        while indexes.len() > VOD_CACHED_INDEXES {
+            indexes.pop_front();
+        }
+        Ok(index)
+    }
+
+    fn vod_playlist(&self, id: &str) -> Result<String, (u16, String)> {
+        /*
+        The chunk currently being recorded is an EVENT playlist without #EXT-X-ENDLIST, so players reload it as it grows.
+        Its last segment is left out until the next keyframe ends it, since listed segments must not change.
+        */
+        let path = standalone_filesystem::recording_path(id)?;
+        let index = self.vod_index(&path)?;
+        let recording = is_being_recorded(&path);
+        let segments = if recording { &index.segments[..index.segments.len().saturating_sub(1)] } else { &index.segments[..] };
+        if segments.is_empty() {
+            return Err((503, format!("{} has no complete segment yet", id)));
+        }
+
+        Ok(render_vod_playlist(segments, recording))
+    }
+
+    fn vod_segment(&self, id: &str, index: usize) -> Result<Vec<u8>, (u16, String)> {
+        let path = standalone_filesystem::recording_path(id)?;
+        let read_error = |e: std::io::Error| (500, format!("Failed to read {}: {}", path.display(), e));
+        let vod = self.vod_index(&path)?;
+        let track = &vod.track;
+        let (frames, _) = vod.segments.get(index).cloned()
+            .ok_or((404, format!("{} has no segment {}", id, index)))?;
+
+        let mut file = File::open(&path).map_err(read_error)?;
+        let mut muxer = TsMuxer::new();
+        muxer.begin_segment();
+        for frame in &track.frames[frames] {
+            let mut access_unit = matroska::read_frame_annex_b(&mut file, frame, track.nal_length_size).map_err(read_error)?;
+            if frame.keyframe && !contains_sps(&access_unit) {
+                // The parameter sets may only be in the track header, but every segment must be decodable on its own
+                let mut with_parameter_sets = Vec::new();
+                for parameter_set in track.sps.iter().chain(track.pps.iter()) {
+                    with_parameter_sets.extend_from_slice(&[0, 0, 0, 1]);
+                    with_parameter_sets.extend_from_slice(parameter_set);
+                }
+                with_parameter_sets.append(&mut access_unit);
+                access_unit = with_parameter_sets;
+            }
+            muxer.write_access_unit(&access_unit, frame.timestamp_ns * 9 / 100_000, frame.keyframe);
+        }
+        Ok(muxer.take_segment())
+    }
+}
+
+fn render_live_playlist(live: &LiveState) -> String {
+    // The segments of the sliding window, at least MIN_PLAYLIST_SEGMENTS
+    let target_duration = live.segments.iter().map(|s| s.duration.as_secs_f64().ceil() as u64).max().unwrap_or(1);
+    let mut playlist = String::new();
+    playlist.push_str("#EXTM3U\n#EXT-X-VERSION:3\n");
+    playlist.push_str(&format!("#EXT-X-TARGETDURATION:{}\n", target_duration));
+    playlist.push_str(&format!("#EXT-X-MEDIA-SEQUENCE:{}\n", live.segments[0].sequence));
+    playlist.push_str(&format!("#EXT-X-DISCONTINUITY-SEQUENCE:{}\n", live.discontinuity_sequence));
+    for segment in &live.segments {
+        if segment.discontinuity {
+            playlist.push_str("#EXT-X-DISCONTINUITY\n");
+        }
+        playlist.push_str(&format!("#EXTINF:{:.3},\nsegment{}.ts\n", segment.duration.as_secs_f64(), segment.sequence));
+    }
+    playlist
+}
+
+fn render_vod_playlist(segments: &[(Range<usize>, f64)], recording: bool) -> String {
+    let target_duration = segments.iter().map(|(_, duration)| duration.ceil() as u64).max().unwrap_or(1);
+    let mut playlist = String::new();
+    playlist.push_str("#EXTM3U\n#EXT-X-VERSION:3\n");
+    playlist.push_str(if recording { "#EXT-X-PLAYLIST-TYPE:EVENT\n" } else { "#EXT-X-PLAYLIST-TYPE:VOD\n" });
+    playlist.push_str(&format!("#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n", target_duration));
+    for (index, (_, duration)) in segments.iter().enumerate() {
+        playlist.push_str(&format!("#EXTINF:{:.3},\nsegment{}.ts\n", duration, index));
+    }
+    if !recording {
+        playlist.push_str("#EXT-X-ENDLIST\n");
+    }
+    playlist
+}
+
+fn segment_number(file: &str) -> Option<u64> {
+    file.strip_prefix("segment")?.strip_suffix(".ts")?.parse().ok()
+}
+
+fn duration_to_90khz(duration: Duration) -> u64 {
+    duration.as_secs() * 90_000 + duration.subsec_nanos() as u64 * 9 / 100_000
+}
+
+fn vod_segments(frames: &[Frame]) -> Vec<(Range<usize>, f64)> {
+    /*
+    Splits the frames into segments of about VOD_SEGMENT_DURATION_NS, each starting at a keyframe.
+    The last frame lasts as long as the average frame.
+    */
+    let Some(first) = frames.iter().position(|frame| frame.keyframe) else { return Vec::new() };
+    let frames_span = frames.last().map_or(0, |last| last.timestamp_ns - frames[first].timestamp_ns);
+    let frame_duration = if frames.len() - first > 1 { frames_span / (frames.len() - first - 1) as u64 } else { 33_333_333 };
+
+    let mut starts = vec![first];
+    for (index, frame) in frames.iter().enumerate().skip(first + 1) {
+        let segment_start = frames[*starts.last().unwrap_or(&first)].timestamp_ns;
+        if frame.keyframe && frame.timestamp_ns.saturating_sub(segment_start) >= VOD_SEGMENT_DURATION_NS {
+            starts.push(index);
+        }
+    }
+
+    let mut segments = Vec::new();
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(frames.len());
+        let end_ns = match starts.get(i + 1) {
+            Some(&next) => frames[next].timestamp_ns,
+            None => frames[end - 1].timestamp_ns + frame_duration,
+        };
+        segments.push((start..end, end_ns.saturating_sub(frames[start].timestamp_ns) as f64 / 1e9));
+    }
+    segments
+}
+
+fn with_query(playlist: String, query: &Option<String>) -> String {
+    match query {
+        Some(query) => playlist.lines()
+            .map(|line| if line.ends_with(".ts") { format!("{}?{}\n", line, query) } else { format!("{}\n", line) })
+            .collect(),
+        None => playlist,
+    }
+}
+
+fn is_being_recorded(path: &Path) -> bool {
+    // The newest chunk, while standalone mode records
+    let newest = standalone_filesystem::files_sorted_by_date(standalone_filesystem::VIDEOS_DIR).ok()
+        .and_then(|files| files.into_iter().rev().map(|(path, _)| path).find(|path| path.extension().is_some_and(|ext| ext == "mkv")));
+    newest.as_deref() == Some(path) && systemctl::is_active(standalone_filesystem::STANDALONE_SERVICE).unwrap_or(false)
+}
+
+fn contains_sps(annex_b: &[u8]) -> bool {
+    let mut parser = h264::AnnexBParser::new();
+    parser.push(annex_b);
+    parser.push(&[0, 0, 0, 1]); // the parser only returns a NAL unit once the next start code arrives
+    std::iter::from_fn(|| parser.next_nal()).any(|nal| nal.nal_type == h264::NAL_SPS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(count: usize, keyframe_interval: usize) -> Vec<Frame> {
+        // 30 frames per second
+        (0..count).map(|i| Frame { offset: 0, size: 0, timestamp_ns: i as u64 * 1_000_000_000 / 30, keyframe: i % keyframe_interval == 0 }).collect()
+    }
+
+    #[test]
+    fn writes_vod_playlists() {
+        let segments = vec![(0..180, 6.0), (180..300, 4.0)];
+        assert_eq!(render_vod_playlist(&segments, false), "\
+            #EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:0\n\
+            #EXTINF:6.000,\nsegment0.ts\n\
+            #EXTINF:4.000,\nsegment1.ts\n\
+            #EXT-X-ENDLIST\n");
+    }
+
+    #[test]
+    fn writes_event_playlists_while_recording() {
+        // The target duration is the longest segment rounded up
+        let segments = vec![(0..181, 6.033)];
+        assert_eq!(render_vod_playlist(&segments, true), "\
+            #EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-PLAYLIST-TYPE:EVENT\n#EXT-X-TARGETDURATION:7\n#EXT-X-MEDIA-SEQUENCE:0\n\
+            #EXTINF:6.033,\nsegment0.ts\n");
+    }
+
+    #[test]
+    fn writes_live_playlists() {
+        let segment = |sequence, millis, discontinuity| LiveSegment {
+            sequence,
+            duration: Duration::from_millis(millis),
+            discontinuity,
+            data: Arc::new(Vec::new()),
+        };
+        let live = LiveState {
+            segments: VecDeque::from([segment(5, 1000, false), segment(6, 1500, true), segment(7, 1000, false)]),
+            discontinuity_sequence: 2,
+            ..LiveState::default()
+        };
+        assert_eq!(render_live_playlist(&live), "\
+            #EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:5\n#EXT-X-DISCONTINUITY-SEQUENCE:2\n\
+            #EXTINF:1.000,\nsegment5.ts\n\
+            #EXT-X-DISCONTINUITY\n#EXTINF:1.500,\nsegment6.ts\n\
+            #EXTINF:1.000,\nsegment7.ts\n");
+    }
+
+    #[test]
+    fn adds_the_query_to_segments() {
+        let playlist = render_vod_playlist(&[(0..30, 1.0)], false);
+        let with_token = with_query(playlist.clone(), &Some("access_token=abc".to_string()));
+        assert!(with_token.contains("\nsegment0.ts?access_token=abc\n"));
+        assert_eq!(with_token.replace("?access_token=abc", ""), playlist);
+        assert_eq!(with_query(playlist.clone(), &None), playlist);
+    }
+
+    #[test]
+    fn cuts_vod_segments_at_keyframes() {
+        // Keyframes every 2 seconds, 20 seconds: segments of 6, 6, 6 and 2 seconds
+        let segments = vod_segments(&frames(600, 60));
+        let ranges: Vec<Range<usize>> = segments.iter().map(|(range, _)| range.clone()).collect();
+        assert_eq!(ranges, vec![0..180, 180..360, 360..540, 540..600]);
+        let durations: Vec<String> = segments.iter().map(|(_, duration)| format!("{:.3}", duration)).collect();
+        assert_eq!(durations, vec!["6.000", "6.000", "6.000", "2.000"]);
+
+        // Frames before the first keyframe can't be decoded
+        let mut frames = frames(90, 60);
+        frames[0].keyframe = false;
+        assert_eq!(vod_segments(&frames)[0].0, 60..90);
+        assert!(vod_segments(&[]).is_empty());
+    }
+
+    #[test]
+    fn plays_the_recording_being_written() {
+        let track = matroska::read_h264_track(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/matroska/recording.mkv")).unwrap();
+        let segments = vod_segments(&track.frames);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].0, 0..3);
+        assert_eq!(render_vod_playlist(&segments, false).lines().nth(5), Some("#EXTINF:0.099,"));
+    }
+
+    #[test]
+    fn parses_segment_names() {
+        assert_eq!(segment_number("segment12.ts"), Some(12));
+        assert_eq!(segment_number("segment.ts"), None);
+        assert_eq!(segment_number("segment1.m3u8"), None);
+    }
+}
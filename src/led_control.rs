@@ -1,63 +1,68 @@
-use std::sync::mpsc::{TryRecvError, Receiver};
+use std::sync::mpsc::{self, TryRecvError, Receiver, Sender};
 use std::thread;
 use std::time::Duration;
 use std::cmp::max;
 
-use rppal;
-use rppal::gpio::{ Gpio, Trigger };
+use rppal::gpio::{ Gpio, OutputPin, Trigger };
 
 use system_shutdown::shutdown;
 
-pub fn shutdown_at_pin() {
+use crate::error::{Context, Result};
+use crate::events::{Event, EventBus};
+use crate::supervisor::Supervisor;
+
+const BUTTON_PIN: u8 = 17;
+const LED_PIN: u8 = 21;
+
+pub fn shutdown_at_pin(events: EventBus, supervisor: &Supervisor) -> Result<()> {
     /*
     Shut down deivce upon rising edge on pin 17
     Raspberry Pi is in responsible for shutting down the system.
     */
-    thread::spawn( || {
-        let gpio = Gpio::new().unwrap();
-        let mut _pin = match gpio.get(17) {
-            Ok(pin) => {
-                let mut input_pin = pin.into_input_pulldown();
-                input_pin.set_interrupt( Trigger::RisingEdge ).unwrap();
-                loop {
-                    if input_pin.is_high() {
-                        match shutdown() {
-                            Ok(_) => println!("Shutting down from button presss."),
-                            Err(error) => eprintln!("Failed to shut down from button press: {}", error), 
-                        }
-                    }
-                    thread::sleep(Duration::from_millis(100));
+    supervisor.spawn("button", Duration::from_secs(30), move |heartbeat| {
+        let mut input_pin = Gpio::new().and_then(|gpio| gpio.get(BUTTON_PIN))
+            .context(format!("Failed to open the power button on GPIO {}", BUTTON_PIN))?
+            .into_input_pulldown();
+        input_pin.set_interrupt(Trigger::RisingEdge).context("Failed to watch the power button")?;
+        loop {
+            heartbeat.beat();
+            if input_pin.is_high() {
+                events.publish(Event::ButtonPressed { button: "power".to_string() });
+                events.publish(Event::ShutdownImminent { reason: "button".to_string() });
+                thread::sleep(Duration::from_millis(500)); // give /events clients a moment to receive it
+                match shutdown() {
+                    Ok(_) => log::info!("Shutting down from button presss."),
+                    Err(error) => log::error!("Failed to shut down from button press: {}", error),
                 }
             }
-            Err(e) => {
-                println!("Failed to get GPIO pin: {}, probably because it's in use by another thread, meaning GPIO is blinking", e);
-                return;
-            }
-        };
-    });
+            thread::sleep(Duration::from_millis(100));
+        }
+    })
 }
 
-pub fn start_listener(rx: Receiver<(bool, u64, u64)>) {
+pub fn start_listener(rx: Receiver<(bool, u64, u64)>, supervisor: &Supervisor) -> Result<Sender<Vec<u8>>> {
     /*
     The channel accepts (bool, u64, u64), where
         bool: Whether LED should be on at all
         first u64: milliseconds LED is turn on, given bool is true
         second u64:  milliseconds LED is turned off, given bool is true
-    */
-    let gpio = Gpio::new().unwrap();
-    let mut pin = match gpio.get(21) {
-        Ok(pin) => pin.into_output(),
-        Err(e) => {
-            println!("Failed to get GPIO pin: {}, probably because it's in use by another thread, meaning GPIO is blinking", e);
-            return;
-        }
-    };
-    thread::spawn(move || {
-        // let mut last_message = "No message received yet.".to_string(); // Default message
-        let mut last_message = (false, 0, 0);
-
 
+    The returned channel accepts a code to show once, as digits from 1 to 9, see blink_code.
+    Afterwards, the LED goes back to the last (bool, u64, u64) pattern.
+    */
+    let (code_tx, code_rx) = mpsc::channel::<Vec<u8>>();
+    // Kept when the worker restarts, so the LED goes back to the pattern it was showing
+    let mut last_message = (false, 0, 0);
+    // Blinking a pairing code twice takes over a minute
+    supervisor.spawn("led", Duration::from_secs(120), move |heartbeat| {
+        let mut pin = Gpio::new().and_then(|gpio| gpio.get(LED_PIN))
+            .context(format!("Failed to open the LED on GPIO {}", LED_PIN))?
+            .into_output();
         loop {
+            heartbeat.beat();
+            if let Ok(code) = code_rx.try_recv() {
+                blink_code(&mut pin, &code);
+            }
             match rx.try_recv() {
                 Ok(message) => {
                     last_message = message;
@@ -66,7 +71,7 @@ pub fn start_listener(rx: Receiver<(bool, u64, u64)>) {
                         pin.set_high();
                         thread::sleep(Duration::from_millis(on_ms));
                         pin.set_low();
-                        thread::sleep(Duration::from_millis(max(off_ms-10,1)));
+                        thread::sleep(Duration::from_millis(max(off_ms.saturating_sub(10), 1)));
                     } else {
                         thread::sleep(Duration::from_millis(100));
                     }
@@ -77,18 +82,39 @@ pub fn start_listener(rx: Receiver<(bool, u64, u64)>) {
                         pin.set_high();
                         thread::sleep(Duration::from_millis(on_ms));
                         pin.set_low();
-                        thread::sleep(Duration::from_millis(max(off_ms-10,1)));
+                        thread::sleep(Duration::from_millis(max(off_ms.saturating_sub(10), 1)));
                     } else {
                         thread::sleep(Duration::from_millis(100));
                     }
                 }
                 Err(TryRecvError::Disconnected) => {
-                    println!("Sender has disconnected");
+                    log::info!("Sender has disconnected");
                     pin.set_low();
-                    break;
+                    return Ok(());
                 }
             }
             thread::sleep(Duration::from_millis(10));
         }
-    });
-}
\ No newline at end of file
+    })?;
+    Ok(code_tx)
+}
+
+fn blink_code(pin: &mut OutputPin, code: &[u8]) {
+    /*
+    Shows each digit as that many quick blinks, with a long pause between digits.
+    The whole code is shown twice, so that it can be counted again.
+    */
+    for _ in 0..2 {
+        pin.set_low();
+        thread::sleep(Duration::from_millis(2000));
+        for &digit in code {
+            for _ in 0..digit {
+                pin.set_high();
+                thread::sleep(Duration::from_millis(250));
+                pin.set_low();
+                thread::sleep(Duration::from_millis(350));
+            }
+            thread::sleep(Duration::from_millis(1200));
+        }
+    }
+}
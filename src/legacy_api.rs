@@ -0,0 +1,300 @@
+/*
+Routes outside /api/v1.
+
+Most are from before /api/v1, kept for app versions that still use them and deprecated.
+They answer exactly as they used to, plain text for single values and errors,
+so that older apps keep parsing them. New features only go into /api/v1, see src/api.rs.
+
+GET /status, GET /stream/clients and GET/PUT /video/... are short aliases the app uses alongside /api/v1, supported rather than deprecated.
+The GET aliases answer the same JSON as their /api/v1 routes, the PUT aliases take and answer plain text.
+Like their /api/v1 counterparts, the routes that change the camera or read recordings require a token, see src/auth.rs.
+*/
+use std::sync::atomic::Ordering;
+
+use serde_json::json;
+use tiny_http::Method;
+
+use crate::api::{self, AppState};
+use crate::openapi::{array, object, string, Body, RouteDoc};
+use crate::router::{json_response, text_response, ApiError, ApiRequest, HttpResponse, Router};
+use crate::standalone_filesystem::{self, VIDEOS_DIR};
+use crate::video_settings::{SrtSettings, StreamCodec, StreamTransport, VideoPreset};
+use crate::{cpu_temp, CPU_TEMP_PATH};
+
+pub fn add_routes(router: Router<AppState>) -> Router<AppState> {
+    router
+        .route(Method::Get, "/", welcome, RouteDoc::new("Welcome message", Body::text()).deprecated())
+        .route(Method::Get, "/status", status, RouteDoc::new("Same as GET /api/v1/status", Body::json(api::status_schema())).alias().priority())
+        .route(Method::Get, "/camera-stream-status", camera_stream_status, RouteDoc::new("Same as GET /api/v1/stream", Body::json(api::stream_status_schema())).deprecated().priority())
+        .route(Method::Get, "/stream/clients", stream_clients, RouteDoc::new("Same as GET /api/v1/stream/clients", Body::json(api::stream_clients_schema())).alias().priority())
+        .route(Method::Get, "/battery-percent", battery_percent, RouteDoc::new("Battery state of charge in percent", Body::text()).deprecated().priority())
+        .route(Method::Get, "/battery-millivolts", battery_millivolts, RouteDoc::new("Battery cell voltage in millivolts", Body::text()).deprecated().priority())
+        .route(Method::Get, "/cpu-temp", cpu_temp, RouteDoc::new("CPU temperature in degrees Celsius", Body::text()).deprecated().priority())
+        .route(Method::Get, "/list-local-videos", list_local_videos, RouteDoc::new("Recordings, oldest first", Body::json(array(object(json!({
+            "path": string(),
+            "date_updated": string(),
+            "hls": string(),
+        }))))).deprecated().requires_auth())
+        .route(Method::Get, "/video/preset", get_preset, RouteDoc::new("Same as GET /api/v1/video/preset", Body::json(api::preset_schema())).alias())
+        .route(Method::Get, "/video/codec", get_codec, RouteDoc::new("Same as GET /api/v1/video/codec", Body::json(api::codec_schema())).alias())
+        .route(Method::Get, "/video/transport", get_transport, RouteDoc::new("Same as GET /api/v1/video/transport", Body::json(api::transport_schema())).alias())
+        .route(Method::Put, "/blink-on", blink_on, RouteDoc::new("Blink the LED", Body::text()).deprecated().requires_auth().priority())
+        .route(Method::Put, "/blink-off", blink_off, RouteDoc::new("Turn the LED off", Body::text()).deprecated().requires_auth().priority())
+        .route(Method::Put, "/restart-stream-mode", restart_stream_mode, RouteDoc::new("Restart streaming mode", Body::text()).deprecated().requires_auth().priority())
+        .route(Method::Put, "/video/preset", put_preset, RouteDoc::new("Select the video preset by name", Body::text()).request(Body::text()).alias().text_errors().requires_auth())
+        .route(Method::Put, "/video/codec", put_codec, RouteDoc::new("Select the stream codec by name", Body::text()).request(Body::text()).alias().text_errors().requires_auth())
+        .route(Method::Put, "/video/transport", put_transport, RouteDoc::new("Select the stream transport by name", Body::text()).request(Body::text()).alias().text_errors().requires_auth())
+        .route(Method::Put, "/video/srt", put_srt, RouteDoc::new("Set the SRT latency and passphrase", Body::text()).request(Body::json(api::srt_settings_schema())).alias().text_errors().requires_auth())
+        .route(Method::Post, "/download-video", download_video, RouteDoc::new("Download a recording by its path", Body::binary("video/x-matroska")).request(Body::text()).deprecated().requires_auth().timeout(api::DOWNLOAD_TIMEOUT))
+}
+
+fn welcome(_: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    Ok(text_response("Welcome to Velovision Rearview", 200))
+}
+
+fn status(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    /*
+    Same as GET /api/v1/status. Everything the status screen shows, in one request. A section that fails reports an error instead of its values:
+    {
+        "version": "0.2.0",
+        "battery": { "percent": 87, "millivolts": 3950, "charge_rate_percent_per_hour": -4.2, "charging": false, "valid": true, "age_secs": 0, "last_error": null },
+        "cpu_temp": { "celsius": 52.6 },
+        "mode": "streaming", // streaming, standalone or idle
+        "stream": { "status": "healthy", ... }, // same as "health" in /camera-stream-status
+        "recording": { "recording": false, "chunk_count": 12, "latest_chunk": { "path": "/opt/velovision/standalone_videos/loop0012.mkv", "size_bytes": 52428800, "date_updated": "2023-06-17T09:13:00" } },
+        "storage": { "error": "Failed to read the filesystem of /opt/velovision/standalone_videos" },
+        "uptime": { "system_secs": 3600, "server_secs": 3580 },
+        "workers": [{ "name": "battery", "state": "running", "restarts": 1, "restart_in_secs": null, "last_heartbeat_age_ms": 210, "heartbeat_timeout_secs": 30, "last_error": "Panicked: ..." }, ...] // see GET /health
+    }
+    */
+    api::get_status(state, request)
+}
+
+fn camera_stream_status(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    /*
+    Same as GET /api/v1/stream. Whether frames are actually flowing, and the TCP clients connected to the stream port, for example:
+    {
+        "streaming": true, // health status is healthy or degraded
+        "health": {
+            "status": "healthy", // healthy, degraded, stalled or stopped
+            "reason": "Frames are flowing",
+            "transport": "tcp",
+            "codec": "mjpeg",
+            "frames_per_second": 29.8, // over the last 5 seconds, null with the srt transport
+            "expected_frames_per_second": 30,
+            "last_frame_age_ms": 21,
+            "frames_received": 5400,
+            "pipeline": { "service": "velovision-camera-mjpeg-over-tcp.service", "active": true, "main_pid": 1234, "process_state": "S (sleeping)" }
+        },
+        "clients": 1,
+        "connections": [
+            { "local": "192.168.9.1:5000", "peer": "192.168.9.160:52144", "state": "ESTABLISHED", "connected_secs": 42 }
+        ]
+    }
+    */
+    Ok(json_response(&api::stream_status(state)))
+}
+
+fn stream_clients(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    /*
+    Same as GET /api/v1/stream/clients. Clients connected to the stream relay, for example:
+    {
+        "upstream_connected": true,
+        "upstream_codec": "mjpeg",
+        "frames_received": 5400,
+        "h264_validation": null, // with the H.264 codec, the report of src/h264.rs on the live stream
+        "srt": { "listening": false, "caller_connected": false, "port": 5000, "latency_ms": 200, "encrypted": false },
+        "count": 1,
+        "clients": [
+            { "id": 0, "kind": "tcp", "peer": "192.168.9.160:52144", "connected_secs": 42, "frames_sent": 1260, "frames_dropped": 3, "bytes_sent": 25020416 }
+        ]
+    }
+    */
+    Ok(json_response(&api::stream_clients(state)))
+}
+
+fn battery_percent(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    if state.battery_soc.1.load(Ordering::Relaxed) {
+        Ok(text_response(format!("{}", state.battery_soc.0.load(Ordering::Relaxed)), 200))
+    } else {
+        Ok(text_response("Failed to get battery state of charge", 500))
+    }
+}
+
+fn battery_millivolts(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    if state.battery_voltage.1.load(Ordering::Relaxed) {
+        Ok(text_response(format!("{}", state.battery_voltage.0.load(Ordering::Relaxed)), 200))
+    } else {
+        Ok(text_response("Failed to get battery voltage", 500))
+    }
+}
+
+fn cpu_temp(_: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    match cpu_temp::read_cpu_temp(CPU_TEMP_PATH) {
+        Ok(temp) => Ok(text_response(temp, 200)),
+        Err(_) => Ok(text_response("Failed to read CPU temperature", 500)),
+    }
+}
+
+fn list_local_videos(_: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    /* Returns JSON of absolute path of videos and their dates, sorted old -> new
+    Example format:
+    [
+        {
+            "path": "/opt/standalone_mode/videos/loop0001.mkv",
+            "date_updated":"2023-06-17T09:13:00",
+            "hls": "/videos/loop0001/index.m3u8"
+        },
+        ...
+    ]
+
+    Use the path in a POST request to /download-video to download the video file,
+    or play the hls playlist with AVPlayer without downloading it.
+    */
+    let sorted_files = standalone_filesystem::files_sorted_by_date(VIDEOS_DIR)
+        .map_err(|e| ApiError::internal(format!("Failed to list {}", VIDEOS_DIR)).with_details(json!(e.to_string())))?;
+    let json_list: Vec<_> = sorted_files.into_iter().map(|(path, date)| {
+        let date_str = standalone_filesystem::format_system_time_to_string(date);
+        let hls = path.file_stem().map(|stem| format!("/videos/{}/index.m3u8", stem.to_string_lossy()));
+        json!({
+            "path": path.to_str().unwrap_or(""),
+            "date_updated": date_str,
+            "hls": hls
+        })
+    }).collect();
+    Ok(text_response(serde_json::to_string(&json_list).unwrap_or_default(), 200))
+}
+
+fn get_preset(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    /*
+    Same as GET /api/v1/video/preset. Returns the active preset and the pipelines it renders to, for example:
+    {
+        "preset": "balanced",
+        "available": ["low-latency", "balanced", "high-quality", "battery-saver"],
+        "stream_pipeline": "libcamerasrc ! ...",
+        "record_pipeline": "libcamerasrc ! ..."
+    }
+    */
+    api::get_preset(state, request)
+}
+
+fn get_codec(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    /*
+    Same as GET /api/v1/video/codec. Codec of the live stream on port 5000, for example:
+    {
+        "codec": "h264", // selected, used from the next streaming mode (re)start
+        "active": "mjpeg", // what the running pipeline sends, null outside streaming mode
+        "available": ["mjpeg", "h264"]
+    }
+    */
+    api::get_codec(state, request)
+}
+
+fn get_transport(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    /*
+    Same as GET /api/v1/video/transport. How the live stream reaches clients, for example:
+    {
+        "transport": "srt", // used from the next streaming mode (re)start
+        "available": ["tcp", "srt"],
+        "srt": { "port": 5000, "latency_ms": 200, "encrypted": true }
+    }
+    The passphrase itself is never returned.
+    */
+    api::get_transport(state, request)
+}
+
+fn blink_on(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    let _ = state.led_tx.send((true, 100, 1000));
+    Ok(text_response("Turned on LED", 200))
+}
+
+fn blink_off(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    let _ = state.led_tx.send((false, 0, 0));
+    Ok(text_response("Turned off LED", 200))
+}
+
+fn restart_stream_mode(state: &AppState, _: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    let _ = state.restart_streaming_tx.send(());
+    Ok(text_response("Restarted streaming mode", 200))
+}
+
+fn saved_or_500(state: &AppState, change: impl FnOnce(&mut crate::video_settings::VideoSettings), saved: String, failed: &str) -> HttpResponse {
+    match state.update_video_settings(change) {
+        Ok(_) => text_response(saved, 200),
+        Err(e) => {
+            log::error!("{}: {:?}", failed, e.details);
+            text_response(failed, 500)
+        }
+    }
+}
+
+fn put_preset(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    /*
+    Example usage:
+    curl -X PUT -d "high-quality" http://192.168.9.1:8000/video/preset
+
+    Applied the next time the streaming or recording pipeline (re)starts, e.g. after PUT /restart-stream-mode
+    */
+    match VideoPreset::from_name(&request.body) {
+        Some(preset) => Ok(saved_or_500(state, |s| s.preset = preset, format!("Video preset set to {}", preset.name()), "Failed to save video preset")),
+        None => Ok(text_response(format!("Unknown video preset: {}", request.body.trim()), 400)),
+    }
+}
+
+fn put_codec(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    /*
+    Example usage:
+    curl -X PUT -d "h264" http://192.168.9.1:8000/video/codec
+
+    Like presets, applied the next time streaming mode (re)starts, e.g. after PUT /restart-stream-mode
+    */
+    match StreamCodec::from_name(&request.body) {
+        Some(codec) => Ok(saved_or_500(state, |s| s.codec = codec, format!("Stream codec set to {}", codec.name()), "Failed to save stream codec")),
+        None => Ok(text_response(format!("Unknown stream codec: {}", request.body.trim()), 400)),
+    }
+}
+
+fn put_transport(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    /*
+    Example usage:
+    curl -X PUT -d "srt" http://192.168.9.1:8000/video/transport
+
+    tcp: MJPEG or H.264 through the stream relay on port 5000 (also RTSP, HLS and /stream.mjpeg)
+    srt: H.264 in MPEG-TS from an SRT listener on UDP port 5000, e.g. ffplay srt://192.168.9.1:5000
+    Applied the next time streaming mode (re)starts, e.g. after PUT /restart-stream-mode
+    */
+    match StreamTransport::from_name(&request.body) {
+        Some(transport) => Ok(saved_or_500(state, |s| s.transport = transport, format!("Stream transport set to {}", transport.name()), "Failed to save stream transport")),
+        None => Ok(text_response(format!("Unknown stream transport: {}", request.body.trim()), 400)),
+    }
+}
+
+fn put_srt(state: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    /*
+    Example usage:
+    curl -X PUT -d '{"latency_ms": 300, "passphrase": "correcthorsebattery"}' http://192.168.9.1:8000/video/srt
+
+    latency_ms: 20 to 8000. passphrase: 10 to 79 letters, digits or - _ . : @ + =, or null to disable encryption.
+    Callers must use the same passphrase, e.g. srt://192.168.9.1:5000?passphrase=correcthorsebattery
+    */
+    let srt_settings = match serde_json::from_str::<SrtSettings>(&request.body) {
+        Ok(srt_settings) => srt_settings,
+        Err(e) => return Ok(text_response(format!("Invalid SRT settings: {}", e), 400)),
+    };
+    if let Err(e) = api::validate_srt_settings(state, &srt_settings) {
+        return Ok(text_response(e.message, 400));
+    }
+    Ok(saved_or_500(state, |s| s.srt = srt_settings, "SRT settings saved".to_string(), "Failed to save SRT settings"))
+}
+
+fn download_video(_: &AppState, request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+    /*
+    Example usage:
+    curl -X POST -o DOWNLOAD_AS_NAME.mkv -d "/PATH/TO/VIDEO/ON/PI.mkv" http://192.168.9.1:8000/download-video
+
+    Get path to video (/PATH/TO/VIDEO/ON/PI.mkv) from GET /list-local-videos.
+    Recommended to use the date_updated field from the same GET request to rename downloaded video (DOWNLOAD_AS_NAME)
+    */
+    log::debug!("POST content: {}", request.body);
+    Ok(standalone_filesystem::yield_video_file(request.body.clone()))
+}
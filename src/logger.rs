@@ -0,0 +1,39 @@
+/*
+Writes log records to stderr, which systemd keeps in the journal with a timestamp:
+    journalctl -u velovision-supreme-server.service
+Level "info" by default, or set with LOG_LEVEL, e.g. LOG_LEVEL=debug cargo run
+*/
+use std::io::Write;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // <3> and <4> are the syslog priorities of errors and warnings, which journald understands on stderr
+        let priority = match record.level() {
+            Level::Error => "<3>",
+            Level::Warn => "<4>",
+            Level::Info => "<6>",
+            Level::Debug | Level::Trace => "<7>",
+        };
+        let _ = writeln!(std::io::stderr(), "{}{} {}: {}", priority, record.level(), record.target(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init() {
+    let level = std::env::var("LOG_LEVEL").ok().and_then(|level| level.parse().ok()).unwrap_or(LevelFilter::Info);
+    if log::set_logger(&StderrLogger).is_ok() {
+        log::set_max_level(level);
+    }
+}
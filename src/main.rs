@@ -2,38 +2,135 @@
 Supreme Server
 Accepts HTTP requests from Velovision iPhone app to control Velovision Rearview Raspberry Pi
 */
-use std::time::Duration;
-use std::sync::{Arc, mpsc};
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex, mpsc};
 use std::sync::atomic::{AtomicI32, AtomicBool, Ordering};
 use std::thread;
 use std::path::Path;
 use std::io;
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
 
 use tiny_http::{Server, Response};
 use system_shutdown::shutdown;
-use serde_json::json;
-use log;
 
+use error::{Context, Error, Result};
+
+mod error;
+mod logger;
+mod supervisor;
+mod watchdog;
 mod tcp_stream_monitor;
 mod cpu_temp;
 mod fuel_gauge;
+mod max17048;
+mod power_policy;
 mod led_control;
 mod standalone_filesystem;
+mod pipeline;
+mod video_settings;
+mod stream_relay;
+mod snapshot;
+mod h264;
+mod rtsp;
+mod mpegts;
+mod matroska;
+mod hls;
+mod srt;
+mod stream_health;
+mod events;
+mod status;
+mod router;
+mod request_pool;
+mod api;
+mod auth;
+mod legacy_api;
+#[cfg(feature = "https")]
+mod tls;
+mod openapi;
+
+const CPU_TEMP_PATH: &str = "/sys/class/thermal/thermal_zone0/temp";
+// A client that stops reading blocks a write for at most this long, then its connection is closed.
+// Covers the long-lived responses as well: /stream.mjpeg, /events and HLS.
+const HTTP_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+// A client that stops sending blocks a read for at most this long, whether of its request headers or its body
+const HTTP_READ_TIMEOUT: Duration = Duration::from_secs(10);
 
 fn main() {
-    check_installation().expect("Some required directories or files are missing. See src/main.rs:check_installation for details.");
+    logger::init();
+    if std::env::args().any(|arg| arg == "--print-pipelines") {
+        // Development aid: print the rendered gst-launch-1.0 pipelines and exit
+        for (name, spec) in [("mjpeg-over-tcp", pipeline::mjpeg_over_tcp()), ("h264-over-tcp", pipeline::h264_over_tcp()), ("h264-over-srt", pipeline::h264_over_srt()), ("splitmux-recording", pipeline::splitmux_recording())] {
+            match spec.render() {
+                Ok(launch) => println!("{}:\n  gst-launch-1.0 {}", name, launch),
+                Err(e) => println!("{}: invalid pipeline: {}", name, e),
+            }
+        }
+        return;
+    }
+    if let Some(position) = std::env::args().position(|arg| arg == "--check-h264") {
+        /*
+        Development aid replacing debug_h264_stream.py. Validates an H.264-over-TCP stream for 10 seconds:
+        supreme-server --check-h264 192.168.9.1:5000 <token>
+        The token is needed by the stream relay, but not by a gstreamer tcpserversink.
+        */
+        let addr = std::env::args().nth(position + 1).unwrap_or_else(|| "192.168.9.1:5000".to_string());
+        let token = std::env::args().nth(position + 2);
+        match h264::check_tcp_stream(&addr, token.as_deref(), Duration::from_secs(10), true) {
+            Ok(report) => {
+                println!("{}", serde_json::to_string_pretty(&report).unwrap_or_default());
+                std::process::exit(if report.violation_count == 0 && report.idr_frames > 0 { 0 } else { 1 });
+            }
+            Err(e) => {
+                println!("Failed to read H.264 stream from {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        }
+    }
 
+    if let Err(e) = run() {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    /*
+    Errors returned here stop the server, systemd then starts it again.
+    Worker threads report their errors and are restarted by the supervisor instead, see src/supervisor.rs
+    */
+    check_installation()?;
+
+    // Systemd units and standalone_gstreamer.sh read their pipelines from pipelines.env, so write it before starting any mode
+    let video_settings = video_settings::VideoSettings::load(video_settings::VIDEO_SETTINGS_PATH);
+    video_settings.apply().context("Failed to write gstreamer pipelines environment file")?;
+
+    let started = Instant::now();
     let address = "0.0.0.0:8000";
 
-    let server: Server = Server::http(address).unwrap();
+    let server = listen(address).map_err(|e| e.to_string())
+        .and_then(|listener| Server::from_listener(listener, None).map_err(|e| e.to_string()))
+        .map_err(|e| Error::Other(format!("Failed to start a server at {}: {}", address, e)))?;
     log::info!("Server started at {}", address);
 
+    // Requests over plain HTTP and HTTPS are answered alike, by the loop at the end of main
+    let (requests_tx, requests_rx) = mpsc::channel::<tiny_http::Request>();
+    accept_requests(server, requests_tx.clone());
+    let tls = start_https(requests_tx);
+
+    // Pushed to the app on GET /events, see src/events.rs
+    let events = events::EventBus::new();
+
+    // Battery, LED, mode and button threads are restarted when they fail, and listed in GET /api/v1/status
+    let supervisor = supervisor::Supervisor::new();
+
     // LED gets controlled by whomever sent the last blinking instruction, consisting of:
     // bool: Enable LED at all, On duration (ms), Off duration (ms). Recommended to keep durations > 10ms.
     let (led_tx, led_rx) = mpsc::channel::<(bool, u64, u64)>(); 
-    led_control::start_listener(led_rx);
+    // Pairing codes are blinked through a second channel, see src/auth.rs
+    let led_code_tx = led_control::start_listener(led_rx, &supervisor)?;
 
-    led_control::shutdown_at_pin();
+    led_control::shutdown_at_pin(events.clone(), &supervisor)?;
 
     // Atomic lacks float, so we will round the state of charge (soc) to the nearest percent
     // Atomic also lacks Result, so the AtomicBool signifies sucess or failure
@@ -42,176 +139,289 @@ fn main() {
     // and multiply voltage by 1000, so that float 3.82 (Volts) will be int 3820 (milliVolts).
     let battery_voltage: Arc<(AtomicI32, AtomicBool)> = Arc::new((AtomicI32::new(4000), AtomicBool::new(false))); 
 
+    // Everything /status reports about the battery: the latest reading with its age and charge rate, or why it failed
+    let battery_status = Arc::new(Mutex::new(fuel_gauge::BatteryStatus::default()));
+    let battery_status_clone = battery_status.clone();
+    // Read by the battery thread, and shared with the API for quick-start, see src/max17048.rs
+    let fuel_gauge = Arc::new(Mutex::new(fuel_gauge::FuelGauge::new()));
+    let fuel_gauge_clone = fuel_gauge.clone();
+    // The gauge's ALRT interrupt wakes the battery thread, which then only polls every fuel_gauge::IDLE_POLL_INTERVAL to let the I2C bus idle
+    let (battery_alert_tx, battery_alert_rx) = mpsc::channel::<()>();
+    let battery_alert_armed = Arc::new(AtomicBool::new(false));
+    fuel_gauge::watch_alert(battery_alert_tx, battery_alert_armed.clone(), &supervisor)?;
+
     let battery_voltage_clone = battery_voltage.clone();
     let led_tx_clone = led_tx.clone();
-    let _battery_checker = thread::spawn(move || {
+    let events_clone = events.clone();
+    let mut last_published: Option<(i32, i32)> = None;
+    // Kept when the worker restarts, so low voltage readings still count
+    let mut power_policy = power_policy::PowerPolicy::from_env();
+    supervisor.spawn("battery", Duration::from_secs(30), move |heartbeat| {
         loop {
-            fuel_gauge::store_battery_stats(&battery_soc_clone, &battery_voltage_clone);
-
-            // Flash LED before shutting down due to low battery as determined by cell voltage
-            // Hardware cutoff is at 3.0V. We shut down at 3.4V to allow for typical 0.3V sag at
-            // boot.
-            let shutdown_millivolts = 3450;
-
-            let shutdown_percentage = 10;
+            heartbeat.beat();
+            let stats = fuel_gauge::store_battery_stats(&fuel_gauge_clone, &battery_soc_clone, &battery_voltage_clone, &battery_status_clone);
 
-            let latest_millivolts = battery_voltage_clone.0.load(Ordering::Relaxed);
-            let battery_voltage_success = battery_voltage_clone.1.load(Ordering::Relaxed);
-
-            let latest_soc_percent = battery_soc_clone.0.load(Ordering::Relaxed);
-            let soc_percent_success = battery_soc_clone.1.load(Ordering::Relaxed);
+            // Publish when the percentage changes, or the voltage moves by 20 mV, rather than on every reading
+            if battery_soc_clone.1.load(Ordering::Relaxed) && battery_voltage_clone.1.load(Ordering::Relaxed) {
+                let percent = battery_soc_clone.0.load(Ordering::Relaxed);
+                let millivolts = battery_voltage_clone.0.load(Ordering::Relaxed);
+                if last_published.is_none_or(|(p, mv)| p != percent || (mv - millivolts).abs() >= 20) {
+                    events_clone.publish(events::Event::Battery { percent, millivolts });
+                    last_published = Some((percent, millivolts));
+                }
+            }
 
-            if (latest_millivolts <= shutdown_millivolts && !battery_voltage_success) || (latest_soc_percent <= shutdown_percentage && !soc_percent_success) {
-                led_tx_clone.send((false, 0, 0)).unwrap();
-                led_tx_clone.send((true, 50, 50)).unwrap();
-                thread::sleep(Duration::from_millis(3000));
-                led_tx_clone.send((false, 0, 0)).unwrap();
-                match shutdown() {
-                    Ok(_) => log::info!("Shutting down due to low battery."),
-                    Err(error) => log::error!("Low battery but failed to shut down: {}", error), 
+            // Flash LED before shutting down due to low battery, see src/power_policy.rs
+            if let power_policy::Decision::Shutdown(_) = power_policy.evaluate(stats.as_ref(), Instant::now()) {
+                if !power_policy.dry_run() {
+                    events_clone.publish(events::Event::ShutdownImminent { reason: "low_battery".to_string() });
+                    // Shut down even if the LED can't flash
+                    let flashed = [(false, 0, 0), (true, 50, 50)].into_iter().all(|blink| led_tx_clone.send(blink).is_ok());
+                    thread::sleep(Duration::from_millis(3000));
+                    if !flashed || led_tx_clone.send((false, 0, 0)).is_err() {
+                        log::warn!("{}", Error::ChannelClosed { channel: "LED" });
+                    }
+                    match shutdown() {
+                        Ok(_) => log::info!("Shutting down due to low battery."),
+                        Err(error) => log::error!("Low battery but failed to shut down: {}", error), 
+                    }
                 }
             }
-            thread::sleep(Duration::from_millis(1000))
+
+            // A low voltage is confirmed over several readings, taken every second
+            let idle = battery_alert_armed.load(Ordering::Relaxed) && !power_policy.confirming();
+            let interval = if idle { fuel_gauge::IDLE_POLL_INTERVAL } else { fuel_gauge::POLL_INTERVAL };
+            match battery_alert_rx.recv_timeout(interval) {
+                Ok(()) | Err(mpsc::RecvTimeoutError::Timeout) => (),
+                // The alert isn't watched, see fuel_gauge::watch_alert, or its worker stopped: poll instead
+                Err(mpsc::RecvTimeoutError::Disconnected) => thread::sleep(interval),
+            }
         }
-    });
+    })?;
+
+    // Owns the client-facing stream port. Gstreamer serves MJPEG or H.264 on loopback and the relay fans it out to clients.
+    // Paired devices are checked on the stream ports too, see src/auth.rs
+    let auth = Arc::new(auth::Auth::load(auth::PAIRED_DEVICES_PATH, led_code_tx));
+    let relay = stream_relay::StreamRelay::new();
+    relay.start(auth.clone());
+    rtsp::start(relay.clone(), auth.clone());
+    let hls_server = hls::HlsServer::new(relay.clone());
+    // With the srt transport, gstreamer serves clients itself and the relay stays idle
+    let srt_monitor = srt::SrtMonitor::new();
+    srt_monitor.start();
+    // Reads /proc/net/tcp every second to see who is connected to the stream port
+    let connection_tracker = tcp_stream_monitor::ConnectionTracker::new();
+    connection_tracker.start();
+    standalone_filesystem::start_storage_monitor(events.clone(), &supervisor)?;
+    cpu_temp::start_thermal_monitor(CPU_TEMP_PATH, events.clone(), &supervisor)?;
 
     let led_tx_clone = led_tx.clone();
     let (restart_streaming_toggle_tx, restart_streaming_toggle_rx) = mpsc::channel::<()>(); 
-    standalone_filesystem::start_streaming_mode(restart_streaming_toggle_rx, led_tx_clone);
+    standalone_filesystem::start_streaming_mode(restart_streaming_toggle_rx, led_tx_clone, relay.clone(), srt_monitor.clone(), connection_tracker.clone(), events.clone(), &supervisor)?;
 
-    restart_streaming_toggle_tx.send(()).unwrap(); // send a signal to start streaming mode immediately after boot.
+    restart_streaming_toggle_tx.send(()).map_err(|_| Error::ChannelClosed { channel: "streaming mode" })?; // send a signal to start streaming mode immediately after boot.
     // At any later time, send a signal through the same channel TX to put device into streaming mode and wait for a minute for a connection.
     // The device will always want to revert back to standalone mode if no connection is made.
 
-    for mut request in server.incoming_requests() {
-        let mut response = Response::from_string("");
+    // Everything but the long-lived responses below is served from the route table, see src/api.rs
+    let router = Arc::new(api::router());
+    let state = Arc::new(api::AppState {
+        video_settings: Mutex::new(video_settings),
+        battery_soc,
+        battery_voltage,
+        battery_status,
+        fuel_gauge,
+        relay: relay.clone(),
+        srt_monitor,
+        connection_tracker,
+        led_tx,
+        restart_streaming_tx: restart_streaming_toggle_tx,
+        auth,
+        tls,
+        supervisor: supervisor.clone(),
+        watchdog: watchdog::timeout(),
+        started,
+        openapi: openapi::document(router.routes()),
+    });
+    // Routed requests are answered on a bounded pool of threads, with status routes first, see src/request_pool.rs
+    let pool = {
+        let (router, state) = (router.clone(), state.clone());
+        let longest_timeout = router.routes().iter()
+            .map(|route| route.doc.request_timeout().unwrap_or(request_pool::DEFAULT_TIMEOUT))
+            .max()
+            .unwrap_or(request_pool::DEFAULT_TIMEOUT);
+        request_pool::RequestPool::start(&supervisor, longest_timeout, move |request| router.handle(&state, request))?
+    };
 
-        let url = request.url();
-        match *request.method() {
-            // GET: Idempotent data retrieval
-            tiny_http::Method::Get => {
-                match url {
-                    "/" => {
-                        response = Response::from_string("Welcome to Velovision Rearview").with_status_code(200);
-                    },
-                    "/camera-stream-status" => {
-                        response = Response::from_string( format!("{}", tcp_stream_monitor::check_tcp_service(5000)) ).with_status_code(200)
-                    },
-                    "/battery-percent" => {
-                        let latest_soc = battery_soc.0.load(Ordering::Relaxed);
-                        let battery_soc_success = battery_soc.1.load(Ordering::Relaxed);
-
-                        if battery_soc_success {
-                            response = Response::from_string(format!("{}", latest_soc)).with_status_code(200);
-                        } else {
-                            response = Response::from_string("Failed to get battery state of charge").with_status_code(500);
-                        }
-                    },
-                    "/battery-millivolts" => {
-                        let latest_millivolts = battery_voltage.0.load(Ordering::Relaxed);
-                        let battery_voltage_success = battery_voltage.1.load(Ordering::Relaxed);
-
-                        if battery_voltage_success {
-                            response = Response::from_string(format!("{}", latest_millivolts)).with_status_code(200);
-                        } else {
-                            response = Response::from_string("Failed to get battery voltage").with_status_code(500);
-                        }
-                    },
-                    "/cpu-temp" => {
-                        let temp = cpu_temp::read_cpu_temp("/sys/class/thermal/thermal_zone0/temp");
-                        match temp {
-                            Ok(temp) => { response = Response::from_string(temp).with_status_code(200) },
-                            Err(_) => { response = Response::from_string("Failed to read CPU temperature").with_status_code(500) }
+    // Beats at least every second while idle, so a loop that stops taking requests gets the server restarted, see src/watchdog.rs
+    let heartbeat = supervisor.register("http", Duration::from_secs(60));
+    watchdog::ready();
+    watchdog::start(supervisor);
+
+    loop {
+        heartbeat.beat();
+        let request = match requests_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(request) => request,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        if *request.method() == tiny_http::Method::Get && request.url().split('?').next() == Some("/stream.mjpeg") {
+            /*
+            Live MJPEG as multipart/x-mixed-replace on the same port as the control API.
+            Example: open http://192.168.9.1:8000/stream.mjpeg?access_token=<token> in a browser or VLC.
+            The response lasts as long as the client watches, so it gets its own thread.
+            */
+            if let Err(error) = state.auth.authenticate_request(&request) {
+                let _ = request.respond(error.to_response());
+                continue;
+            }
+            let relay = relay.clone();
+            thread::spawn(move || relay.serve_http_client(request));
+            continue;
+        }
+        if *request.method() == tiny_http::Method::Get && request.url().split('?').next() == Some("/events") {
+            /*
+            Server-Sent Events with battery, mode, recording, storage, thermal, button and shutdown events.
+            Example: curl -N http://192.168.9.1:8000/events?access_token=<token>
+            The response lasts as long as the client listens, so it gets its own thread.
+            */
+            if let Err(error) = state.auth.authenticate_request(&request) {
+                let _ = request.respond(error.to_response());
+                continue;
+            }
+            let events = events.clone();
+            thread::spawn(move || events.serve_sse(request));
+            continue;
+        }
+        if *request.method() == tiny_http::Method::Get && request.url().split('?').next() == Some("/snapshot.jpg") {
+            /*
+            Most recent JPEG frame, optionally scaled: GET /snapshot.jpg?width=320&access_token=<token>
+            In standalone mode this decodes the recording in progress, which can take a few seconds, so it gets its own thread.
+            */
+            if let Err(error) = state.auth.authenticate_request(&request) {
+                let _ = request.respond(error.to_response());
+                continue;
+            }
+            let relay = relay.clone();
+            thread::spawn(move || {
+                let query = request.url().split_once('?').map(|(_, q)| q.to_string()).unwrap_or_default();
+                let response = match snapshot::SnapshotSize::from_query(&query) {
+                    Ok(size) => match snapshot::take_snapshot(&relay, size) {
+                        Ok(snapshot) => {
+                            let content_type = tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"image/jpeg"[..]).unwrap();
+                            let source = tiny_http::Header::from_bytes(&b"X-Snapshot-Source"[..], snapshot.source.as_bytes()).unwrap();
+                            Response::from_data(snapshot.jpeg).with_header(content_type).with_header(source).with_status_code(200)
+                        },
+                        Err(e) => {
+                            log::warn!("Snapshot failed: {}", e);
+                            Response::from_string(e).with_status_code(503)
                         }
-                    }
-                    "/list-local-videos" => {
-                        /* Returns JSON of absolute path of videos and their dates, sorted old -> new 
-                        Example format:
-                        [
-                            {
-                                "path": "/opt/standalone_mode/videos/loop0001.mkv",
-                                "date_updated":"2023-06-17T09:13:00"
-                            },
-                            ...
-                        ]
-
-                        Use the path in a POST request to /download-video to download the video file
-                        */
-                        let path = "/opt/velovision/standalone_videos";
-                        let sorted_files = standalone_filesystem::files_sorted_by_date(path).unwrap();
-                        let json_list: Vec<_> = sorted_files.into_iter().map(|(path, date)| {
-                            let date_str = standalone_filesystem::format_system_time_to_string(date);
-                            json!({
-                                "path": path.to_str().unwrap_or(""),
-                                "date_updated": date_str
-                            })
-                        }).collect();
-                        let json_string = serde_json::to_string(&json_list).unwrap();
-                        response = Response::from_string(json_string);
-                    }
-                    
-                    _ => {
-                        log::warn!("Unknown GET request");
-                        response = Response::from_string("Unknown GET request").with_status_code(501);
-                    },
-                }
-            },
-            // PUT: Idempotent data submission
-            tiny_http::Method::Put => {
-                match url {
-                   "/blink-on" => {
-                        led_tx.send((true, 100, 1000)).unwrap();
-                        response = Response::from_string("Turned on LED").with_status_code(200);
                     },
-                    "/blink-off" => {
-                        led_tx.send((false, 0, 0)).unwrap();
-                        response = Response::from_string("Turned off LED").with_status_code(200);
-                    },
-                    "/restart-stream-mode" => {
-                        restart_streaming_toggle_tx.send(()).unwrap();
-                        response = Response::from_string("Restarted streaming mode").with_status_code(200);
-                    },
-                    _ => {
-                        log::warn!("Unknown PUT request");
-                        response = Response::from_string("Unknown PUT request").with_status_code(501);
-                    },
-                }
-            },
-            tiny_http::Method::Post=> {
-                match url {
-                    "/download-video" => {
-                        /*
-                        Example usage:
-                        curl -X POST -o DOWNLOAD_AS_NAME.mkv -d "/PATH/TO/VIDEO/ON/PI.mkv" http://192.168.9.1:8000/download-video
-
-                        Get path to video (/PATH/TO/VIDEO/ON/PI.mkv) from GET /list-local-videos.
-                        Recommended to use the date_updated field from the same GET request to rename downloaded video (DOWNLOAD_AS_NAME)
-                        */
-                        let mut post_content = String::new();
-                        request.as_reader().read_to_string(&mut post_content).unwrap();
-                        log::debug!("POST content: {}", post_content);
-
-                        response = standalone_filesystem::yield_video_file(post_content)
-                    },
-                    _ => {
-                        log::warn!("Unknown POST request");
-                        response = Response::from_string("Unknown POST request").with_status_code(501);
-                    },
-                }       
-            },
-            _ => () // other HTTP methods not implemented
+                    Err(e) => Response::from_string(e).with_status_code(400),
+                };
+                let _ = request.respond(response);
+            });
+            continue;
+        }
+
+        if *request.method() == tiny_http::Method::Get && (request.url().starts_with("/live/") || request.url().starts_with("/videos/")) {
+            /*
+            HLS playlists and segments for AVPlayer, see src/hls.rs:
+            http://192.168.9.1:8000/live/index.m3u8 (requires the h264 stream codec)
+            http://192.168.9.1:8000/videos/log0001/index.m3u8
+            The token of a paired device goes in ?access_token=<token>, and the playlists pass it on to their segments.
+            The first live playlist waits for segments and VOD segments are remuxed on request, so they get their own thread.
+            */
+            if let Err(error) = state.auth.authenticate_request(&request) {
+                let _ = request.respond(error.to_response());
+                continue;
+            }
+            let hls_server = hls_server.clone();
+            thread::spawn(move || hls_server.serve(request));
+            continue;
+        }
+
+        let route = router.find(request.method(), request.url());
+        let priority = route.is_some_and(|route| route.doc.is_priority());
+        let timeout = route.and_then(|route| route.doc.request_timeout()).unwrap_or(request_pool::DEFAULT_TIMEOUT);
+        pool.submit(request, priority, timeout);
+    }
+    Ok(())
+}
+
+fn accept_requests(server: Server, requests: mpsc::Sender<tiny_http::Request>) {
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            if requests.send(request).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(feature = "https")]
+fn start_https(requests: mpsc::Sender<tiny_http::Request>) -> Option<rearview_client::types::Tls> {
+    // Plain HTTP keeps working when the certificate can't be made or the port is taken
+    let certificate = match tls::DeviceCertificate::load_or_generate(tls::TLS_DIR) {
+        Ok(certificate) => certificate,
+        Err(e) => {
+            log::error!("HTTPS disabled, failed to load the device certificate: {}", e);
+            return None;
+        }
+    };
+    let address = format!("0.0.0.0:{}", tls::HTTPS_PORT);
+    let server = listen(&address).map_err(|e| e.to_string())
+        .and_then(|listener| Server::from_listener(listener, Some(certificate.ssl_config())).map_err(|e| e.to_string()));
+    match server {
+        Ok(server) => {
+            log::info!("HTTPS server started at {}, certificate SHA-256 {}", address, certificate.info().certificate_sha256);
+            accept_requests(server, requests);
+            Some(certificate.info())
+        }
+        Err(e) => {
+            log::error!("HTTPS disabled, failed to start a server at {}: {}", address, e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "https"))]
+fn start_https(_: mpsc::Sender<tiny_http::Request>) -> Option<rearview_client::types::Tls> {
+    None
+}
+
+fn listen(address: &str) -> io::Result<TcpListener> {
+    // tiny_http can't set timeouts on its connections, but they inherit SO_SNDTIMEO and SO_RCVTIMEO from the listening socket
+    let listener = TcpListener::bind(address)?;
+    for (option, timeout) in [(libc::SO_SNDTIMEO, HTTP_WRITE_TIMEOUT), (libc::SO_RCVTIMEO, HTTP_READ_TIMEOUT)] {
+        let timeout = libc::timeval { tv_sec: timeout.as_secs() as libc::time_t, tv_usec: 0 };
+        let result = unsafe {
+            libc::setsockopt(
+                listener.as_raw_fd(),
+                libc::SOL_SOCKET,
+                option,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
         }
-        let _ = request.respond(response);
     }
+    Ok(listener)
 }
 
-fn check_installation() -> Result<(), io::Error> {
+fn check_installation() -> Result<()> {
     /*
     This program requires the following directories and files to exist.
     An installation script must configure these directories and files.
-    This function merely checks that they exist.
+    This function merely checks that they exist, and makes the videos directory if it's missing so recording can go on.
 
     /opt/velovision
+        ├── pipelines.env // generated by this program at startup, see src/pipeline.rs
+        ├── video_settings.json // generated by this program when the video preset changes, see src/video_settings.rs
+        ├── paired_devices.json // generated by this program when a device pairs, see src/auth.rs
+        ├── tls // certificate and private key for HTTPS, generated by this program on first boot, see src/tls.rs
         ├── supreme-server // this executable binary. Not required in development because we use `cargo run` instead of `sudo systemctl start velovision-supreme-server.service`
         ├── scripts
             └── standalone_gstreamer.sh // Offloaded the standalone mode gstreamer pipeline logic to an external script.
@@ -225,10 +435,10 @@ fn check_installation() -> Result<(), io::Error> {
         ├── velovision-camera-mjpeg-over-tcp.service // Runs gstreamer to stream camera over TCP at port 5000
         └── velovision-standalone-mode.service // Runs gstreamer to record to local disk, which records videos to /opt/velovision/standalone_videos. 
     */
-    let path = Path::new("/opt/velovision/standalone_videos");
-    // raise error if path does not exist
+    let path = Path::new(standalone_filesystem::VIDEOS_DIR);
     if !path.exists() {
-        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Directory {} does not exist", path.display())));
+        log::warn!("Directory {} does not exist, creating it", path.display());
+        std::fs::create_dir_all(path).context(format!("Failed to create {}", path.display()))?;
     }
 
     let files_to_check = [
@@ -241,7 +451,7 @@ fn check_installation() -> Result<(), io::Error> {
     for path_str in &files_to_check {
         let path = Path::new(path_str);
         if !path.exists() {
-            return Err(io::Error::new(io::ErrorKind::NotFound, format!("File {} does not exist", path.display())));
+            return Err(Error::Other(format!("File {} does not exist. See src/main.rs:check_installation for details.", path.display())));
         }
     }
 
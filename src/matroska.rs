@@ -0,0 +1,348 @@
+/*
+Reads the H.264 frames out of the Matroska chunks written by splitmuxsink, for HLS VOD.
+
+Only what HLS needs is parsed: the timestamp scale, the avcC record of the H.264 track (SPS, PPS, NAL length size)
+and the position, timestamp and keyframe flag of every SimpleBlock. Frame data is read on demand.
+The chunk being recorded has no final sizes yet, so master elements are entered rather than skipped,
+which works for unknown-size elements too.
+*/
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const ID_SEGMENT: u32 = 0x1853_8067;
+const ID_INFO: u32 = 0x1549_A966;
+const ID_TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+const ID_TRACKS: u32 = 0x1654_AE6B;
+const ID_TRACK_ENTRY: u32 = 0xAE;
+const ID_TRACK_NUMBER: u32 = 0xD7;
+const ID_CODEC_ID: u32 = 0x86;
+const ID_CODEC_PRIVATE: u32 = 0x63A2;
+const ID_CLUSTER: u32 = 0x1F43_B675;
+const ID_CLUSTER_TIMESTAMP: u32 = 0xE7;
+const ID_SIMPLE_BLOCK: u32 = 0xA3;
+
+const H264_CODEC_ID: &str = "V_MPEG4/ISO/AVC";
+const MAX_HEADER_ELEMENT_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub offset: u64, // of the frame data in the file, after the block header
+    pub size: u64,
+    pub timestamp_ns: u64,
+    pub keyframe: bool,
+}
+
+pub struct H264Track {
+    pub sps: Vec<Vec<u8>>,
+    pub pps: Vec<Vec<u8>>,
+    pub nal_length_size: usize,
+    pub frames: Vec<Frame>, // in file order, which is decode order
+}
+
+#[derive(Default)]
+struct TrackEntry {
+    number: Option<u64>,
+    codec_id: String,
+    codec_private: Vec<u8>,
+}
+
+pub fn read_h264_track<P: AsRef<Path>>(path: P) -> io::Result<H264Track> {
+    let file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut timestamp_scale: u64 = 1_000_000; // Matroska default, 1 ms
+    let mut tracks: Vec<TrackEntry> = Vec::new();
+    let mut video_track: Option<(u64, H264Track)> = None;
+    let mut cluster_timestamp: u64 = 0;
+    let mut position: u64 = 0;
+
+    while position < file_size {
+        let (id, size, header_size) = match read_element_header(&mut reader) {
+            Ok(Some(header)) => header,
+            Ok(None) => break,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break, // chunk still being written
+            Err(e) => return Err(e),
+        };
+        let data_start = position + header_size;
+        let data_end = size.map_or(file_size, |size| data_start + size);
+        if data_end > file_size && !matches!(id, ID_SEGMENT | ID_CLUSTER) {
+            break; // the last element of a chunk still being written is incomplete
+        }
+
+        match id {
+            ID_SEGMENT | ID_INFO | ID_TRACKS | ID_CLUSTER => {
+                // Enter the master element
+                position = data_start;
+                continue;
+            }
+            ID_TRACK_ENTRY => {
+                tracks.push(TrackEntry::default());
+                position = data_start;
+                continue;
+            }
+            ID_TIMESTAMP_SCALE => timestamp_scale = read_uint(&mut reader, size)?,
+            ID_TRACK_NUMBER => {
+                let number = read_uint(&mut reader, size)?;
+                if let Some(track) = tracks.last_mut() {
+                    track.number = Some(number);
+                }
+            }
+            ID_CODEC_ID => {
+                let codec_id = String::from_utf8_lossy(&read_bytes(&mut reader, size)?).trim_end_matches('\0').to_string();
+                if let Some(track) = tracks.last_mut() {
+                    track.codec_id = codec_id;
+                }
+            }
+            ID_CODEC_PRIVATE => {
+                let codec_private = read_bytes(&mut reader, size)?;
+                if let Some(track) = tracks.last_mut() {
+                    track.codec_private = codec_private;
+                }
+            }
+            ID_CLUSTER_TIMESTAMP => cluster_timestamp = read_uint(&mut reader, size)?,
+            ID_SIMPLE_BLOCK => {
+                if video_track.is_none() {
+                    // Tracks always come before the first cluster
+                    let entry = tracks.iter().find(|t| t.codec_id == H264_CODEC_ID && t.number.is_some())
+                        .ok_or_else(|| invalid("No H.264 track"))?;
+                    video_track = Some((entry.number.unwrap_or_default(), parse_avc_configuration(&entry.codec_private)?));
+                }
+                let (track_number, track) = video_track.as_mut().ok_or_else(|| invalid("No H.264 track"))?;
+
+                let (block_track, track_size) = read_vint(&mut reader)?.ok_or_else(|| invalid("Truncated block"))?;
+                let mut block_header = [0u8; 3];
+                reader.read_exact(&mut block_header)?;
+                let relative = i16::from_be_bytes([block_header[0], block_header[1]]) as i64;
+                let flags = block_header[2];
+                let frame_offset = data_start + track_size as u64 + 3;
+
+                if block_track == *track_number && flags & 0x06 == 0 && frame_offset < data_end {
+                    // Lacing is never used for video, so such blocks are skipped
+                    let timestamp = (cluster_timestamp as i64 + relative).max(0) as u64;
+                    track.frames.push(Frame {
+                        offset: frame_offset,
+                        size: data_end - frame_offset,
+                        timestamp_ns: timestamp * timestamp_scale,
+                        keyframe: flags & 0x80 != 0,
+                    });
+                }
+            }
+            _ => (),
+        }
+
+        // Skip to the next element. An unknown-size element that isn't entered can't be skipped, so stop there.
+        if size.is_none() {
+            break;
+        }
+        position = data_end;
+        reader.seek(SeekFrom::Start(position))?;
+    }
+
+    match video_track {
+        Some((_, track)) => Ok(track),
+        None => Err(invalid("No H.264 frames")),
+    }
+}
+
+pub fn read_frame_annex_b(reader: &mut File, frame: &Frame, nal_length_size: usize) -> io::Result<Vec<u8>> {
+    // Matroska stores NAL units with a length prefix (avcC), HLS and h264parse expect start codes
+    let mut data = vec![0u8; frame.size as usize];
+    reader.seek(SeekFrom::Start(frame.offset))?;
+    reader.read_exact(&mut data)?;
+
+    let mut annex_b = Vec::with_capacity(data.len() + 16);
+    let mut rest = &data[..];
+    while rest.len() >= nal_length_size {
+        let length = rest[..nal_length_size].iter().fold(0usize, |acc, &b| (acc << 8) | b as usize);
+        rest = &rest[nal_length_size..];
+        if length > rest.len() {
+            return Err(invalid("NAL unit length past the end of the frame"));
+        }
+        annex_b.extend_from_slice(&[0, 0, 0, 1]);
+        annex_b.extend_from_slice(&rest[..length]);
+        rest = &rest[length..];
+    }
+    Ok(annex_b)
+}
+
+fn parse_avc_configuration(record: &[u8]) -> io::Result<H264Track> {
+    /*
+    AVCDecoderConfigurationRecord (ISO/IEC 14496-15):
+    version, profile, compatibility, level, 6 bits reserved + 2 bits NAL length size - 1,
+    3 bits reserved + 5 bits SPS count, then length-prefixed SPS, then PPS count and length-prefixed PPS
+    */
+    if record.len() < 7 || record[0] != 1 {
+        return Err(invalid("Invalid avcC record"));
+    }
+    let nal_length_size = (record[4] & 0x03) as usize + 1;
+    let mut rest = &record[5..];
+    let read_sets = |count: usize, rest: &mut &[u8]| -> io::Result<Vec<Vec<u8>>> {
+        let mut sets = Vec::new();
+        for _ in 0..count {
+            if rest.len() < 2 {
+                return Err(invalid("Truncated avcC record"));
+            }
+            let length = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+            if rest.len() < 2 + length {
+                return Err(invalid("Truncated avcC record"));
+            }
+            sets.push(rest[2..2 + length].to_vec());
+            *rest = &rest[2 + length..];
+        }
+        Ok(sets)
+    };
+    let sps_count = (rest[0] & 0x1F) as usize;
+    rest = &rest[1..];
+    let sps = read_sets(sps_count, &mut rest)?;
+    let pps_count = *rest.first().ok_or_else(|| invalid("Truncated avcC record"))? as usize;
+    rest = &rest[1..];
+    let pps = read_sets(pps_count, &mut rest)?;
+
+    Ok(H264Track { sps, pps, nal_length_size, frames: Vec::new() })
+}
+
+fn read_element_header<R: Read>(reader: &mut R) -> io::Result<Option<(u32, Option<u64>, u64)>> {
+    // Returns the element ID, its data size (None if unknown) and the header length
+    let mut first = [0u8; 1];
+    if reader.read(&mut first)? == 0 {
+        return Ok(None);
+    }
+    let id_length = first[0].leading_zeros() as usize + 1;
+    if id_length > 4 {
+        return Err(invalid("Invalid element ID"));
+    }
+    let mut id = first[0] as u32;
+    for _ in 1..id_length {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        id = (id << 8) | byte[0] as u32;
+    }
+    let Some((size, size_length)) = read_vint(reader)? else { return Ok(None) };
+    let unknown = size == (1u64 << (7 * size_length)) - 1;
+    Ok(Some((id, if unknown { None } else { Some(size) }, (id_length + size_length) as u64)))
+}
+
+fn read_vint<R: Read>(reader: &mut R) -> io::Result<Option<(u64, usize)>> {
+    // EBML variable size integer, returns the value without the length marker, and its length in bytes
+    let mut first = [0u8; 1];
+    if reader.read(&mut first)? == 0 {
+        return Ok(None);
+    }
+    let length = first[0].leading_zeros() as usize + 1;
+    if length > 8 {
+        return Err(invalid("Invalid variable size integer"));
+    }
+    let mut value = (first[0] as u64) & (0xFF >> length);
+    for _ in 1..length {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        value = (value << 8) | byte[0] as u64;
+    }
+    Ok(Some((value, length)))
+}
+
+fn read_uint<R: Read>(reader: &mut R, size: Option<u64>) -> io::Result<u64> {
+    let bytes = read_bytes(reader, size)?;
+    if bytes.len() > 8 {
+        return Err(invalid("Integer element too long"));
+    }
+    Ok(bytes.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64))
+}
+
+fn read_bytes<R: Read>(reader: &mut R, size: Option<u64>) -> io::Result<Vec<u8>> {
+    match size {
+        Some(size) if size <= MAX_HEADER_ELEMENT_SIZE => {
+            let mut data = vec![0u8; size as usize];
+            reader.read_exact(&mut data)?;
+            Ok(data)
+        }
+        _ => Err(invalid("Element too large")),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+    testdata/matroska/recording.mkv is a chunk as splitmuxsink leaves it while still recording:
+    an unknown-size Segment with TimestampScale 1 ms, an H.264 track 1 and an Opus track 2,
+    a Cluster at 0 ms with an IDR at 0, an Opus block and a P-frame at 33,
+    and an unknown-size Cluster at 66 ms with a P-frame at 66 and one at 99 that is cut off by the end of the file.
+    */
+    const RECORDING: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/matroska/recording.mkv");
+
+    fn temp_copy(name: &str, data: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("matroska-{}-{}.mkv", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_a_chunk_being_recorded() {
+        let track = read_h264_track(RECORDING).unwrap();
+        assert_eq!(track.sps, vec![vec![0x67, 0x42, 0xC0, 0x1E, 0x95, 0xA0]]);
+        assert_eq!(track.pps, vec![vec![0x68, 0xCE, 0x3C, 0x80]]);
+        assert_eq!(track.nal_length_size, 4);
+
+        let frames: Vec<(u64, bool)> = track.frames.iter().map(|f| (f.timestamp_ns, f.keyframe)).collect();
+        assert_eq!(frames, vec![(0, true), (33_000_000, false), (66_000_000, false)]);
+
+        let mut file = File::open(RECORDING).unwrap();
+        let annex_b: Vec<Vec<u8>> = track.frames.iter()
+            .map(|frame| read_frame_annex_b(&mut file, frame, track.nal_length_size).unwrap())
+            .collect();
+        assert_eq!(annex_b, vec![
+            vec![0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00, 0x11, 0x22],
+            vec![0, 0, 0, 1, 0x41, 0x9A, 0x00, 0x33],
+            vec![0, 0, 0, 1, 0x41, 0x9A, 0x01, 0x44, 0x55],
+        ]);
+    }
+
+    #[test]
+    fn reads_any_truncation() {
+        // Wherever the recording stops, only complete frames are listed
+        let data = std::fs::read(RECORDING).unwrap();
+        let mut most_frames = 0;
+        for length in 0..=data.len() {
+            let path = temp_copy("truncated", &data[..length]);
+            if let Ok(track) = read_h264_track(&path) {
+                let mut file = File::open(&path).unwrap();
+                for frame in &track.frames {
+                    assert!(frame.offset + frame.size <= length as u64, "frame past {} bytes", length);
+                    read_frame_annex_b(&mut file, frame, track.nal_length_size).unwrap();
+                }
+                assert!(track.frames.len() >= most_frames, "{} bytes lost frames", length);
+                most_frames = track.frames.len();
+            }
+            std::fs::remove_file(&path).unwrap();
+        }
+        assert_eq!(most_frames, 3);
+    }
+
+    #[test]
+    fn rejects_files_without_h264() {
+        let data = std::fs::read(RECORDING).unwrap();
+        let codec = data.windows(H264_CODEC_ID.len()).position(|w| w == H264_CODEC_ID.as_bytes()).unwrap();
+        let mut other_codec = data.clone();
+        other_codec[codec..codec + H264_CODEC_ID.len()].copy_from_slice(b"V_MPEGH/ISO/HEV");
+        let path = temp_copy("hevc", &other_codec);
+        assert_eq!(read_h264_track(&path).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_truncated_avc_configuration() {
+        let record = [1, 0x42, 0xC0, 0x1E, 0xFF, 0xE1, 0x00, 0x06, 0x67, 0x42, 0xC0, 0x1E, 0x95, 0xA0, 0x01, 0x00, 0x04, 0x68, 0xCE, 0x3C, 0x80];
+        assert!(parse_avc_configuration(&record).is_ok());
+        for length in 0..record.len() {
+            assert!(parse_avc_configuration(&record[..length]).is_err(), "{} bytes", length);
+        }
+    }
+}
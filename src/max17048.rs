@@ -0,0 +1,365 @@
+/*
+Driver for the MAX17048 fuel gauge on the I/O board, at I2C address 0x36.
+Register map and units from the datasheet, all registers are 16 bits, most significant byte first.
+
+The gauge is reached through the Registers trait: rppal's I2c on the Pi, or MockRegisters, which keeps the registers
+in memory and behaves like the chip where it matters: power-on values, read-only registers and STATUS flags that are only cleared.
+Set SIMULATE_FUEL_GAUGE=1 to run with MockRegisters where there's no fuel gauge, e.g. on a laptop.
+
+    let mut gauge = Max17048::new(I2c::new()?)?;
+    gauge.set_alert_threshold(10)?;
+    let percent = gauge.state_of_charge()?;
+*/
+use rppal::i2c::I2c;
+
+use crate::error::{Context, Error, Result};
+
+pub const ADDRESS: u16 = 0x36;
+
+const VCELL: u8 = 0x02;
+const SOC: u8 = 0x04;
+const MODE: u8 = 0x06;
+const VERSION: u8 = 0x08;
+const HIBRT: u8 = 0x0A;
+const CONFIG: u8 = 0x0C;
+const VALRT: u8 = 0x14;
+const CRATE: u8 = 0x16;
+const VRESET_ID: u8 = 0x18;
+const STATUS: u8 = 0x1A;
+
+const MODE_QUICK_START: u16 = 1 << 14;
+const MODE_HIBERNATING: u16 = 1 << 12;
+const CONFIG_ALRT: u16 = 1 << 5; // an alert is asserted, cleared by writing 0
+const CONFIG_ATHD: u16 = 0x1F; // empty alert threshold, 32% - ATHD
+const HIBRT_DEFAULT: u16 = 0x8030; // hibernate below 26.6%/h for 6 min, wake above 60 mV
+const HIBRT_DISABLED: u16 = 0x0000;
+
+const MICROVOLTS_PER_VCELL: f32 = 78.125;
+const PERCENT_PER_HOUR_PER_CRATE: f32 = 0.208;
+const MILLIVOLTS_PER_VALRT: u16 = 20;
+const MILLIVOLTS_PER_VRESET: u16 = 40;
+
+pub trait Registers {
+    fn read(&mut self, register: u8) -> Result<u16>;
+    fn write(&mut self, register: u8, value: u16) -> Result<()>;
+}
+
+impl Registers for I2c {
+    fn read(&mut self, register: u8) -> Result<u16> {
+        let mut buf = [0u8; 2];
+        self.write_read(&[register], &mut buf).context(format!("Failed to read fuel gauge register {:#04x}", register))?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    fn write(&mut self, register: u8, value: u16) -> Result<()> {
+        let [high, low] = value.to_be_bytes();
+        I2c::write(self, &[register, high, low]).context(format!("Failed to write fuel gauge register {:#04x}", register))?;
+        Ok(())
+    }
+}
+
+impl<R: Registers + ?Sized> Registers for Box<R> {
+    fn read(&mut self, register: u8) -> Result<u16> {
+        (**self).read(register)
+    }
+
+    fn write(&mut self, register: u8, value: u16) -> Result<()> {
+        (**self).write(register, value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StatusFlags {
+    pub reset: bool, // RI: the gauge was powered up or reset and isn't configured yet
+    pub voltage_high: bool, // VH: VCELL above the VALRT maximum
+    pub voltage_low: bool, // VL: VCELL below the VALRT minimum
+    pub voltage_reset: bool, // VR: the battery was removed or VCELL dropped below VRESET
+    pub soc_low: bool, // HD: SOC below the empty alert threshold
+    pub soc_change: bool, // SC: SOC changed by 1%, with CONFIG.ALSC
+}
+
+impl StatusFlags {
+    const BITS: [u16; 6] = [1 << 8, 1 << 9, 1 << 10, 1 << 11, 1 << 12, 1 << 13];
+
+    fn from_bits(bits: u16) -> Self {
+        let [reset, voltage_high, voltage_low, voltage_reset, soc_low, soc_change] = Self::BITS.map(|bit| bits & bit != 0);
+        StatusFlags { reset, voltage_high, voltage_low, voltage_reset, soc_low, soc_change }
+    }
+
+    fn bits(&self) -> u16 {
+        let flags = [self.reset, self.voltage_high, self.voltage_low, self.voltage_reset, self.soc_low, self.soc_change];
+        Self::BITS.iter().zip(flags).filter(|(_, set)| *set).map(|(bit, _)| bit).sum()
+    }
+
+    pub fn any(&self) -> bool {
+        self.bits() != 0
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        let names = ["reset", "voltage_high", "voltage_low", "voltage_reset", "soc_low", "soc_change"];
+        Self::BITS.iter().zip(names).filter(|(bit, _)| self.bits() & **bit != 0).map(|(_, name)| name).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub alert: bool, // the ALRT pin is asserted
+    pub alert_threshold_percent: u8, // 1 to 32
+}
+
+pub struct Max17048<R: Registers> {
+    registers: R,
+}
+
+impl<R: Registers> Max17048<R> {
+    pub fn new(registers: R) -> Result<Self> {
+        // Reads VERSION, so a missing or different chip fails here rather than with odd readings later
+        let mut gauge = Max17048 { registers };
+        let version = gauge.version()?;
+        if version & 0xFFF0 != 0x0010 {
+            return Err(Error::Other(format!("Unexpected fuel gauge version {:#06x}, expected a MAX17048", version)));
+        }
+        Ok(gauge)
+    }
+
+    pub fn cell_millivolts(&mut self) -> Result<f32> {
+        Ok(self.registers.read(VCELL)? as f32 * MICROVOLTS_PER_VCELL / 1_000.0)
+    }
+
+    pub fn state_of_charge(&mut self) -> Result<f32> {
+        // Percent, in 1/256 %. Can be over 100% when the battery holds more than the model expects.
+        Ok(self.registers.read(SOC)? as f32 / 256.0)
+    }
+
+    pub fn charge_rate(&mut self) -> Result<f32> {
+        // Percent per hour, positive while charging
+        Ok(self.registers.read(CRATE)? as i16 as f32 * PERCENT_PER_HOUR_PER_CRATE)
+    }
+
+    pub fn version(&mut self) -> Result<u16> {
+        self.registers.read(VERSION)
+    }
+
+    pub fn config(&mut self) -> Result<Config> {
+        let config = self.registers.read(CONFIG)?;
+        Ok(Config {
+            alert: config & CONFIG_ALRT != 0,
+            alert_threshold_percent: 32 - (config & CONFIG_ATHD) as u8,
+        })
+    }
+
+    pub fn set_alert_threshold(&mut self, percent: u8) -> Result<()> {
+        // The ALRT pin is asserted once the SOC drops below this, from 1% to 32%
+        if !(1..=32).contains(&percent) {
+            return Err(Error::Other(format!("Alert threshold {}% is outside 1% to 32%", percent)));
+        }
+        self.update(CONFIG, |config| (config & !CONFIG_ATHD) | (32 - percent as u16))
+    }
+
+    pub fn clear_alert(&mut self) -> Result<()> {
+        // Releases the ALRT pin. Clear the STATUS flags that caused it first, or it's asserted again.
+        self.update(CONFIG, |config| config & !CONFIG_ALRT)
+    }
+
+    pub fn status(&mut self) -> Result<StatusFlags> {
+        Ok(StatusFlags::from_bits(self.registers.read(STATUS)?))
+    }
+
+    pub fn clear_status(&mut self, flags: StatusFlags) -> Result<()> {
+        self.update(STATUS, |status| status & !flags.bits())
+    }
+
+    pub fn set_voltage_alerts(&mut self, min_millivolts: u16, max_millivolts: u16) -> Result<()> {
+        /*
+        VL and VH are set outside this range, in 20 mV steps up to 5.1 V.
+        Limits between steps are rounded into the range, so an alert is never later than asked for: 3450 mV alerts below 3460 mV.
+        */
+        let min_steps = min_millivolts.div_ceil(MILLIVOLTS_PER_VALRT).min(0xFF);
+        let max_steps = (max_millivolts / MILLIVOLTS_PER_VALRT).min(0xFF);
+        self.registers.write(VALRT, (min_steps << 8) | max_steps)
+    }
+
+    pub fn set_reset_voltage(&mut self, millivolts: u16) -> Result<()> {
+        // Below this the battery counts as removed and VR is set, in 40 mV steps up to 5.08 V, rounded to the nearest step
+        let steps = ((millivolts as u32 + MILLIVOLTS_PER_VRESET as u32 / 2) / MILLIVOLTS_PER_VRESET as u32).min(0x7F) as u16;
+        self.update(VRESET_ID, |vreset| (steps << 9) | (vreset & 0x01FF))
+    }
+
+    pub fn reset_voltage(&mut self) -> Result<u16> {
+        Ok((self.registers.read(VRESET_ID)? >> 9) * MILLIVOLTS_PER_VRESET)
+    }
+
+    pub fn set_hibernation(&mut self, enabled: bool) -> Result<()> {
+        // Hibernating saves power, but the SOC is updated every 45 s instead of every 250 ms. Enabled with the datasheet's thresholds.
+        self.registers.write(HIBRT, if enabled { HIBRT_DEFAULT } else { HIBRT_DISABLED })
+    }
+
+    pub fn hibernation_enabled(&mut self) -> Result<bool> {
+        Ok(self.registers.read(HIBRT)? != HIBRT_DISABLED)
+    }
+
+    pub fn is_hibernating(&mut self) -> Result<bool> {
+        Ok(self.registers.read(MODE)? & MODE_HIBERNATING != 0)
+    }
+
+    pub fn quick_start(&mut self) -> Result<()> {
+        /*
+        Estimates the SOC again from the voltage right now, as on power-up.
+        Only for when the first estimate was wrong, e.g. the battery was inserted under load: the estimate is worse while charging or under load.
+        */
+        self.registers.write(MODE, MODE_QUICK_START)
+    }
+
+    fn update(&mut self, register: u8, change: impl FnOnce(u16) -> u16) -> Result<()> {
+        let value = self.registers.read(register)?;
+        self.registers.write(register, change(value))
+    }
+}
+
+pub struct MockRegisters {
+    values: [u16; 256],
+}
+
+impl MockRegisters {
+    pub fn new(percent: f32, millivolts: f32, percent_per_hour: f32) -> Self {
+        // Power-on values from the datasheet, with a battery at the given charge
+        let mut values = [0u16; 256];
+        values[VCELL as usize] = (millivolts * 1_000.0 / MICROVOLTS_PER_VCELL) as u16;
+        values[SOC as usize] = (percent * 256.0) as u16;
+        values[VERSION as usize] = 0x0012;
+        values[HIBRT as usize] = HIBRT_DEFAULT;
+        values[CONFIG as usize] = 0x971C;
+        values[VALRT as usize] = 0x00FF;
+        values[CRATE as usize] = (percent_per_hour / PERCENT_PER_HOUR_PER_CRATE) as i16 as u16;
+        values[VRESET_ID as usize] = 0x9600;
+        values[STATUS as usize] = 0x0100; // RI, as after power-up
+        MockRegisters { values }
+    }
+}
+
+#[cfg(test)]
+impl MockRegisters {
+    pub fn raise(&mut self, flags: StatusFlags) {
+        // As the chip does when it measures, asserting ALRT with the flags
+        self.values[STATUS as usize] |= flags.bits();
+        self.values[CONFIG as usize] |= CONFIG_ALRT;
+    }
+}
+
+impl Registers for MockRegisters {
+    fn read(&mut self, register: u8) -> Result<u16> {
+        Ok(self.values[register as usize])
+    }
+
+    fn write(&mut self, register: u8, value: u16) -> Result<()> {
+        let current = &mut self.values[register as usize];
+        match register {
+            // Read-only on the chip. Quick-start estimates the SOC again, which the mock already knows.
+            VCELL | SOC | VERSION | CRATE | MODE => (),
+            // Flags can only be cleared, ENVR is written as is
+            STATUS => *current = (*current & value) | (value & (1 << 14)),
+            // The alert can only be cleared, the rest of CONFIG is written as is
+            CONFIG => *current = value & !(CONFIG_ALRT & !*current),
+            _ => *current = value,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gauge() -> Max17048<MockRegisters> {
+        Max17048::new(MockRegisters::new(80.0, 3900.0, -12.0)).unwrap()
+    }
+
+    #[test]
+    fn accepts_max17048_versions() {
+        let mut registers = MockRegisters::new(80.0, 3900.0, 0.0);
+        registers.values[VERSION as usize] = 0x0011;
+        assert!(Max17048::new(registers).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_chips() {
+        for version in [0x0000, 0x0020, 0xFFFF] {
+            let mut registers = MockRegisters::new(80.0, 3900.0, 0.0);
+            registers.values[VERSION as usize] = version;
+            assert!(Max17048::new(registers).is_err(), "{:#06x}", version);
+        }
+    }
+
+    #[test]
+    fn encodes_alert_threshold_as_32_minus_athd() {
+        let mut gauge = gauge();
+        assert_eq!(gauge.config().unwrap().alert_threshold_percent, 4); // ATHD 0x1C after power-up
+        gauge.set_alert_threshold(10).unwrap();
+        assert_eq!(gauge.registers.values[CONFIG as usize] & CONFIG_ATHD, 22);
+        assert_eq!(gauge.config().unwrap().alert_threshold_percent, 10);
+        gauge.set_alert_threshold(32).unwrap();
+        assert_eq!(gauge.registers.values[CONFIG as usize] & CONFIG_ATHD, 0);
+        // The rest of CONFIG is kept
+        assert_eq!(gauge.registers.values[CONFIG as usize] & !CONFIG_ATHD, 0x9700);
+    }
+
+    #[test]
+    fn rejects_alert_threshold_outside_range() {
+        let mut gauge = gauge();
+        assert!(gauge.set_alert_threshold(0).is_err());
+        assert!(gauge.set_alert_threshold(33).is_err());
+        assert_eq!(gauge.config().unwrap().alert_threshold_percent, 4);
+    }
+
+    #[test]
+    fn rounds_voltage_alerts_into_the_range() {
+        let mut gauge = gauge();
+        // 3460 mV and 4200 mV
+        gauge.set_voltage_alerts(3450, 4210).unwrap();
+        assert_eq!(gauge.registers.values[VALRT as usize], (173 << 8) | 210);
+        // 3440 mV and 5100 mV
+        gauge.set_voltage_alerts(3440, u16::MAX).unwrap();
+        assert_eq!(gauge.registers.values[VALRT as usize], (172 << 8) | 0xFF);
+    }
+
+    #[test]
+    fn rounds_reset_voltage_to_nearest_step() {
+        let mut gauge = gauge();
+        gauge.set_reset_voltage(2800).unwrap();
+        assert_eq!(gauge.reset_voltage().unwrap(), 2800);
+        gauge.set_reset_voltage(2819).unwrap();
+        assert_eq!(gauge.reset_voltage().unwrap(), 2800);
+        gauge.set_reset_voltage(2820).unwrap();
+        assert_eq!(gauge.reset_voltage().unwrap(), 2840);
+        // ID in the low byte is kept
+        assert_eq!(gauge.registers.values[VRESET_ID as usize] & 0x01FF, 0);
+    }
+
+    #[test]
+    fn reads_charge_rate_sign() {
+        let mut discharging = Max17048::new(MockRegisters::new(80.0, 3900.0, -12.48)).unwrap();
+        assert!((discharging.charge_rate().unwrap() + 12.48).abs() < 0.21);
+        let mut charging = Max17048::new(MockRegisters::new(80.0, 3900.0, 20.8)).unwrap();
+        assert!((charging.charge_rate().unwrap() - 20.8).abs() < 0.21);
+    }
+
+    #[test]
+    fn clears_only_given_status_flags() {
+        let mut registers = MockRegisters::new(80.0, 3900.0, 0.0);
+        registers.raise(StatusFlags { voltage_low: true, soc_low: true, ..StatusFlags::default() });
+        let mut gauge = Max17048::new(registers).unwrap();
+        gauge.clear_status(StatusFlags { voltage_low: true, ..StatusFlags::default() }).unwrap();
+        let status = gauge.status().unwrap();
+        assert_eq!(status.names(), vec!["reset", "soc_low"]);
+    }
+
+    #[test]
+    fn toggles_hibernation() {
+        let mut gauge = gauge();
+        assert!(gauge.hibernation_enabled().unwrap());
+        gauge.set_hibernation(false).unwrap();
+        assert!(!gauge.hibernation_enabled().unwrap());
+        gauge.set_hibernation(true).unwrap();
+        assert_eq!(gauge.registers.values[HIBRT as usize], HIBRT_DEFAULT);
+    }
+}
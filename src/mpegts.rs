@@ -0,0 +1,319 @@
+/*
+Minimal MPEG transport stream (ISO/IEC 13818-1) muxer for HLS segments.
+
+Carries a single H.264 video stream: every segment starts with a PAT and PMT,
+and every access unit becomes one PES packet with a PTS and a PCR.
+Continuity counters carry over between segments taken from the same muxer, as a live stream requires.
+There are no B-frames from v4l2h264enc, so the DTS always equals the PTS and is omitted.
+*/
+use crate::h264;
+
+const PACKET_SIZE: usize = 188;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+const STREAM_TYPE_H264: u8 = 0x1B;
+const VIDEO_STREAM_ID: u8 = 0xE0;
+const PTS_OFFSET: u64 = 9_000; // 100 ms at 90 kHz, keeps the PTS ahead of the PCR
+const AUD: [u8; 6] = [0, 0, 0, 1, h264::NAL_AUD, 0xF0];
+
+pub struct TsMuxer {
+    output: Vec<u8>,
+    pat_continuity: u8,
+    pmt_continuity: u8,
+    video_continuity: u8,
+}
+
+impl TsMuxer {
+    pub fn new() -> Self {
+        TsMuxer {
+            output: Vec::new(),
+            pat_continuity: 0,
+            pmt_continuity: 0,
+            video_continuity: 0,
+        }
+    }
+
+    pub fn begin_segment(&mut self) {
+        // Each segment must be decodable on its own, so it repeats the program tables
+        self.write_tables();
+    }
+
+    pub fn write_access_unit(&mut self, annex_b: &[u8], timestamp_90khz: u64, keyframe: bool) {
+        /*
+        annex_b holds every NAL unit of one picture with start codes.
+        Apple's HLS validator expects an access unit delimiter ahead of every picture, so one is added if missing.
+        */
+        let pts = timestamp_90khz + PTS_OFFSET;
+        let starts_with_aud = annex_b.len() > 4 && annex_b[..4] == [0, 0, 0, 1] && annex_b[4] & 0x1F == h264::NAL_AUD;
+
+        let mut pes = vec![0, 0, 1, VIDEO_STREAM_ID, 0, 0]; // PES_packet_length 0: unbounded, allowed for video
+        pes.push(0x80); // marker bits
+        pes.push(0x80); // PTS only
+        pes.push(5);
+        pes.push(0x21 | (((pts >> 30) & 0x07) << 1) as u8);
+        pes.extend_from_slice(&((((pts >> 15) & 0x7FFF) << 1 | 1) as u16).to_be_bytes());
+        pes.extend_from_slice(&((((pts & 0x7FFF) << 1) | 1) as u16).to_be_bytes());
+        if !starts_with_aud {
+            pes.extend_from_slice(&AUD);
+        }
+        pes.extend_from_slice(annex_b);
+
+        let mut remaining = &pes[..];
+        let mut first = true;
+        while !remaining.is_empty() {
+            // The adaptation field holds the PCR on the first packet of a picture, and stuffing on the last
+            let mut adaptation: Option<Vec<u8>> = None;
+            if first {
+                // Random access indicator on keyframes, and a PCR with every picture
+                let pcr = timestamp_90khz & 0x1_FFFF_FFFF;
+                let mut field = vec![if keyframe { 0x50 } else { 0x10 }];
+                field.extend_from_slice(&((pcr >> 1) as u32).to_be_bytes());
+                field.push((((pcr & 1) << 7) as u8) | 0x7E);
+                field.push(0);
+                adaptation = Some(field);
+            }
+            let adaptation_size = adaptation.as_ref().map_or(0, |field| field.len() + 1);
+            if remaining.len() < PACKET_SIZE - 4 - adaptation_size {
+                let stuffing = PACKET_SIZE - 4 - adaptation_size - remaining.len();
+                let field = adaptation.get_or_insert_with(Vec::new);
+                if field.is_empty() {
+                    // The length byte takes the first stuffing byte, the flags byte the second
+                    if stuffing > 1 {
+                        field.push(0x00);
+                        field.extend(std::iter::repeat_n(0xFF, stuffing - 2));
+                    }
+                } else {
+                    field.extend(std::iter::repeat_n(0xFF, stuffing));
+                }
+            }
+
+            let continuity = self.video_continuity;
+            self.video_continuity = (self.video_continuity + 1) & 0x0F;
+            self.write_packet_header(VIDEO_PID, first, continuity, adaptation.is_some());
+            if let Some(field) = &adaptation {
+                self.output.push(field.len() as u8);
+                self.output.extend_from_slice(field);
+            }
+            let payload_size = PACKET_SIZE - 4 - adaptation.as_ref().map_or(0, |field| field.len() + 1);
+            self.output.extend_from_slice(&remaining[..payload_size]);
+            remaining = &remaining[payload_size..];
+            first = false;
+        }
+    }
+
+    pub fn take_segment(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn write_tables(&mut self) {
+        // Program association table: program 1 -> PMT
+        let mut pat = vec![0x00, 0xB0, 0x0D, 0x00, 0x01, 0xC1, 0x00, 0x00, 0x00, 0x01];
+        pat.extend_from_slice(&(0xE000 | PMT_PID).to_be_bytes());
+        pat.extend_from_slice(&crc32(&pat).to_be_bytes());
+        let continuity = self.pat_continuity;
+        self.pat_continuity = (self.pat_continuity + 1) & 0x0F;
+        self.write_section(0x0000, continuity, &pat);
+
+        // Program map table: one H.264 stream, which also carries the PCR
+        let mut pmt = vec![0x02, 0xB0, 0x12, 0x00, 0x01, 0xC1, 0x00, 0x00];
+        pmt.extend_from_slice(&(0xE000 | VIDEO_PID).to_be_bytes());
+        pmt.extend_from_slice(&[0xF0, 0x00, STREAM_TYPE_H264]);
+        pmt.extend_from_slice(&(0xE000 | VIDEO_PID).to_be_bytes());
+        pmt.extend_from_slice(&[0xF0, 0x00]);
+        pmt.extend_from_slice(&crc32(&pmt).to_be_bytes());
+        let continuity = self.pmt_continuity;
+        self.pmt_continuity = (self.pmt_continuity + 1) & 0x0F;
+        self.write_section(PMT_PID, continuity, &pmt);
+    }
+
+    fn write_section(&mut self, pid: u16, continuity: u8, section: &[u8]) {
+        self.write_packet_header(pid, true, continuity, false);
+        self.output.push(0); // pointer field
+        self.output.extend_from_slice(section);
+        let padding = PACKET_SIZE - 5 - section.len();
+        self.output.extend(std::iter::repeat_n(0xFF, padding));
+    }
+
+    fn write_packet_header(&mut self, pid: u16, payload_start: bool, continuity: u8, has_adaptation: bool) {
+        self.output.push(0x47);
+        self.output.push(((payload_start as u8) << 6) | ((pid >> 8) as u8 & 0x1F));
+        self.output.push(pid as u8);
+        self.output.push(if has_adaptation { 0x30 } else { 0x10 } | continuity);
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    // CRC-32/MPEG-2 used by PSI sections
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04C1_1DB7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Packet<'a> {
+        pid: u16,
+        payload_start: bool,
+        continuity: u8,
+        adaptation: Option<&'a [u8]>, // without its length byte
+        payload: &'a [u8],
+    }
+
+    fn packets(segment: &[u8]) -> Vec<Packet<'_>> {
+        assert_eq!(segment.len() % PACKET_SIZE, 0);
+        segment.chunks(PACKET_SIZE).map(|packet| {
+            assert_eq!(packet[0], 0x47);
+            let (adaptation, payload) = match packet[3] & 0x30 {
+                0x30 => {
+                    let length = packet[4] as usize;
+                    (Some(&packet[5..5 + length]), &packet[5 + length..])
+                }
+                0x10 => (None, &packet[4..]),
+                other => panic!("adaptation_field_control {:#x}", other),
+            };
+            Packet {
+                pid: u16::from_be_bytes([packet[1] & 0x1F, packet[2]]),
+                payload_start: packet[1] & 0x40 != 0,
+                continuity: packet[3] & 0x0F,
+                adaptation,
+                payload,
+            }
+        }).collect()
+    }
+
+    fn video_pes(segment: &[u8]) -> Vec<u8> {
+        packets(segment).iter().filter(|p| p.pid == VIDEO_PID).flat_map(|p| p.payload.to_vec()).collect()
+    }
+
+    fn access_unit(length: usize) -> Vec<u8> {
+        let mut annex_b = vec![0, 0, 0, 1, 0x65];
+        annex_b.extend((0..length - 5).map(|i| i as u8));
+        annex_b
+    }
+
+    #[test]
+    fn computes_mpeg2_crc() {
+        assert_eq!(crc32(b"123456789"), 0x0376_E6E7);
+    }
+
+    #[test]
+    fn writes_program_tables() {
+        let mut muxer = TsMuxer::new();
+        muxer.begin_segment();
+        let segment = muxer.take_segment();
+        let tables = packets(&segment);
+        assert_eq!(tables.len(), 2);
+        for (packet, pid, table_id) in [(&tables[0], 0x0000, 0x00), (&tables[1], PMT_PID, 0x02)] {
+            assert_eq!(packet.pid, pid);
+            assert!(packet.payload_start);
+            assert_eq!(packet.payload[0], 0); // pointer field
+            let section = &packet.payload[1..];
+            assert_eq!(section[0], table_id);
+            let length = 3 + (u16::from_be_bytes([section[1], section[2]]) & 0x0FFF) as usize;
+            // A section's CRC makes the CRC over the whole section 0
+            assert_eq!(crc32(&section[..length]), 0);
+            assert!(section[length..].iter().all(|&b| b == 0xFF));
+        }
+        // PAT: program 1 on the PMT PID. PMT: PCR and H.264 stream on the video PID
+        let pat = &tables[0].payload[1..];
+        assert_eq!(u16::from_be_bytes([pat[8], pat[9]]), 1);
+        assert_eq!(u16::from_be_bytes([pat[10], pat[11]]) & 0x1FFF, PMT_PID);
+        let pmt = &tables[1].payload[1..];
+        assert_eq!(u16::from_be_bytes([pmt[8], pmt[9]]) & 0x1FFF, VIDEO_PID);
+        assert_eq!(pmt[12], STREAM_TYPE_H264);
+        assert_eq!(u16::from_be_bytes([pmt[13], pmt[14]]) & 0x1FFF, VIDEO_PID);
+    }
+
+    #[test]
+    fn encodes_pcr_and_pts() {
+        let mut muxer = TsMuxer::new();
+        let timestamp = 0x1_2345_6789; // uses all 33 bits
+        muxer.write_access_unit(&access_unit(100), timestamp, true);
+        let segment = muxer.take_segment();
+        let first = &packets(&segment)[0];
+
+        let adaptation = first.adaptation.unwrap();
+        assert_eq!(adaptation[0], 0x50); // random access, PCR
+        let pcr = (u32::from_be_bytes(adaptation[1..5].try_into().unwrap()) as u64) << 1 | (adaptation[5] >> 7) as u64;
+        assert_eq!(pcr, timestamp);
+        assert_eq!(adaptation[5] & 0x7F, 0x7E);
+        assert_eq!(adaptation[6], 0); // extension
+
+        let pes = first.payload;
+        assert_eq!(&pes[..4], &[0, 0, 1, VIDEO_STREAM_ID]);
+        assert_eq!(pes[7], 0x80); // PTS only
+        let p = &pes[9..14];
+        assert_eq!(p[0] & 0xF1, 0x21);
+        let pts = ((p[0] as u64 >> 1) & 0x07) << 30 | (u16::from_be_bytes([p[1], p[2]]) as u64 >> 1) << 15 | u16::from_be_bytes([p[3], p[4]]) as u64 >> 1;
+        assert_eq!(pts, (timestamp + PTS_OFFSET) & 0x1_FFFF_FFFF);
+        // An access unit delimiter is added ahead of the picture
+        assert_eq!(&pes[14..20], &AUD);
+
+        muxer.write_access_unit(&access_unit(100), 0, false);
+        assert_eq!(packets(&muxer.take_segment())[0].adaptation.unwrap()[0], 0x10);
+    }
+
+    #[test]
+    fn stuffs_the_last_packet() {
+        /*
+        The first packet carries 176 bytes of PES after its PCR, the others 184.
+        A PES of 176 + 183 bytes leaves 183 for the second packet: a single stuffing byte, the adaptation field length 0.
+        176 + 182 leaves 182: the length byte and an empty flags byte.
+        */
+        const PES_HEADER: usize = 14;
+        for (rest, expected) in [(183, &[][..]), (182, &[0x00][..]), (100, &[0x00; 83][..])] {
+            let pes_length = 176 + rest;
+            let annex_b = access_unit(pes_length - PES_HEADER - AUD.len());
+            let mut muxer = TsMuxer::new();
+            muxer.write_access_unit(&annex_b, 0, true);
+            let segment = muxer.take_segment();
+            let video = packets(&segment);
+            assert_eq!(video.len(), 2, "{} bytes left", rest);
+            let adaptation = video[1].adaptation.unwrap();
+            assert_eq!(adaptation.len(), expected.len(), "{} bytes left", rest);
+            assert!(adaptation.iter().skip(1).all(|&b| b == 0xFF), "{} bytes left", rest);
+            assert_eq!(video[1].payload.len(), rest);
+            assert_eq!(&video_pes(&segment)[PES_HEADER + AUD.len()..], &annex_b[..]);
+        }
+
+        // Exactly filled packets need no stuffing
+        let mut muxer = TsMuxer::new();
+        muxer.write_access_unit(&access_unit(176 + 184 - PES_HEADER - AUD.len()), 0, true);
+        assert!(packets(&muxer.take_segment())[1].adaptation.is_none());
+    }
+
+    #[test]
+    fn keeps_an_existing_access_unit_delimiter() {
+        let mut annex_b = AUD.to_vec();
+        annex_b.extend(access_unit(50));
+        let mut muxer = TsMuxer::new();
+        muxer.write_access_unit(&annex_b, 0, true);
+        assert_eq!(&video_pes(&muxer.take_segment())[14..], &annex_b[..]);
+    }
+
+    #[test]
+    fn carries_continuity_counters_over_segments() {
+        let mut muxer = TsMuxer::new();
+        let mut continuity: Vec<(u16, u8)> = Vec::new();
+        for _ in 0..3 {
+            muxer.begin_segment();
+            for _ in 0..4 {
+                muxer.write_access_unit(&access_unit(1000), 0, true);
+            }
+            continuity.extend(packets(&muxer.take_segment()).iter().map(|p| (p.pid, p.continuity)));
+        }
+        for pid in [0x0000, PMT_PID, VIDEO_PID] {
+            let counters: Vec<u8> = continuity.iter().filter(|(p, _)| *p == pid).map(|(_, c)| *c).collect();
+            assert!(counters.len() >= 3);
+            assert_eq!(counters[0], 0);
+            assert!(counters.windows(2).all(|pair| pair[1] == (pair[0] + 1) & 0x0F), "{:#x}: {:?}", pid, counters);
+        }
+    }
+}
@@ -0,0 +1,352 @@
+/*
+OpenAPI 3 document for GET /openapi.json, generated from the route table in src/api.rs and src/legacy_api.rs.
+
+Every route is registered together with its RouteDoc, and a RouteDoc can't be made without a response schema,
+so a route can't be added without documenting it. Path parameters are taken from the {braces} in the pattern.
+The Swift client is generated from this document.
+
+/stream.mjpeg, /events and /snapshot.jpg are answered outside the table, by the request loop in src/main.rs,
+since their responses outlast a pool thread, so they are documented by hand in unrouted().
+HLS playlists and segments aren't included: AVPlayer only needs their URLs.
+Routes that require a paired device list the bearerAuth security scheme.
+*/
+use std::time::Duration;
+
+use serde_json::{json, Map, Value};
+use tiny_http::Method;
+
+use crate::router::Route;
+
+pub struct Body {
+    content_type: &'static str,
+    schema: Value,
+}
+
+impl Body {
+    pub fn json(schema: Value) -> Self {
+        Body { content_type: "application/json", schema }
+    }
+
+    pub fn text() -> Self {
+        Body { content_type: "text/plain", schema: string() }
+    }
+
+    pub fn binary(content_type: &'static str) -> Self {
+        Body { content_type, schema: json!({ "type": "string", "format": "binary" }) }
+    }
+}
+
+pub struct RouteDoc {
+    summary: &'static str,
+    query: Vec<(&'static str, Value, &'static str)>,
+    request: Option<Body>,
+    status: u16,
+    response: Body,
+    deprecated: bool,
+    alias: bool,
+    text_errors: bool,
+    requires_auth: bool,
+    priority: bool,
+    timeout: Option<Duration>,
+}
+
+impl RouteDoc {
+    pub fn new(summary: &'static str, response: Body) -> Self {
+        RouteDoc { summary, query: Vec::new(), request: None, status: 200, response, deprecated: false, alias: false, text_errors: false, requires_auth: false, priority: false, timeout: None }
+    }
+
+    pub fn query(mut self, name: &'static str, schema: Value, description: &'static str) -> Self {
+        self.query.push((name, schema, description));
+        self
+    }
+
+    pub fn request(mut self, body: Body) -> Self {
+        self.request = Some(body);
+        self
+    }
+
+    pub fn status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn deprecated(mut self) -> Self {
+        // Routes from before /api/v1, which answer errors in plain text
+        self.deprecated = true;
+        self.text_errors = true;
+        self
+    }
+
+    pub fn alias(mut self) -> Self {
+        // Short route outside /api/v1 for a /api/v1 route, supported like it rather than deprecated
+        self.alias = true;
+        self
+    }
+
+    pub fn text_errors(mut self) -> Self {
+        // Errors in plain text, like the legacy routes, for aliases that take and answer plain text
+        self.text_errors = true;
+        self
+    }
+
+    pub fn requires_auth(mut self) -> Self {
+        // Only answered with the token of a paired device, see src/auth.rs. The router enforces it.
+        self.requires_auth = true;
+        self
+    }
+
+    pub fn priority(mut self) -> Self {
+        // Lightweight, answered on threads of their own so a download doesn't hold it up, see src/request_pool.rs
+        self.priority = true;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        // Instead of request_pool::DEFAULT_TIMEOUT, e.g. for downloads
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn has_text_errors(&self) -> bool {
+        self.text_errors
+    }
+
+    pub fn is_auth_required(&self) -> bool {
+        self.requires_auth
+    }
+
+    pub fn is_priority(&self) -> bool {
+        self.priority
+    }
+
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+}
+
+pub fn document<S>(routes: &[Route<S>]) -> Value {
+    let mut paths = Map::new();
+    let unrouted = unrouted();
+    let operations = routes.iter().map(|route| (&route.method, route.pattern, &route.doc))
+        .chain(unrouted.iter().map(|(pattern, doc)| (&Method::Get, *pattern, doc)));
+    for (method, pattern, doc) in operations {
+        let path = paths.entry(pattern).or_insert_with(|| json!({}));
+        path[method.to_string().to_lowercase()] = operation(method, pattern, doc);
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Velovision Rearview",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Control API of the Velovision Rearview camera. Routes outside /api/v1 are either short aliases of /api/v1 routes, or deprecated and kept for older app versions.",
+        },
+        "servers": [{ "url": "http://192.168.9.1:8000" }, { "url": "https://192.168.9.1:8443", "description": "Self-signed, pin the certificate from GET /api/v1/tls" }],
+        "paths": paths,
+        "components": {
+            "schemas": {
+                "Error": object(json!({
+                    "code": string(),
+                    "message": string(),
+                    "details": json!({ "nullable": true }),
+                })),
+            },
+            "securitySchemes": {
+                "bearerAuth": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "Token from POST /api/v1/pairing/{pairing_id}/confirm",
+                },
+            },
+        },
+    })
+}
+
+fn unrouted() -> Vec<(&'static str, RouteDoc)> {
+    // GET routes answered by the request loop in src/main.rs, kept in step with it by hand
+    vec![
+        ("/stream.mjpeg", RouteDoc::new("Live MJPEG stream, one JPEG per part, for as long as the client watches",
+            Body::binary("multipart/x-mixed-replace")).requires_auth()),
+        ("/events", RouteDoc::new("Server-Sent Events with battery, mode, recording, storage, thermal, button and shutdown events",
+            Body { content_type: "text/event-stream", schema: string() }).requires_auth()),
+        ("/snapshot.jpg", RouteDoc::new("Most recent frame as a JPEG, optionally scaled, decoded from the recording in standalone mode",
+            Body::binary("image/jpeg"))
+            .query("width", integer(), "Even number of pixels between 16 and 1920, the height follows the aspect ratio unless given")
+            .query("height", integer(), "Even number of pixels between 16 and 1920, the width follows the aspect ratio unless given")
+            .requires_auth()),
+    ]
+}
+
+fn operation(method: &Method, pattern: &str, doc: &RouteDoc) -> Value {
+    let mut parameters: Vec<Value> = pattern.split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": string() }))
+        .collect();
+    parameters.extend(doc.query.iter().map(|(name, schema, description)| {
+        json!({ "name": name, "in": "query", "required": false, "schema": schema, "description": description })
+    }));
+
+    let mut responses = Map::new();
+    responses.insert(doc.status.to_string(), json!({
+        "description": doc.summary,
+        "content": { doc.response.content_type: { "schema": doc.response.schema } },
+    }));
+    let error = if doc.text_errors {
+        json!({ "description": "Error", "content": { "text/plain": { "schema": string() } } })
+    } else {
+        json!({ "description": "Error", "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Error" } } } })
+    };
+    responses.insert("default".to_string(), error);
+
+    let mut operation = json!({
+        "summary": doc.summary,
+        "operationId": operation_id(method, pattern, doc),
+        "parameters": parameters,
+        "responses": responses,
+    });
+    if let Some(request) = &doc.request {
+        operation["requestBody"] = json!({
+            "required": true,
+            "content": { request.content_type: { "schema": request.schema } },
+        });
+    }
+    if doc.deprecated {
+        operation["deprecated"] = json!(true);
+    }
+    if doc.requires_auth {
+        operation["security"] = json!([{ "bearerAuth": [] }]);
+    }
+    operation
+}
+
+fn operation_id(method: &Method, pattern: &str, doc: &RouteDoc) -> String {
+    // GET /api/v1/videos/{id}/file -> getVideosIdFile, GET /battery-percent -> getBatteryPercentLegacy, GET /status -> getStatusAlias
+    let mut id = method.to_string().to_lowercase();
+    for word in pattern.trim_start_matches("/api/v1").split(['/', '-', '{', '}', '.']).filter(|w| !w.is_empty()) {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            id.push(first.to_ascii_uppercase());
+            id.push_str(chars.as_str());
+        }
+    }
+    if doc.deprecated {
+        id.push_str("Legacy");
+    } else if doc.alias {
+        id.push_str("Alias");
+    }
+    id
+}
+
+// Shorthands for schemas
+
+pub fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+pub fn string_enum(values: &[&str]) -> Value {
+    json!({ "type": "string", "enum": values })
+}
+
+pub fn integer() -> Value {
+    json!({ "type": "integer" })
+}
+
+pub fn number() -> Value {
+    json!({ "type": "number" })
+}
+
+pub fn boolean() -> Value {
+    json!({ "type": "boolean" })
+}
+
+pub fn nullable(mut schema: Value) -> Value {
+    schema["nullable"] = json!(true);
+    schema
+}
+
+pub fn described(mut schema: Value, description: &str) -> Value {
+    schema["description"] = json!(description);
+    schema
+}
+
+pub fn array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+pub fn object(properties: Value) -> Value {
+    // All properties are required, optional values are nullable instead
+    let required: Vec<String> = properties.as_object().map(|p| p.keys().cloned().collect()).unwrap_or_default();
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+pub fn any_object() -> Value {
+    json!({ "type": "object", "additionalProperties": true })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api;
+
+    fn path_parameters(pattern: &str) -> Vec<&str> {
+        pattern.split('/').filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}')).collect()
+    }
+
+    #[test]
+    fn every_route_is_documented() {
+        let router = api::router();
+        let document = document(router.routes());
+        for route in router.routes() {
+            let method = route.method.to_string().to_lowercase();
+            let operation = &document["paths"][route.pattern][&method];
+            assert!(operation.is_object(), "{} {} missing", method, route.pattern);
+
+            let response = &operation["responses"][route.doc.status.to_string()];
+            let schema = response["content"].as_object().and_then(|content| content.values().next()).map(|body| &body["schema"]);
+            assert!(schema.is_some_and(|schema| schema.get("type").is_some()), "{} {} has no response schema", method, route.pattern);
+
+            let documented: Vec<&str> = operation["parameters"].as_array().unwrap().iter()
+                .filter(|parameter| parameter["in"] == "path")
+                .filter_map(|parameter| parameter["name"].as_str())
+                .collect();
+            for parameter in path_parameters(route.pattern) {
+                assert!(documented.contains(&parameter), "{} {} doesn't document {{{}}}", method, route.pattern, parameter);
+            }
+        }
+    }
+
+    #[test]
+    fn operation_ids_are_unique() {
+        let router = api::router();
+        let document = document(router.routes());
+        let mut ids: Vec<&str> = document["paths"].as_object().unwrap().values()
+            .flat_map(|path| path.as_object().unwrap().values())
+            .map(|operation| operation["operationId"].as_str().unwrap())
+            .collect();
+        let count = ids.len();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), count);
+    }
+
+    #[test]
+    fn aliases_are_not_deprecated() {
+        let document = document(api::router().routes());
+        for (pattern, method) in [("/status", "get"), ("/stream/clients", "get"), ("/video/preset", "get"), ("/video/preset", "put"), ("/video/srt", "put")] {
+            assert!(document["paths"][pattern][method].get("deprecated").is_none(), "{} {} is deprecated", method, pattern);
+        }
+        assert_eq!(document["paths"]["/status"]["get"]["operationId"], "getStatusAlias");
+        assert_eq!(document["paths"]["/battery-percent"]["get"]["deprecated"], true);
+        assert!(document["paths"]["/video/preset"]["put"]["responses"]["default"]["content"]["text/plain"].is_object());
+    }
+
+    #[test]
+    fn documents_unrouted_responses() {
+        let document = document(api::router().routes());
+        assert!(document["paths"]["/stream.mjpeg"]["get"]["responses"]["200"]["content"]["multipart/x-mixed-replace"].is_object());
+        assert!(document["paths"]["/events"]["get"]["responses"]["200"]["content"]["text/event-stream"].is_object());
+        let snapshot = &document["paths"]["/snapshot.jpg"]["get"];
+        assert!(snapshot["responses"]["200"]["content"]["image/jpeg"].is_object());
+        assert_eq!(snapshot["parameters"].as_array().unwrap().len(), 2);
+        assert_eq!(snapshot["security"], json!([{ "bearerAuth": [] }]));
+    }
+}
@@ -0,0 +1,465 @@
+/*
+Typed builder for gst-launch-1.0 pipeline descriptions.
+
+The camera pipelines used to be hand-written strings spread across systemd unit files and shell scripts.
+A PipelineSpec describes a pipeline as source -> resolution/framerate -> encoder -> muxer -> sink,
+and render() validates the combination before producing the launch string.
+
+The rendered strings are written to /opt/velovision/pipelines.env at startup,
+which the systemd units and standalone_gstreamer.sh read instead of hard-coding their own pipelines.
+*/
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+pub const PIPELINES_ENV_PATH: &str = "/opt/velovision/pipelines.env";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    LibCamera, // Raspberry Pi CSI camera via libcamera
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoder {
+    Jpeg { quality: u8 }, // software jpegenc, quality 0-100
+    H264 { level: &'static str, repeat_sequence_header: bool }, // hardware-accelerated v4l2h264enc
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Muxer {
+    None, // raw byte stream, e.g. H.264 Annex-B
+    Multipart, // multipart/x-mixed-replace, used for MJPEG
+    Matroska, // only valid as the muxer of splitmuxsink
+    MpegTs, // MPEG transport stream, used for SRT
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sink {
+    TcpServer { host: String, port: u16 },
+    SplitMux { location: String, max_size_time_ns: u64, max_files: u32, start_index: Option<u32> },
+    Srt { port: u16, latency_ms: u32, passphrase: Option<String> }, // listener mode, clients call in
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipelineError {
+    InvalidResolution(u32, u32),
+    InvalidFramerate(u32),
+    InvalidJpegQuality(u8),
+    InvalidH264Level(&'static str),
+    InvalidBitrate(u32),
+    BitrateWithoutH264,
+    IncompatibleMuxer(Muxer, Encoder),
+    IncompatibleSink(&'static str),
+    InvalidSinkParameter(String),
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PipelineError::InvalidResolution(w, h) => write!(f, "Invalid resolution {}x{}: width and height must be even and non-zero", w, h),
+            PipelineError::InvalidFramerate(fps) => write!(f, "Invalid framerate {}: must be between 1 and 120", fps),
+            PipelineError::InvalidJpegQuality(q) => write!(f, "Invalid JPEG quality {}: must be between 0 and 100", q),
+            PipelineError::InvalidH264Level(level) => write!(f, "Invalid H.264 level {}", level),
+            PipelineError::InvalidBitrate(bps) => write!(f, "Invalid bitrate {} bps", bps),
+            PipelineError::BitrateWithoutH264 => write!(f, "Bitrate can only be set for the H.264 encoder"),
+            PipelineError::IncompatibleMuxer(muxer, encoder) => write!(f, "Muxer {:?} cannot carry {:?} output", muxer, encoder),
+            PipelineError::IncompatibleSink(reason) => write!(f, "Incompatible sink: {}", reason),
+            PipelineError::InvalidSinkParameter(reason) => write!(f, "Invalid sink parameter: {}", reason),
+        }
+    }
+}
+
+const H264_LEVELS: [&str; 8] = ["3", "3.1", "3.2", "4", "4.1", "4.2", "5", "5.1"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineSpec {
+    source: Source,
+    width: u32,
+    height: u32,
+    framerate: u32,
+    encoder: Encoder,
+    bitrate: Option<u32>,
+    muxer: Muxer,
+    sink: Sink,
+}
+
+impl PipelineSpec {
+    pub fn new(source: Source) -> Self {
+        PipelineSpec {
+            source,
+            width: 640,
+            height: 360,
+            framerate: 30,
+            encoder: Encoder::Jpeg { quality: 30 },
+            bitrate: None,
+            muxer: Muxer::Multipart,
+            sink: Sink::TcpServer { host: "0.0.0.0".to_string(), port: 5000 },
+        }
+    }
+
+    pub fn resolution(mut self, width: u32, height: u32) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    pub fn framerate(mut self, framerate: u32) -> Self {
+        self.framerate = framerate;
+        self
+    }
+
+    pub fn encoder(mut self, encoder: Encoder) -> Self {
+        self.encoder = encoder;
+        self
+    }
+
+    pub fn bitrate(mut self, bits_per_second: u32) -> Self {
+        self.bitrate = Some(bits_per_second);
+        self
+    }
+
+    pub fn muxer(mut self, muxer: Muxer) -> Self {
+        self.muxer = muxer;
+        self
+    }
+
+    pub fn sink(mut self, sink: Sink) -> Self {
+        self.sink = sink;
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), PipelineError> {
+        if self.width == 0 || self.height == 0 || !self.width.is_multiple_of(2) || !self.height.is_multiple_of(2) {
+            return Err(PipelineError::InvalidResolution(self.width, self.height));
+        }
+        if self.framerate == 0 || self.framerate > 120 {
+            return Err(PipelineError::InvalidFramerate(self.framerate));
+        }
+
+        match self.encoder {
+            Encoder::Jpeg { quality } => {
+                if quality > 100 {
+                    return Err(PipelineError::InvalidJpegQuality(quality));
+                }
+                if self.bitrate.is_some() {
+                    return Err(PipelineError::BitrateWithoutH264);
+                }
+            }
+            Encoder::H264 { level, .. } => {
+                if !H264_LEVELS.contains(&level) {
+                    return Err(PipelineError::InvalidH264Level(level));
+                }
+                if let Some(bitrate) = self.bitrate {
+                    // v4l2h264enc on the Pi accepts 25 kbps up to 25 Mbps
+                    if !(25_000..=25_000_000).contains(&bitrate) {
+                        return Err(PipelineError::InvalidBitrate(bitrate));
+                    }
+                }
+            }
+        }
+
+        match (self.muxer, self.encoder) {
+            (Muxer::Multipart, Encoder::Jpeg { .. }) => (),
+            (Muxer::None, Encoder::H264 { .. }) => (),
+            (Muxer::Matroska, Encoder::H264 { .. }) => (),
+            (Muxer::MpegTs, Encoder::H264 { .. }) => (),
+            (muxer, encoder) => return Err(PipelineError::IncompatibleMuxer(muxer, encoder)),
+        }
+
+        match &self.sink {
+            Sink::TcpServer { host, port } => {
+                if matches!(self.muxer, Muxer::Matroska | Muxer::MpegTs) {
+                    return Err(PipelineError::IncompatibleSink("tcpserversink carries multipart or raw H.264 only"));
+                }
+                if host.is_empty() || host.contains(char::is_whitespace) || host.contains('\'') {
+                    return Err(PipelineError::InvalidSinkParameter(format!("host '{}'", host)));
+                }
+                if *port == 0 {
+                    return Err(PipelineError::InvalidSinkParameter("port 0".to_string()));
+                }
+            }
+            Sink::SplitMux { location, max_size_time_ns, max_files, .. } => {
+                if self.muxer != Muxer::Matroska {
+                    return Err(PipelineError::IncompatibleSink("splitmuxsink requires the Matroska muxer"));
+                }
+                // splitmuxsink substitutes the fragment index with printf-style formatting
+                if !location.contains("%0") || !location.contains('d') {
+                    return Err(PipelineError::InvalidSinkParameter(format!("location '{}' has no %0Nd index pattern", location)));
+                }
+                if location.contains(char::is_whitespace) || location.contains('\'') {
+                    return Err(PipelineError::InvalidSinkParameter(format!("location '{}'", location)));
+                }
+                if *max_size_time_ns == 0 || *max_files == 0 {
+                    return Err(PipelineError::InvalidSinkParameter("max-size-time and max-files must be non-zero".to_string()));
+                }
+            }
+            Sink::Srt { port, latency_ms, passphrase } => {
+                if self.muxer != Muxer::MpegTs {
+                    return Err(PipelineError::IncompatibleSink("srtsink requires the MPEG-TS muxer"));
+                }
+                if *port == 0 {
+                    return Err(PipelineError::InvalidSinkParameter("port 0".to_string()));
+                }
+                if !(20..=8000).contains(latency_ms) {
+                    return Err(PipelineError::InvalidSinkParameter(format!("SRT latency {} ms: must be between 20 and 8000", latency_ms)));
+                }
+                if let Some(passphrase) = passphrase {
+                    // libsrt requires 10 to 79 characters. Rendered unquoted, so only characters that need no escaping.
+                    if !(10..=79).contains(&passphrase.len()) {
+                        return Err(PipelineError::InvalidSinkParameter("SRT passphrase must be 10 to 79 characters".to_string()));
+                    }
+                    if !passphrase.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:@+=".contains(c)) {
+                        return Err(PipelineError::InvalidSinkParameter("SRT passphrase may only contain letters, digits and - _ . : @ + =".to_string()));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn render(&self) -> Result<String, PipelineError> {
+        /*
+        Render as the pipeline description that follows `gst-launch-1.0` on the command line.
+        Elements are separated by " ! " and no part of the string contains quotes or spaces within a property value,
+        so it may be passed through an unquoted environment variable expansion, which splits it on whitespace only.
+        */
+        self.validate()?;
+
+        let mut elements: Vec<String> = Vec::new();
+
+        elements.push(match self.source {
+            Source::LibCamera => "libcamerasrc".to_string(),
+        });
+
+        match self.encoder {
+            Encoder::Jpeg { quality } => {
+                elements.push(format!("video/x-raw,width={},height={},framerate={}/1", self.width, self.height, self.framerate));
+                elements.push(format!("jpegenc quality={}", quality));
+            }
+            Encoder::H264 { level, repeat_sequence_header } => {
+                elements.push(format!("video/x-raw,width={},height={},format=NV12,framerate={}/1", self.width, self.height, self.framerate));
+                elements.push("v4l2convert".to_string());
+
+                let mut controls: Vec<String> = Vec::new();
+                if let Some(bitrate) = self.bitrate {
+                    controls.push(format!("video_bitrate={}", bitrate));
+                }
+                if repeat_sequence_header {
+                    // SPS and PPS ahead of every IDR frame, required by the iOS decoder
+                    controls.push("repeat_sequence_header=1".to_string());
+                }
+                if controls.is_empty() {
+                    elements.push("v4l2h264enc".to_string());
+                } else {
+                    // A structure without spaces needs no quoting, see render()
+                    elements.push(format!("v4l2h264enc extra-controls=controls,{}", controls.join(",")));
+                }
+                elements.push(format!("video/x-h264,level=(string){},stream-format=byte-stream", level));
+                elements.push("h264parse".to_string());
+            }
+        }
+
+        match self.muxer {
+            Muxer::Multipart => elements.push("multipartmux".to_string()),
+            Muxer::MpegTs => elements.push("mpegtsmux".to_string()),
+            Muxer::None | Muxer::Matroska => (), // matroskamux is a property of splitmuxsink
+        }
+
+        match &self.sink {
+            Sink::TcpServer { host, port } => {
+                let mut sink = format!("tcpserversink host={} port={}", host, port);
+                if self.muxer == Muxer::Multipart {
+                    // Fixes a gradual memory leak in tcpserversink when a client is connected. See VIDEO_ENCODING.md
+                    sink.push_str(" buffers-soft-max=2 recover-policy=latest");
+                }
+                elements.push(sink);
+            }
+            Sink::SplitMux { location, max_size_time_ns, max_files, start_index } => {
+                let mut sink = format!("splitmuxsink location={}", location);
+                if let Some(start_index) = start_index {
+                    sink.push_str(&format!(" start-index={}", start_index));
+                }
+                sink.push_str(&format!(" max-size-time={} max-files={} muxer=matroskamux", max_size_time_ns, max_files));
+                elements.push(sink);
+            }
+            Sink::Srt { port, latency_ms, passphrase } => {
+                // Without a host in the URI, srtsink listens. It keeps the pipeline running while no caller is connected.
+                let mut sink = format!("srtsink uri=srt://:{} latency={} wait-for-connection=false", port, latency_ms);
+                if let Some(passphrase) = passphrase {
+                    sink.push_str(&format!(" passphrase={}", passphrase));
+                }
+                elements.push(sink);
+            }
+        }
+
+        Ok(elements.join(" ! "))
+    }
+}
+
+pub fn mjpeg_over_tcp() -> PipelineSpec {
+    /*
+    Live view for the iPhone app. 640x360 doesn't crop much and is small enough for 30fps.
+    Served on loopback only: clients connect to the relay in src/stream_relay.rs, which reads from here.
+    */
+    PipelineSpec::new(Source::LibCamera)
+        .resolution(640, 360)
+        .framerate(30)
+        .encoder(Encoder::Jpeg { quality: 30 })
+        .muxer(Muxer::Multipart)
+        .sink(Sink::TcpServer { host: "127.0.0.1".to_string(), port: crate::stream_relay::UPSTREAM_PORT })
+}
+
+pub fn h264_over_tcp() -> PipelineSpec {
+    /*
+    1 Mbps was a good medium between quality and reliability in testing with the iPhone.
+    Served on loopback only, like mjpeg_over_tcp. The relay sends SPS/PPS to clients that join late.
+    */
+    PipelineSpec::new(Source::LibCamera)
+        .resolution(640, 360)
+        .framerate(30)
+        .encoder(Encoder::H264 { level: "5", repeat_sequence_header: true })
+        .bitrate(1_000_000)
+        .muxer(Muxer::None)
+        .sink(Sink::TcpServer { host: "127.0.0.1".to_string(), port: crate::stream_relay::UPSTREAM_PORT })
+}
+
+pub fn h264_over_srt() -> PipelineSpec {
+    /*
+    The mpegtsmux ! srtsink pipeline from VIDEO_ENCODING.md, which used 40% of a core.
+    Clients call in directly, so unlike the TCP pipelines it doesn't go through the stream relay.
+    */
+    PipelineSpec::new(Source::LibCamera)
+        .resolution(640, 360)
+        .framerate(30)
+        .encoder(Encoder::H264 { level: "5", repeat_sequence_header: true })
+        .bitrate(1_000_000)
+        .muxer(Muxer::MpegTs)
+        .sink(Sink::Srt { port: crate::srt::SRT_PORT, latency_ms: 200, passphrase: None })
+}
+
+pub fn splitmux_recording() -> PipelineSpec {
+    /*
+    Standalone mode recording: one minute chunks, 360 files (6 hours) looped.
+    start-index is left unset because standalone_gstreamer.sh appends it after finding the latest chunk.
+    */
+    PipelineSpec::new(Source::LibCamera)
+        .resolution(1280, 720)
+        .framerate(30)
+        .encoder(Encoder::H264 { level: "4", repeat_sequence_header: true })
+        .bitrate(8_000_000)
+        .muxer(Muxer::Matroska)
+        .sink(Sink::SplitMux {
+            location: "/opt/velovision/standalone_videos/log%04d.mkv".to_string(),
+            max_size_time_ns: 60_000_000_000,
+            max_files: 360,
+            start_index: None,
+        })
+}
+
+pub fn write_pipelines_env<P: AsRef<Path>>(path: P, stream: &PipelineSpec, record: &PipelineSpec) -> io::Result<()> {
+    /*
+    Writes an environment file read by both systemd (EnvironmentFile=) and bash (source).
+    Values are single-quoted so that both treat the pipeline literally.
+    */
+    let invalid = |e: PipelineError| io::Error::new(io::ErrorKind::InvalidInput, e.to_string());
+    let contents = format!(
+        "# Generated by supreme-server at startup. Do not edit, changes are overwritten.\nSTREAM_PIPELINE='{}'\nRECORD_PIPELINE='{}'\n",
+        stream.render().map_err(invalid)?,
+        record.render().map_err(invalid)?,
+    );
+    crate::standalone_filesystem::write_atomically(path, contents.as_bytes(), 0o644)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rendered launch strings, checked in under testdata/pipelines. Update them along with the pipelines.
+    fn assert_renders(spec: PipelineSpec, golden: &str) {
+        assert_eq!(spec.render().unwrap(), golden.trim_end());
+    }
+
+    #[test]
+    fn renders_mjpeg_over_tcp() {
+        assert_renders(mjpeg_over_tcp(), include_str!("../testdata/pipelines/mjpeg_over_tcp.txt"));
+    }
+
+    #[test]
+    fn renders_h264_over_tcp() {
+        assert_renders(h264_over_tcp(), include_str!("../testdata/pipelines/h264_over_tcp.txt"));
+    }
+
+    #[test]
+    fn renders_h264_over_srt() {
+        assert_renders(h264_over_srt(), include_str!("../testdata/pipelines/h264_over_srt.txt"));
+    }
+
+    #[test]
+    fn renders_splitmux_recording() {
+        assert_renders(splitmux_recording(), include_str!("../testdata/pipelines/splitmux_recording.txt"));
+    }
+
+    #[test]
+    fn renders_without_quotes() {
+        for spec in [mjpeg_over_tcp(), h264_over_tcp(), h264_over_srt(), splitmux_recording()] {
+            let launch = spec.render().unwrap();
+            assert!(!launch.contains(['"', '\'', '\\']), "{}", launch);
+        }
+    }
+
+    #[test]
+    fn standalone_script_defaults_to_recording_pipeline() {
+        // Used when pipelines.env hasn't been written yet, e.g. on first boot before supreme-server starts
+        let script = include_str!("../systemd/standalone_gstreamer.sh");
+        assert!(script.contains(&format!("DEFAULT_RECORD_PIPELINE='{}'", splitmux_recording().render().unwrap())));
+    }
+
+    #[test]
+    fn rejects_invalid_resolution_and_framerate() {
+        assert_eq!(mjpeg_over_tcp().resolution(641, 360).validate(), Err(PipelineError::InvalidResolution(641, 360)));
+        assert_eq!(mjpeg_over_tcp().resolution(0, 360).validate(), Err(PipelineError::InvalidResolution(0, 360)));
+        assert_eq!(mjpeg_over_tcp().framerate(0).validate(), Err(PipelineError::InvalidFramerate(0)));
+        assert_eq!(mjpeg_over_tcp().framerate(121).validate(), Err(PipelineError::InvalidFramerate(121)));
+    }
+
+    #[test]
+    fn rejects_invalid_encoder_settings() {
+        assert_eq!(mjpeg_over_tcp().encoder(Encoder::Jpeg { quality: 101 }).validate(), Err(PipelineError::InvalidJpegQuality(101)));
+        assert_eq!(mjpeg_over_tcp().bitrate(1_000_000).validate(), Err(PipelineError::BitrateWithoutH264));
+        let level = Encoder::H264 { level: "6", repeat_sequence_header: true };
+        assert_eq!(h264_over_tcp().encoder(level).validate(), Err(PipelineError::InvalidH264Level("6")));
+        assert_eq!(h264_over_tcp().bitrate(10_000).validate(), Err(PipelineError::InvalidBitrate(10_000)));
+    }
+
+    #[test]
+    fn rejects_incompatible_muxers_and_sinks() {
+        let jpeg = Encoder::Jpeg { quality: 30 };
+        assert_eq!(mjpeg_over_tcp().muxer(Muxer::Matroska).validate(), Err(PipelineError::IncompatibleMuxer(Muxer::Matroska, jpeg)));
+        assert!(matches!(h264_over_tcp().muxer(Muxer::MpegTs).validate(), Err(PipelineError::IncompatibleSink(_))));
+        assert!(matches!(splitmux_recording().muxer(Muxer::None).validate(), Err(PipelineError::IncompatibleSink(_))));
+        assert!(matches!(h264_over_srt().muxer(Muxer::None).validate(), Err(PipelineError::IncompatibleSink(_))));
+    }
+
+    #[test]
+    fn rejects_invalid_sink_parameters() {
+        let tcp = |host: &str, port| Sink::TcpServer { host: host.to_string(), port };
+        assert!(matches!(mjpeg_over_tcp().sink(tcp("127.0.0.1", 0)).validate(), Err(PipelineError::InvalidSinkParameter(_))));
+        assert!(matches!(mjpeg_over_tcp().sink(tcp("a b", 5000)).validate(), Err(PipelineError::InvalidSinkParameter(_))));
+
+        let splitmux = |location: &str| Sink::SplitMux { location: location.to_string(), max_size_time_ns: 1, max_files: 1, start_index: None };
+        assert!(matches!(splitmux_recording().sink(splitmux("/tmp/log.mkv")).validate(), Err(PipelineError::InvalidSinkParameter(_))));
+        assert!(matches!(splitmux_recording().sink(splitmux("/tmp/it's%04d.mkv")).validate(), Err(PipelineError::InvalidSinkParameter(_))));
+
+        let srt = |latency_ms, passphrase: Option<&str>| Sink::Srt { port: 5000, latency_ms, passphrase: passphrase.map(str::to_string) };
+        assert!(matches!(h264_over_srt().sink(srt(10, None)).validate(), Err(PipelineError::InvalidSinkParameter(_))));
+        assert!(matches!(h264_over_srt().sink(srt(200, Some("short"))).validate(), Err(PipelineError::InvalidSinkParameter(_))));
+        assert!(matches!(h264_over_srt().sink(srt(200, Some("has spaces in it"))).validate(), Err(PipelineError::InvalidSinkParameter(_))));
+        assert!(h264_over_srt().sink(srt(200, Some("long-enough-passphrase"))).validate().is_ok());
+    }
+
+    #[test]
+    fn render_validates_first() {
+        assert_eq!(mjpeg_over_tcp().framerate(0).render(), Err(PipelineError::InvalidFramerate(0)));
+    }
+}
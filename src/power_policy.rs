@@ -0,0 +1,265 @@
+/*
+Decides when to shut down for a low battery, from the fuel gauge readings of the battery thread in main.rs.
+
+Shuts down when any of these hold:
+    - the cell voltage was at or below fuel_gauge::SHUTDOWN_MILLIVOLTS for LOW_VOLTAGE_SAMPLES readings in a row,
+      since the voltage dips for a moment under load, e.g. while the encoder starts
+    - the state of charge is at or below fuel_gauge::SHUTDOWN_PERCENT, which the gauge already averages
+    - readings failed for longer than READ_FAILURE_GRACE, and the last reading was at or below RESERVE_PERCENT,
+      so the battery may have run out while it couldn't be read
+A failed reading is otherwise ignored: without a fuel gauge, e.g. on a laptop, the server never shuts down.
+
+Set POWER_POLICY_DRY_RUN=1 to only log the decision instead of shutting down, e.g. to try new thresholds on a discharging battery.
+*/
+use std::fmt;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use crate::fuel_gauge::{BatteryStats, SHUTDOWN_MILLIVOLTS, SHUTDOWN_PERCENT};
+
+const LOW_VOLTAGE_SAMPLES: u32 = 5;
+const READ_FAILURE_GRACE: Duration = Duration::from_secs(60);
+const RESERVE_PERCENT: i32 = SHUTDOWN_PERCENT + 5;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Reason {
+    LowVoltage { millivolts: i32, samples: u32 },
+    LowCharge { percent: i32 },
+    ReadFailures { failing_secs: u64, last_percent: i32 },
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::LowVoltage { millivolts, samples } =>
+                write!(f, "cell voltage at {} mV, at or below {} mV for {} readings", millivolts, SHUTDOWN_MILLIVOLTS, samples),
+            Reason::LowCharge { percent } =>
+                write!(f, "state of charge at {}%, at or below {}%", percent, SHUTDOWN_PERCENT),
+            Reason::ReadFailures { failing_secs, last_percent } =>
+                write!(f, "fuel gauge unreadable for {} s, last read at {}%", failing_secs, last_percent),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Decision {
+    Run,
+    Shutdown(Reason),
+}
+
+pub struct PowerPolicy {
+    dry_run: bool,
+    low_voltage_samples: u32,
+    last_percent: Option<i32>, // of the last successful reading
+    failing_since: Option<Instant>,
+    last_reason: Option<Reason>, // to log the decision only when it changes
+}
+
+impl PowerPolicy {
+    pub fn from_env() -> Self {
+        let dry_run = std::env::var_os("POWER_POLICY_DRY_RUN").is_some();
+        if dry_run {
+            log::warn!("Power policy dry run, the server won't shut down for a low battery");
+        }
+        PowerPolicy::new(dry_run)
+    }
+
+    fn new(dry_run: bool) -> Self {
+        PowerPolicy { dry_run, low_voltage_samples: 0, last_percent: None, failing_since: None, last_reason: None }
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn confirming(&self) -> bool {
+        // A low voltage was read but not often enough yet, so the battery thread should read again soon
+        self.low_voltage_samples > 0
+    }
+
+    pub fn evaluate(&mut self, reading: Option<&BatteryStats>, now: Instant) -> Decision {
+        // Called with every reading, None if it failed, and when it was taken. The decision is logged whenever it changes.
+        let decision = match reading {
+            Some(stats) => self.evaluate_reading(stats),
+            None => self.evaluate_failure(now),
+        };
+        let reason = match decision {
+            Decision::Shutdown(reason) => Some(reason),
+            Decision::Run => None,
+        };
+        if reason.as_ref().map(mem::discriminant) != self.last_reason.as_ref().map(mem::discriminant) {
+            match (reason, self.dry_run) {
+                (Some(reason), false) => log::warn!("Shutting down for a low battery: {}", reason),
+                (Some(reason), true) => log::warn!("Would shut down for a low battery, but this is a dry run: {}", reason),
+                (None, _) => log::info!("Battery no longer low, not shutting down"),
+            }
+            self.last_reason = reason;
+        }
+        decision
+    }
+
+    fn evaluate_reading(&mut self, stats: &BatteryStats) -> Decision {
+        self.failing_since = None;
+        self.last_percent = Some(stats.state_of_charge_percent);
+
+        // VL is raised when the gauge measured a low voltage since the last reading, even if this one isn't
+        if stats.cell_millivolts <= SHUTDOWN_MILLIVOLTS || stats.gauge.flags.voltage_low {
+            self.low_voltage_samples += 1;
+        } else {
+            self.low_voltage_samples = 0;
+        }
+
+        if stats.state_of_charge_percent <= SHUTDOWN_PERCENT || stats.gauge.flags.soc_low {
+            Decision::Shutdown(Reason::LowCharge { percent: stats.state_of_charge_percent })
+        } else if self.low_voltage_samples >= LOW_VOLTAGE_SAMPLES {
+            Decision::Shutdown(Reason::LowVoltage { millivolts: stats.cell_millivolts, samples: self.low_voltage_samples })
+        } else {
+            Decision::Run
+        }
+    }
+
+    fn evaluate_failure(&mut self, now: Instant) -> Decision {
+        // Samples must be in a row, a failed reading breaks them
+        self.low_voltage_samples = 0;
+        let failing = now.saturating_duration_since(*self.failing_since.get_or_insert(now));
+        match self.last_percent {
+            Some(last_percent) if failing > READ_FAILURE_GRACE && last_percent <= RESERVE_PERCENT =>
+                Decision::Shutdown(Reason::ReadFailures { failing_secs: failing.as_secs(), last_percent }),
+            _ => Decision::Run,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fuel_gauge::GaugeState;
+    use crate::max17048::StatusFlags;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn reading(percent: i32, millivolts: i32) -> BatteryStats {
+        BatteryStats {
+            state_of_charge_percent: percent,
+            cell_millivolts: millivolts,
+            charge_rate_percent_per_hour: -12.0,
+            gauge: GaugeState {
+                version: 0x0012,
+                alert: false,
+                alert_threshold_percent: SHUTDOWN_PERCENT as u8,
+                reset_millivolts: 2800,
+                hibernation_enabled: false,
+                hibernating: false,
+                flags: StatusFlags::default(),
+            },
+        }
+    }
+
+    fn shuts_down(decision: Decision) -> bool {
+        matches!(decision, Decision::Shutdown(_))
+    }
+
+    // Feeds the readings one second apart, None for a failed one, and returns the decisions
+    fn trace(policy: &mut PowerPolicy, readings: &[Option<BatteryStats>]) -> Vec<bool> {
+        let start = Instant::now();
+        readings.iter().enumerate()
+            .map(|(i, reading)| shuts_down(policy.evaluate(reading.as_ref(), start + SECOND * i as u32)))
+            .collect()
+    }
+
+    #[test]
+    fn load_sag_that_recovers_keeps_running() {
+        let mut policy = PowerPolicy::new(false);
+        let sag = Some(reading(60, SHUTDOWN_MILLIVOLTS - 100));
+        let mut readings = vec![sag; LOW_VOLTAGE_SAMPLES as usize - 1];
+        readings.push(Some(reading(60, 3800)));
+        readings.extend(vec![sag; LOW_VOLTAGE_SAMPLES as usize - 1]);
+        assert!(trace(&mut policy, &readings).iter().all(|&shutdown| !shutdown));
+        assert!(policy.confirming());
+    }
+
+    #[test]
+    fn sustained_low_voltage_shuts_down() {
+        let mut policy = PowerPolicy::new(false);
+        let readings = vec![Some(reading(60, SHUTDOWN_MILLIVOLTS)); LOW_VOLTAGE_SAMPLES as usize];
+        let decisions = trace(&mut policy, &readings);
+        assert_eq!(decisions.iter().position(|&shutdown| shutdown), Some(LOW_VOLTAGE_SAMPLES as usize - 1));
+        assert!(matches!(
+            policy.evaluate(readings[0].as_ref(), Instant::now()),
+            Decision::Shutdown(Reason::LowVoltage { samples, .. }) if samples == LOW_VOLTAGE_SAMPLES + 1
+        ));
+    }
+
+    #[test]
+    fn low_voltage_flag_counts_as_a_sample() {
+        let mut policy = PowerPolicy::new(false);
+        let mut flagged = reading(60, 3800);
+        flagged.gauge.flags.voltage_low = true;
+        let decisions = trace(&mut policy, &vec![Some(flagged); LOW_VOLTAGE_SAMPLES as usize]);
+        assert_eq!(decisions.last(), Some(&true));
+    }
+
+    #[test]
+    fn failed_reading_breaks_low_voltage_samples() {
+        let mut policy = PowerPolicy::new(false);
+        let sag = Some(reading(60, SHUTDOWN_MILLIVOLTS - 100));
+        let mut readings = vec![sag; LOW_VOLTAGE_SAMPLES as usize - 1];
+        readings.push(None);
+        readings.push(sag);
+        assert!(trace(&mut policy, &readings).iter().all(|&shutdown| !shutdown));
+    }
+
+    #[test]
+    fn state_of_charge_floor_shuts_down_at_once() {
+        let mut policy = PowerPolicy::new(false);
+        assert_eq!(trace(&mut policy, &[Some(reading(SHUTDOWN_PERCENT + 1, 3800)), Some(reading(SHUTDOWN_PERCENT, 3800))]), vec![false, true]);
+        assert!(matches!(
+            policy.evaluate(Some(&reading(SHUTDOWN_PERCENT - 1, 3800)), Instant::now()),
+            Decision::Shutdown(Reason::LowCharge { percent }) if percent == SHUTDOWN_PERCENT - 1
+        ));
+    }
+
+    #[test]
+    fn read_failures_at_reserve_shut_down_after_grace() {
+        let mut policy = PowerPolicy::new(false);
+        let start = Instant::now();
+        assert!(!shuts_down(policy.evaluate(Some(&reading(RESERVE_PERCENT, 3700)), start)));
+        assert!(!shuts_down(policy.evaluate(None, start + SECOND)));
+        assert!(!shuts_down(policy.evaluate(None, start + SECOND + READ_FAILURE_GRACE)));
+        assert!(matches!(
+            policy.evaluate(None, start + SECOND * 2 + READ_FAILURE_GRACE),
+            Decision::Shutdown(Reason::ReadFailures { failing_secs: 61, last_percent: RESERVE_PERCENT })
+        ));
+        // A good reading resets the grace
+        assert!(!shuts_down(policy.evaluate(Some(&reading(RESERVE_PERCENT, 3700)), start + SECOND * 100)));
+        assert!(!shuts_down(policy.evaluate(None, start + SECOND * 101)));
+    }
+
+    #[test]
+    fn read_failures_above_reserve_keep_running() {
+        let mut policy = PowerPolicy::new(false);
+        let start = Instant::now();
+        assert!(!shuts_down(policy.evaluate(Some(&reading(RESERVE_PERCENT + 1, 3700)), start)));
+        for secs in [1, 60, 600, 3600] {
+            assert!(!shuts_down(policy.evaluate(None, start + SECOND * secs)));
+        }
+    }
+
+    #[test]
+    fn read_failures_without_a_reading_keep_running() {
+        // No fuel gauge at all, e.g. on a laptop
+        let mut policy = PowerPolicy::new(false);
+        assert!(trace(&mut policy, &vec![None; 120]).iter().all(|&shutdown| !shutdown));
+    }
+
+    #[test]
+    fn dry_run_decides_the_same() {
+        let readings = [Some(reading(60, 3800)), Some(reading(SHUTDOWN_PERCENT, 3800)), Some(reading(60, 3800))];
+        // The battery thread only skips the shutdown for a dry run, so the decision and its log are the same
+        let mut dry_run = PowerPolicy::new(true);
+        let mut policy = PowerPolicy::new(false);
+        assert!(dry_run.dry_run() && !policy.dry_run());
+        assert_eq!(trace(&mut dry_run, &readings), vec![false, true, false]);
+        assert_eq!(trace(&mut policy, &readings), vec![false, true, false]);
+    }
+}
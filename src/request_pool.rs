@@ -0,0 +1,241 @@
+/*
+Answers routed requests on a fixed number of threads, so a recording being downloaded doesn't hold up everything else.
+
+Requests are queued by priority. Lightweight routes, documented with RouteDoc::priority (status, battery, mode control),
+have threads of their own, so polling /battery-percent is answered during a 70 MB download.
+Everything else shares the NORMAL_WORKERS threads, which also bounds how many recordings are sent at once.
+
+Each request must be answered within its route's timeout, RouteDoc::timeout or DEFAULT_TIMEOUT, counted from when it arrived:
+    - a request still queued when it runs out is answered with 503, the client has probably given up already
+    - a request body still arriving when it runs out is answered with 408, see router::IncomingRequest::read
+    - a handler still running when it runs out is answered with 503 right away. It runs on a thread of its own
+      for this, and the worker waits for it before taking the next request, so the pool stays bounded.
+    - a response still being sent when it runs out is cut off, so a slow client can't keep a thread for good
+A full queue is answered with 503 and Retry-After right away.
+
+The long-lived responses in main.rs (/stream.mjpeg, /events, /snapshot.jpg and HLS) keep their own threads.
+*/
+use std::io::{self, Read};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tiny_http::{Header, Request, Response};
+
+use crate::error::Result;
+use crate::router::{without_query, ApiError, HttpResponse, IncomingRequest};
+use crate::supervisor::Supervisor;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+// Names of the threads in GET /health, one per thread
+const PRIORITY_WORKERS: [&str; 2] = ["http-priority-1", "http-priority-2"];
+const NORMAL_WORKERS: [&str; 3] = ["http-1", "http-2", "http-3"];
+const QUEUE_LENGTH: usize = 16; // per priority
+/*
+Heartbeats are missed while a request is handled and answered, which ends by its route's timeout,
+unless the handler itself keeps running. A worker is only hung once a handler ran this much longer than the longest timeout.
+*/
+const HEARTBEAT_GRACE: Duration = Duration::from_secs(60);
+
+struct Job {
+    request: Request,
+    deadline: Instant,
+}
+
+pub struct RequestPool {
+    priority: SyncSender<Job>,
+    normal: SyncSender<Job>,
+}
+
+impl RequestPool {
+    pub fn start<F>(supervisor: &Supervisor, longest_timeout: Duration, handle: F) -> Result<RequestPool>
+    where
+        F: Fn(&IncomingRequest) -> HttpResponse + Send + Sync + 'static,
+    {
+        let handle = Arc::new(handle);
+        let (priority, priority_rx) = mpsc::sync_channel(QUEUE_LENGTH);
+        let (normal, normal_rx) = mpsc::sync_channel(QUEUE_LENGTH);
+        let priority_rx = Arc::new(Mutex::new(priority_rx));
+        let normal_rx = Arc::new(Mutex::new(normal_rx));
+        let workers = PRIORITY_WORKERS.iter().map(|name| (name, &priority_rx)).chain(NORMAL_WORKERS.iter().map(|name| (name, &normal_rx)));
+        for (name, jobs) in workers {
+            let jobs = jobs.clone();
+            let handle = handle.clone();
+            supervisor.spawn(name, longest_timeout + HEARTBEAT_GRACE, move |heartbeat| {
+                loop {
+                    heartbeat.beat();
+                    // The lock is only held while waiting, so the other threads take the next job as soon as this one has it
+                    let job = match jobs.lock().unwrap().recv_timeout(Duration::from_secs(1)) {
+                        Ok(job) => job,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                    };
+                    answer(job, handle.as_ref());
+                }
+            })?;
+        }
+        Ok(RequestPool { priority, normal })
+    }
+
+    pub fn submit(&self, request: Request, priority: bool, timeout: Duration) {
+        let queue = if priority { &self.priority } else { &self.normal };
+        let job = Job { request, deadline: Instant::now() + timeout };
+        match queue.try_send(job) {
+            Ok(()) => (),
+            Err(TrySendError::Full(job)) => {
+                log::warn!("Too many requests queued, rejecting {} {}", job.request.method(), without_query(job.request.url()));
+                let retry_after = Header::from_bytes(&b"Retry-After"[..], &b"1"[..]).unwrap();
+                let response = ApiError::unavailable("server_busy", "Too many requests, try again in a second").to_response().with_header(retry_after);
+                let _ = job.request.respond(response);
+            }
+            Err(TrySendError::Disconnected(job)) => {
+                let _ = job.request.respond(ApiError::unavailable("server_busy", "The server is shutting down").to_response());
+            }
+        }
+    }
+}
+
+fn answer<F: Fn(&IncomingRequest) -> HttpResponse + Sync>(mut job: Job, handle: &F) {
+    if Instant::now() > job.deadline {
+        log::warn!("{} {} timed out waiting in the queue", job.request.method(), without_query(job.request.url()));
+        let _ = job.request.respond(ApiError::unavailable("timeout", "The request waited too long to be handled").to_response());
+        return;
+    }
+    let incoming = IncomingRequest::read(&mut job.request, job.deadline);
+    let (method, path) = (incoming.method.clone(), without_query(&incoming.url).to_string());
+    if let Err(error) = &incoming.body {
+        log::warn!("{} {} answered with {}: {}", method, path, error.status, error.message);
+        let _ = job.request.respond(error.to_response());
+        return;
+    }
+    // The scope joins the handler before returning, also after a timeout
+    thread::scope(|scope| {
+        let (done, handled) = mpsc::channel();
+        let incoming = &incoming;
+        scope.spawn(move || {
+            let _ = done.send(handle(incoming));
+        });
+        let sent = match handled.recv_timeout(job.deadline.saturating_duration_since(Instant::now())) {
+            Ok(response) => job.request.respond(with_deadline(response, job.deadline)),
+            Err(RecvTimeoutError::Timeout) => {
+                log::warn!("{} {} took longer than its timeout to handle", method, path);
+                job.request.respond(ApiError::unavailable("timeout", "The request took too long to handle").to_response())
+            }
+            // The handler panicked, which the scope passes on to the supervisor
+            Err(RecvTimeoutError::Disconnected) => job.request.respond(ApiError::internal("The request failed").to_response()),
+        };
+        if let Err(e) = sent {
+            log::debug!("Failed to send the response to {} {}: {}", method, path, e);
+        }
+    });
+}
+
+fn with_deadline(response: HttpResponse, deadline: Instant) -> Response<DeadlineReader> {
+    let status = response.status_code();
+    let headers = response.headers().to_vec();
+    let length = response.data_length();
+    let body = DeadlineReader { inner: response.into_reader(), deadline };
+    Response::new(status, headers, body, length, None)
+}
+
+struct DeadlineReader {
+    inner: Box<dyn Read + Send>,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Read by tiny_http as the response is written, so this cuts off a response that takes too long to send
+        if Instant::now() > self.deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Response took longer than its timeout to send"));
+        }
+        self.inner.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    use crate::router::text_response;
+
+    fn exchange<F>(pool: &RequestPool, timeout: Duration, client: F) -> String
+    where
+        F: FnOnce(&mut TcpStream) -> String + Send + 'static,
+    {
+        // One request over a real connection, since tiny_http requests can't be made otherwise
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap();
+        let client = thread::spawn(move || client(&mut TcpStream::connect(address).unwrap()));
+        pool.submit(server.recv().unwrap(), false, timeout);
+        client.join().unwrap()
+    }
+
+    fn get(pool: &RequestPool, timeout: Duration) -> String {
+        exchange(pool, timeout, |stream| {
+            stream.write_all(b"GET /test HTTP/1.0\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        })
+    }
+
+    fn pool_taking(handling: Duration) -> RequestPool {
+        RequestPool::start(&Supervisor::new(), DEFAULT_TIMEOUT, move |_| {
+            thread::sleep(handling);
+            text_response("done", 200)
+        }).unwrap()
+    }
+
+    #[test]
+    fn answers_within_timeout() {
+        let response = get(&pool_taking(Duration::ZERO), Duration::from_secs(5));
+        assert!(response.starts_with("HTTP/1.0 200"), "{}", response);
+        assert!(response.ends_with("done"));
+    }
+
+    #[test]
+    fn slow_handler_is_answered_at_its_deadline() {
+        let started = Instant::now();
+        let response = get(&pool_taking(Duration::from_secs(2)), Duration::from_millis(200));
+        assert!(response.starts_with("HTTP/1.0 503"), "{}", response);
+        assert!(response.contains("\"timeout\""));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn oversized_body_is_rejected_unread() {
+        let response = exchange(&pool_taking(Duration::ZERO), Duration::from_secs(5), |stream| {
+            stream.write_all(b"POST /test HTTP/1.0\r\nContent-Length: 100000000\r\n\r\n").unwrap();
+            // tiny_http drains the unread body, so end it for the connection to close
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        assert!(response.starts_with("HTTP/1.0 413"), "{}", response);
+        assert!(response.contains("\"body_too_large\""));
+    }
+
+    #[test]
+    fn slow_body_is_answered_at_its_deadline() {
+        let started = Instant::now();
+        let response = exchange(&pool_taking(Duration::ZERO), Duration::from_millis(300), |stream| {
+            // Larger than tiny_http reads up front, and sent a byte at a time until the response arrives
+            stream.write_all(b"POST /test HTTP/1.0\r\nContent-Length: 2000\r\n\r\n").unwrap();
+            stream.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+            let mut response = [0; 256];
+            loop {
+                match stream.read(&mut response) {
+                    Ok(read) => return String::from_utf8_lossy(&response[..read]).into_owned(),
+                    Err(_) => stream.write_all(b"x").unwrap(),
+                }
+            }
+        });
+        assert!(response.starts_with("HTTP/1.0 408"), "{}", response);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
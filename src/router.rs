@@ -0,0 +1,411 @@
+/*
+Route table for the HTTP API, replacing the match on method and url in main.rs.
+Each route is documented as it's added, see src/openapi.rs.
+
+Patterns are matched segment by segment, and a segment in braces captures that part of the path:
+    GET /api/v1/videos/{id} matches /api/v1/videos/loop0001 with id = "loop0001"
+
+A path that matches no pattern is a 404, and a path that matches only with another method is a 405 with an Allow header.
+Routes documented with RouteDoc::requires_auth are only handed to their handler once the guard given to
+Router::authenticate_with accepts the request, see src/auth.rs.
+Errors are JSON, for example:
+    { "code": "not_found", "message": "No route for GET /api/v1/nope", "details": null }
+*/
+use std::collections::HashMap;
+use std::io;
+use std::time::Instant;
+
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, ResponseBox};
+
+use crate::openapi::RouteDoc;
+
+// Boxed so that a handler can stream a file instead of reading it into memory
+pub type HttpResponse = ResponseBox;
+pub type Handler<S> = fn(&S, &ApiRequest) -> Result<HttpResponse, ApiError>;
+pub type Guard<S> = fn(&S, &ApiRequest) -> Result<(), ApiError>;
+
+// Request bodies are small JSON objects, anything larger is answered with 413 instead of being read
+pub const MAX_BODY_BYTES: usize = 64 * 1024;
+
+pub struct IncomingRequest {
+    // Owned, so that the handler can run on another thread than the one answering the connection, see src/request_pool.rs
+    pub method: Method,
+    pub url: String,
+    pub headers: Vec<Header>,
+    pub body: Result<String, ApiError>, // the error is the response to a body that couldn't be read
+}
+
+impl IncomingRequest {
+    pub fn read(request: &mut Request, deadline: Instant) -> IncomingRequest {
+        let body = read_body(request, deadline);
+        IncomingRequest { method: request.method().clone(), url: request.url().to_string(), headers: request.headers().to_vec(), body }
+    }
+}
+
+fn read_body(request: &mut Request, deadline: Instant) -> Result<String, ApiError> {
+    /*
+    Read up to the request's deadline. A read itself blocks for at most the socket's receive timeout, see listen() in main.rs,
+    so a client that sends its body slowly or not at all doesn't keep the thread past that.
+    */
+    let too_large = || ApiError::new(413, "body_too_large", format!("Request body is larger than {} bytes", MAX_BODY_BYTES));
+    if request.body_length().is_some_and(|length| length > MAX_BODY_BYTES) {
+        return Err(too_large());
+    }
+    let reader = request.as_reader();
+    let mut body = Vec::new();
+    let mut buffer = [0; 8192];
+    loop {
+        if Instant::now() > deadline {
+            return Err(ApiError::new(408, "timeout", "The request body took too long to arrive"));
+        }
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) if body.len() + read > MAX_BODY_BYTES => return Err(too_large()),
+            Ok(read) => body.extend_from_slice(&buffer[..read]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                return Err(ApiError::new(408, "timeout", "The request body took too long to arrive"));
+            }
+            Err(e) => return Err(ApiError::bad_request("invalid_body", format!("Failed to read request body: {}", e))),
+        }
+    }
+    String::from_utf8(body).map_err(|_| ApiError::bad_request("invalid_body", "Request body is not UTF-8"))
+}
+
+pub fn without_query(url: &str) -> &str {
+    // For logging: an access_token in the query must not end up in the journal
+    url.split('?').next().unwrap_or_default()
+}
+
+pub struct ApiRequest {
+    pub params: HashMap<&'static str, String>,
+    pub query: HashMap<String, String>,
+    pub headers: Vec<Header>,
+    pub body: String,
+}
+
+impl ApiRequest {
+    pub fn param(&self, name: &str) -> &str {
+        // Only called with names from the route's own pattern, which always captured a value
+        self.params.get(name).map(String::as_str).unwrap_or_default()
+    }
+
+    pub fn header(&self, name: &'static str) -> Option<&str> {
+        self.headers.iter().find(|h| h.field.equiv(name)).map(|h| h.value.as_str())
+    }
+
+    pub fn query_parsed<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>, ApiError> {
+        match self.query.get(name) {
+            Some(value) => value.parse().map(Some).map_err(|_| {
+                ApiError::bad_request("invalid_query", format!("Invalid value for {}: {}", name, value))
+            }),
+            None => Ok(None),
+        }
+    }
+
+    pub fn json_body<T: serde::de::DeserializeOwned>(&self) -> Result<T, ApiError> {
+        serde_json::from_str(&self.body).map_err(|e| {
+            ApiError::bad_request("invalid_body", "Request body is not valid JSON for this route").with_details(json!(e.to_string()))
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: u16,
+    pub code: &'static str,
+    pub message: String,
+    pub details: Option<Value>,
+}
+
+impl ApiError {
+    pub fn new(status: u16, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError { status, code, message: message.into(), details: None }
+    }
+
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        ApiError::new(400, code, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ApiError::new(404, "not_found", message)
+    }
+
+    pub fn unavailable(code: &'static str, message: impl Into<String>) -> Self {
+        ApiError::new(503, code, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        ApiError::new(500, "internal_error", message)
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn to_response(&self) -> HttpResponse {
+        let body = json!({ "code": self.code, "message": self.message, "details": self.details });
+        json_response(&body).with_status_code(self.status)
+    }
+}
+
+pub fn json_response<T: serde::Serialize>(body: &T) -> HttpResponse {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    Response::from_string(serde_json::to_string(body).unwrap_or_default()).with_header(content_type).with_status_code(200).boxed()
+}
+
+pub fn text_response(body: impl Into<String>, status: u16) -> HttpResponse {
+    Response::from_string(body.into()).with_status_code(status).boxed()
+}
+
+pub struct Route<S> {
+    pub method: Method,
+    pub pattern: &'static str,
+    pub handler: Handler<S>,
+    pub doc: RouteDoc,
+}
+
+pub struct Router<S> {
+    routes: Vec<Route<S>>,
+    authenticate: Option<Guard<S>>,
+}
+
+impl<S> Router<S> {
+    pub fn new() -> Self {
+        Router { routes: Vec::new(), authenticate: None }
+    }
+
+    pub fn authenticate_with(mut self, guard: Guard<S>) -> Self {
+        // Checked before the handler of every route documented with RouteDoc::requires_auth
+        self.authenticate = Some(guard);
+        self
+    }
+
+    pub fn route(mut self, method: Method, pattern: &'static str, handler: Handler<S>, doc: RouteDoc) -> Self {
+        self.routes.push(Route { method, pattern, handler, doc });
+        self
+    }
+
+    pub fn routes(&self) -> &[Route<S>] {
+        &self.routes
+    }
+
+    pub fn find(&self, method: &Method, url: &str) -> Option<&Route<S>> {
+        let path = url.split('?').next().unwrap_or_default();
+        self.routes.iter().find(|route| route.method == *method && match_pattern(route.pattern, path).is_some())
+    }
+
+    pub fn handle(&self, state: &S, request: &IncomingRequest) -> HttpResponse {
+        let (path, query) = match request.url.split_once('?') {
+            Some((path, query)) => (path.to_string(), parse_query(query)),
+            None => (request.url.clone(), HashMap::new()),
+        };
+        let method = request.method.clone();
+
+        let matching: Vec<(&Route<S>, HashMap<&'static str, String>)> = self.routes.iter()
+            .filter_map(|route| match_pattern(route.pattern, &path).map(|params| (route, params)))
+            .collect();
+        let (route, params) = match matching.iter().find(|(route, _)| route.method == method) {
+            Some(found) => found,
+            None if matching.is_empty() => {
+                return ApiError::not_found(format!("No route for {} {}", method, path)).to_response();
+            }
+            None => {
+                let allowed: Vec<String> = matching.iter().map(|(route, _)| route.method.to_string()).collect();
+                let allow = Header::from_bytes(&b"Allow"[..], allowed.join(", ").as_bytes()).unwrap();
+                return ApiError::new(405, "method_not_allowed", format!("{} is not supported on {}", method, path))
+                    .with_details(json!({ "allowed": allowed }))
+                    .to_response()
+                    .with_header(allow);
+            }
+        };
+
+        let body = match &request.body {
+            Ok(body) => body.clone(),
+            Err(error) => return error.to_response(),
+        };
+        let api_request = ApiRequest { params: params.clone(), query, headers: request.headers.clone(), body };
+        if route.doc.is_auth_required() {
+            // Without a guard, protected routes stay closed rather than open
+            let authenticated = match self.authenticate {
+                Some(authenticate) => authenticate(state, &api_request),
+                None => Err(ApiError::internal("No authentication configured")),
+            };
+            if let Err(error) = authenticated {
+                // Older apps parse legacy errors as plain text
+                return match route.doc.has_text_errors() {
+                    true => text_response(error.message, error.status),
+                    false => error.to_response(),
+                };
+            }
+        }
+        (route.handler)(state, &api_request).unwrap_or_else(|error| {
+            if error.status >= 500 {
+                log::error!("{} {} failed: {}", method, path, error.message);
+            }
+            error.to_response()
+        })
+    }
+}
+
+fn match_pattern(pattern: &'static str, path: &str) -> Option<HashMap<&'static str, String>> {
+    let pattern_segments: Vec<&'static str> = pattern.trim_matches('/').split('/').collect();
+    let path_segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    if pattern_segments.len() != path_segments.len() {
+        return None;
+    }
+    let mut params = HashMap::new();
+    for (pattern_segment, path_segment) in pattern_segments.iter().zip(path_segments) {
+        match pattern_segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+            Some(name) if !path_segment.is_empty() => { params.insert(name, percent_decode(path_segment, false)); },
+            Some(_) => return None,
+            None if *pattern_segment == path_segment => (),
+            None => return None,
+        }
+    }
+    Some(params)
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key, true), percent_decode(value, true))
+        })
+        .collect()
+}
+
+fn percent_decode(text: &str, plus_is_space: bool) -> String {
+    // Malformed escapes are kept as they are
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => { decoded.push(byte); i += 3; },
+                    None => { decoded.push(b'%'); i += 1; },
+                }
+            }
+            b'+' if plus_is_space => { decoded.push(b' '); i += 1; },
+            byte => { decoded.push(byte); i += 1; },
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openapi::Body;
+
+    fn echo(_: &(), request: &ApiRequest) -> Result<HttpResponse, ApiError> {
+        Ok(json_response(&json!({ "params": request.params, "query": request.query })))
+    }
+
+    fn deny(_: &(), _: &ApiRequest) -> Result<(), ApiError> {
+        Err(ApiError::new(401, "unauthorized", "Missing token"))
+    }
+
+    fn router() -> Router<()> {
+        Router::new()
+            .route(Method::Get, "/api/v1/videos/{id}", echo, RouteDoc::new("One recording", Body::text()))
+            .route(Method::Delete, "/api/v1/videos/{id}", echo, RouteDoc::new("Delete a recording", Body::text()).requires_auth())
+            .route(Method::Put, "/blink-on", echo, RouteDoc::new("Blink the LED", Body::text()).deprecated().requires_auth())
+    }
+
+    fn send(router: &Router<()>, method: Method, url: &str) -> (u16, Vec<Header>, String) {
+        let request = IncomingRequest { method, url: url.to_string(), headers: Vec::new(), body: Ok(String::new()) };
+        let response = router.handle(&(), &request);
+        let (status, headers) = (response.status_code().0, response.headers().to_vec());
+        let mut body = String::new();
+        response.into_reader().read_to_string(&mut body).unwrap();
+        (status, headers, body)
+    }
+
+    #[test]
+    fn matches_parameters() {
+        let params = match_pattern("/api/v1/videos/{id}/file", "/api/v1/videos/loop%200001/file").unwrap();
+        assert_eq!(params["id"], "loop 0001");
+        assert!(match_pattern("/api/v1/videos/{id}", "/api/v1/videos").is_none());
+        assert!(match_pattern("/api/v1/videos/{id}", "/api/v1/videos/a/b").is_none());
+        assert!(match_pattern("/api/v1/status", "/api/v1/stat").is_none());
+    }
+
+    #[test]
+    fn ignores_trailing_slashes() {
+        assert!(match_pattern("/api/v1/status", "/api/v1/status/").is_some());
+        assert_eq!(match_pattern("/api/v1/videos/{id}", "/api/v1/videos/loop0001/").unwrap()["id"], "loop0001");
+    }
+
+    #[test]
+    fn rejects_empty_parameters() {
+        assert!(match_pattern("/api/v1/videos/{id}/file", "/api/v1/videos//file").is_none());
+    }
+
+    #[test]
+    fn parses_queries() {
+        let query = parse_query("width=640&name=a+b%2Bc&flag&&empty=");
+        assert_eq!(query["width"], "640");
+        assert_eq!(query["name"], "a b+c");
+        assert_eq!(query["flag"], "");
+        assert_eq!(query["empty"], "");
+        assert_eq!(query.len(), 4);
+    }
+
+    #[test]
+    fn keeps_malformed_escapes() {
+        assert_eq!(percent_decode("100%", false), "100%");
+        assert_eq!(percent_decode("%zz%41", false), "%zzA");
+        assert_eq!(percent_decode("a%4", false), "a%4");
+        assert_eq!(percent_decode("a+b", false), "a+b");
+        assert_eq!(percent_decode("a+b", true), "a b");
+    }
+
+    #[test]
+    fn answers_unknown_paths_with_404() {
+        let (status, _, body) = send(&router(), Method::Get, "/api/v1/nothing?x=1");
+        assert_eq!(status, 404);
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["code"], "not_found");
+    }
+
+    #[test]
+    fn answers_other_methods_with_405_and_allow() {
+        let (status, headers, body) = send(&router(), Method::Post, "/api/v1/videos/loop0001");
+        assert_eq!(status, 405);
+        let allow = headers.iter().find(|h| h.field.equiv("Allow")).unwrap();
+        assert_eq!(allow.value.as_str(), "GET, DELETE");
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["details"]["allowed"], json!(["GET", "DELETE"]));
+    }
+
+    #[test]
+    fn passes_parameters_and_query_to_the_handler() {
+        let (status, _, body) = send(&router(), Method::Get, "/api/v1/videos/loop0001?width=640");
+        assert_eq!(status, 200);
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap(), json!({ "params": { "id": "loop0001" }, "query": { "width": "640" } }));
+    }
+
+    #[test]
+    fn protected_routes_stay_closed_without_a_guard() {
+        let (status, _, body) = send(&router(), Method::Delete, "/api/v1/videos/loop0001");
+        assert_eq!(status, 500);
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["code"], "internal_error");
+        // Open routes are still answered
+        assert_eq!(send(&router(), Method::Get, "/api/v1/videos/loop0001").0, 200);
+    }
+
+    #[test]
+    fn guard_errors_follow_the_route() {
+        let router = router().authenticate_with(deny);
+        let (status, _, body) = send(&router, Method::Delete, "/api/v1/videos/loop0001");
+        assert_eq!(status, 401);
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["code"], "unauthorized");
+        // Older apps parse legacy errors as plain text
+        let (status, _, body) = send(&router, Method::Put, "/blink-on");
+        assert_eq!((status, body.as_str()), (401, "Missing token"));
+    }
+}
@@ -0,0 +1,580 @@
+/*
+Minimal RTSP server (RFC 2326) for the live H.264 stream, packetized as RTP (RFC 6184).
+
+gst-rtsp-server didn't build on Raspberry Pi OS (see VIDEO_ENCODING.md), so this is implemented here instead.
+It serves the same H.264 NAL units as the stream relay, so it only works while streaming mode runs with the H.264 codec.
+
+    rtsp://192.168.9.1:8554/live?access_token=<token>
+    rtsp://app:<token>@192.168.9.1:8554/live
+
+Every request but OPTIONS needs the token of a paired device, in the URL or as a Basic password, see src/auth.rs.
+Once a request on a connection carried it, the rest of that connection's requests don't have to.
+Supports OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN and GET_PARAMETER (keep-alive),
+with RTP over the RTSP connection (TCP interleaved) or over UDP. RTCP from clients is read and ignored.
+A connection that sends no request or RTCP for longer than the session timeout is closed.
+*/
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rearview_client::encoding::{base64, hex, random_bytes};
+
+use crate::auth::Auth;
+use crate::stream_relay::StreamRelay;
+use crate::video_settings::StreamCodec;
+
+pub const RTSP_PORT: u16 = 8554;
+
+const RTP_PAYLOAD_TYPE: u8 = 96;
+const RTP_CLOCK_RATE: u64 = 90_000;
+const MAX_RTP_PAYLOAD: usize = 1400; // stays under the Wi-Fi MTU with IP/UDP/RTP headers
+const SESSION_TIMEOUT_SECS: u64 = 60;
+// A client that stops reading holds the connection's writer, so RTP and responses give up on it after this long
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+// Clients send a request or RTCP at least once per session timeout, so a connection silent for longer is gone
+const READ_TIMEOUT: Duration = Duration::from_secs(SESSION_TIMEOUT_SECS + 10);
+
+pub fn start(relay: StreamRelay, auth: Arc<Auth>) {
+    thread::spawn(move || {
+        let listener = match TcpListener::bind(("0.0.0.0", RTSP_PORT)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!("RTSP server failed to listen on port {}: {}", RTSP_PORT, e);
+                return;
+            }
+        };
+        log::info!("RTSP server listening on port {}", RTSP_PORT);
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let (relay, auth) = (relay.clone(), auth.clone());
+                    thread::spawn(move || {
+                        let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+                        if let Err(e) = serve_connection(stream, relay, &auth) {
+                            log::info!("RTSP connection from {} ended: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => log::warn!("RTSP server failed to accept client: {}", e),
+            }
+        }
+    });
+}
+
+struct RtspRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+}
+
+impl RtspRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+enum Transport {
+    Interleaved { rtp_channel: u8 },
+    Udp { rtp_socket: UdpSocket, client_rtp: SocketAddr },
+}
+
+struct Session {
+    id: String,
+    ssrc: u32,
+    transport: Option<Transport>,
+    playing: Option<Arc<AtomicBool>>, // cleared to stop the RTP sender thread
+}
+
+fn read_request(reader: &mut BufReader<TcpStream>) -> io::Result<Option<RtspRequest>> {
+    /*
+    Reads one RTSP request. Interleaved binary frames ($, channel, 16 bit length, data),
+    which clients use for RTCP receiver reports in TCP mode, are skipped.
+    */
+    loop {
+        let first = match reader.fill_buf()? {
+            [] => return Ok(None),
+            buf => buf[0],
+        };
+        if first == b'$' {
+            let mut header = [0u8; 4];
+            reader.read_exact(&mut header)?;
+            let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+            io::copy(&mut reader.by_ref().take(length as u64), &mut io::sink())?;
+            continue;
+        }
+
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line)? == 0 {
+            return Ok(None);
+        }
+        if request_line.trim().is_empty() {
+            continue;
+        }
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let url = parts.next().unwrap_or_default().to_string();
+
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+
+        let request = RtspRequest { method, url, headers };
+        // Bodies (e.g. GET_PARAMETER with parameter names) aren't used, but must be consumed
+        let content_length = request.header("Content-Length").and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+        io::copy(&mut reader.by_ref().take(content_length), &mut io::sink())?;
+        return Ok(Some(request));
+    }
+}
+
+fn write_response(writer: &Mutex<TcpStream>, status: &str, cseq: &str, headers: &[(&str, String)], body: &str) -> io::Result<()> {
+    let mut response = format!("RTSP/1.0 {}\r\nCSeq: {}\r\nServer: supreme-server\r\n", status, cseq);
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !body.is_empty() {
+        response.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    writer.lock().unwrap().write_all(response.as_bytes())
+}
+
+fn serve_connection(stream: TcpStream, relay: StreamRelay, auth: &Auth) -> io::Result<()> {
+    let local_ip = stream.local_addr()?.ip();
+    let peer_ip = stream.peer_addr()?.ip();
+    // Shared with the clone used for writing
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let mut reader = BufReader::new(stream);
+    let mut session: Option<Session> = None;
+    let mut authenticated = false;
+
+    let result = loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => break Ok(()),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                break Err(io::Error::new(io::ErrorKind::TimedOut, format!("nothing received for {} seconds", READ_TIMEOUT.as_secs())));
+            }
+            Err(e) => break Err(e),
+        };
+        let cseq = request.header("CSeq").unwrap_or("0").to_string();
+        log::debug!("RTSP {} {}", request.method, request.url.split('?').next().unwrap_or_default());
+
+        if !authenticated && request.method != "OPTIONS" {
+            match auth.authenticate_credentials(&request.url, request.header("Authorization")) {
+                Ok(device) => {
+                    log::info!("RTSP client {} authenticated as {}", peer_ip, device.name);
+                    authenticated = true;
+                }
+                Err(error) => {
+                    log::info!("RTSP client {} rejected: {}", peer_ip, error.message);
+                    // Makes players ask for credentials, which are then sent as Basic
+                    let challenge = ("WWW-Authenticate", "Basic realm=\"Velovision Rearview\"".to_string());
+                    if let Err(e) = write_response(&writer, "401 Unauthorized", &cseq, &[challenge], "") {
+                        break Err(e);
+                    }
+                    continue;
+                }
+            }
+        }
+
+        let result = match request.method.as_str() {
+            "OPTIONS" => write_response(&writer, "200 OK", &cseq, &[("Public", "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER".to_string())], ""),
+            "DESCRIBE" => {
+                if relay.upstream_codec() != Some(StreamCodec::H264) {
+                    write_response(&writer, "503 Service Unavailable", &cseq, &[], "")
+                } else {
+                    let sdp = session_description(relay.parameter_sets(), local_ip);
+                    // Without the query, so the token isn't repeated in every control URL
+                    let base = format!("{}/", request.url.split('?').next().unwrap_or_default().trim_end_matches('/'));
+                    write_response(&writer, "200 OK", &cseq, &[("Content-Base", base), ("Content-Type", "application/sdp".to_string())], &sdp)
+                }
+            }
+            "SETUP" => {
+                let requested = request.header("Transport").unwrap_or_default().to_string();
+                let current = session.get_or_insert_with(|| Session {
+                    id: random_hex(8),
+                    ssrc: u32::from_str_radix(&random_hex(4), 16).unwrap_or(0x5EED),
+                    transport: None,
+                    playing: None,
+                });
+                match setup_transport(&requested, peer_ip) {
+                    Ok((transport, reply)) => {
+                        current.transport = Some(transport);
+                        let reply = format!("{};ssrc={:08X}", reply, current.ssrc);
+                        let session_header = format!("{};timeout={}", current.id, SESSION_TIMEOUT_SECS);
+                        write_response(&writer, "200 OK", &cseq, &[("Transport", reply), ("Session", session_header)], "")
+                    }
+                    Err(_) => write_response(&writer, "461 Unsupported Transport", &cseq, &[], ""),
+                }
+            }
+            "PLAY" => match session.as_mut() {
+                Some(current) if current.transport.is_some() || current.playing.is_some() => {
+                    if current.playing.is_none() {
+                        let sender = RtpSender::new(current.ssrc);
+                        let rtp_info = format!("url={};seq={};rtptime={}", request.url, sender.sequence, sender.timestamp_base);
+                        let response = write_response(&writer, "200 OK", &cseq, &[("Session", current.id.clone()), ("Range", "npt=0.000-".to_string()), ("RTP-Info", rtp_info)], "");
+
+                        // RTP only starts after the PLAY response, so the client is ready for interleaved data
+                        let playing = Arc::new(AtomicBool::new(true));
+                        current.playing = Some(playing.clone());
+                        let transport = current.transport.take().unwrap();
+                        let relay = relay.clone();
+                        let writer = writer.clone();
+                        let peer = peer_ip.to_string();
+                        thread::spawn(move || sender.run(relay, transport, writer, playing, peer));
+                        response
+                    } else {
+                        write_response(&writer, "200 OK", &cseq, &[("Session", current.id.clone())], "")
+                    }
+                }
+                _ => write_response(&writer, "455 Method Not Valid in This State", &cseq, &[], ""),
+            },
+            "GET_PARAMETER" => match &session {
+                Some(current) => write_response(&writer, "200 OK", &cseq, &[("Session", current.id.clone())], ""),
+                None => write_response(&writer, "200 OK", &cseq, &[], ""),
+            },
+            "TEARDOWN" => {
+                let id = session.as_ref().map(|s| s.id.clone()).unwrap_or_default();
+                if let Some(playing) = session.take().and_then(|s| s.playing) {
+                    playing.store(false, Ordering::Relaxed);
+                }
+                write_response(&writer, "200 OK", &cseq, &[("Session", id)], "")
+            }
+            _ => write_response(&writer, "501 Not Implemented", &cseq, &[], ""),
+        };
+        if let Err(e) = result {
+            break Err(e);
+        }
+    };
+
+    if let Some(playing) = session.and_then(|s| s.playing) {
+        playing.store(false, Ordering::Relaxed);
+    }
+    result
+}
+
+fn session_description(parameter_sets: Option<(Vec<u8>, Vec<u8>)>, local_ip: IpAddr) -> String {
+    /*
+    SDP for a single H.264 video track. sprop-parameter-sets lets clients configure their decoder
+    before the first IDR arrives. It is left out if no SPS/PPS has been seen yet, since they are also sent in-band.
+    */
+    let mut fmtp = "packetization-mode=1".to_string();
+    if let Some((sps, pps)) = parameter_sets {
+        // profile_idc, constraint flags and level_idc are the three bytes after the NAL header
+        if sps.len() >= 4 {
+            fmtp.push_str(&format!(";profile-level-id={:02X}{:02X}{:02X}", sps[1], sps[2], sps[3]));
+        }
+        fmtp.push_str(&format!(";sprop-parameter-sets={},{}", base64(&sps), base64(&pps)));
+    }
+    format!(
+        "v=0\r\n\
+         o=- {session} 1 IN IP4 {ip}\r\n\
+         s=Velovision Rearview\r\n\
+         c=IN IP4 0.0.0.0\r\n\
+         t=0 0\r\n\
+         a=control:*\r\n\
+         a=range:npt=0-\r\n\
+         m=video 0 RTP/AVP {pt}\r\n\
+         a=rtpmap:{pt} H264/{clock}\r\n\
+         a=fmtp:{pt} {fmtp}\r\n\
+         a=control:trackID=0\r\n",
+        session = u64::from_str_radix(&random_hex(6), 16).unwrap_or(1),
+        ip = local_ip,
+        pt = RTP_PAYLOAD_TYPE,
+        clock = RTP_CLOCK_RATE,
+        fmtp = fmtp,
+    )
+}
+
+fn setup_transport(requested: &str, peer_ip: IpAddr) -> io::Result<(Transport, String)> {
+    /*
+    Picks the transport from the client's Transport header and returns it with the reply header value.
+        RTP/AVP/TCP;unicast;interleaved=0-1   -> RTP in the RTSP connection on channel 0
+        RTP/AVP;unicast;client_port=5000-5001 -> RTP over UDP to the client's port
+    */
+    let unsupported = || io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported transport: {}", requested));
+    let option = |name: &str| {
+        requested.split(';').find_map(|part| part.trim().strip_prefix(name).and_then(|v| v.strip_prefix('=')))
+    };
+
+    if requested.contains("RTP/AVP/TCP") {
+        let (rtp_channel, rtcp_channel) = option("interleaved").and_then(parse_port_range).unwrap_or((0, 1));
+        let rtp_channel = u8::try_from(rtp_channel).map_err(|_| unsupported())?;
+        let reply = format!("RTP/AVP/TCP;unicast;interleaved={}-{}", rtp_channel, rtcp_channel);
+        return Ok((Transport::Interleaved { rtp_channel }, reply));
+    }
+
+    if requested.contains("RTP/AVP") && !requested.contains("multicast") {
+        let (client_rtp, client_rtcp) = option("client_port").and_then(parse_port_range).ok_or_else(unsupported)?;
+        let (rtp_socket, rtcp_socket) = bind_udp_pair()?;
+        let server_rtp = rtp_socket.local_addr()?.port();
+        let server_rtcp = rtcp_socket.local_addr()?.port();
+        // Receiver reports from the client are read and discarded so they don't pile up in the socket buffer
+        thread::spawn(move || {
+            let _ = rtcp_socket.set_read_timeout(Some(Duration::from_secs(SESSION_TIMEOUT_SECS * 2)));
+            let mut buf = [0u8; 1500];
+            while rtcp_socket.recv(&mut buf).is_ok() {}
+        });
+        let reply = format!("RTP/AVP;unicast;client_port={}-{};server_port={}-{}", client_rtp, client_rtcp, server_rtp, server_rtcp);
+        let client_rtp = SocketAddr::new(peer_ip, client_rtp);
+        return Ok((Transport::Udp { rtp_socket, client_rtp }, reply));
+    }
+
+    Err(unsupported())
+}
+
+fn parse_port_range(value: &str) -> Option<(u16, u16)> {
+    // "5000-5001", or "5000" meaning 5000-5001
+    let (first, second) = value.split_once('-').unwrap_or((value, ""));
+    let first: u16 = first.parse().ok()?;
+    let second: u16 = second.parse().unwrap_or(first.saturating_add(1));
+    Some((first, second))
+}
+
+fn bind_udp_pair() -> io::Result<(UdpSocket, UdpSocket)> {
+    // RTP on an even port and RTCP on the next odd port, as RFC 3550 recommends
+    for _ in 0..20 {
+        let rtp = UdpSocket::bind(("0.0.0.0", 0))?;
+        let port = rtp.local_addr()?.port();
+        if port % 2 != 0 || port == u16::MAX {
+            continue;
+        }
+        if let Ok(rtcp) = UdpSocket::bind(("0.0.0.0", port + 1)) {
+            return Ok((rtp, rtcp));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AddrInUse, "no free UDP port pair for RTP/RTCP"))
+}
+
+struct RtpSender {
+    ssrc: u32,
+    sequence: u16,
+    timestamp_base: u32,
+}
+
+impl RtpSender {
+    fn new(ssrc: u32) -> Self {
+        RtpSender {
+            ssrc,
+            sequence: u16::from_str_radix(&random_hex(2), 16).unwrap_or(0),
+            timestamp_base: u32::from_str_radix(&random_hex(4), 16).unwrap_or(0),
+        }
+    }
+
+    fn run(mut self, relay: StreamRelay, transport: Transport, writer: Arc<Mutex<TcpStream>>, playing: Arc<AtomicBool>, peer: String) {
+        /*
+        Sends every NAL unit from the relay as RTP until TEARDOWN, disconnection or a codec change.
+        All NAL units of one access unit share a timestamp. The encoder produces one slice per picture,
+        so a new access unit starts with the first NAL unit after a slice, and the marker bit is set on slices.
+        */
+        let mut subscription = relay.subscribe("rtsp", peer);
+        let started = Instant::now();
+        let mut timestamp = self.timestamp_base;
+        let mut previous_was_vcl = true;
+
+        while playing.load(Ordering::Relaxed) {
+            let packets = match subscription.next_packet(Duration::from_secs(1)) {
+                Some(packets) => packets,
+                None => continue,
+            };
+            for packet in packets {
+                if packet.codec != StreamCodec::H264 {
+                    playing.store(false, Ordering::Relaxed);
+                    break;
+                }
+                // The relay hands out NAL units with a four byte start code
+                let nal = &packet.data[4.min(packet.data.len())..];
+                let Some(&header) = nal.first() else { continue };
+                let is_vcl = (1..=5).contains(&(header & 0x1F));
+
+                if previous_was_vcl {
+                    let elapsed = started.elapsed();
+                    let ticks = elapsed.as_secs() * RTP_CLOCK_RATE + elapsed.subsec_nanos() as u64 * RTP_CLOCK_RATE / 1_000_000_000;
+                    timestamp = self.timestamp_base.wrapping_add(ticks as u32);
+                }
+                previous_was_vcl = is_vcl;
+
+                let mut sent = 0;
+                for rtp in self.packetize(nal, timestamp, is_vcl) {
+                    let result = match &transport {
+                        Transport::Interleaved { rtp_channel } => {
+                            let mut frame = vec![b'$', *rtp_channel];
+                            frame.extend_from_slice(&(rtp.len() as u16).to_be_bytes());
+                            frame.extend_from_slice(&rtp);
+                            writer.lock().unwrap().write_all(&frame)
+                        }
+                        Transport::Udp { rtp_socket, client_rtp } => rtp_socket.send_to(&rtp, client_rtp).map(|_| ()),
+                    };
+                    if result.is_err() {
+                        playing.store(false, Ordering::Relaxed);
+                        break;
+                    }
+                    sent += rtp.len();
+                }
+                subscription.record_sent(1, sent);
+            }
+        }
+    }
+
+    fn packetize(&mut self, nal: &[u8], timestamp: u32, marker: bool) -> Vec<Vec<u8>> {
+        /*
+        Single NAL unit packets when they fit, otherwise FU-A fragments (RFC 6184 section 5.8).
+        */
+        if nal.len() <= MAX_RTP_PAYLOAD {
+            return vec![self.rtp_packet(nal, timestamp, marker)];
+        }
+
+        let header = nal[0];
+        let indicator = (header & 0xE0) | 28; // FU-A
+        let chunks: Vec<&[u8]> = nal[1..].chunks(MAX_RTP_PAYLOAD - 2).collect();
+        let last = chunks.len() - 1;
+        chunks.iter().enumerate().map(|(i, chunk)| {
+            let mut fu_header = header & 0x1F;
+            if i == 0 {
+                fu_header |= 0x80; // start
+            }
+            if i == last {
+                fu_header |= 0x40; // end
+            }
+            let mut payload = Vec::with_capacity(chunk.len() + 2);
+            payload.push(indicator);
+            payload.push(fu_header);
+            payload.extend_from_slice(chunk);
+            self.rtp_packet(&payload, timestamp, marker && i == last)
+        }).collect()
+    }
+
+    fn rtp_packet(&mut self, payload: &[u8], timestamp: u32, marker: bool) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(12 + payload.len());
+        packet.push(0x80); // version 2, no padding, no extension, no CSRC
+        packet.push(if marker { 0x80 | RTP_PAYLOAD_TYPE } else { RTP_PAYLOAD_TYPE });
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(payload);
+        self.sequence = self.sequence.wrapping_add(1);
+        packet
+    }
+}
+
+fn random_hex(bytes: usize) -> String {
+    // Session IDs, SSRCs and initial sequence numbers only need to be unpredictable, not cryptographically secure
+    let buf = random_bytes(bytes).unwrap_or_else(|_| {
+        let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        (0..bytes).map(|i| (nanos >> ((i % 4) * 8)) as u8).collect()
+    });
+    hex(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: usize = 12;
+
+    #[test]
+    fn sends_small_nal_units_whole() {
+        let mut sender = RtpSender::new(0x1234_5678);
+        let sequence = sender.sequence;
+        let nal = [0x41; MAX_RTP_PAYLOAD];
+        let packets = sender.packetize(&nal, 90_000, true);
+        assert_eq!(packets.len(), 1);
+        let packet = &packets[0];
+        assert_eq!(packet[0], 0x80);
+        assert_eq!(packet[1], 0x80 | RTP_PAYLOAD_TYPE); // marker
+        assert_eq!(u16::from_be_bytes([packet[2], packet[3]]), sequence);
+        assert_eq!(&packet[4..8], &90_000u32.to_be_bytes());
+        assert_eq!(&packet[8..12], &0x1234_5678u32.to_be_bytes());
+        assert_eq!(&packet[HEADER..], &nal[..]);
+
+        let packets = sender.packetize(&[0x67, 0x42], 90_000, false);
+        assert_eq!(packets[0][1], RTP_PAYLOAD_TYPE);
+        assert_eq!(u16::from_be_bytes([packets[0][2], packets[0][3]]), sequence.wrapping_add(1));
+    }
+
+    #[test]
+    fn fragments_large_nal_units() {
+        let mut sender = RtpSender::new(1);
+        let mut nal = vec![0x65]; // nal_ref_idc 3, IDR slice
+        nal.extend((0..3000).map(|i| i as u8));
+        let packets = sender.packetize(&nal, 0, true);
+        assert_eq!(packets.len(), 3);
+
+        let mut reassembled = Vec::new();
+        for (i, packet) in packets.iter().enumerate() {
+            assert!(packet.len() <= HEADER + MAX_RTP_PAYLOAD);
+            let last = i == packets.len() - 1;
+            assert_eq!(packet[1] & 0x80 != 0, last, "marker on packet {}", i);
+            assert_eq!(packet[HEADER], 0x60 | 28); // FU indicator keeps nal_ref_idc
+            let fu_header = packet[HEADER + 1];
+            assert_eq!(fu_header & 0x80 != 0, i == 0, "start bit on packet {}", i);
+            assert_eq!(fu_header & 0x40 != 0, last, "end bit on packet {}", i);
+            assert_eq!(fu_header & 0x1F, 5);
+            reassembled.extend_from_slice(&packet[HEADER + 2..]);
+        }
+        assert_eq!(reassembled, nal[1..]);
+        let sequences: Vec<u16> = packets.iter().map(|p| u16::from_be_bytes([p[2], p[3]])).collect();
+        assert!(sequences.windows(2).all(|pair| pair[1] == pair[0].wrapping_add(1)));
+    }
+
+    #[test]
+    fn describes_parameter_sets() {
+        let sps = vec![0x67, 0x42, 0xC0, 0x1E, 0x95, 0xA0];
+        let pps = vec![0x68, 0xCE, 0x3C, 0x80];
+        let sdp = session_description(Some((sps, pps)), "192.168.9.1".parse().unwrap());
+        assert!(sdp.contains("a=fmtp:96 packetization-mode=1;profile-level-id=42C01E;sprop-parameter-sets=Z0LAHpWg,aM48gA==\r\n"), "{}", sdp);
+        assert!(sdp.contains(" IN IP4 192.168.9.1\r\n"));
+
+        let sdp = session_description(None, "192.168.9.1".parse().unwrap());
+        assert!(sdp.contains("a=fmtp:96 packetization-mode=1\r\n"), "{}", sdp);
+    }
+
+    #[test]
+    fn sets_up_interleaved_transport() {
+        let peer = "127.0.0.1".parse().unwrap();
+        let (transport, reply) = setup_transport("RTP/AVP/TCP;unicast;interleaved=2-3", peer).unwrap();
+        assert!(matches!(transport, Transport::Interleaved { rtp_channel: 2 }));
+        assert_eq!(reply, "RTP/AVP/TCP;unicast;interleaved=2-3");
+
+        let (transport, reply) = setup_transport("RTP/AVP/TCP;unicast", peer).unwrap();
+        assert!(matches!(transport, Transport::Interleaved { rtp_channel: 0 }));
+        assert_eq!(reply, "RTP/AVP/TCP;unicast;interleaved=0-1");
+
+        assert!(setup_transport("RTP/AVP/TCP;unicast;interleaved=300-301", peer).is_err());
+    }
+
+    #[test]
+    fn sets_up_udp_transport() {
+        let peer = "127.0.0.1".parse().unwrap();
+        let (transport, reply) = setup_transport("RTP/AVP;unicast;client_port=5000-5001", peer).unwrap();
+        let Transport::Udp { rtp_socket, client_rtp } = transport else { panic!("expected UDP, got {}", reply) };
+        assert_eq!(client_rtp, "127.0.0.1:5000".parse().unwrap());
+        let server_rtp = rtp_socket.local_addr().unwrap().port();
+        assert_eq!(server_rtp % 2, 0);
+        assert_eq!(reply, format!("RTP/AVP;unicast;client_port=5000-5001;server_port={}-{}", server_rtp, server_rtp + 1));
+
+        let (_, reply) = setup_transport("RTP/AVP;unicast;client_port=6000", peer).unwrap();
+        assert!(reply.starts_with("RTP/AVP;unicast;client_port=6000-6001;"), "{}", reply);
+
+        assert!(setup_transport("RTP/AVP;unicast", peer).is_err());
+        assert!(setup_transport("RTP/AVP;multicast;client_port=5000-5001", peer).is_err());
+        assert!(setup_transport("RAW/RAW/UDP;unicast", peer).is_err());
+    }
+}
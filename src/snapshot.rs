@@ -0,0 +1,214 @@
+/*
+Single-frame JPEG snapshots for GET /snapshot.jpg
+
+In streaming mode, the most recent frame from the stream relay is returned without touching the camera.
+With the H.264 codec, the latest keyframe (SPS, PPS and IDR) is decoded to JPEG.
+In standalone mode the camera belongs to the recording pipeline, so instead of interrupting it,
+a short-lived gstreamer pipeline decodes the first keyframe of the chunk currently being recorded
+(at most one chunk, i.e. one minute, old).
+
+Optional ?width=W&height=H scale the image. If only one is given, the aspect ratio is kept.
+*/
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::stream_relay::{LatestFrame, StreamRelay};
+use crate::standalone_filesystem;
+
+const MAX_LIVE_FRAME_AGE: Duration = Duration::from_secs(2);
+const MAX_LIVE_KEYFRAME_AGE: Duration = Duration::from_secs(10);
+const GSTREAMER_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Snapshot {
+    pub jpeg: Vec<u8>,
+    pub source: &'static str, // "live" or "recording"
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotSize {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl SnapshotSize {
+    pub fn from_query(query: &str) -> Result<SnapshotSize, String> {
+        let mut size = SnapshotSize::default();
+        // Other parameters, e.g. access_token or a cache buster, are ignored
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let dimension = match key {
+                "width" => &mut size.width,
+                "height" => &mut size.height,
+                _ => continue,
+            };
+            match value.parse::<u32>() {
+                Ok(v) if (16..=1920).contains(&v) && v.is_multiple_of(2) => *dimension = Some(v),
+                _ => return Err(format!("{} must be an even number between 16 and 1920", key)),
+            }
+        }
+        Ok(size)
+    }
+
+    fn is_original(&self) -> bool {
+        self.width.is_none() && self.height.is_none()
+    }
+
+    fn caps(&self) -> String {
+        // pixel-aspect-ratio=1/1 lets videoscale pick the missing dimension from the source aspect ratio
+        let mut caps = "video/x-raw,pixel-aspect-ratio=1/1".to_string();
+        if let Some(width) = self.width {
+            caps.push_str(&format!(",width={}", width));
+        }
+        if let Some(height) = self.height {
+            caps.push_str(&format!(",height={}", height));
+        }
+        caps
+    }
+}
+
+pub fn take_snapshot(relay: &StreamRelay, size: SnapshotSize) -> Result<Snapshot, String> {
+    if let Some((frame, received_at)) = relay.latest_frame() {
+        // H.264 keyframes only arrive every few seconds, so they are allowed to be older
+        match frame {
+            LatestFrame::Jpeg(jpeg) if received_at.elapsed() <= MAX_LIVE_FRAME_AGE => {
+                let jpeg = if size.is_original() {
+                    jpeg.to_vec()
+                } else {
+                    scale_jpeg(&jpeg, size)?
+                };
+                return Ok(Snapshot { jpeg, source: "live" });
+            }
+            LatestFrame::H264Keyframe(keyframe) if received_at.elapsed() <= MAX_LIVE_KEYFRAME_AGE => {
+                return Ok(Snapshot { jpeg: decode_h264_keyframe(keyframe, size)?, source: "live" });
+            }
+            _ => (),
+        }
+    }
+
+    let videos = standalone_filesystem::files_sorted_by_date(standalone_filesystem::VIDEOS_DIR)
+        .map_err(|e| format!("No live stream and failed to list recordings: {}", e))?;
+    let latest_chunk = videos.into_iter().rev()
+        .map(|(path, _)| path)
+        .find(|path| path.extension().is_some_and(|ext| ext == "mkv"))
+        .ok_or("No live stream and no recordings to take a snapshot from")?;
+
+    // jpegenc snapshot=true ends the pipeline after the first encoded frame
+    let mut args: Vec<String> = vec![
+        "-q".to_string(),
+        "filesrc".to_string(), format!("location={}", latest_chunk.display()), "!".to_string(),
+        "matroskademux".to_string(), "!".to_string(),
+        "h264parse".to_string(), "!".to_string(),
+        "decodebin".to_string(), "!".to_string(),
+        "videoconvert".to_string(), "!".to_string(),
+    ];
+    if !size.is_original() {
+        args.extend(["videoscale".to_string(), "!".to_string(), size.caps(), "!".to_string()]);
+    }
+    args.extend(["jpegenc".to_string(), "snapshot=true".to_string(), "!".to_string(), "fdsink".to_string(), "fd=1".to_string()]);
+
+    let jpeg = run_gstreamer(&args, None)?;
+    Ok(Snapshot { jpeg, source: "recording" })
+}
+
+fn scale_jpeg(jpeg: &[u8], size: SnapshotSize) -> Result<Vec<u8>, String> {
+    let args: Vec<String> = vec![
+        "-q".to_string(),
+        "fdsrc".to_string(), "fd=0".to_string(), "!".to_string(),
+        "jpegdec".to_string(), "!".to_string(),
+        "videoconvert".to_string(), "!".to_string(),
+        "videoscale".to_string(), "!".to_string(),
+        size.caps(), "!".to_string(),
+        "jpegenc".to_string(), "snapshot=true".to_string(), "!".to_string(),
+        "fdsink".to_string(), "fd=1".to_string(),
+    ];
+    run_gstreamer(&args, Some(jpeg.to_vec()))
+}
+
+fn decode_h264_keyframe(keyframe: Vec<u8>, size: SnapshotSize) -> Result<Vec<u8>, String> {
+    let mut args: Vec<String> = vec![
+        "-q".to_string(),
+        "fdsrc".to_string(), "fd=0".to_string(), "!".to_string(),
+        "h264parse".to_string(), "!".to_string(),
+        "decodebin".to_string(), "!".to_string(),
+        "videoconvert".to_string(), "!".to_string(),
+    ];
+    if !size.is_original() {
+        args.extend(["videoscale".to_string(), "!".to_string(), size.caps(), "!".to_string()]);
+    }
+    args.extend(["jpegenc".to_string(), "snapshot=true".to_string(), "!".to_string(), "fdsink".to_string(), "fd=1".to_string()]);
+    run_gstreamer(&args, Some(keyframe))
+}
+
+fn run_gstreamer(args: &[String], stdin_data: Option<Vec<u8>>) -> Result<Vec<u8>, String> {
+    /*
+    Runs gst-launch-1.0 with the given arguments and returns what it wrote to stdout.
+    Killed after GSTREAMER_TIMEOUT so that a stuck pipeline can't hold up the HTTP server.
+    */
+    let mut child = Command::new("gst-launch-1.0")
+        .args(args)
+        .stdin(if stdin_data.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Failed to start gst-launch-1.0: {}", e))?;
+
+    if let (Some(data), Some(mut stdin)) = (stdin_data, child.stdin.take()) {
+        thread::spawn(move || {
+            let _ = stdin.write_all(&data);
+            // stdin is dropped here, which sends EOS to fdsrc
+        });
+    }
+
+    let mut stdout = child.stdout.take().ok_or("Failed to capture gst-launch-1.0 output")?;
+    let reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = stdout.read_to_end(&mut output);
+        output
+    });
+
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                let output = reader.join().unwrap_or_default();
+                if !status.success() || output.is_empty() {
+                    return Err(format!("gst-launch-1.0 failed to produce a snapshot ({})", status));
+                }
+                return Ok(output);
+            }
+            Ok(None) if started.elapsed() > GSTREAMER_TIMEOUT => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err("Timed out waiting for gst-launch-1.0 to produce a snapshot".to_string());
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(50)),
+            Err(e) => return Err(format!("Failed to wait for gst-launch-1.0: {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_size() {
+        assert_eq!(SnapshotSize::from_query(""), Ok(SnapshotSize::default()));
+        assert_eq!(SnapshotSize::from_query("width=320&height=240"), Ok(SnapshotSize { width: Some(320), height: Some(240) }));
+    }
+
+    #[test]
+    fn ignores_other_parameters() {
+        assert_eq!(SnapshotSize::from_query("width=320&access_token=abc"), Ok(SnapshotSize { width: Some(320), height: None }));
+        assert_eq!(SnapshotSize::from_query("access_token=&t=1700000000"), Ok(SnapshotSize::default()));
+    }
+
+    #[test]
+    fn rejects_invalid_size() {
+        assert!(SnapshotSize::from_query("width=321").is_err());
+        assert!(SnapshotSize::from_query("height=8").is_err());
+        assert!(SnapshotSize::from_query("width=").is_err());
+    }
+}
@@ -0,0 +1,89 @@
+/*
+Status of the SRT listener run by gstreamer (srtsink) when the srt stream transport is selected.
+
+Gstreamer owns the SRT socket, so the server can only observe it from the outside.
+libsrt serves every caller through the listener's own unconnected UDP socket,
+so callers don't show up in /proc/net/udp the way TCP clients show up in netstat.
+However, srtsink only sends while a caller is connected, so the rate of outgoing UDP datagrams tells whether anyone is watching.
+That is a heuristic: Linux only counts datagrams for the whole host, not per socket,
+so any other UDP sender at a video bitrate looks like a caller too. The API schema says so.
+*/
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const SRT_PORT: u16 = 5000; // UDP, so it doesn't collide with the TCP stream port
+
+// The lowest preset sends 600 kbps, around 55 datagrams per second. DNS and mDNS stay far below this.
+const MIN_CALLER_DATAGRAMS_PER_SEC: f64 = 20.0;
+
+#[derive(Clone, Default)]
+pub struct SrtMonitor {
+    listening: Arc<AtomicBool>,
+    caller_connected: Arc<AtomicBool>,
+}
+
+impl SrtMonitor {
+    pub fn new() -> Self {
+        SrtMonitor::default()
+    }
+
+    pub fn start(&self) {
+        let monitor = self.clone();
+        thread::spawn(move || {
+            let mut previous: Option<(Instant, u64)> = None;
+            loop {
+                let listening = is_udp_port_bound(SRT_PORT);
+                let sample = udp_out_datagrams().map(|count| (Instant::now(), count));
+                let sending = match (previous, sample) {
+                    (Some((then, before)), Some((now, after))) => {
+                        let rate = after.saturating_sub(before) as f64 / now.duration_since(then).as_secs_f64().max(0.001);
+                        rate >= MIN_CALLER_DATAGRAMS_PER_SEC
+                    }
+                    _ => false,
+                };
+                previous = sample;
+
+                monitor.listening.store(listening, Ordering::Relaxed);
+                monitor.caller_connected.store(listening && sending, Ordering::Relaxed);
+                thread::sleep(Duration::from_secs(1));
+            }
+        });
+    }
+
+    pub fn listening(&self) -> bool {
+        self.listening.load(Ordering::Relaxed)
+    }
+
+    pub fn caller_connected(&self) -> bool {
+        self.caller_connected.load(Ordering::Relaxed)
+    }
+}
+
+fn is_udp_port_bound(port: u16) -> bool {
+    /*
+    Lines of /proc/net/udp look like:
+      sl  local_address rem_address   st tx_queue rx_queue ...
+       0: 00000000:1388 00000000:0000 07 00000000:00000000 ...
+    */
+    ["/proc/net/udp", "/proc/net/udp6"].iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .any(|table| table.lines().skip(1).any(|line| {
+            line.split_whitespace().nth(1)
+                .and_then(|local| local.rsplit(':').next())
+                .and_then(|hex_port| u16::from_str_radix(hex_port, 16).ok())
+                == Some(port)
+        }))
+}
+
+fn udp_out_datagrams() -> Option<u64> {
+    // /proc/net/snmp has a "Udp:" line with field names, followed by a "Udp:" line with values
+    let snmp = fs::read_to_string("/proc/net/snmp").ok()?;
+    let mut udp_lines = snmp.lines().filter(|line| line.starts_with("Udp:"));
+    let names = udp_lines.next()?;
+    let values = udp_lines.next()?;
+    let index = names.split_whitespace().position(|name| name == "OutDatagrams")?;
+    values.split_whitespace().nth(index)?.parse().ok()
+}
@@ -1,22 +1,48 @@
 use std::time::SystemTime;
 use std::path::{Path, PathBuf};
-use std::fs;
-use std::io::{self, Read, Cursor};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::process::ExitStatus;
 use std::time::Duration;
-use std::process::Command;
 use std::thread;
 use std::sync::mpsc::{Sender, Receiver};
 
 
-use tiny_http::Response;
+use tiny_http::{Response, ResponseBox};
 
-pub fn start_streaming_mode(rx: Receiver<()>, led_tx_clone: Sender<(bool, u64, u64)>) {
+use crate::error::{self, Context, Error};
+use crate::events::{Event, EventBus};
+use crate::srt::SrtMonitor;
+use crate::stream_relay::{self, StreamRelay};
+use crate::supervisor::Supervisor;
+use crate::tcp_stream_monitor::ConnectionTracker;
+
+pub const VIDEOS_DIR: &str = "/opt/velovision/standalone_videos";
+pub const STREAMING_SERVICE: &str = "velovision-camera-mjpeg-over-tcp.service"; // runs STREAM_PIPELINE, whatever its codec or transport
+pub const STANDALONE_SERVICE: &str = "velovision-standalone-mode.service";
+
+pub fn start_streaming_mode(rx: Receiver<()>, led_tx_clone: Sender<(bool, u64, u64)>, relay: StreamRelay, srt: SrtMonitor, connections: ConnectionTracker, events: EventBus, supervisor: &Supervisor) -> error::Result<()> {
     /*
     The channel accepts a unit object. When a unit object is received, streaming mode is re-started and waits for another minute for connection
+
+    RTSP and /stream.mjpeg viewers aren't connected to port 5000, so subscribers of the stream relay count as clients too,
+    and so does an SRT caller when the srt transport is selected.
+
+    A mode switch that fails is reported and the thread carries on, so the next signal or disconnection tries again.
     */
-    thread::spawn(move || {
+    let any_client_connected = move || !connections.clients(stream_relay::CLIENT_PORT).is_empty() || relay.client_count() > 0 || srt.caller_connected();
+    let reporter = supervisor.clone();
+    let switch_to = move |mode: Mode| {
+        if let Err(e) = switch_mode(mode, &led_tx_clone, &events) {
+            reporter.report("mode", &e);
+        }
+    };
+    // A mode switch waits a minute for a client, and systemctl waits up to 90 s for a service to stop
+    supervisor.spawn("mode", Duration::from_secs(300), move |heartbeat| {
         let mut client_was_connected = true;
         loop {
+            heartbeat.beat();
             match rx.try_recv() {
 
                 Ok(_signal) => {
@@ -24,42 +50,23 @@ pub fn start_streaming_mode(rx: Receiver<()>, led_tx_clone: Sender<(bool, u64, u
                     // The fact that we got a signal means client was at least try8ing to connect,
                     // so
                     client_was_connected = true;
-                    // println!("Received signal - streaming mode and waiting a minute");
-                    led_tx_clone.send((true, 1200, 100)).unwrap(); // Majority on, short off = streaming mode
-                    systemctl::disable("velovision-standalone-mode.service").unwrap(); // standalone mode does not start on boot by default
-                    systemctl::stop("velovision-standalone-mode.service").unwrap(); // ensure camera isn't being used by standalone mode
-                    // println!("Received signal - streaming mode and waiting a minute - disabled and stopped standalone mode");
-
-                    systemctl::enable("velovision-camera-mjpeg-over-tcp.service").unwrap(); // streaming service starts on boot by default
-                    systemctl::start("velovision-camera-mjpeg-over-tcp.service").unwrap();
-                    // println!("Received signal - streaming mode and waiting a minute - enabled and started streaming mode");
+                    switch_to(Mode::Streaming);
 
                     thread::sleep(Duration::from_secs(60));
                     while rx.try_recv().is_ok() {} // ignore any messages received during the minute
 
-                    if !is_client_connected("192.168.9.1", 5000) {
+                    if !any_client_connected() {
                         client_was_connected = false;
-                        // println!("Client isn't connected, switching to standalone mode");
-                        led_tx_clone.send((true, 100, 1200)).unwrap(); // Short on, majority off = standalone mode
-                        systemctl::disable("velovision-camera-mjpeg-over-tcp.service").unwrap(); // streaming service does not start on boot by default
-                        systemctl::stop("velovision-camera-mjpeg-over-tcp.service").unwrap(); // ensure camera isn't being used by streaming mode
-
-                        systemctl::enable("velovision-standalone-mode.service").unwrap(); // standalone mode starts on boot by default
-                        systemctl::start("velovision-standalone-mode.service").unwrap();
+                        switch_to(Mode::Standalone);
                     }
                 }
                 _ => {
                      // no message, revert to standalone mode unless client is connected
-                    let client_is_connected = is_client_connected("192.168.9.1",  5000);
-                    if (client_was_connected != client_is_connected) && (client_is_connected == false) {
+                    let client_is_connected = any_client_connected();
+                    if (client_was_connected != client_is_connected) && !client_is_connected {
                         client_was_connected = client_is_connected;
-                        // println!("Switching to standalone mode because of client disconnection");
-                        led_tx_clone.send((true, 100, 1200)).unwrap(); // Short on, majority off = standalone mode
-                        systemctl::disable("velovision-camera-mjpeg-over-tcp.service").unwrap(); // streaming service does not start on boot by default
-                        systemctl::stop("velovision-camera-mjpeg-over-tcp.service").unwrap(); // ensure camera isn't being used by streaming mode
-
-                        systemctl::enable("velovision-standalone-mode.service").unwrap(); // standalone mode starts on boot by default
-                        systemctl::start("velovision-standalone-mode.service").unwrap();
+                        // Switching to standalone mode because of client disconnection
+                        switch_to(Mode::Standalone);
                     }
 
                 }            
@@ -67,11 +74,133 @@ pub fn start_streaming_mode(rx: Receiver<()>, led_tx_clone: Sender<(bool, u64, u
             thread::sleep(Duration::from_millis(1000));
             // check if client is connected not too often
         }
-    });
+    })
+}
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Streaming,
+    Standalone,
+}
+
+fn switch_mode(mode: Mode, led_tx: &Sender<(bool, u64, u64)>, events: &EventBus) -> error::Result<()> {
+    /*
+    Every step is tried even when one before it failed, e.g. a failed disable mustn't keep the camera from recording.
+    Returns the first error.
+    */
+    let (name, led, from_service, to_service) = match mode {
+        Mode::Streaming => ("streaming", (true, 1200, 100), STANDALONE_SERVICE, STREAMING_SERVICE), // Majority on, short off = streaming mode
+        Mode::Standalone => ("standalone", (true, 100, 1200), STREAMING_SERVICE, STANDALONE_SERVICE), // Short on, majority off = standalone mode
+    };
+    log::info!("Switching to {} mode", name);
+    let led = led_tx.send(led).map_err(|_| Error::ChannelClosed { channel: "LED" });
+    let steps = [
+        systemctl(systemctl::disable, "disable", from_service), // the other mode does not start on boot
+        systemctl(systemctl::stop, "stop", from_service), // ensure camera isn't being used by the other mode
+        systemctl(systemctl::enable, "enable", to_service), // this mode starts on boot
+        systemctl(systemctl::start, "start", to_service),
+    ];
+    let switched: error::Result<()> = steps.into_iter().collect();
+    // The app is only told once the mode actually changed, whether or not the LED shows it
+    if switched.is_ok() {
+        events.publish(Event::ModeChanged { mode: name.to_string() });
+    }
+    led.and(switched)
+}
+
+fn systemctl(action: fn(&str) -> io::Result<ExitStatus>, verb: &str, unit: &str) -> error::Result<()> {
+    // systemctl failing is an Ok(ExitStatus) from the systemctl crate, only failing to run it is an Err
+    let context = format!("Failed to {} {}", verb, unit);
+    let status = action(unit).context(context.clone())?;
+    if !status.success() {
+        return Err(Error::Systemctl { context, status: status.code() });
+    }
+    Ok(())
+}
+
+pub fn start_storage_monitor(events: EventBus, supervisor: &Supervisor) -> error::Result<()> {
+    /*
+    Publishes an event when splitmuxsink moves on to the next chunk, which means the previous one is complete,
+    and warns when the videos partition runs low on space.
+    */
+    const LOW_STORAGE_PERCENT: u64 = 10;
+    const STORAGE_RECOVERED_PERCENT: u64 = 15;
+
+    let mut newest_chunk: Option<PathBuf> = None;
+    let mut warned = false;
+    supervisor.spawn("storage", Duration::from_secs(60), move |heartbeat| {
+        loop {
+            heartbeat.beat();
+            let chunk = files_sorted_by_date(VIDEOS_DIR).ok()
+                .and_then(|files| files.into_iter().rev().map(|(path, _)| path).find(|path| path.extension().is_some_and(|ext| ext == "mkv")));
+            if let (Some(previous), Some(current)) = (&newest_chunk, &chunk) {
+                if previous != current {
+                    if let Ok(metadata) = fs::metadata(previous) {
+                        events.publish(Event::RecordingChunkClosed { path: previous.display().to_string(), size_bytes: metadata.len() });
+                    }
+                }
+            }
+            newest_chunk = chunk;
+
+            if let Some((free_bytes, total_bytes)) = disk_space(VIDEOS_DIR) {
+                let free_percent = free_bytes * 100 / total_bytes.max(1);
+                if free_percent < LOW_STORAGE_PERCENT && !warned {
+                    events.publish(Event::StorageWarning { free_bytes, total_bytes });
+                    warned = true;
+                } else if free_percent >= STORAGE_RECOVERED_PERCENT {
+                    warned = false;
+                }
+            }
+            thread::sleep(Duration::from_secs(5));
+        }
+    })
+}
+
+pub fn disk_space<P: AsRef<Path>>(path: P) -> Option<(u64, u64)> {
+    // Bytes available to unprivileged users, and the size of the filesystem containing path
+    let path = std::ffi::CString::new(path.as_ref().to_str()?).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let fragment_size = stat.f_frsize as u64;
+    Some((stat.f_bavail as u64 * fragment_size, stat.f_blocks as u64 * fragment_size))
+}
+
+pub fn recording_path(id: &str) -> Result<PathBuf, (u16, String)> {
+    // Only plain file names, so that the id can't point outside the videos directory
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err((400, format!("Invalid video id: {}", id)));
+    }
+    let path = PathBuf::from(VIDEOS_DIR).join(format!("{}.mkv", id));
+    if !path.is_file() {
+        return Err((404, format!("No recording named {}", id)));
+    }
+    Ok(path)
+}
+
+pub fn write_atomically<P: AsRef<Path>>(path: P, contents: &[u8], mode: u32) -> io::Result<()> {
+    /*
+    Written to a temporary file next to path and renamed over it, so a power cut leaves either the old or the new file, never half of one.
+    Settings, paired devices and the TLS key are all saved this way.
+    */
+    let path = path.as_ref();
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(mode).open(&temporary)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)?;
+    // The rename itself is only durable once the directory is synced
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => Ok(()),
+    }
 }
 
 pub fn format_system_time_to_string(st: SystemTime) -> String {
-    let duration_since_epoch = st.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+    // Times before 1970 only come from a wrong clock, and are shown as 1970
+    let duration_since_epoch = st.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
     let secs_since_epoch = duration_since_epoch.as_secs();
 
     let year = 1970 + (secs_since_epoch / (60 * 60 * 24 * 365));
@@ -103,48 +232,29 @@ pub fn files_sorted_by_date<P: AsRef<Path>>(path: P) -> io::Result<Vec<(PathBuf,
     Ok(entries)
 }
 
-pub fn yield_video_file(post_content: String) -> Response<Cursor<Vec<u8>>> {
-    // validate that path in post_content exists
-    let path = Path::new(&post_content);
-    if !path.exists() {
-        Response::from_string("Path does not exist").with_status_code(400);
-    }
+pub fn yield_video_file(post_content: String) -> ResponseBox {
+    // validate that path in post_content exists, and resolves to a file in the videos directory rather than anywhere on the filesystem
+    let path = match (fs::canonicalize(post_content.trim()), fs::canonicalize(VIDEOS_DIR)) {
+        (Ok(path), Ok(videos_dir)) if path.starts_with(&videos_dir) => path,
+        (Err(_), _) => return Response::from_string("Path does not exist").with_status_code(400).boxed(),
+        _ => return Response::from_string("Path is not in the videos directory").with_status_code(400).boxed(),
+    };
 
     // validate that path is .mkv video file
-    let extension = path.extension().unwrap();
-    if extension != "mkv" {
-        return Response::from_string("Path is not a .mkv video file").with_status_code(400);
+    if path.extension().is_none_or(|extension| extension != "mkv") {
+        return Response::from_string("Path is not a .mkv video file").with_status_code(400).boxed();
     }
 
-    let mut file = fs::File::open(path).unwrap();
-    let mut contents = Vec::new();
-    file.read_to_end(&mut contents).unwrap();
+    // Streamed from the file as tiny_http sends it, rather than read into memory
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            log::error!("Failed to read {}: {}", path.display(), e);
+            return Response::from_string("Failed to read video file").with_status_code(500).boxed();
+        }
+    };
     let header = tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"video/x-matroska"[..]).unwrap();
 
-    Response::from_data(contents).with_header(header).with_status_code(200)
-
-}
-
-pub fn is_client_connected(ip: &str, port: u16) -> bool {
-    /* 
-    Run the netstat command to retrieve active connections
-
-    Used to detect if a client is connected to the video stream port at 5000,
-    and if not, standalone mode is started.
-    */
-    let output = Command::new("netstat")
-        .arg("-an")
-        .output()
-        .expect("Failed to run netstat command");
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
-
-    // Search for lines that represent established connections to the specified port
-    for line in stdout.lines() {
-        if line.contains(ip) && line.contains(&format!(":{} ", port)) && line.contains("ESTABLISHED") {
-            return true;
-        }
-    }
+    Response::from_file(file).with_header(header).with_status_code(200).boxed()
 
-    false
 }
@@ -0,0 +1,125 @@
+/*
+Everything the app shows on its status screen in one JSON document, for GET /status.
+
+Each section is read independently. A section that can't be read reports {"error": "..."}
+in place of its values, so a missing fuel gauge doesn't hide the stream health and vice versa.
+*/
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rearview_client::types::{BatteryStatus as BatteryReport, Chunk, CpuTemp, Recording, Status, Storage, Uptime, Worker};
+
+use crate::fuel_gauge::BatteryStatus;
+use crate::srt::SrtMonitor;
+use crate::standalone_filesystem::{self, STANDALONE_SERVICE, STREAMING_SERVICE, VIDEOS_DIR};
+use crate::stream_health;
+use crate::stream_relay::StreamRelay;
+use crate::video_settings::VideoSettings;
+
+// The fuel gauge is read at least every fuel_gauge::IDLE_POLL_INTERVAL, so an older reading means the I2C bus stopped answering
+const BATTERY_STALE_AFTER: Duration = Duration::from_secs(30);
+
+pub struct StatusSources<'a> {
+    pub battery: &'a Mutex<BatteryStatus>,
+    pub cpu_temp_path: &'a str,
+    pub relay: &'a StreamRelay,
+    pub srt: &'a SrtMonitor,
+    pub video_settings: &'a VideoSettings,
+    pub started: Instant,
+    pub workers: Vec<Worker>, // see src/supervisor.rs
+}
+
+pub fn report(sources: &StatusSources) -> Status {
+    Status {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        battery: battery(sources.battery).into(),
+        cpu_temp: cpu_temp(sources.cpu_temp_path).into(),
+        mode: mode().to_string(),
+        stream: stream_health::check(sources.relay, sources.srt, sources.video_settings),
+        recording: recording().into(),
+        storage: storage().into(),
+        uptime: uptime(sources.started).into(),
+        workers: sources.workers.clone(),
+    }
+}
+
+fn battery(status: &Mutex<BatteryStatus>) -> Result<BatteryReport, String> {
+    let status = status.lock().unwrap();
+    let (stats, read_at) = match (&status.reading, &status.error) {
+        (Some(reading), _) => *reading,
+        (None, Some(error)) => return Err(error.clone()),
+        (None, None) => return Err("The fuel gauge hasn't been read yet".to_string()),
+    };
+    // The last good reading is still reported when a later read failed, marked as not valid
+    let age = read_at.elapsed();
+    Ok(BatteryReport {
+        percent: stats.state_of_charge_percent,
+        millivolts: stats.cell_millivolts,
+        charge_rate_percent_per_hour: (stats.charge_rate_percent_per_hour * 10.0).round() / 10.0,
+        charging: stats.charge_rate_percent_per_hour > 0.0,
+        valid: status.error.is_none() && age < BATTERY_STALE_AFTER,
+        age_secs: age.as_secs(),
+        last_error: status.error.clone(),
+    })
+}
+
+pub fn cpu_temp(path: &str) -> Result<CpuTemp, String> {
+    let millidegrees = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let millidegrees: i32 = millidegrees.trim().parse().map_err(|_| format!("Failed to parse {}", path))?;
+    Ok(CpuTemp { celsius: (millidegrees as f32 / 100.0).round() / 10.0 })
+}
+
+fn mode() -> &'static str {
+    // systemctl isn't available in development, which reports idle
+    if systemctl::is_active(STREAMING_SERVICE).unwrap_or(false) {
+        "streaming"
+    } else if systemctl::is_active(STANDALONE_SERVICE).unwrap_or(false) {
+        "standalone"
+    } else {
+        "idle"
+    }
+}
+
+fn recording() -> Result<Recording, String> {
+    let chunks: Vec<_> = standalone_filesystem::files_sorted_by_date(VIDEOS_DIR)
+        .map_err(|e| format!("Failed to list {}: {}", VIDEOS_DIR, e))?
+        .into_iter()
+        .filter(|(path, _)| path.extension().is_some_and(|ext| ext == "mkv"))
+        .collect();
+    let latest_chunk = chunks.last().map(|(path, modified)| Chunk {
+        path: path.display().to_string(),
+        size_bytes: fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+        date_updated: standalone_filesystem::format_system_time_to_string(*modified),
+    });
+    Ok(Recording {
+        recording: systemctl::is_active(STANDALONE_SERVICE).unwrap_or(false),
+        chunk_count: chunks.len(),
+        latest_chunk,
+    })
+}
+
+fn storage() -> Result<Storage, String> {
+    let (free_bytes, total_bytes) = standalone_filesystem::disk_space(VIDEOS_DIR)
+        .ok_or_else(|| format!("Failed to read the filesystem of {}", VIDEOS_DIR))?;
+    let videos_bytes: u64 = standalone_filesystem::files_sorted_by_date(VIDEOS_DIR)
+        .map(|files| files.iter().filter_map(|(path, _)| fs::metadata(path).ok()).map(|m| m.len()).sum())
+        .unwrap_or(0);
+    Ok(Storage {
+        free_bytes,
+        total_bytes,
+        free_percent: free_bytes * 100 / total_bytes.max(1),
+        videos_bytes,
+    })
+}
+
+fn uptime(started: Instant) -> Result<Uptime, String> {
+    // /proc/uptime is "<seconds since boot> <idle seconds>"
+    let system_secs = fs::read_to_string("/proc/uptime").ok()
+        .and_then(|uptime| uptime.split_whitespace().next()?.parse::<f64>().ok())
+        .ok_or_else(|| "Failed to read /proc/uptime".to_string())?;
+    Ok(Uptime {
+        system_secs: system_secs as u64,
+        server_secs: started.elapsed().as_secs(),
+    })
+}
@@ -0,0 +1,122 @@
+/*
+Health of the live camera stream, for GET /camera-stream-status.
+
+Accepting a TCP connection on the pipeline's port only proves that gstreamer started.
+A frozen camera keeps the port open, and the probe connection itself counted as a client.
+Instead, health is judged from the frames the stream relay actually receives,
+together with the state of the streaming service and its gst-launch-1.0 process.
+
+With the srt transport, gstreamer serves callers directly and no frames pass through the relay,
+so only the service and the SRT listener can be checked.
+*/
+use std::fs;
+use std::process::Command;
+use std::time::Duration;
+
+use rearview_client::types::{HealthStatus, PipelineState, StreamHealth};
+
+use crate::srt::SrtMonitor;
+use crate::standalone_filesystem;
+use crate::stream_relay::StreamRelay;
+use crate::video_settings::{StreamTransport, VideoSettings};
+
+const STALLED_AFTER: Duration = Duration::from_secs(2);
+const DEGRADED_BELOW: f64 = 0.5; // of the expected frame rate
+
+pub fn check(relay: &StreamRelay, srt: &SrtMonitor, settings: &VideoSettings) -> StreamHealth {
+    let pipeline = pipeline_state();
+    let expected = settings.stream_framerate();
+
+    // The running pipeline may still use the previous transport until streaming mode restarts
+    let transport = if relay.upstream_connected() {
+        StreamTransport::Tcp
+    } else if srt.listening() {
+        StreamTransport::Srt
+    } else {
+        settings.transport
+    };
+
+    let mut health = StreamHealth {
+        status: HealthStatus::Stopped,
+        reason: String::new(),
+        transport: transport.name().to_string(),
+        codec: relay.upstream_codec().map(|c| c.name().to_string()),
+        frames_per_second: None,
+        expected_frames_per_second: expected,
+        last_frame_age_ms: None,
+        frames_received: relay.frames_received(),
+        pipeline,
+    };
+
+    if !health.pipeline.active {
+        health.reason = format!("{} is not running", health.pipeline.service);
+        return health;
+    }
+    if health.pipeline.process_state.as_deref().is_some_and(|state| state.starts_with('Z') || state.starts_with('T')) {
+        health.status = HealthStatus::Stalled;
+        health.reason = format!("gst-launch-1.0 is {}", health.pipeline.process_state.as_deref().unwrap_or_default());
+        return health;
+    }
+
+    match transport {
+        StreamTransport::Srt => {
+            if srt.listening() {
+                health.status = HealthStatus::Healthy;
+                health.reason = "SRT listener is up, frames can't be observed".to_string();
+            } else {
+                health.status = HealthStatus::Stalled;
+                health.reason = "Pipeline is running but the SRT listener is not up".to_string();
+            }
+        }
+        StreamTransport::Tcp => {
+            let frames_per_second = relay.frame_rate();
+            let last_frame_age = relay.last_frame_age();
+            health.frames_per_second = Some((frames_per_second * 10.0).round() / 10.0);
+            health.last_frame_age_ms = last_frame_age.map(|age| age.as_millis() as u64);
+
+            if !relay.upstream_connected() {
+                health.status = HealthStatus::Stalled;
+                health.reason = "Pipeline is running but not serving a stream".to_string();
+            } else if last_frame_age.is_none_or(|age| age > STALLED_AFTER) {
+                health.status = HealthStatus::Stalled;
+                health.reason = format!("No frame in the last {} seconds", STALLED_AFTER.as_secs());
+            } else if frames_per_second < expected as f64 * DEGRADED_BELOW {
+                health.status = HealthStatus::Degraded;
+                health.reason = format!("{:.1} frames per second, expected {}", frames_per_second, expected);
+            } else {
+                health.status = HealthStatus::Healthy;
+                health.reason = "Frames are flowing".to_string();
+            }
+        }
+    }
+    health
+}
+
+fn pipeline_state() -> PipelineState {
+    // systemctl isn't available in development, which reports the pipeline as not running
+    let service = standalone_filesystem::STREAMING_SERVICE;
+    let active = systemctl::is_active(service).unwrap_or(false);
+    let main_pid = Command::new("systemctl")
+        .args(["show", "--property=MainPID", "--value", service])
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8_lossy(&output.stdout).trim().parse::<u32>().ok())
+        .filter(|&pid| pid != 0);
+    let process_state = main_pid.and_then(process_state);
+    PipelineState { service: service.to_string(), active, main_pid, process_state }
+}
+
+fn process_state(pid: u32) -> Option<String> {
+    // /proc/<pid>/stat is "pid (comm) state ...", and comm may contain spaces, so split after the last ')'
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let state = stat.rsplit_once(')')?.1.split_whitespace().next()?;
+    let description = match state {
+        "R" => "running",
+        "S" => "sleeping",
+        "D" => "disk sleep",
+        "T" | "t" => "stopped",
+        "Z" => "zombie",
+        _ => "other",
+    };
+    Some(format!("{} ({})", state, description))
+}
@@ -0,0 +1,840 @@
+/*
+Native stream relay with multi-client fan-out.
+
+The streaming gstreamer pipeline serves either multipart MJPEG or H.264 byte-stream on a loopback-only port.
+This module reads that stream, splits it into packets and serves them on the client-facing port,
+so the server knows exactly who is connected instead of inferring it from netstat.
+
+    gstreamer (127.0.0.1:5001) -> upstream thread -> per-client queues -> client threads (0.0.0.0:5000)
+
+The codec is detected from the first bytes of the upstream stream, so the relay always matches
+whatever pipeline is running, even while a newly selected codec waits for the next pipeline restart.
+
+MJPEG: every packet is a JPEG frame. When a slow client falls behind, its oldest frame is dropped
+so that it always sees the most recent picture and never holds back the other clients.
+
+A TCP client first sends the token of a paired device as a line, see src/auth.rs, and is disconnected without it:
+    Authorization: Bearer <token>\r\n
+An HTTP client such as URLSession in MJPEGView.swift may send a request instead, with the token in its Authorization
+header or access_token query parameter. Its headers are read up to the blank line, and it's answered with an HTTP/1.0
+response head (401 without a valid token) before the same byte stream.
+
+H.264: every packet is a NAL unit. A client only starts receiving at an IDR frame, and the relay sends
+the latest SPS and PPS right before it, so late joiners can decode immediately.
+Dropping a NAL unit would corrupt every frame up to the next IDR, so a client that falls behind
+has its queue cleared and resynchronizes at the next IDR instead.
+*/
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_derive::Serialize;
+
+use crate::auth::Auth;
+use crate::h264::{self, AnnexBParser, StreamReport, StreamValidator};
+use crate::video_settings::StreamCodec;
+
+pub const UPSTREAM_PORT: u16 = 5001; // gstreamer tcpserversink, loopback only
+pub const CLIENT_PORT: u16 = 5000; // what the iPhone app connects to
+
+// Same boundary as gstreamer's multipartmux, so existing clients see an identical byte stream
+pub const BOUNDARY: &str = "ThisRandomString";
+
+const MJPEG_QUEUE_PACKETS: usize = 2; // frames
+const H264_QUEUE_PACKETS: usize = 256; // NAL units, a few seconds of video
+const MAX_UPSTREAM_BUFFER: usize = 8 * 1024 * 1024;
+const FRAME_RATE_WINDOW: Duration = Duration::from_secs(5);
+const TOKEN_LINE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_TOKEN_LINE: usize = 256;
+const MAX_REQUEST_HEAD: usize = 4096; // of an HTTP client's request line and headers
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientStats {
+    pub id: u64,
+    pub kind: &'static str,
+    pub peer: String,
+    pub connected_secs: u64,
+    pub frames_sent: u64,
+    pub frames_dropped: u64,
+    pub bytes_sent: u64,
+}
+
+#[derive(Clone)]
+pub struct Packet {
+    pub codec: StreamCodec,
+    pub data: Arc<Vec<u8>>, // a JPEG image, or an H.264 NAL unit with start code
+    pub keyframe: bool, // every JPEG, or an IDR slice
+}
+
+pub enum LatestFrame {
+    Jpeg(Arc<Vec<u8>>),
+    H264Keyframe(Vec<u8>), // SPS, PPS and IDR slice as a decodable byte stream
+}
+
+struct ClientSlot {
+    id: u64,
+    kind: &'static str,
+    peer: String,
+    connected_at: Instant,
+    queue: Mutex<VecDeque<Packet>>,
+    ready: Condvar,
+    resync: AtomicBool,
+    frames_sent: AtomicU64,
+    frames_dropped: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+#[derive(Default)]
+struct ParameterSets {
+    sps: Option<Arc<Vec<u8>>>,
+    pps: Option<Arc<Vec<u8>>>,
+}
+
+struct Shared {
+    clients: Mutex<Vec<Arc<ClientSlot>>>,
+    upstream_codec: Mutex<Option<StreamCodec>>,
+    latest_frame: Mutex<Option<(LatestFrame, Instant)>>,
+    parameter_sets: Mutex<ParameterSets>,
+    h264_report: Mutex<Option<StreamReport>>,
+    frames_received: AtomicU64,
+    frame_times: Mutex<VecDeque<Instant>>, // arrival of each frame within FRAME_RATE_WINDOW
+    upstream_connected: AtomicBool,
+    next_client_id: AtomicU64,
+}
+
+#[derive(Clone)]
+pub struct StreamRelay {
+    shared: Arc<Shared>,
+}
+
+pub struct Subscription {
+    slot: Arc<ClientSlot>,
+    shared: Arc<Shared>,
+    synced: bool,
+}
+
+impl Subscription {
+    pub fn next_packet(&mut self, timeout: Duration) -> Option<Vec<Packet>> {
+        /*
+        Blocks until a packet is queued for this client, or returns None after the timeout.
+        Returns the packets to write, which is empty while an H.264 client waits for the next IDR,
+        and includes the cached SPS and PPS ahead of the IDR it starts at.
+        */
+        let packet = {
+            let queue = self.slot.queue.lock().unwrap();
+            let (mut queue, _) = self.slot.ready.wait_timeout_while(queue, timeout, |q| q.is_empty()).unwrap();
+            queue.pop_front()?
+        };
+
+        if self.slot.resync.swap(false, Ordering::Relaxed) {
+            self.synced = false;
+        }
+        if packet.codec == StreamCodec::Mjpeg {
+            return Some(vec![packet]);
+        }
+        if self.synced {
+            return Some(vec![packet]);
+        }
+        if !packet.keyframe {
+            return Some(Vec::new());
+        }
+
+        let parameter_sets = self.shared.parameter_sets.lock().unwrap();
+        match (&parameter_sets.sps, &parameter_sets.pps) {
+            (Some(sps), Some(pps)) => {
+                self.synced = true;
+                let parameter_set = |data: &Arc<Vec<u8>>| Packet { codec: StreamCodec::H264, data: data.clone(), keyframe: false };
+                Some(vec![parameter_set(sps), parameter_set(pps), packet])
+            }
+            _ => Some(Vec::new()), // IDR before any SPS/PPS was seen, wait for the next one
+        }
+    }
+
+    pub fn record_sent(&self, frames: u64, bytes: usize) {
+        self.slot.frames_sent.fetch_add(frames, Ordering::Relaxed);
+        self.slot.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut clients = self.shared.clients.lock().unwrap();
+        clients.retain(|c| c.id != self.slot.id);
+    }
+}
+
+impl Default for StreamRelay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamRelay {
+    pub fn new() -> Self {
+        StreamRelay {
+            shared: Arc::new(Shared {
+                clients: Mutex::new(Vec::new()),
+                upstream_codec: Mutex::new(None),
+                latest_frame: Mutex::new(None),
+                parameter_sets: Mutex::new(ParameterSets::default()),
+                h264_report: Mutex::new(None),
+                frames_received: AtomicU64::new(0),
+                frame_times: Mutex::new(VecDeque::new()),
+                upstream_connected: AtomicBool::new(false),
+                next_client_id: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn start(&self, auth: Arc<Auth>) {
+        let relay = self.clone();
+        thread::spawn(move || relay.run_upstream());
+
+        let relay = self.clone();
+        thread::spawn(move || relay.run_listener(auth));
+    }
+
+    pub fn subscribe(&self, kind: &'static str, peer: String) -> Subscription {
+        let slot = Arc::new(ClientSlot {
+            id: self.shared.next_client_id.fetch_add(1, Ordering::Relaxed),
+            kind,
+            peer,
+            connected_at: Instant::now(),
+            queue: Mutex::new(VecDeque::new()),
+            ready: Condvar::new(),
+            resync: AtomicBool::new(false),
+            frames_sent: AtomicU64::new(0),
+            frames_dropped: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+        });
+        self.shared.clients.lock().unwrap().push(slot.clone());
+        Subscription { slot, shared: self.shared.clone(), synced: false }
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.shared.clients.lock().unwrap().len()
+    }
+
+    pub fn client_stats(&self) -> Vec<ClientStats> {
+        self.shared.clients.lock().unwrap().iter().map(|c| ClientStats {
+            id: c.id,
+            kind: c.kind,
+            peer: c.peer.clone(),
+            connected_secs: c.connected_at.elapsed().as_secs(),
+            frames_sent: c.frames_sent.load(Ordering::Relaxed),
+            frames_dropped: c.frames_dropped.load(Ordering::Relaxed),
+            bytes_sent: c.bytes_sent.load(Ordering::Relaxed),
+        }).collect()
+    }
+
+    pub fn upstream_connected(&self) -> bool {
+        self.shared.upstream_connected.load(Ordering::Relaxed)
+    }
+
+    pub fn upstream_codec(&self) -> Option<StreamCodec> {
+        *self.shared.upstream_codec.lock().unwrap()
+    }
+
+    pub fn frames_received(&self) -> u64 {
+        self.shared.frames_received.load(Ordering::Relaxed)
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        // Frames per second over the last FRAME_RATE_WINDOW, counting JPEG images or H.264 slices
+        let frame_times = self.shared.frame_times.lock().unwrap();
+        let recent = frame_times.iter().filter(|received_at| received_at.elapsed() <= FRAME_RATE_WINDOW).count();
+        recent as f64 / FRAME_RATE_WINDOW.as_secs_f64()
+    }
+
+    pub fn last_frame_age(&self) -> Option<Duration> {
+        self.shared.frame_times.lock().unwrap().back().map(|received_at| received_at.elapsed())
+    }
+
+    fn record_frame(&self) {
+        self.shared.frames_received.fetch_add(1, Ordering::Relaxed);
+        let mut frame_times = self.shared.frame_times.lock().unwrap();
+        frame_times.push_back(Instant::now());
+        prune_frame_times(&mut frame_times);
+    }
+
+    pub fn parameter_sets(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        // Latest SPS and PPS without start codes, e.g. for RTSP sprop-parameter-sets
+        let parameter_sets = self.shared.parameter_sets.lock().unwrap();
+        match (&parameter_sets.sps, &parameter_sets.pps) {
+            (Some(sps), Some(pps)) => Some((sps[4..].to_vec(), pps[4..].to_vec())),
+            _ => None,
+        }
+    }
+
+    pub fn h264_report(&self) -> Option<StreamReport> {
+        self.shared.h264_report.lock().unwrap().clone()
+    }
+
+    pub fn latest_frame(&self) -> Option<(LatestFrame, Instant)> {
+        let latest = self.shared.latest_frame.lock().unwrap();
+        latest.as_ref().map(|(frame, received_at)| {
+            let frame = match frame {
+                LatestFrame::Jpeg(jpeg) => LatestFrame::Jpeg(jpeg.clone()),
+                LatestFrame::H264Keyframe(bytes) => LatestFrame::H264Keyframe(bytes.clone()),
+            };
+            (frame, *received_at)
+        })
+    }
+
+    fn publish(&self, packet: Packet) {
+        for client in self.shared.clients.lock().unwrap().iter() {
+            let mut queue = client.queue.lock().unwrap();
+            match packet.codec {
+                StreamCodec::Mjpeg if queue.len() >= MJPEG_QUEUE_PACKETS => {
+                    queue.pop_front();
+                    client.frames_dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                StreamCodec::H264 if queue.len() >= H264_QUEUE_PACKETS => {
+                    client.frames_dropped.fetch_add(queue.len() as u64, Ordering::Relaxed);
+                    queue.clear();
+                    client.resync.store(true, Ordering::Relaxed);
+                }
+                _ => (),
+            }
+            queue.push_back(packet.clone());
+            client.ready.notify_one();
+        }
+    }
+
+    fn run_upstream(&self) {
+        /*
+        The pipeline only runs in streaming mode, so failing to connect is normal. Keep retrying.
+        */
+        loop {
+            match TcpStream::connect(("127.0.0.1", UPSTREAM_PORT)) {
+                Ok(stream) => {
+                    log::info!("Stream relay connected to gstreamer on port {}", UPSTREAM_PORT);
+                    self.shared.upstream_connected.store(true, Ordering::Relaxed);
+                    if let Err(e) = self.relay_upstream(stream) {
+                        log::warn!("Stream relay lost gstreamer stream: {}", e);
+                    }
+                    self.shared.upstream_connected.store(false, Ordering::Relaxed);
+                    *self.shared.upstream_codec.lock().unwrap() = None;
+                }
+                Err(_) => thread::sleep(Duration::from_millis(1000)),
+            }
+        }
+    }
+
+    fn relay_upstream(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        let mut chunk = [0u8; 64 * 1024];
+
+        // Multipart MJPEG starts with the "--" of its boundary, H.264 byte-stream with a 00 00 01 start code
+        let mut head = Vec::new();
+        let codec = loop {
+            let n = stream.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(());
+            }
+            head.extend_from_slice(&chunk[..n]);
+            let first = head.iter().position(|&b| !b.is_ascii_whitespace()).unwrap_or(head.len());
+            match head.get(first) {
+                Some(b'-') => break StreamCodec::Mjpeg,
+                Some(0) => break StreamCodec::H264,
+                Some(_) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "unrecognized stream format")),
+                None => continue,
+            }
+        };
+        log::info!("Stream relay detected {} stream", codec.name());
+        *self.shared.upstream_codec.lock().unwrap() = Some(codec);
+
+        match codec {
+            StreamCodec::Mjpeg => {
+                let mut parser = MultipartParser::new();
+                parser.push(&head);
+                loop {
+                    while let Some(jpeg) = parser.next_frame() {
+                        let jpeg = Arc::new(jpeg);
+                        *self.shared.latest_frame.lock().unwrap() = Some((LatestFrame::Jpeg(jpeg.clone()), Instant::now()));
+                        self.record_frame();
+                        self.publish(Packet { codec, data: jpeg, keyframe: true });
+                    }
+                    let n = stream.read(&mut chunk)?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    parser.push(&chunk[..n]);
+                }
+            }
+            StreamCodec::H264 => {
+                let mut parser = AnnexBParser::new();
+                let mut validator = StreamValidator::new();
+                *self.shared.parameter_sets.lock().unwrap() = ParameterSets::default();
+                parser.push(&head);
+                loop {
+                    while let Some(nal) = parser.next_nal() {
+                        if let Some(violation) = validator.inspect(&nal) {
+                            log::warn!("Live H.264 stream violation: {:?}", violation);
+                        }
+                        let data = Arc::new(nal.to_annex_b());
+                        match nal.nal_type {
+                            h264::NAL_SPS => self.shared.parameter_sets.lock().unwrap().sps = Some(data.clone()),
+                            h264::NAL_PPS => self.shared.parameter_sets.lock().unwrap().pps = Some(data.clone()),
+                            h264::NAL_IDR => {
+                                let parameter_sets = self.shared.parameter_sets.lock().unwrap();
+                                if let (Some(sps), Some(pps)) = (&parameter_sets.sps, &parameter_sets.pps) {
+                                    let keyframe = [sps.as_slice(), pps.as_slice(), data.as_slice()].concat();
+                                    *self.shared.latest_frame.lock().unwrap() = Some((LatestFrame::H264Keyframe(keyframe), Instant::now()));
+                                }
+                            }
+                            _ => (),
+                        }
+                        if nal.is_vcl() {
+                            self.record_frame();
+                        }
+                        self.publish(Packet { codec, data, keyframe: nal.nal_type == h264::NAL_IDR });
+                    }
+                    *self.shared.h264_report.lock().unwrap() = Some(validator.report());
+
+                    let n = stream.read(&mut chunk)?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    parser.push(&chunk[..n]);
+                }
+            }
+        }
+    }
+
+    fn run_listener(&self, auth: Arc<Auth>) {
+        let listener = match TcpListener::bind(("0.0.0.0", CLIENT_PORT)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!("Stream relay failed to listen on port {}: {}", CLIENT_PORT, e);
+                return;
+            }
+        };
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let (relay, auth) = (self.clone(), auth.clone());
+                    thread::spawn(move || relay.serve_tcp_client(stream, &auth));
+                }
+                Err(e) => log::warn!("Stream relay failed to accept client: {}", e),
+            }
+        }
+    }
+
+    fn serve_tcp_client(&self, mut stream: TcpStream, auth: &Auth) {
+        let peer = stream.peer_addr().map(|a: SocketAddr| a.to_string()).unwrap_or_default();
+        let credentials = stream.set_read_timeout(Some(TOKEN_LINE_TIMEOUT))
+            .and_then(|_| read_credentials(&stream))
+            .and_then(|credentials| stream.set_read_timeout(None).map(|_| credentials));
+        let (url, authorization) = match credentials {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                log::info!("Stream client {} sent no token: {}", peer, e);
+                return;
+            }
+        };
+        let http = !url.is_empty();
+        if let Err(error) = auth.authenticate_credentials(&url, authorization.as_deref()) {
+            log::info!("Stream client {} rejected: {}", peer, error.message);
+            if http {
+                let _ = stream.write_all(b"HTTP/1.0 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\nContent-Length: 0\r\n\r\n");
+            }
+            return;
+        }
+        log::info!("Stream client connected: {}", peer);
+        let _ = stream.set_nodelay(true);
+        let _ = stream.set_write_timeout(Some(Duration::from_secs(5)));
+
+        let mut subscription = self.subscribe("tcp", peer.clone());
+        let mut client_codec = None;
+        loop {
+            // Wake up periodically so that a client of a stalled pipeline is still noticed when it disconnects
+            let packets = match subscription.next_packet(Duration::from_secs(1)) {
+                Some(packets) => packets,
+                None => {
+                    if is_closed(&stream) {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            if packets.is_empty() {
+                continue;
+            }
+
+            // The pipeline restarted with a different codec. Hang up so the client reconnects with the right decoder.
+            let codec = packets[0].codec;
+            let first_packets = client_codec.is_none();
+            if *client_codec.get_or_insert(codec) != codec {
+                break;
+            }
+
+            let mut out = Vec::new();
+            if http && first_packets {
+                out.extend_from_slice(response_head(codec).as_bytes());
+            }
+            for packet in &packets {
+                match packet.codec {
+                    StreamCodec::Mjpeg => out.extend_from_slice(&multipart_part(&packet.data)),
+                    StreamCodec::H264 => out.extend_from_slice(&packet.data),
+                }
+            }
+            if stream.write_all(&out).is_err() {
+                break;
+            }
+            subscription.record_sent(packets.len() as u64, out.len());
+        }
+        log::info!("Stream client disconnected: {}", peer);
+    }
+
+    pub fn serve_http_client(&self, request: tiny_http::Request) {
+        /*
+        GET /stream.mjpeg on the main HTTP server, viewable in browsers, VLC and standard iOS/Android components.
+
+        The response never ends, so it is written directly to the socket instead of going through
+        tiny_http's Response, which would buffer parts of a frame until the next one arrives.
+        Ends if no frame arrives for STALLED_HTTP_STREAM, e.g. because the device switched to standalone mode,
+        or if the pipeline restarts with the H.264 codec.
+        */
+        const STALLED_HTTP_STREAM: Duration = Duration::from_secs(30);
+
+        if self.upstream_codec() == Some(StreamCodec::H264) {
+            let _ = request.respond(tiny_http::Response::from_string("Stream codec is H.264, MJPEG is not available").with_status_code(409));
+            return;
+        }
+
+        let peer = request.remote_addr().map(|a| a.to_string()).unwrap_or_default();
+        log::info!("MJPEG HTTP client connected: {}", peer);
+        let mut subscription = self.subscribe("http", peer.clone());
+
+        let mut writer = request.into_writer();
+        let header = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={}\r\nCache-Control: no-cache, no-store\r\nPragma: no-cache\r\nConnection: close\r\n\r\n",
+            BOUNDARY
+        );
+        if writer.write_all(header.as_bytes()).and_then(|_| writer.flush()).is_err() {
+            return;
+        }
+
+        let mut last_frame_at = Instant::now();
+        'stream: loop {
+            let packets = match subscription.next_packet(Duration::from_secs(1)) {
+                Some(packets) => packets,
+                None if last_frame_at.elapsed() > STALLED_HTTP_STREAM => break,
+                None => continue,
+            };
+            last_frame_at = Instant::now();
+            for packet in packets {
+                if packet.codec != StreamCodec::Mjpeg {
+                    break 'stream;
+                }
+                let part = multipart_part(&packet.data);
+                if writer.write_all(&part).and_then(|_| writer.flush()).is_err() {
+                    break 'stream;
+                }
+                subscription.record_sent(1, part.len());
+            }
+        }
+        log::info!("MJPEG HTTP client disconnected: {}", peer);
+    }
+}
+
+fn read_credentials<R: Read>(mut reader: R) -> io::Result<(String, Option<String>)> {
+    /*
+    Either the token line, or an HTTP request whose headers are read up to the blank line.
+    Returns the request's url, empty for a token line, and the Authorization value.
+    */
+    let first = read_line(&mut reader, MAX_TOKEN_LINE)?;
+    if let Some(value) = header_value(&first, "Authorization") {
+        return Ok((String::new(), Some(value)));
+    }
+    let url = match first.split(' ').collect::<Vec<&str>>()[..] {
+        [_, url, version] if version.starts_with("HTTP/") => url.to_string(),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Expected an Authorization line or an HTTP request")),
+    };
+    let mut authorization = None;
+    let mut remaining = MAX_REQUEST_HEAD.saturating_sub(first.len());
+    loop {
+        let line = read_line(&mut reader, remaining)?;
+        if line.is_empty() {
+            return Ok((url, authorization));
+        }
+        remaining = remaining.saturating_sub(line.len() + 2);
+        if let Some(value) = header_value(&line, "Authorization") {
+            authorization = Some(value);
+        }
+    }
+}
+
+fn read_line<R: Read>(reader: &mut R, max: usize) -> io::Result<String> {
+    // Read a byte at a time, so nothing after the line is consumed. Clients send nothing else anyway.
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while line.len() < max {
+        if reader.read(&mut byte)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Disconnected"));
+        }
+        if byte[0] == b'\n' {
+            return String::from_utf8(line).map(|line| line.trim_end().to_string())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Line is not UTF-8"));
+        }
+        line.push(byte[0]);
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "Line too long"))
+}
+
+fn response_head(codec: StreamCodec) -> String {
+    // Sent once the codec is known from the first packets
+    let content_type = match codec {
+        StreamCodec::Mjpeg => format!("multipart/x-mixed-replace; boundary={}", BOUNDARY),
+        StreamCodec::H264 => "video/h264".to_string(),
+    };
+    format!("HTTP/1.0 200 OK\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n", content_type)
+}
+
+fn header_value(line: &str, name: &str) -> Option<String> {
+    let (field, value) = line.split_once(':')?;
+    field.trim().eq_ignore_ascii_case(name).then(|| value.trim().to_string())
+}
+
+fn is_closed(stream: &TcpStream) -> bool {
+    // Clients never send anything, so a readable socket with zero bytes means the peer hung up
+    let mut probe = [0u8; 1];
+    let _ = stream.set_nonblocking(true);
+    let closed = matches!(stream.peek(&mut probe), Ok(0));
+    let _ = stream.set_nonblocking(false);
+    closed
+}
+
+fn prune_frame_times(frame_times: &mut VecDeque<Instant>) {
+    // The newest frame is always kept for last_frame_age
+    while frame_times.len() > 1 && frame_times.front().is_some_and(|t| t.elapsed() > FRAME_RATE_WINDOW) {
+        frame_times.pop_front();
+    }
+}
+
+pub fn multipart_part(jpeg: &[u8]) -> Vec<u8> {
+    let mut part = format!(
+        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        BOUNDARY,
+        jpeg.len()
+    ).into_bytes();
+    part.extend_from_slice(jpeg);
+    part.extend_from_slice(b"\r\n");
+    part
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+struct MultipartParser {
+    buf: Vec<u8>,
+    delimiter: Vec<u8>, // "--ThisRandomString", the only thing that separates parts
+}
+
+impl MultipartParser {
+    fn new() -> Self {
+        MultipartParser { buf: Vec::new(), delimiter: format!("--{}", BOUNDARY).into_bytes() }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() > MAX_UPSTREAM_BUFFER {
+            // Never found a frame boundary in 8 MB. Start over rather than growing forever.
+            log::warn!("Stream relay discarding {} bytes without a frame boundary", self.buf.len());
+            self.buf.clear();
+        }
+    }
+
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        /*
+        Each part from multipartmux looks like:
+            --ThisRandomString\r\n
+            Content-Type: image/jpeg\r\n
+            Content-Length: 12345\r\n
+            \r\n
+            <12345 bytes of JPEG>\r\n
+        Content-Length is used when present, otherwise the part ends at the next "\r\n--ThisRandomString".
+        JPEG data can contain "--" or "\r\n--", so only the full delimiter separates parts.
+        Parts that don't start with a JPEG SOI marker, or that could never fit the buffer, are skipped.
+        */
+        loop {
+            let Some(boundary_start) = find(&self.buf, &self.delimiter) else {
+                // Keep what may be the start of a delimiter cut off by the end of the read
+                let keep = self.buf.len().min(self.delimiter.len() - 1);
+                self.buf.drain(..self.buf.len() - keep);
+                return None;
+            };
+            self.buf.drain(..boundary_start);
+            let header_end = find(&self.buf, b"\r\n\r\n")?;
+            let headers = String::from_utf8_lossy(&self.buf[..header_end]).to_string();
+            let body_start = header_end + 4;
+
+            let content_length = headers.lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, value)| value.trim().parse::<usize>().ok());
+
+            let body = match content_length {
+                Some(length) if body_start + length > MAX_UPSTREAM_BUFFER => {
+                    log::warn!("Stream relay skipping a {} byte frame", length);
+                    self.buf.drain(..body_start);
+                    continue;
+                }
+                Some(length) => {
+                    if self.buf.len() < body_start + length {
+                        return None;
+                    }
+                    let body = self.buf[body_start..body_start + length].to_vec();
+                    self.buf.drain(..body_start + length);
+                    body
+                }
+                None => {
+                    let mut end_marker = b"\r\n".to_vec();
+                    end_marker.extend_from_slice(&self.delimiter);
+                    let body_end = body_start + find(&self.buf[body_start..], &end_marker)?;
+                    let body = self.buf[body_start..body_end].to_vec();
+                    self.buf.drain(..body_end + 2);
+                    body
+                }
+            };
+
+            if body.starts_with(&[0xFF, 0xD8]) {
+                return Some(body);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_token_line() {
+        let (url, authorization) = read_credentials(&b"Authorization: Bearer abc\r\n--more"[..]).unwrap();
+        assert_eq!(url, "");
+        assert_eq!(authorization.as_deref(), Some("Bearer abc"));
+    }
+
+    #[test]
+    fn reads_http_request_head() {
+        let request = b"GET /?access_token=abc HTTP/1.1\r\nHost: 192.168.9.1:5000\r\nauthorization: Bearer def\r\n\r\n";
+        let (url, authorization) = read_credentials(&request[..]).unwrap();
+        assert_eq!(url, "/?access_token=abc");
+        assert_eq!(authorization.as_deref(), Some("Bearer def"));
+
+        let (_, authorization) = read_credentials(&b"GET / HTTP/1.1\r\nHost: camera\r\n\r\n"[..]).unwrap();
+        assert_eq!(authorization, None);
+    }
+
+    #[test]
+    fn rejects_other_first_lines() {
+        assert!(read_credentials(&b"hello\r\n"[..]).is_err());
+        assert!(read_credentials(&b"Authorization: Bearer abc"[..]).is_err()); // disconnected before the line ended
+        assert!(read_credentials(&[b'a'; MAX_TOKEN_LINE + 1][..]).is_err());
+        let mut endless = b"GET / HTTP/1.1\r\n".to_vec();
+        endless.extend(b"X-Padding: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n".repeat(MAX_REQUEST_HEAD / 16));
+        assert!(read_credentials(&endless[..]).is_err());
+    }
+
+    fn jpeg(fill: &[u8], len: usize) -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend(fill.iter().cycle().take(len - 4));
+        jpeg.extend([0xFF, 0xD9]);
+        jpeg
+    }
+
+    fn part_without_length(jpeg: &[u8]) -> Vec<u8> {
+        let mut part = format!("--{}\r\nContent-Type: image/jpeg\r\n\r\n", BOUNDARY).into_bytes();
+        part.extend_from_slice(jpeg);
+        part.extend_from_slice(b"\r\n");
+        part
+    }
+
+    fn frames(parser: &mut MultipartParser) -> Vec<Vec<u8>> {
+        std::iter::from_fn(|| parser.next_frame()).collect()
+    }
+
+    #[test]
+    fn parses_parts_read_in_pieces() {
+        let (first, second) = (jpeg(b"first", 300), jpeg(b"second", 200));
+        let mut stream = multipart_part(&first);
+        stream.extend(multipart_part(&second));
+        for piece in [1, 7, 64, stream.len()] {
+            let mut parser = MultipartParser::new();
+            let mut parsed = Vec::new();
+            for chunk in stream.chunks(piece) {
+                parser.push(chunk);
+                parsed.extend(frames(&mut parser));
+            }
+            assert_eq!(parsed, vec![first.clone(), second.clone()], "read {} bytes at a time", piece);
+        }
+    }
+
+    #[test]
+    fn finds_boundaries_across_reads() {
+        // Split at every byte of the second part's delimiter, after some bytes to skip
+        let (first, second) = (jpeg(b"a", 100), jpeg(b"b", 100));
+        let mut stream = b"garbage before the first part".to_vec();
+        stream.extend(multipart_part(&first));
+        let second_at = stream.len();
+        stream.extend(multipart_part(&second));
+        for split in second_at..second_at + BOUNDARY.len() + 4 {
+            let mut parser = MultipartParser::new();
+            parser.push(&stream[..split]);
+            let mut parsed = frames(&mut parser);
+            parser.push(&stream[split..]);
+            parsed.extend(frames(&mut parser));
+            assert_eq!(parsed, vec![first.clone(), second.clone()], "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn parses_parts_without_content_length() {
+        // Dashes and line breaks inside the JPEG don't end the part
+        let first = jpeg(b"\r\n--\r\n--This", 200);
+        let second = jpeg(b"x", 50);
+        let mut stream = part_without_length(&first);
+        stream.extend(part_without_length(&second));
+        stream.extend(format!("--{}\r\n", BOUNDARY).as_bytes());
+        let mut parser = MultipartParser::new();
+        for chunk in stream.chunks(5) {
+            parser.push(chunk);
+        }
+        assert_eq!(frames(&mut parser), vec![first, second]);
+    }
+
+    #[test]
+    fn skips_parts_that_are_not_jpeg() {
+        let frame = jpeg(b"c", 20);
+        let mut stream = multipart_part(b"not a jpeg");
+        stream.extend(multipart_part(&frame));
+        let mut parser = MultipartParser::new();
+        parser.push(&stream);
+        assert_eq!(frames(&mut parser), vec![frame]);
+    }
+
+    #[test]
+    fn skips_oversized_frames() {
+        let frame = jpeg(b"d", 20);
+        let mut stream = format!("--{}\r\nContent-Length: {}\r\n\r\n", BOUNDARY, MAX_UPSTREAM_BUFFER).into_bytes();
+        stream.extend(jpeg(b"e", 1000));
+        stream.extend(multipart_part(&frame));
+        let mut parser = MultipartParser::new();
+        parser.push(&stream);
+        assert_eq!(frames(&mut parser), vec![frame]);
+    }
+
+    #[test]
+    fn drops_bytes_without_a_boundary() {
+        let mut parser = MultipartParser::new();
+        parser.push(&[0x42; 10_000]);
+        assert!(parser.next_frame().is_none());
+        assert!(parser.buf.len() < BOUNDARY.len() + 2);
+    }
+}
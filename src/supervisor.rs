@@ -0,0 +1,242 @@
+/*
+Worker threads that are started again when they fail, instead of dying silently.
+
+A worker is a function that runs until it returns. It is started again after it returns an error or panics,
+on the same thread and with the same captured state, so a worker that owns a channel receiver keeps it:
+    supervisor.spawn("led", Duration::from_secs(120), move |heartbeat| {
+        let pin = Gpio::new().context("Failed to open GPIO")?;
+        loop {
+            heartbeat.beat();
+            ...
+        }
+    })?;
+Returning Ok(()) stops the worker for good. That's how a worker finishes, e.g. once what it served went away,
+so a stopped worker doesn't make the server unhealthy. Only failing and hung workers do.
+
+Restarts back off from 1 s to a minute while a worker keeps failing, and start over at 1 s once it ran for a minute.
+A worker that doesn't beat within its heartbeat timeout is hung. A thread can't be stopped from outside, so a hung worker
+isn't restarted here: the systemd watchdog stops being notified and restarts the whole server, see src/watchdog.rs.
+
+Each worker's state and last error is listed in GET /health and GET /api/v1/status.
+*/
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rearview_client::types::{Health, ServerHealth, Worker, WorkerState};
+
+use crate::error::{Context, Error, Result};
+
+const INITIAL_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+// A worker that ran this long before failing is restarted after INITIAL_RESTART_DELAY again
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+struct Entry {
+    name: &'static str,
+    state: WorkerState,
+    restarts: u32,
+    restart_at: Option<Instant>,
+    last_heartbeat: Instant,
+    heartbeat_timeout: Duration,
+    last_error: Option<String>,
+}
+
+impl Entry {
+    fn worker(&self) -> Worker {
+        let hung = self.state == WorkerState::Running && self.last_heartbeat.elapsed() > self.heartbeat_timeout;
+        Worker {
+            name: self.name.to_string(),
+            state: if hung { WorkerState::Hung } else { self.state },
+            restarts: self.restarts,
+            restart_in_secs: self.restart_at.map(|at| at.saturating_duration_since(Instant::now()).as_secs()),
+            last_heartbeat_age_ms: self.last_heartbeat.elapsed().as_millis() as u64,
+            heartbeat_timeout_secs: self.heartbeat_timeout.as_secs(),
+            last_error: self.last_error.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Supervisor {
+    workers: Arc<Mutex<Vec<Entry>>>,
+}
+
+#[derive(Clone)]
+pub struct Heartbeat {
+    supervisor: Supervisor,
+    name: &'static str,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        self.supervisor.update(self.name, |entry| entry.last_heartbeat = Instant::now());
+    }
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Supervisor::default()
+    }
+
+    pub fn spawn<F>(&self, name: &'static str, heartbeat_timeout: Duration, mut work: F) -> Result<()>
+    where
+        F: FnMut(&Heartbeat) -> Result<()> + Send + 'static,
+    {
+        let heartbeat = self.register(name, heartbeat_timeout);
+        let supervisor = self.clone();
+        thread::Builder::new().name(name.to_string()).spawn(move || {
+            let mut delay = INITIAL_RESTART_DELAY;
+            loop {
+                let started = Instant::now();
+                let error = match panic::catch_unwind(AssertUnwindSafe(|| work(&heartbeat))) {
+                    Ok(Ok(())) => {
+                        log::info!("Worker {} stopped", name);
+                        supervisor.update(name, |entry| entry.state = WorkerState::Stopped);
+                        return;
+                    }
+                    Ok(Err(error)) => error.to_string(),
+                    Err(panic) => format!("Panicked: {}", panic_message(&panic)),
+                };
+                if started.elapsed() >= STABLE_AFTER {
+                    delay = INITIAL_RESTART_DELAY;
+                }
+                log::error!("Worker {} failed, restarting in {} s: {}", name, delay.as_secs(), error);
+                supervisor.update(name, |entry| {
+                    entry.state = WorkerState::Restarting;
+                    entry.restarts += 1;
+                    entry.restart_at = Some(Instant::now() + delay);
+                    entry.last_error = Some(error);
+                });
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_RESTART_DELAY);
+                supervisor.update(name, |entry| {
+                    entry.state = WorkerState::Running;
+                    entry.restart_at = None;
+                    entry.last_heartbeat = Instant::now();
+                });
+            }
+        }).context(format!("Failed to start the {} worker", name))?;
+        Ok(())
+    }
+
+    pub fn register(&self, name: &'static str, heartbeat_timeout: Duration) -> Heartbeat {
+        // For a thread that isn't spawned here but should beat all the same, e.g. the loop answering requests in main.rs
+        self.workers.lock().unwrap().push(Entry {
+            name,
+            state: WorkerState::Running,
+            restarts: 0,
+            restart_at: None,
+            last_heartbeat: Instant::now(),
+            heartbeat_timeout,
+            last_error: None,
+        });
+        Heartbeat { supervisor: self.clone(), name }
+    }
+
+    pub fn report(&self, name: &str, error: &Error) {
+        // For errors a worker recovers from by itself, e.g. a mode switch that failed but is tried again later
+        log::error!("{}", error);
+        self.update(name, |entry| entry.last_error = Some(error.to_string()));
+    }
+
+    pub fn workers(&self) -> Vec<Worker> {
+        self.workers.lock().unwrap().iter().map(Entry::worker).collect()
+    }
+
+    pub fn health(&self, watchdog: Option<Duration>) -> Health {
+        let workers = self.workers();
+        let status = if workers.iter().any(|worker| worker.state == WorkerState::Hung) {
+            ServerHealth::Unhealthy
+        } else if workers.iter().any(|worker| worker.state == WorkerState::Restarting) {
+            ServerHealth::Degraded
+        } else {
+            ServerHealth::Healthy
+        };
+        Health { status, watchdog_secs: watchdog.map(|timeout| timeout.as_secs()), workers }
+    }
+
+    pub fn hung_workers(&self) -> Vec<&'static str> {
+        self.workers.lock().unwrap().iter()
+            .filter(|entry| entry.worker().state == WorkerState::Hung)
+            .map(|entry| entry.name)
+            .collect()
+    }
+
+    fn update(&self, name: &str, change: impl FnOnce(&mut Entry)) {
+        if let Some(entry) = self.workers.lock().unwrap().iter_mut().find(|entry| entry.name == name) {
+            change(entry);
+        }
+    }
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> String {
+    panic.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_of(supervisor: &Supervisor, name: &str) -> WorkerState {
+        supervisor.workers().into_iter().find(|worker| worker.name == name).unwrap().state
+    }
+
+    fn wait_for(supervisor: &Supervisor, name: &str, state: WorkerState) {
+        let started = Instant::now();
+        while state_of(supervisor, name) != state {
+            assert!(started.elapsed() < Duration::from_secs(5), "{} never became {:?}", name, state);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn finished_worker_is_healthy() {
+        let supervisor = Supervisor::new();
+        supervisor.spawn("finishes", Duration::from_secs(60), |_| Ok(())).unwrap();
+        wait_for(&supervisor, "finishes", WorkerState::Stopped);
+        assert_eq!(supervisor.health(None).status, ServerHealth::Healthy);
+    }
+
+    #[test]
+    fn failed_worker_is_degraded() {
+        let supervisor = Supervisor::new();
+        supervisor.spawn("fails", Duration::from_secs(60), |_| Err(Error::ChannelClosed { channel: "test" })).unwrap();
+        wait_for(&supervisor, "fails", WorkerState::Restarting);
+        let health = supervisor.health(None);
+        assert_eq!(health.status, ServerHealth::Degraded);
+        assert!(health.workers[0].last_error.is_some());
+    }
+
+    #[test]
+    fn panicking_worker_is_restarted() {
+        let supervisor = Supervisor::new();
+        let mut runs = 0;
+        supervisor.spawn("panics", Duration::from_secs(60), move |_| {
+            runs += 1;
+            if runs == 1 {
+                panic!("first run");
+            }
+            Ok(())
+        }).unwrap();
+        wait_for(&supervisor, "panics", WorkerState::Restarting);
+        wait_for(&supervisor, "panics", WorkerState::Stopped);
+        assert_eq!(supervisor.workers()[0].restarts, 1);
+    }
+
+    #[test]
+    fn worker_without_heartbeat_is_hung() {
+        let supervisor = Supervisor::new();
+        let heartbeat = supervisor.register("silent", Duration::ZERO);
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(supervisor.health(None).status, ServerHealth::Unhealthy);
+        assert_eq!(supervisor.hung_workers(), vec!["silent"]);
+        heartbeat.beat();
+        supervisor.update("silent", |entry| entry.heartbeat_timeout = Duration::from_secs(60));
+        assert_eq!(supervisor.health(None).status, ServerHealth::Healthy);
+    }
+}
@@ -1,8 +1,266 @@
-use std::net::TcpStream;
+/*
+Tracks TCP connections by reading the kernel's socket tables, replacing `netstat -an`,
+which isn't installed on minimal images and was matched by substring.
 
-// check that gstreamer is up and publishing a TCP stream
-pub fn check_tcp_service(port: u16) -> bool {
-    let addr = format!("localhost:{}", port);
-    TcpStream::connect(addr).is_ok()
+/proc/net/tcp doesn't record when a connection was established,
+so a connection's duration is counted from the first poll that saw it.
+*/
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_derive::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum TcpState {
+    Established,
+    SynSent,
+    SynRecv,
+    FinWait1,
+    FinWait2,
+    TimeWait,
+    Close,
+    CloseWait,
+    LastAck,
+    Listen,
+    Closing,
+    NewSynRecv,
+    Unknown,
+}
+
+impl TcpState {
+    fn from_hex(code: &str) -> TcpState {
+        // include/net/tcp_states.h
+        match u8::from_str_radix(code, 16).unwrap_or(0) {
+            0x01 => TcpState::Established,
+            0x02 => TcpState::SynSent,
+            0x03 => TcpState::SynRecv,
+            0x04 => TcpState::FinWait1,
+            0x05 => TcpState::FinWait2,
+            0x06 => TcpState::TimeWait,
+            0x07 => TcpState::Close,
+            0x08 => TcpState::CloseWait,
+            0x09 => TcpState::LastAck,
+            0x0A => TcpState::Listen,
+            0x0B => TcpState::Closing,
+            0x0C => TcpState::NewSynRecv,
+            _ => TcpState::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TcpConnection {
+    pub local: SocketAddr,
+    pub peer: SocketAddr,
+    pub state: TcpState,
+    pub connected_secs: u64,
+}
+
+pub trait TcpTableSource: Send {
+    // Contents of /proc/net/tcp and /proc/net/tcp6, or canned tables in place of them
+    fn read_tables(&self) -> io::Result<Vec<String>>;
+}
+
+pub struct ProcNetTcp;
+
+impl TcpTableSource for ProcNetTcp {
+    fn read_tables(&self) -> io::Result<Vec<String>> {
+        let ipv4 = fs::read_to_string("/proc/net/tcp")?;
+        // tcp6 is missing when IPv6 is disabled
+        let ipv6 = fs::read_to_string("/proc/net/tcp6").unwrap_or_default();
+        Ok(vec![ipv4, ipv6])
+    }
+}
+
+#[derive(Clone)]
+pub struct ConnectionTracker {
+    source: Arc<Mutex<Box<dyn TcpTableSource>>>,
+    state: Arc<Mutex<TrackerState>>,
+}
+
+#[derive(Default)]
+struct TrackerState {
+    first_seen: HashMap<(SocketAddr, SocketAddr), Instant>,
+    connections: Vec<TcpConnection>,
 }
 
+impl ConnectionTracker {
+    pub fn new() -> Self {
+        ConnectionTracker::with_source(Box::new(ProcNetTcp))
+    }
+
+    pub fn with_source(source: Box<dyn TcpTableSource>) -> Self {
+        ConnectionTracker {
+            source: Arc::new(Mutex::new(source)),
+            state: Arc::new(Mutex::new(TrackerState::default())),
+        }
+    }
+
+    pub fn start(&self) {
+        let tracker = self.clone();
+        thread::spawn(move || loop {
+            if let Err(e) = tracker.poll() {
+                log::warn!("Failed to read TCP connections: {}", e);
+            }
+            thread::sleep(Duration::from_secs(1));
+        });
+    }
+
+    pub fn poll(&self) -> io::Result<()> {
+        let tables = self.source.lock().unwrap().read_tables()?;
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        let mut connections = Vec::new();
+        let mut first_seen = HashMap::new();
+        for (local, peer, tcp_state) in tables.iter().flat_map(|table| parse_table(table)) {
+            let seen = *state.first_seen.get(&(local, peer)).unwrap_or(&now);
+            first_seen.insert((local, peer), seen);
+            connections.push(TcpConnection { local, peer, state: tcp_state, connected_secs: now.duration_since(seen).as_secs() });
+        }
+        // Connections that are gone are forgotten, so a reconnect from the same port starts from zero
+        state.first_seen = first_seen;
+        state.connections = connections;
+        Ok(())
+    }
+
+    pub fn clients(&self, local_port: u16) -> Vec<TcpConnection> {
+        // Established connections to a local port, excluding the server's own loopback connections
+        self.state.lock().unwrap().connections.iter()
+            .filter(|c| c.state == TcpState::Established && c.local.port() == local_port && !c.peer.ip().is_loopback())
+            .cloned()
+            .collect()
+    }
+}
+
+impl Default for ConnectionTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn parse_table(table: &str) -> Vec<(SocketAddr, SocketAddr, TcpState)> {
+    /*
+    After a header line, each line looks like:
+      sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+       0: 0109A8C0:1388 A009A8C0:CB70 01 00000000:00000000 00:00000000 00000000     0        0 12345 ...
+    */
+    table.lines().skip(1).filter_map(|line| {
+        let mut fields = line.split_whitespace().skip(1);
+        let local = parse_address(fields.next()?)?;
+        let peer = parse_address(fields.next()?)?;
+        let state = TcpState::from_hex(fields.next()?);
+        Some((local, peer, state))
+    }).collect()
+}
+
+fn parse_address(field: &str) -> Option<SocketAddr> {
+    /*
+    The address is printed as 32-bit words in host byte order, so 192.168.9.1 is 0109A8C0 on the Pi.
+    IPv6 sockets accepting IPv4 clients report them as ::ffff:a.b.c.d, which is unwrapped to IPv4.
+    */
+    let (address, port) = field.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut bytes = Vec::with_capacity(16);
+    for word in address.as_bytes().chunks(8) {
+        if word.len() != 8 {
+            return None;
+        }
+        let word = u32::from_str_radix(std::str::from_utf8(word).ok()?, 16).ok()?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+    let ip = match bytes.len() {
+        4 => IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])),
+        16 => {
+            let octets: [u8; 16] = bytes.try_into().ok()?;
+            let ipv6 = Ipv6Addr::from(octets);
+            match ipv6.to_ipv4_mapped() {
+                Some(ipv4) => IpAddr::V4(ipv4),
+                None => IpAddr::V6(ipv6),
+            }
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rows as printed on a little-endian Pi, with the columns after the inode cut off
+    const TCP: &str = "\
+  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:1F40 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1001
+   1: 0109A8C0:1388 A009A8C0:CB70 01 00000000:00000000 00:00000000 00000000     0        0 1002
+   2: 0109A8C0:1388 A109A8C0:CB71 06 00000000:00000000 00:00000000 00000000     0        0 0
+   3: 0100007F:1388 0100007F:9C40 01 00000000:00000000 00:00000000 00000000     0        0 1003
+";
+    const TCP6: &str = "\
+  sl  local_address                         remote_address                        st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0000000000000000FFFF00000109A8C0:1388 0000000000000000FFFF0000A209A8C0:D000 01 00000000:00000000 00:00000000 00000000     0        0 1004
+   1: 000080FE000000000000000001000000:1388 000080FE000000000000000002000000:D001 01 00000000:00000000 00:00000000 00000000     0        0 1005
+   2: 000080FE000000000000000001000000:1388 000080FE000000000000000003000000:D002 08 00000000:00000000 00:00000000 00000000     0        0 1006
+";
+
+    struct Canned;
+
+    impl TcpTableSource for Canned {
+        fn read_tables(&self) -> io::Result<Vec<String>> {
+            Ok(vec![TCP.to_string(), TCP6.to_string()])
+        }
+    }
+
+    fn address(text: &str) -> SocketAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ipv4_address() {
+        assert_eq!(parse_address("0109A8C0:1388"), Some(address("192.168.9.1:5000")));
+        assert_eq!(parse_address("00000000:1F40"), Some(address("0.0.0.0:8000")));
+    }
+
+    #[test]
+    fn parses_ipv6_address() {
+        assert_eq!(parse_address("000080FE000000000000000001000000:1388"), Some(address("[fe80::1]:5000")));
+        assert_eq!(parse_address("00000000000000000000000001000000:0050"), Some(address("[::1]:80")));
+    }
+
+    #[test]
+    fn unwraps_ipv4_mapped_address() {
+        assert_eq!(parse_address("0000000000000000FFFF0000A209A8C0:D000"), Some(address("192.168.9.162:53248")));
+    }
+
+    #[test]
+    fn rejects_malformed_address() {
+        assert_eq!(parse_address("0109A8C0"), None);
+        assert_eq!(parse_address("0109A8:1388"), None);
+        assert_eq!(parse_address("ZZZZZZZZ:1388"), None);
+    }
+
+    #[test]
+    fn parses_table_states() {
+        let rows = parse_table(TCP);
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0], (address("0.0.0.0:8000"), address("0.0.0.0:0"), TcpState::Listen));
+        assert_eq!(rows[1], (address("192.168.9.1:5000"), address("192.168.9.160:52080"), TcpState::Established));
+        assert_eq!(rows[2].2, TcpState::TimeWait);
+        assert_eq!(parse_table(TCP6)[2].2, TcpState::CloseWait);
+    }
+
+    #[test]
+    fn clients_are_established_remote_connections() {
+        let tracker = ConnectionTracker::with_source(Box::new(Canned));
+        tracker.poll().unwrap();
+        let peers: Vec<SocketAddr> = tracker.clients(5000).into_iter().map(|c| c.peer).collect();
+        assert_eq!(peers, vec![address("192.168.9.160:52080"), address("192.168.9.162:53248"), address("[fe80::2]:53249")]);
+        assert!(tracker.clients(8000).is_empty());
+    }
+}
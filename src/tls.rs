@@ -0,0 +1,88 @@
+/*
+HTTPS on HTTPS_PORT, next to plain HTTP on port 8000, so recordings and tokens can't be read by other devices on the hotspot.
+
+Every camera makes its own self-signed certificate on first boot and keeps it in TLS_DIR.
+No certificate authority vouches for it, so the app pins its SHA-256 instead: the fingerprint is returned when pairing
+starts (POST /api/v1/pairing) and at GET /api/v1/tls, and the app then only trusts that certificate. For example:
+    curl --cacert /opt/velovision/tls/certificate.pem https://192.168.9.1:8443/api/v1/status
+A new certificate is only made when the files are missing, e.g. after reinstalling, and paired apps must then pair again.
+
+Built with the https feature, which is on by default.
+*/
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use rcgen::{CertificateParams, DistinguishedName, DnType, SanType};
+use sha2::{Digest, Sha256};
+use tiny_http::SslConfig;
+
+use rearview_client::encoding::hex;
+use rearview_client::types::Tls;
+
+use crate::standalone_filesystem;
+
+pub const TLS_DIR: &str = "/opt/velovision/tls";
+pub const HTTPS_PORT: u16 = 8443;
+const CERTIFICATE_FILE: &str = "certificate.pem";
+const PRIVATE_KEY_FILE: &str = "private_key.pem";
+const HOTSPOT_ADDRESS: [u8; 4] = [192, 168, 9, 1];
+
+pub struct DeviceCertificate {
+    certificate_pem: Vec<u8>,
+    private_key_pem: Vec<u8>,
+    sha256: String,
+}
+
+impl DeviceCertificate {
+    pub fn load_or_generate<P: AsRef<Path>>(dir: P) -> io::Result<DeviceCertificate> {
+        let certificate_path = dir.as_ref().join(CERTIFICATE_FILE);
+        let private_key_path = dir.as_ref().join(PRIVATE_KEY_FILE);
+        if !certificate_path.exists() || !private_key_path.exists() {
+            generate(dir.as_ref())?;
+            log::info!("Generated a new device certificate in {}", dir.as_ref().display());
+        }
+        let certificate_pem = fs::read(&certificate_path)?;
+        let private_key_pem = fs::read(&private_key_path)?;
+
+        let der = rustls_pemfile::certs(&mut certificate_pem.as_slice())?.into_iter().next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("No certificate in {}", certificate_path.display())))?;
+        let sha256 = hex(&Sha256::digest(&der));
+        Ok(DeviceCertificate { certificate_pem, private_key_pem, sha256 })
+    }
+
+    pub fn ssl_config(&self) -> SslConfig {
+        SslConfig { certificate: self.certificate_pem.clone(), private_key: self.private_key_pem.clone() }
+    }
+
+    pub fn info(&self) -> Tls {
+        Tls { https_port: HTTPS_PORT, certificate_sha256: self.sha256.clone() }
+    }
+}
+
+fn generate(dir: &Path) -> io::Result<()> {
+    /*
+    ECDSA P-256, valid for the hotspot address and the hostname, and without an expiry date worth the name:
+    the camera's clock starts at 1970 until it finds a time source, and the app pins the certificate rather than trusting dates.
+    */
+    let hostname = fs::read_to_string("/etc/hostname").map(|h| h.trim().to_string()).unwrap_or_default();
+    let mut params = CertificateParams::new(Vec::<String>::new());
+    params.subject_alt_names.push(SanType::IpAddress(HOTSPOT_ADDRESS.into()));
+    if !hostname.is_empty() {
+        params.subject_alt_names.push(SanType::DnsName(hostname.clone()));
+        params.subject_alt_names.push(SanType::DnsName(format!("{}.local", hostname)));
+    }
+    let mut name = DistinguishedName::new();
+    name.push(DnType::OrganizationName, "Velovision");
+    name.push(DnType::CommonName, format!("Velovision Rearview {}", hostname).trim());
+    params.distinguished_name = name;
+
+    let invalid = |e: rcgen::RcgenError| io::Error::other(e.to_string());
+    let certificate = rcgen::Certificate::from_params(params).map_err(invalid)?;
+    let certificate_pem = certificate.serialize_pem().map_err(invalid)?;
+
+    fs::create_dir_all(dir)?;
+    // The key is only readable by the server. The certificate is written last, so a half-finished pair is made again.
+    standalone_filesystem::write_atomically(dir.join(PRIVATE_KEY_FILE), certificate.serialize_private_key_pem().as_bytes(), 0o600)?;
+    standalone_filesystem::write_atomically(dir.join(CERTIFICATE_FILE), certificate_pem.as_bytes(), 0o644)
+}
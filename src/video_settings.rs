@@ -0,0 +1,243 @@
+/*
+Runtime-selectable video settings, persisted across reboots.
+
+Changing a setting re-renders /opt/velovision/pipelines.env right away,
+but running pipelines are left alone: the new settings take effect the next time
+streaming or standalone mode (re)starts its gstreamer service.
+*/
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::pipeline::{self, Encoder, PipelineSpec, Sink};
+use crate::standalone_filesystem;
+
+pub const VIDEO_SETTINGS_PATH: &str = "/opt/velovision/video_settings.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VideoPreset {
+    LowLatency,
+    Balanced,
+    HighQuality,
+    BatterySaver,
+}
+
+pub const ALL_PRESETS: [VideoPreset; 4] = [
+    VideoPreset::LowLatency,
+    VideoPreset::Balanced,
+    VideoPreset::HighQuality,
+    VideoPreset::BatterySaver,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum StreamCodec {
+    #[default]
+    Mjpeg,
+    H264, // byte-stream, roughly a tenth of the MJPEG bandwidth
+}
+
+pub const ALL_CODECS: [StreamCodec; 2] = [StreamCodec::Mjpeg, StreamCodec::H264];
+
+impl StreamCodec {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamCodec::Mjpeg => "mjpeg",
+            StreamCodec::H264 => "h264",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<StreamCodec> {
+        ALL_CODECS.iter().copied().find(|codec| codec.name() == name.trim())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum StreamTransport {
+    #[default]
+    Tcp, // through the stream relay on port 5000, also feeding RTSP, HLS and /stream.mjpeg
+    Srt, // H.264 in MPEG-TS, clients call the SRT listener directly
+}
+
+pub const ALL_TRANSPORTS: [StreamTransport; 2] = [StreamTransport::Tcp, StreamTransport::Srt];
+
+impl StreamTransport {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamTransport::Tcp => "tcp",
+            StreamTransport::Srt => "srt",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<StreamTransport> {
+        ALL_TRANSPORTS.iter().copied().find(|transport| transport.name() == name.trim())
+    }
+}
+
+pub use rearview_client::types::SrtSettings;
+
+impl VideoPreset {
+    pub fn name(&self) -> &'static str {
+        match self {
+            VideoPreset::LowLatency => "low-latency",
+            VideoPreset::Balanced => "balanced",
+            VideoPreset::HighQuality => "high-quality",
+            VideoPreset::BatterySaver => "battery-saver",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<VideoPreset> {
+        ALL_PRESETS.iter().copied().find(|preset| preset.name() == name.trim())
+    }
+
+    fn stream_parameters(&self, codec: StreamCodec) -> (u32, u32, u32, u8, u32) {
+        // width, height, framerate, JPEG quality, H.264 bitrate
+        // Remember that JPEG quality has a large impact on bitrate. Degradation is unnoticeable until below 20.
+        // jpegenc encodes in software and can't keep up with 1280x720 at 30 fps on a Pi, so high quality MJPEG is smaller.
+        match (self, codec) {
+            (VideoPreset::LowLatency, _) => (512, 288, 30, 25, 800_000),
+            (VideoPreset::Balanced, _) => (640, 360, 30, 30, 1_000_000),
+            (VideoPreset::HighQuality, StreamCodec::Mjpeg) => (960, 540, 20, 40, 3_000_000),
+            (VideoPreset::HighQuality, StreamCodec::H264) => (1280, 720, 30, 50, 3_000_000),
+            (VideoPreset::BatterySaver, _) => (640, 360, 15, 25, 600_000),
+        }
+    }
+
+    pub fn stream_pipeline(&self, codec: StreamCodec) -> PipelineSpec {
+        let (width, height, framerate, quality, h264_bitrate) = self.stream_parameters(codec);
+        match codec {
+            StreamCodec::Mjpeg => pipeline::mjpeg_over_tcp()
+                .resolution(width, height)
+                .framerate(framerate)
+                .encoder(Encoder::Jpeg { quality }),
+            StreamCodec::H264 => pipeline::h264_over_tcp()
+                .resolution(width, height)
+                .framerate(framerate)
+                .bitrate(h264_bitrate),
+        }
+    }
+
+    pub fn srt_pipeline(&self, srt: &SrtSettings) -> PipelineSpec {
+        // SRT always carries H.264, at the same resolution and bitrate as H.264 over TCP
+        let (width, height, framerate, _, h264_bitrate) = self.stream_parameters(StreamCodec::H264);
+        pipeline::h264_over_srt()
+            .resolution(width, height)
+            .framerate(framerate)
+            .bitrate(h264_bitrate)
+            .sink(Sink::Srt { port: crate::srt::SRT_PORT, latency_ms: srt.latency_ms, passphrase: srt.passphrase.clone() })
+    }
+
+    pub fn record_pipeline(&self) -> PipelineSpec {
+        let (width, height, framerate, level, bitrate) = match self {
+            VideoPreset::LowLatency => (1280, 720, 30, "4", 8_000_000),
+            VideoPreset::Balanced => (1280, 720, 30, "4", 8_000_000),
+            VideoPreset::HighQuality => (1920, 1080, 30, "4.1", 12_000_000),
+            VideoPreset::BatterySaver => (1280, 720, 15, "4", 4_000_000),
+        };
+        pipeline::splitmux_recording()
+            .resolution(width, height)
+            .framerate(framerate)
+            .encoder(Encoder::H264 { level, repeat_sequence_header: true })
+            .bitrate(bitrate)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VideoSettings {
+    pub preset: VideoPreset,
+    #[serde(default)]
+    pub codec: StreamCodec,
+    #[serde(default)]
+    pub transport: StreamTransport,
+    #[serde(default)]
+    pub srt: SrtSettings,
+}
+
+impl Default for VideoSettings {
+    fn default() -> Self {
+        VideoSettings {
+            preset: VideoPreset::Balanced,
+            codec: StreamCodec::Mjpeg,
+            transport: StreamTransport::Tcp,
+            srt: SrtSettings::default(),
+        }
+    }
+}
+
+impl VideoSettings {
+    pub fn load<P: AsRef<Path>>(path: P) -> VideoSettings {
+        /*
+        Falls back to defaults if the file is missing (first boot) or unreadable,
+        because a corrupted settings file must never keep the camera from working.
+        */
+        match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(settings) => settings,
+                Err(e) => {
+                    log::warn!("Ignoring invalid video settings in {}: {}", path.as_ref().display(), e);
+                    VideoSettings::default()
+                }
+            },
+            Err(_) => VideoSettings::default(),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let contents = serde_json::to_string_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        standalone_filesystem::write_atomically(path, contents.as_bytes(), 0o644)
+    }
+
+    pub fn stream_framerate(&self) -> u32 {
+        // SRT always carries H.264
+        let codec = match self.transport {
+            StreamTransport::Tcp => self.codec,
+            StreamTransport::Srt => StreamCodec::H264,
+        };
+        self.preset.stream_parameters(codec).2
+    }
+
+    pub fn stream_pipeline(&self) -> PipelineSpec {
+        match self.transport {
+            StreamTransport::Tcp => self.preset.stream_pipeline(self.codec),
+            StreamTransport::Srt => self.preset.srt_pipeline(&self.srt),
+        }
+    }
+
+    pub fn apply(&self) -> io::Result<()> {
+        // Re-render the pipelines read by the gstreamer services on their next start
+        pipeline::write_pipelines_env(pipeline::PIPELINES_ENV_PATH, &self.stream_pipeline(), &self.preset.record_pipeline())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_and_loads_settings() {
+        let dir = std::env::temp_dir().join(format!("video-settings-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("video_settings.json");
+        let settings = VideoSettings { preset: VideoPreset::HighQuality, codec: StreamCodec::H264, ..VideoSettings::default() };
+        settings.save(&path).unwrap();
+        assert_eq!(VideoSettings::load(&path), settings);
+        // Nothing is left behind by the atomic write
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn high_quality_mjpeg_is_smaller_than_h264() {
+        let mjpeg = VideoSettings { preset: VideoPreset::HighQuality, ..VideoSettings::default() };
+        let h264 = VideoSettings { codec: StreamCodec::H264, ..mjpeg.clone() };
+        assert_eq!(mjpeg.stream_framerate(), 20);
+        assert_eq!(h264.stream_framerate(), 30);
+        assert!(mjpeg.stream_pipeline().render().unwrap().contains("width=960,height=540"));
+        assert!(h264.stream_pipeline().render().unwrap().contains("width=1280,height=720"));
+    }
+}
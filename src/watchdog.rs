@@ -0,0 +1,73 @@
+/*
+systemd notification and watchdog, for velovision-supreme-server.service with Type=notify and WatchdogSec:
+systemd restarts the server when it isn't notified within WatchdogSec.
+
+The server notifies READY=1 once it answers requests, then WATCHDOG=1 every half WatchdogSec as long as
+no worker hangs, see src/supervisor.rs. A hung worker, e.g. a request thread stuck in a handler, thus gets the server restarted.
+
+Without NOTIFY_SOCKET, e.g. with `cargo run`, nothing is sent.
+*/
+use std::env;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::thread;
+use std::time::Duration;
+
+use crate::supervisor::Supervisor;
+
+pub fn timeout() -> Option<Duration> {
+    // WatchdogSec of the unit, if the watchdog is meant for this process
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec))
+}
+
+pub fn ready() {
+    if let Err(e) = notify("READY=1") {
+        log::warn!("Failed to notify systemd that the server is ready: {}", e);
+    }
+}
+
+pub fn start(supervisor: Supervisor) {
+    let Some(timeout) = timeout() else {
+        return;
+    };
+    log::info!("Notifying the systemd watchdog every {} ms", (timeout / 2).as_millis());
+    thread::spawn(move || {
+        let mut withheld = false;
+        loop {
+            let hung = supervisor.hung_workers();
+            if hung.is_empty() {
+                if let Err(e) = notify("WATCHDOG=1") {
+                    log::warn!("Failed to notify the systemd watchdog: {}", e);
+                }
+                withheld = false;
+            } else if !withheld {
+                // Logged once, systemd logs the restart itself
+                log::error!("Not notifying the systemd watchdog, hung workers: {}", hung.join(", "));
+                withheld = true;
+            }
+            thread::sleep(timeout / 2);
+        }
+    });
+}
+
+fn notify(state: &str) -> io::Result<()> {
+    let Ok(path) = env::var("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    // A leading @ is a socket in the abstract namespace
+    if let Some(name) = path.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &address)?;
+    } else {
+        socket.send_to(state.as_bytes(), &path)?;
+    }
+    Ok(())
+}
@@ -38,4 +38,17 @@ echo "Next number:"
 echo $NEXT_NUM
 
 # Launch GStreamer pipeline with the updated starting number
-gst-launch-1.0 libcamerasrc ! video/x-raw,width=1280,height=720,format=NV12,framerate=30/1 ! v4l2convert ! v4l2h264enc ! video/x-h264,level='(string)4' ! h264parse ! splitmuxsink location=/opt/velovision/standalone_videos/log%04d.mkv start-index=$NEXT_NUM max-size-time=60000000000 max-files=360 muxer=matroskamux
+# RECORD_PIPELINE is rendered by supreme-server (src/pipeline.rs) and ends with splitmuxsink,
+# so start-index can be appended as one more property of that element.
+# Until supreme-server has written pipelines.env, e.g. on first boot or if it fails to start,
+# record with the Balanced preset's pipeline instead (kept in sync by a test in src/pipeline.rs).
+DEFAULT_RECORD_PIPELINE='libcamerasrc ! video/x-raw,width=1280,height=720,format=NV12,framerate=30/1 ! v4l2convert ! v4l2h264enc extra-controls=controls,video_bitrate=8000000,repeat_sequence_header=1 ! video/x-h264,level=(string)4,stream-format=byte-stream ! h264parse ! splitmuxsink location=/opt/velovision/standalone_videos/log%04d.mkv max-size-time=60000000000 max-files=360 muxer=matroskamux'
+PIPELINES_ENV=/opt/velovision/pipelines.env
+if [[ -f $PIPELINES_ENV ]]; then
+    source $PIPELINES_ENV
+fi
+if [[ -z "$RECORD_PIPELINE" ]]; then
+    echo "No RECORD_PIPELINE in $PIPELINES_ENV, recording with the default pipeline"
+    RECORD_PIPELINE=$DEFAULT_RECORD_PIPELINE
+fi
+gst-launch-1.0 $RECORD_PIPELINE start-index=$NEXT_NUM